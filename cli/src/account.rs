@@ -0,0 +1,396 @@
+use argon2::{PasswordHasher, password_hash::SaltString};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chacha20poly1305::aead::OsRng;
+use dialoguer::{Confirm, Input, Password, theme::ColorfulTheme};
+use sanctum_shared::models::{
+    ChangePasswordFinishRequest, ChangePasswordStartRequest, DeleteAccountRequest,
+    DeleteAccountStartRequest, EmailChangeRequest, EmailChangeStartRequest, RewrappedVault,
+};
+
+use crate::{
+    crypto::{VaultKeys, derive_master_key, derive_subkeys},
+    onboarding::validate_email,
+    storage::{Metadata, db_connection, set_vault_revision, upsert_vault, wipe_db},
+    sync::{connect, connect_with, prompt_password},
+    vault::{EncryptedVault, list_vaults},
+};
+
+/// Change the master password.
+///
+/// The vault names and keys are encrypted with keys derived from the master
+/// password, so every vault is re-encrypted, on the server and locally.
+/// The items are encrypted with the vault keys and stay untouched.
+/// Every other device is logged out by the server.
+pub fn change_password() {
+    let mut conn = db_connection().expect("Failed to connect to vault database");
+
+    let password = prompt_password(&conn);
+    let old_keys = keys_for(
+        &password,
+        &Metadata::get_str(&conn, "salt")
+            .expect("Failed to retrieve master salt from metadata")
+            .unwrap(),
+    );
+
+    let new_password = Password::with_theme(&ColorfulTheme::default())
+        .with_prompt("New password")
+        .with_confirmation("Repeat new password", "Error: the passwords don't match.")
+        .validate_with(|input: &String| -> Result<(), &str> {
+            if input.chars().count() > 2 {
+                Ok(())
+            } else {
+                Err("Password must be longer than 2")
+            }
+        })
+        .interact()
+        .unwrap();
+
+    let password_salt = SaltString::generate(&mut OsRng);
+    let master_salt = SaltString::generate(&mut OsRng);
+    let new_keys = keys_for(&new_password, master_salt.as_str());
+
+    let client = connect_with(&conn, &password);
+
+    // the server refuses the change unless every one of its vaults is included
+    let server_vaults = match client.fetch_vaults() {
+        Ok(vaults) => vaults,
+        Err(e) => {
+            eprintln!("Failed to fetch vaults: {}", e);
+            let _ = client.logout();
+            return;
+        }
+    };
+    let mut vaults = Vec::with_capacity(server_vaults.len());
+    for vault in server_vaults {
+        let vault = rewrap(
+            &EncryptedVault {
+                id: vault.id,
+                encrypted_name: BASE64_STANDARD.decode(vault.encrypted_name).unwrap(),
+                encrypted_vsk: BASE64_STANDARD.decode(vault.encrypted_vault_key).unwrap(),
+                created_at: vault.created_at.unix_timestamp(),
+                updated_at: vault.updated_at.unix_timestamp(),
+                revision: vault.revision,
+            },
+            &old_keys,
+            &new_keys,
+        );
+        vaults.push(RewrappedVault {
+            id: vault.id,
+            encrypted_vault_key: BASE64_STANDARD.encode(&vault.encrypted_vsk),
+            encrypted_name: BASE64_STANDARD.encode(&vault.encrypted_name),
+            revision: vault.revision,
+        });
+    }
+
+    // the server wants to see the current password again, not just the session
+    let (login_state, login_message) =
+        sanctum_shared::login::client_start(password.as_bytes()).unwrap();
+    let (state, message) = sanctum_shared::register::client_start(new_password.as_bytes()).unwrap();
+    let response = client
+        .change_password_start(&ChangePasswordStartRequest {
+            client_start: BASE64_STANDARD.encode(message),
+            login_start: BASE64_STANDARD.encode(login_message),
+        })
+        .and_then(|response| {
+            let server_message = BASE64_STANDARD.decode(response.login.message).unwrap();
+            let login_message = sanctum_shared::login::client_finish(
+                password.as_bytes(),
+                &login_state,
+                &server_message,
+            )
+            .unwrap();
+
+            let server_message = BASE64_STANDARD
+                .decode(response.registration.server_start)
+                .unwrap();
+            let message = sanctum_shared::register::client_finish(
+                new_password.as_bytes(),
+                &state,
+                &server_message,
+            )
+            .unwrap();
+
+            client.change_password_finish(&ChangePasswordFinishRequest {
+                login_id: response.login.login_id,
+                login_finish: BASE64_STANDARD.encode(login_message),
+                client_finish: BASE64_STANDARD.encode(message),
+                credential_id: response.registration.credential_id,
+                salt: password_salt.to_string(),
+                kdf: crate::crypto::kdf_params(),
+                vaults,
+            })
+        });
+
+    let updated = match response {
+        Ok(updated) => updated,
+        Err(e) => {
+            eprintln!("Failed to change password: {}", e);
+            let _ = client.logout();
+            return;
+        }
+    };
+
+    let password_hash = argon2::Argon2::default()
+        .hash_password(new_password.as_bytes(), &password_salt)
+        .unwrap();
+
+    let tx = conn.transaction().unwrap();
+
+    Metadata::set_str(&tx, "password_hash", password_hash.to_string().as_str()).unwrap();
+    Metadata::set_str(&tx, "salt", master_salt.as_str()).unwrap();
+
+    // local vaults keep their changes, which are pushed by the next sync
+    for vault in list_vaults(&tx).unwrap() {
+        let vault = rewrap(&vault, &old_keys, &new_keys);
+        upsert_vault(
+            &tx,
+            &vault.id.to_string(),
+            &vault.encrypted_name,
+            &vault.encrypted_vsk,
+            vault.created_at,
+            vault.updated_at,
+            vault.revision,
+        )
+        .unwrap();
+    }
+    for vault in updated {
+        set_vault_revision(&tx, &vault.id.to_string(), vault.revision).unwrap();
+    }
+
+    tx.commit().unwrap();
+
+    println!("Password changed, all other devices have been logged out.");
+
+    if let Err(e) = client.logout() {
+        eprintln!("Failed to log out: {}", e);
+    }
+}
+
+/// Print the profile of the account as the server knows it.
+pub fn info() {
+    let conn = db_connection().expect("Failed to connect to vault database");
+    let client = connect(&conn);
+
+    match client.profile() {
+        Ok(profile) => {
+            println!("ID: {}", profile.id);
+            println!("Email: {}", profile.email);
+            println!("Created: {}", profile.created_at);
+            println!(
+                "KDF: Argon2id, {} KiB, {} iterations, {} lanes",
+                profile.kdf.memory_kib, profile.kdf.iterations, profile.kdf.parallelism
+            );
+            println!(
+                "Storage: {} vaults, {} items, {} bytes",
+                profile.usage.vaults, profile.usage.records, profile.usage.bytes
+            );
+            println!(
+                "Two-factor: {}",
+                if profile.totp_enabled {
+                    "authenticator app"
+                } else {
+                    "off"
+                }
+            );
+        }
+        Err(e) => eprintln!("Failed to fetch account: {}", e),
+    }
+
+    let _ = client.logout();
+}
+
+/// Change the email of the account, after confirming the new address
+/// with the code mailed to it.
+pub fn change_email() {
+    let conn = db_connection().expect("Failed to connect to vault database");
+    // the server wants to see the password again, not just the session
+    let password = prompt_password(&conn);
+    let client = connect_with(&conn, &password);
+
+    let email: String = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt("New email")
+        .validate_with(|input: &String| validate_email(input))
+        .interact_text()
+        .unwrap();
+
+    let (state, message) = sanctum_shared::login::client_start(password.as_bytes()).unwrap();
+    let response = client
+        .email_change_start(&EmailChangeStartRequest {
+            client_start: BASE64_STANDARD.encode(message),
+        })
+        .and_then(|response| {
+            let server_message = BASE64_STANDARD.decode(response.message).unwrap();
+            let message =
+                sanctum_shared::login::client_finish(password.as_bytes(), &state, &server_message)
+                    .unwrap();
+
+            client.request_email_change(&EmailChangeRequest {
+                email: email.clone(),
+                login_id: response.login_id,
+                client_finish: BASE64_STANDARD.encode(message),
+            })
+        });
+
+    if let Err(e) = response {
+        eprintln!("Failed to change email: {}", e);
+        let _ = client.logout();
+        return;
+    }
+
+    loop {
+        let code: String = Input::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Confirmation code (sent to {})", email))
+            .interact_text()
+            .unwrap();
+
+        match client.confirm_email_change(&code) {
+            Ok(()) => break,
+            Err(e) => println!("The confirmation code is invalid or expired ({})", e),
+        }
+    }
+
+    // the server stores emails in lower case, and so does the login
+    Metadata::set_str(&conn, "email", &email.to_lowercase()).unwrap();
+    println!("Email changed to {}.", email);
+
+    if let Err(e) = client.logout() {
+        eprintln!("Failed to log out: {}", e);
+    }
+}
+
+/// Set up an authenticator app, which logins ask for a code from now on.
+pub fn enable_totp() {
+    let conn = db_connection().expect("Failed to connect to vault database");
+    let client = connect(&conn);
+
+    let enrollment = match client.enroll_totp() {
+        Ok(enrollment) => enrollment,
+        Err(e) => {
+            eprintln!(
+                "Failed to set up the authenticator (is it enabled already?): {}",
+                e
+            );
+            let _ = client.logout();
+            return;
+        }
+    };
+
+    println!("Add this account to your authenticator app:");
+    println!("  {}", enrollment.uri);
+    println!("or enter the secret by hand: {}", enrollment.secret);
+
+    let codes = loop {
+        let code: String = Input::with_theme(&ColorfulTheme::default())
+            .with_prompt("Code from the authenticator")
+            .interact_text()
+            .unwrap();
+
+        match client.confirm_totp(&code) {
+            Ok(codes) => break codes.codes,
+            Err(e) => println!("The code is invalid ({})", e),
+        }
+    };
+
+    println!("Authenticator enabled. Keep these recovery codes somewhere safe,");
+    println!("each of them logs you in once when the authenticator is lost:");
+    for code in codes {
+        println!("  {}", code);
+    }
+
+    if let Err(e) = client.logout() {
+        eprintln!("Failed to log out: {}", e);
+    }
+}
+
+/// Stop asking for authenticator codes on login.
+pub fn disable_totp() {
+    let conn = db_connection().expect("Failed to connect to vault database");
+    let client = connect(&conn);
+
+    let code: String = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt("Code from the authenticator (or a recovery code)")
+        .interact_text()
+        .unwrap();
+
+    match client.disable_totp(&code) {
+        Ok(()) => println!("Authenticator disabled."),
+        Err(e) => eprintln!("Failed to disable the authenticator: {}", e),
+    }
+
+    let _ = client.logout();
+}
+
+/// Delete the account on the server and the local vault database.
+pub fn delete() {
+    let conn = db_connection().expect("Failed to connect to vault database");
+    let email = Metadata::get_str(&conn, "email")
+        .expect("Failed to retrieve email from metadata")
+        .unwrap();
+
+    let confirmed = Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!(
+            "Permanently delete the account {} and all of its vaults on every device?",
+            email
+        ))
+        .default(false)
+        .interact()
+        .unwrap();
+    if !confirmed {
+        return;
+    }
+
+    // the server wants to see the password again, not just the session
+    let password = prompt_password(&conn);
+    let client = connect_with(&conn, &password);
+
+    let (state, message) = sanctum_shared::login::client_start(password.as_bytes()).unwrap();
+    let response = client
+        .delete_account_start(&DeleteAccountStartRequest {
+            client_start: BASE64_STANDARD.encode(message),
+        })
+        .and_then(|response| {
+            let server_message = BASE64_STANDARD.decode(response.message).unwrap();
+            let message =
+                sanctum_shared::login::client_finish(password.as_bytes(), &state, &server_message)
+                    .unwrap();
+
+            client.delete_account(&DeleteAccountRequest {
+                login_id: response.login_id,
+                client_finish: BASE64_STANDARD.encode(message),
+            })
+        });
+
+    if let Err(e) = response {
+        eprintln!("Failed to delete account: {}", e);
+        let _ = client.logout();
+        return;
+    }
+
+    match wipe_db(conn) {
+        Ok(()) => println!("Account {} deleted.", email),
+        Err(e) => eprintln!(
+            "Account {} deleted, but the local data is left: {}",
+            email, e
+        ),
+    }
+}
+
+fn keys_for(password: &str, master_salt: &str) -> VaultKeys {
+    let root_key =
+        derive_master_key(password, &SaltString::from_b64(master_salt).unwrap()).unwrap();
+    derive_subkeys(&root_key)
+}
+
+/// Re-encrypt the name and key of a vault with the new keys.
+fn rewrap(vault: &EncryptedVault, old_keys: &VaultKeys, new_keys: &VaultKeys) -> EncryptedVault {
+    let mut rewrapped = EncryptedVault::encrypt(
+        vault.id,
+        &vault.decrypt_name(old_keys).unwrap(),
+        &vault.decrypt_vsk(old_keys).unwrap(),
+        vault.created_at,
+        vault.updated_at,
+        new_keys,
+    );
+    rewrapped.revision = vault.revision;
+    rewrapped
+}
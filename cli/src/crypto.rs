@@ -4,6 +4,7 @@ use chacha20poly1305::{
     aead::{Aead, KeyInit, OsRng, Payload},
 };
 use hkdf::Hkdf;
+use sanctum_shared::models::KdfParams;
 use secrecy::SecretBox;
 use sha2::Sha256;
 
@@ -11,12 +12,22 @@ use argon2::{Algorithm, Argon2, Params, Version, password_hash::SaltString};
 
 use crate::error::Error;
 
+/// OWASP recommended baseline parameters for Argon2id, told to the
+/// server so other clients derive the same master key.
+pub fn kdf_params() -> KdfParams {
+    KdfParams {
+        memory_kib: 65536, // 64 MB memory
+        iterations: 3,
+        parallelism: 4,
+    }
+}
+
 pub fn derive_master_key(password: &str, salt: &SaltString) -> Result<[u8; 32], Error> {
-    // OWASP recommended baseline parameters for Argon2id
+    let kdf = kdf_params();
     let params = Params::new(
-        65536,    // m_cost: 64 MB memory
-        3,        // t_cost: 3 iterations
-        4,        // p_cost: 4 degrees of parallelism
+        kdf.memory_kib,
+        kdf.iterations,
+        kdf.parallelism,
         Some(32), // Output length: 32 bytes (256 bits) for XChaCha20
     )?;
 
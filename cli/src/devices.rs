@@ -0,0 +1,56 @@
+use uuid::Uuid;
+
+use crate::{storage::db_connection, sync::connect};
+
+/// Print the devices (sessions) that are logged in to the account.
+pub fn list() {
+    let conn = db_connection().expect("Failed to connect to vault database");
+    let client = connect(&conn);
+
+    let sessions = match client.list_sessions() {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            eprintln!("Failed to list devices: {}", e);
+            return;
+        }
+    };
+
+    for session in sessions {
+        // the session of this command only lives until it is done
+        if session.current {
+            continue;
+        }
+        println!(
+            "ID: {}, Device: {}, Platform: {}, Created: {}, Last seen: {}",
+            session.id,
+            session.device_name.as_deref().unwrap_or("unknown"),
+            session.platform.as_deref().unwrap_or("unknown"),
+            session.created_at,
+            session.last_seen_at,
+        );
+    }
+
+    let _ = client.logout();
+}
+
+/// Log out the device with the given session id, or every other device.
+pub fn revoke(id: Option<Uuid>, all: bool) {
+    let conn = db_connection().expect("Failed to connect to vault database");
+    let client = connect(&conn);
+
+    let result = match (id, all) {
+        (Some(id), false) => client.revoke_session(&id),
+        (None, true) => client.revoke_other_sessions(),
+        _ => {
+            eprintln!("Pass either a device id or --all");
+            return;
+        }
+    };
+
+    match result {
+        Ok(()) => println!("Device logged out"),
+        Err(e) => eprintln!("Failed to log out device: {}", e),
+    }
+
+    let _ = client.logout();
+}
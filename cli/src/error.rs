@@ -1,3 +1,6 @@
+use reqwest::StatusCode;
+use sanctum_shared::models::{ErrorCode, ErrorResponse};
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Storage error: {0}")]
@@ -12,6 +15,46 @@ pub enum Error {
     ItemNotFound(String),
     #[error("String Conversion: {0}")]
     UTF8Error(#[from] std::string::FromUtf8Error),
-    #[error("API error: {0}")]
-    ApiError(#[from] reqwest::Error),
+    #[error("Failed to reach the server: {0}")]
+    Http(#[from] reqwest::Error),
+    /// The server answered with an error, match on the `code`.
+    #[error("{message}")]
+    Api {
+        status: StatusCode,
+        code: ErrorCode,
+        message: String,
+        request_id: Option<String>,
+    },
+}
+
+impl Error {
+    /// Pass a successful `response` on, and turn an error answer into
+    /// [`Error::Api`].
+    pub fn check(
+        response: reqwest::blocking::Response,
+    ) -> Result<reqwest::blocking::Response, Error> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        let body = response.bytes()?;
+        Err(match serde_json::from_slice::<ErrorResponse>(&body) {
+            Ok(error) => Error::Api {
+                status,
+                code: error.code,
+                message: error.message,
+                request_id: Some(error.request_id),
+            },
+            Err(_) => Error::Api {
+                status,
+                code: ErrorCode::Unknown,
+                message: status
+                    .canonical_reason()
+                    .unwrap_or("Request failed")
+                    .to_string(),
+                request_id: None,
+            },
+        })
+    }
 }
@@ -8,7 +8,9 @@ use crate::{
 };
 
 // #![allow(unused)]
+pub mod account;
 pub mod crypto;
+pub mod devices;
 pub mod error;
 pub mod onboarding;
 pub mod password;
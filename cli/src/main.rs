@@ -6,6 +6,7 @@ use cli::record::Entry;
 use cli::password::{generate_password, score_password};
 use cli::sync::sync;
 use cli::vault::create_vault;
+use uuid::Uuid;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -31,6 +32,56 @@ enum Commands {
         cmd: ItemCommand,
     },
     Sync {},
+    /// Manage your account
+    Account {
+        #[command(subcommand)]
+        cmd: AccountCommand,
+    },
+    /// Manage the devices logged in to your account
+    Devices {
+        #[command(subcommand)]
+        cmd: DevicesCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum AccountCommand {
+    /// Show your account as the server knows it
+    Info,
+    /// Change the master password, logging out all other devices
+    ChangePassword,
+    /// Change the email of your account
+    ChangeEmail,
+    /// Delete your account and all of its data, here and on the server
+    Delete,
+    /// Ask for a code from an authenticator app on login
+    Totp {
+        #[command(subcommand)]
+        cmd: TotpCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum TotpCommand {
+    /// Set up an authenticator app and get recovery codes
+    Enable,
+    /// Stop asking for authenticator codes
+    Disable,
+}
+
+#[derive(Subcommand)]
+enum DevicesCommand {
+    /// List all other devices logged in to your account
+    List,
+    /// Log out a device, e.g. a lost laptop
+    Revoke {
+        /// The id of the device, as shown by `devices list`
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        id: Option<Uuid>,
+        /// Log out every other device
+        #[arg(long)]
+        all: bool,
+    },
 }
 
 #[derive(Subcommand)]
@@ -237,5 +288,19 @@ fn main() {
             }
         }
         Commands::Sync {} => sync(),
+        Commands::Account { cmd } => match cmd {
+            AccountCommand::Info => cli::account::info(),
+            AccountCommand::ChangePassword => cli::account::change_password(),
+            AccountCommand::ChangeEmail => cli::account::change_email(),
+            AccountCommand::Delete => cli::account::delete(),
+            AccountCommand::Totp { cmd } => match cmd {
+                TotpCommand::Enable => cli::account::enable_totp(),
+                TotpCommand::Disable => cli::account::disable_totp(),
+            },
+        },
+        Commands::Devices { cmd } => match cmd {
+            DevicesCommand::List => cli::devices::list(),
+            DevicesCommand::Revoke { id, all } => cli::devices::revoke(id, all),
+        },
     }
 }
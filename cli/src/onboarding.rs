@@ -5,7 +5,7 @@ use directories::ProjectDirs;
 
 use crate::{
     crypto::{derive_master_key, derive_subkeys},
-    remote::register,
+    remote::{confirm_registration, register},
     storage::{Metadata, init_schema, open_vault_db},
     vault::create_vault,
 };
@@ -63,19 +63,21 @@ pub fn onboard() {
     println!("Onboarding complete.");
 }
 
+/// Accept plain addresses like `user@example.com`, the server refuses
+/// anything else.
+pub(crate) fn validate_email(input: &str) -> Result<(), &'static str> {
+    let options = email_address::Options::default().without_display_text();
+    match email_address::EmailAddress::parse_with_options(input, options) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("This is not a mail address"),
+    }
+}
+
 fn create_account(mut conn: rusqlite::Connection) {
     println!("Create new account");
     let email: String = Input::with_theme(&ColorfulTheme::default())
         .with_prompt("Email")
-        .validate_with({
-            move |input: &String| -> Result<(), &str> {
-                if input.contains('@') {
-                    Ok(())
-                } else {
-                    Err("This is not a mail address")
-                }
-            }
-        })
+        .validate_with(|input: &String| validate_email(input))
         .interact_text()
         .unwrap();
 
@@ -96,6 +98,18 @@ fn create_account(mut conn: rusqlite::Connection) {
 
     println!("Creating online account...");
     register(&email, &password, &password_salt).unwrap();
+
+    loop {
+        let code: String = Input::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Confirmation code (sent to {})", email))
+            .interact_text()
+            .unwrap();
+
+        match confirm_registration(&code) {
+            Ok(()) => break,
+            Err(e) => println!("{}", e),
+        }
+    }
     println!("Online account created successfully.");
 
     let argon2 = argon2::Argon2::default();
@@ -1,7 +1,9 @@
 use argon2::password_hash::SaltString;
 use base64::{Engine, prelude::BASE64_STANDARD};
+use dialoguer::{Input, theme::ColorfulTheme};
 use sanctum_shared::models::{
-    LoginFinishRequest, LoginFinishResponse, LoginStartRequest, LoginStartResponse,
+    LoginFinishRequest, LoginFinishResponse, LoginResult, LoginStartRequest, LoginStartResponse,
+    MfaChallenge, MfaMethod, MfaVerifyRequest, RegistrationConfirmRequest,
     RegistrationFinishRequest, RegistrationStartRequest, RegistrationStartResponse,
 };
 
@@ -44,16 +46,42 @@ pub fn register(email: &str, password: &str, salt: &SaltString) -> Result<(), St
             email: email.to_string(),
             salt: salt.to_string(),
             client_finish: BASE64_STANDARD.encode(message),
+            credential_id: response.credential_id,
+            kdf: crate::crypto::kdf_params(),
         })
         .send()
         .unwrap()
         .status();
 
-    dbg!(status);
+    // the account only exists once the code mailed to `email` is confirmed
+    if status != 202 {
+        return Err("Registration finish failed".to_string());
+    }
 
     Ok(())
 }
 
+/// Create the account registered with [`register`], using the
+/// code that was mailed to its email address.
+pub fn confirm_registration(code: &str) -> Result<(), String> {
+    let client = reqwest::blocking::Client::new();
+
+    let status = client
+        .post("http://localhost:3000/api/v1/auth/register/confirm")
+        .json(&RegistrationConfirmRequest {
+            code: code.trim().to_string(),
+        })
+        .send()
+        .map_err(|e| e.to_string())?
+        .status();
+
+    match status.as_u16() {
+        201 => Ok(()),
+        409 => Err("The account already exists".to_string()),
+        _ => Err("The confirmation code is invalid or expired".to_string()),
+    }
+}
+
 pub fn login(email: &str, password: &str) -> Result<LoginFinishResponse, String> {
     let client = reqwest::blocking::Client::new();
 
@@ -78,13 +106,86 @@ pub fn login(email: &str, password: &str) -> Result<LoginFinishResponse, String>
     let response = client
         .post("http://localhost:3000/api/v1/auth/login/finish")
         .json(&LoginFinishRequest {
+            login_id: response.login_id,
             email: email.to_string(),
             client_finish: BASE64_STANDARD.encode(message_bytes),
+            device_name: device_name(),
+            platform: Some(std::env::consts::OS.to_string()),
         })
         .send()
         .unwrap()
-        .json::<LoginFinishResponse>()
+        .json::<LoginResult>()
         .unwrap();
 
-    Ok(response)
+    match response {
+        LoginResult::Done(response) => Ok(response),
+        LoginResult::MfaRequired(challenge) => second_factor(&client, challenge),
+    }
+}
+
+/// Wrong codes a login gets before it gives up.
+const SECOND_FACTOR_ATTEMPTS: usize = 3;
+
+/// Ask for a TOTP or recovery code, for accounts with a second factor.
+fn second_factor(
+    client: &reqwest::blocking::Client,
+    challenge: MfaChallenge,
+) -> Result<LoginFinishResponse, String> {
+    // there is no way to talk to security keys from here
+    if !challenge.methods.contains(&MfaMethod::Totp) {
+        return Err(
+            "This account asks for a security key, which the CLI doesn't support".to_string(),
+        );
+    }
+    let recovery = challenge.methods.contains(&MfaMethod::RecoveryCode);
+
+    for _ in 0..SECOND_FACTOR_ATTEMPTS {
+        let code: String = Input::with_theme(&ColorfulTheme::default())
+            .with_prompt(if recovery {
+                "Authenticator code (or a recovery code)"
+            } else {
+                "Authenticator code"
+            })
+            .interact_text()
+            .unwrap();
+        let code = code.trim().to_string();
+
+        // authenticator codes are digits only, recovery codes never are
+        let path = if recovery && !code.chars().all(|c| c.is_ascii_digit()) {
+            "recovery"
+        } else {
+            "totp"
+        };
+
+        let response = client
+            .post(format!("http://localhost:3000/api/v1/auth/mfa/{}", path))
+            .json(&MfaVerifyRequest {
+                mfa_token: challenge.mfa_token.clone(),
+                code,
+            })
+            .send()
+            .map_err(|e| e.to_string())?;
+
+        match response.status().as_u16() {
+            200 => return response.json().map_err(|e| e.to_string()),
+            401 => println!("The code is invalid"),
+            429 => return Err("Too many failed attempts, try again later".to_string()),
+            status => return Err(format!("Second factor failed with status {}", status)),
+        }
+    }
+
+    Err("Too many invalid codes".to_string())
+}
+
+/// The host name, to tell this device apart in the list of sessions.
+fn device_name() -> Option<String> {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| {
+            std::fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|name| name.trim().to_string())
+        })
+        .filter(|name| !name.is_empty())
 }
@@ -1,7 +1,7 @@
 use directories::ProjectDirs;
 use rusqlite::{Connection, Result};
 use std::{
-    path::Path,
+    path::{Path, PathBuf},
     time::{SystemTime, UNIX_EPOCH},
 };
 
@@ -21,23 +21,51 @@ pub fn open_vault_db<P: AsRef<Path>>(db_path: P) -> Result<Connection> {
     Ok(conn)
 }
 
-pub fn db_connection() -> Result<rusqlite::Connection, String> {
+/// The path of `vault.db` in the data directory.
+pub fn db_path() -> Result<PathBuf, String> {
     let Some(project_dirs) = ProjectDirs::from("dev", "lucalewin", "sanctum") else {
         return Err("Could not determine project directories.".to_string());
     };
 
-    let data_dir = project_dirs.data_dir();
+    Ok(project_dirs.data_dir().join("vault.db"))
+}
 
-    let conn = match crate::storage::open_vault_db(data_dir.join("vault.db")) {
+pub fn db_connection() -> Result<rusqlite::Connection, String> {
+    let conn = match crate::storage::open_vault_db(db_path()?) {
         Ok(conn) => conn,
         Err(e) => {
             return Err(format!("Failed to open vault database: {}", e));
         }
     };
 
+    // databases created by older versions need to be upgraded
+    if let Err(e) = init_schema(&conn) {
+        return Err(format!("Failed to upgrade vault database: {}", e));
+    }
+
     Ok(conn)
 }
 
+/// Close the connection and delete the vault database, along with
+/// the files SQLite keeps next to it in WAL mode.
+pub fn wipe_db(conn: Connection) -> Result<(), String> {
+    conn.close()
+        .map_err(|(_, e)| format!("Failed to close vault database: {}", e))?;
+
+    let path = db_path()?;
+    for suffix in ["", "-wal", "-shm"] {
+        let mut file = path.clone().into_os_string();
+        file.push(suffix);
+        let file = PathBuf::from(file);
+        match std::fs::remove_file(&file) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Failed to delete {}: {}", file.display(), e)),
+        }
+    }
+    Ok(())
+}
+
 pub fn init_schema(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
     conn.execute_batch(
         "
@@ -64,6 +92,27 @@ pub fn init_schema(conn: &Connection) -> Result<(), Box<dyn std::error::Error>>
         ) STRICT;
         ",
     )?;
+    migrate_schema(conn)?;
+    Ok(())
+}
+
+/// Bring the tables created by [`init_schema`] up to date.
+///
+/// Every migration bumps `PRAGMA user_version`, so each one only ever runs once.
+fn migrate_schema(conn: &Connection) -> Result<()> {
+    let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    if version < 1 {
+        // the server revision each vault and item is based on, 0 if never synced
+        conn.execute_batch(
+            "
+            ALTER TABLE vaults ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE items ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;
+            PRAGMA user_version = 1;
+            ",
+        )?;
+    }
+
     Ok(())
 }
 
@@ -85,9 +134,11 @@ pub fn create_vault(
     Ok(())
 }
 
-pub fn list_vaults(conn: &Connection) -> Result<Vec<(String, Vec<u8>, Vec<u8>, i64, i64)>> {
-    let mut stmt = conn
-        .prepare("SELECT id, encrypted_name, encrypted_vsk, created_at, updated_at FROM vaults")?;
+#[allow(clippy::type_complexity)]
+pub fn list_vaults(conn: &Connection) -> Result<Vec<(String, Vec<u8>, Vec<u8>, i64, i64, i64)>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, encrypted_name, encrypted_vsk, created_at, updated_at, revision FROM vaults",
+    )?;
     let vault_iter = stmt.query_map([], |row| {
         Ok((
             row.get(0)?,
@@ -95,6 +146,7 @@ pub fn list_vaults(conn: &Connection) -> Result<Vec<(String, Vec<u8>, Vec<u8>, i
             row.get(2)?,
             row.get(3)?,
             row.get(4)?,
+            row.get(5)?,
         ))
     })?;
 
@@ -105,6 +157,35 @@ pub fn list_vaults(conn: &Connection) -> Result<Vec<(String, Vec<u8>, Vec<u8>, i
     Ok(vaults)
 }
 
+pub fn upsert_vault(
+    conn: &Connection,
+    id: &str,
+    encrypted_name: &[u8],
+    encrypted_vsk: &[u8],
+    created_at: i64,
+    updated_at: i64,
+    revision: i64,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO vaults (id, encrypted_name, encrypted_vsk, created_at, updated_at, revision) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT(id) DO UPDATE SET
+            encrypted_name = excluded.encrypted_name,
+            encrypted_vsk = excluded.encrypted_vsk,
+            updated_at = excluded.updated_at,
+            revision = excluded.revision",
+        (id, encrypted_name, encrypted_vsk, created_at, updated_at, revision),
+    )?;
+    Ok(())
+}
+
+pub fn set_vault_revision(conn: &Connection, vault_id: &str, revision: i64) -> Result<()> {
+    conn.execute(
+        "UPDATE vaults SET revision = ?2 WHERE id = ?1",
+        (vault_id, revision),
+    )?;
+    Ok(())
+}
+
 pub fn delete_vault(conn: &Connection, vault_id: &str) -> Result<()> {
     conn.execute("DELETE FROM vaults WHERE id = ?1", [vault_id])?;
     Ok(())
@@ -126,12 +207,51 @@ pub fn create_record(conn: &Connection, vault_id: &str, encrypted_payload: &[u8]
     Ok(())
 }
 
-pub fn list_records(conn: &Connection, vault_id: &str) -> Result<Vec<(String, Vec<u8>, u32, u32)>> {
+pub fn upsert_record(
+    conn: &Connection,
+    id: &str,
+    vault_id: &str,
+    encrypted_payload: &[u8],
+    created_at: i64,
+    updated_at: i64,
+    revision: i64,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO items (id, vault_id, encrypted_payload, created_at, updated_at, revision) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT(id) DO UPDATE SET
+            vault_id = excluded.vault_id,
+            encrypted_payload = excluded.encrypted_payload,
+            updated_at = excluded.updated_at,
+            revision = excluded.revision",
+        (id, vault_id, encrypted_payload, created_at, updated_at, revision),
+    )?;
+    Ok(())
+}
+
+pub fn set_record_revision(conn: &Connection, item_id: &str, revision: i64) -> Result<()> {
+    conn.execute(
+        "UPDATE items SET revision = ?2 WHERE id = ?1",
+        (item_id, revision),
+    )?;
+    Ok(())
+}
+
+#[allow(clippy::type_complexity)]
+pub fn list_records(
+    conn: &Connection,
+    vault_id: &str,
+) -> Result<Vec<(String, Vec<u8>, u32, u32, i64)>> {
     let mut stmt = conn.prepare(
-        "SELECT id, encrypted_payload, created_at, updated_at FROM items WHERE vault_id = ?1 ORDER BY updated_at DESC",
+        "SELECT id, encrypted_payload, created_at, updated_at, revision FROM items WHERE vault_id = ?1 ORDER BY updated_at DESC",
     )?;
     let record_iter = stmt.query_map([vault_id], |row| {
-        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        Ok((
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+        ))
     })?;
 
     let mut records = Vec::new();
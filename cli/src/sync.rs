@@ -1,23 +1,247 @@
-use std::time::{SystemTime, UNIX_EPOCH};
-
 use argon2::{PasswordHash, PasswordVerifier};
 use dialoguer::{Password, theme::ColorfulTheme};
+use rusqlite::Connection;
 
 use crate::{
     remote::login,
-    storage::{Metadata, db_connection, list_records},
-    vault::list_vaults,
+    storage::{
+        Metadata, db_connection, list_records, set_record_revision, set_vault_revision,
+        upsert_record, upsert_vault,
+    },
+    vault::{EncryptedVault, list_vaults},
 };
 
 pub fn sync() {
     println!("sync");
 
     let conn = db_connection().expect("Failed to connect to vault database");
-    let password_hash = Metadata::get_str(&conn, "password_hash")
+    let client = connect(&conn);
+
+    let last_sync_timestamp: u32 = Metadata::get_str(&conn, "last_sync_timestamp")
+        .unwrap()
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    let vaults = list_vaults(&conn).unwrap();
+
+    // items whose local changes didn't reach the server,
+    // the pull must not overwrite them
+    let mut unsent = HashSet::new();
+
+    for vault in vaults {
+        // never synced ones are pushed however old they are
+        if vault.updated_at > last_sync_timestamp as i64 || vault.revision == 0 {
+            match push_vault(&client, &conn, &vault) {
+                Ok(()) => {}
+                // deleted on another device, which takes its items with it,
+                // the pull removes them here as well
+                Err(Error::Api {
+                    code: ErrorCode::VaultNotFound,
+                    ..
+                }) => continue,
+                Err(e) => {
+                    eprintln!("Failed to push vault {}: {}", vault.id, e);
+                    unsent.insert(vault.id);
+                }
+            }
+        }
+
+        let items = list_records(&conn, &vault.id.to_string()).unwrap();
+
+        for item in items {
+            if item.3 > last_sync_timestamp || item.4 == 0 {
+                let record = CreateRecordRequest {
+                    // items are encrypted directly with the vault key
+                    encrypted_record_key: String::new(),
+                    encrypted_data_blob: BASE64_STANDARD.encode(&item.1),
+                };
+                let item_id = Uuid::parse_str(&item.0).unwrap();
+                let pushed = match client.update_record(&vault.id, &item_id, item.4, &record) {
+                    // deleted on another device in the meantime, the edit brings it back
+                    Err(Error::Api {
+                        code: ErrorCode::RecordNotFound,
+                        ..
+                    }) if item.4 != 0 => client.update_record(&vault.id, &item_id, 0, &record),
+                    result => result,
+                };
+                match pushed {
+                    Ok(updated) => set_record_revision(&conn, &item.0, updated.revision).unwrap(),
+                    // the pull brings the other device's version, ours is kept next to it
+                    Err(e) if is_conflict(&e) => {
+                        match keep_copy(&client, &conn, &vault.id, &item, &record) {
+                            Ok(copy_id) => eprintln!(
+                                "Item {} was changed on another device, your version is kept as {}",
+                                item_id, copy_id
+                            ),
+                            Err(e) => eprintln!("Failed to push item {}: {}", item_id, e),
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to push item {}: {}", item_id, e);
+                        unsent.insert(item_id);
+                    }
+                }
+            }
+        }
+    }
+
+    // pull everything that changed on the server since the last sync
+    let changes = client.sync(Some(last_sync_timestamp as i64)).unwrap();
+
+    // deletions first, an id may have been deleted and re-created since
+    for tombstone in changes.tombstones {
+        match tombstone {
+            Tombstone::Vault { id, .. } if !unsent.contains(&id) => {
+                crate::storage::delete_vault(&conn, &id.to_string()).unwrap();
+            }
+            Tombstone::Record { id, vault_id, .. } if !unsent.contains(&id) => {
+                crate::storage::delete_record(&conn, &vault_id.to_string(), &id.to_string())
+                    .unwrap();
+            }
+            _ => {}
+        }
+    }
+
+    if changes.full {
+        // deletions may be missing, so whatever was synced before
+        // and the server doesn't have any more is gone
+        let known = changes
+            .vaults
+            .iter()
+            .map(|vault| vault.id)
+            .chain(changes.records.iter().map(|record| record.id))
+            .collect::<HashSet<_>>();
+        for vault in list_vaults(&conn).unwrap() {
+            if vault.revision != 0 && !known.contains(&vault.id) && !unsent.contains(&vault.id) {
+                crate::storage::delete_vault(&conn, &vault.id.to_string()).unwrap();
+                continue;
+            }
+            for item in list_records(&conn, &vault.id.to_string()).unwrap() {
+                let item_id = Uuid::parse_str(&item.0).unwrap();
+                if item.4 != 0 && !known.contains(&item_id) && !unsent.contains(&item_id) {
+                    crate::storage::delete_record(&conn, &vault.id.to_string(), &item.0).unwrap();
+                }
+            }
+        }
+    }
+
+    for vault in changes.vaults {
+        if unsent.contains(&vault.id) {
+            continue;
+        }
+        upsert_vault(
+            &conn,
+            &vault.id.to_string(),
+            &BASE64_STANDARD.decode(vault.encrypted_name).unwrap(),
+            &BASE64_STANDARD.decode(vault.encrypted_vault_key).unwrap(),
+            vault.created_at.unix_timestamp(),
+            vault.updated_at.unix_timestamp(),
+            vault.revision,
+        )
+        .unwrap();
+    }
+
+    for record in changes.records {
+        if unsent.contains(&record.id) {
+            continue;
+        }
+        upsert_record(
+            &conn,
+            &record.id.to_string(),
+            &record.vault_id.to_string(),
+            &BASE64_STANDARD.decode(record.encrypted_data_blob).unwrap(),
+            record.created_at.unix_timestamp(),
+            record.updated_at.unix_timestamp(),
+            record.revision,
+        )
+        .unwrap();
+    }
+
+    // otherwise the next sync pushes the unsent changes again
+    if unsent.is_empty() {
+        Metadata::set_str(&conn, "last_sync_timestamp", &changes.cursor.to_string()).unwrap();
+    }
+
+    // every sync logs in again, don't leave the session lying around
+    if let Err(e) = client.logout() {
+        eprintln!("Failed to log out: {}", e);
+    }
+}
+
+/// Push a vault, on top of the server's version if it was changed on
+/// another device in the meantime.
+fn push_vault(client: &ApiClient, conn: &Connection, vault: &EncryptedVault) -> Result<(), Error> {
+    let request = CreateVaultRequest {
+        encrypted_vault_key: BASE64_STANDARD.encode(&vault.encrypted_vsk),
+        encrypted_name: BASE64_STANDARD.encode(&vault.encrypted_name),
+    };
+    let updated = match client.update_vault(vault.id, vault.revision, &request) {
+        // the name is the only thing edited locally, the vault key is
+        // rewrapped by password changes and the server's wrapping is current
+        Err(e) if is_conflict(&e) => {
+            let remote = client.fetch_vault(&vault.id)?;
+            let request = CreateVaultRequest {
+                encrypted_vault_key: remote.encrypted_vault_key,
+                ..request
+            };
+            client.update_vault(vault.id, remote.revision, &request)?
+        }
+        result => result?,
+    };
+    set_vault_revision(conn, &vault.id.to_string(), updated.revision)?;
+    Ok(())
+}
+
+/// Store the local version of an item that was changed on another device
+/// as a new item, and push it. Returns the id of the copy.
+fn keep_copy(
+    client: &ApiClient,
+    conn: &Connection,
+    vault_id: &Uuid,
+    item: &(String, Vec<u8>, u32, u32, i64),
+    record: &CreateRecordRequest,
+) -> Result<Uuid, Error> {
+    let copy_id = Uuid::new_v4();
+    // with revision 0 the next sync pushes it if this one fails
+    upsert_record(
+        conn,
+        &copy_id.to_string(),
+        &vault_id.to_string(),
+        &item.1,
+        item.2 as i64,
+        item.3 as i64,
+        0,
+    )?;
+    let created = client.update_record(vault_id, &copy_id, 0, record)?;
+    set_record_revision(conn, &copy_id.to_string(), created.revision)?;
+    Ok(copy_id)
+}
+
+/// Whether the server refused a write because its copy changed since the
+/// revision it was based on.
+fn is_conflict(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::Api { status, .. }
+            if *status == StatusCode::PRECONDITION_FAILED
+                || *status == StatusCode::PRECONDITION_REQUIRED
+    )
+}
+
+/// Ask for the master password and log in to the server.
+pub(crate) fn connect(conn: &Connection) -> ApiClient {
+    let password = prompt_password(conn);
+    connect_with(conn, &password)
+}
+
+/// Ask for the master password until it matches the local password hash.
+pub(crate) fn prompt_password(conn: &Connection) -> String {
+    let password_hash = Metadata::get_str(conn, "password_hash")
         .expect("Failed to retrieve password hash from metadata")
         .unwrap();
 
-    let password = Password::with_theme(&ColorfulTheme::default())
+    Password::with_theme(&ColorfulTheme::default())
         .with_prompt("Password")
         .report(false)
         .validate_with(|input: &String| -> Result<(), &str> {
@@ -32,110 +256,283 @@ pub fn sync() {
             }
         })
         .interact()
-        .unwrap();
+        .unwrap()
+}
 
-    let email = Metadata::get_str(&conn, "email")
+/// Log in to the server with the (already checked) master password.
+pub(crate) fn connect_with(conn: &Connection, password: &str) -> ApiClient {
+    let email = Metadata::get_str(conn, "email")
         .expect("Failed to retrieve email from metadata")
         .unwrap();
 
-    let a = login(&email, &password).unwrap();
+    let a = login(&email, password).unwrap();
 
-    let last_sync_timestamp: u32 = Metadata::get_str(&conn, "last_sync_timestamp")
-        .unwrap()
-        .unwrap()
-        .parse()
-        .unwrap();
-
-    let client = ApiClient::new("http://localhost:3000".to_string(), a.access_token);
+    let client = ApiClient::new(
+        "http://localhost:3000".to_string(),
+        a.access_token,
+        a.refresh_token,
+    );
 
-    let vaults = list_vaults(&conn).unwrap();
-
-    for vault in vaults {
-        dbg!(vault.updated_at, last_sync_timestamp);
-        if vault.updated_at > last_sync_timestamp as i64 {
-            client.update_vault(
-                vault.id,
-                &CreateVaultRequest {
-                    encrypted_vault_key: base64::encode(vault.encrypted_vsk),
-                    encrypted_name: base64::encode(vault.encrypted_name),
-                },
-            );
-        }
-
-        let items = list_records(&conn, &vault.id.to_string()).unwrap();
-
-        for item in items {
-            if item.3 > last_sync_timestamp {
-                let record = CreateRecordRequest {
-                    encrypted_data_blob: base64::encode(item.1),
-                };
-                let item_id = Uuid::parse_str(&item.0).unwrap();
-                client.update_record(&vault.id, &item_id, &record).unwrap();
-            }
+    if a.reregister {
+        // the server asks again on the next login if this fails
+        if let Err(e) = reregister(&client, password) {
+            eprintln!("Failed to register password again: {}", e);
         }
     }
-    let current_timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_secs() as i64;
 
-    Metadata::set_str(&conn, "last_sync_timestamp", &current_timestamp.to_string()).unwrap();
+    client
+}
 
-    // GET https://sanctum.dev/api/v1/sync?since=2024-06-01T00:00:00Z
-    // GET https://sanctum.dev/api/v1/me
+/// Register the password again under a credential id, for accounts whose
+/// password file is still bound to their email.
+fn reregister(client: &ApiClient, password: &str) -> Result<(), Error> {
+    let (login_state, login_message) = sanctum_shared::login::client_start(password.as_bytes())
+        .map_err(|e| Error::CryptoError(e.to_string()))?;
+    let (state, message) = sanctum_shared::register::client_start(password.as_bytes())
+        .map_err(|e| Error::CryptoError(e.to_string()))?;
+    let response = client.reregister_start(&ReregisterStartRequest {
+        client_start: BASE64_STANDARD.encode(message),
+        login_start: BASE64_STANDARD.encode(login_message),
+    })?;
+
+    // the server wants to see the password again, not just the session
+    let login_message = BASE64_STANDARD
+        .decode(response.login.message)
+        .map_err(|e| Error::CryptoError(e.to_string()))?;
+    let login_message =
+        sanctum_shared::login::client_finish(password.as_bytes(), &login_state, &login_message)
+            .map_err(|e| Error::CryptoError(e.to_string()))?;
+    let server_message = BASE64_STANDARD
+        .decode(response.registration.server_start)
+        .map_err(|e| Error::CryptoError(e.to_string()))?;
+    let message =
+        sanctum_shared::register::client_finish(password.as_bytes(), &state, &server_message)
+            .map_err(|e| Error::CryptoError(e.to_string()))?;
+
+    client.reregister_finish(&ReregisterFinishRequest {
+        login_id: response.login.login_id,
+        login_finish: BASE64_STANDARD.encode(login_message),
+        client_finish: BASE64_STANDARD.encode(message),
+    })
 }
 
-use sanctum_shared::models::{CreateRecordRequest, CreateVaultRequest, Record, Vault};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use reqwest::StatusCode;
+use sanctum_shared::models::{
+    ChangePasswordFinishRequest, ChangePasswordStartRequest, ChangePasswordStartResponse,
+    CreateRecordRequest, CreateVaultRequest, DeleteAccountRequest, DeleteAccountResponse,
+    DeleteAccountStartRequest, EmailChangeConfirmRequest, EmailChangeRequest,
+    EmailChangeStartRequest, ErrorCode, LoginStartResponse, Profile, Record, RecoveryCodes,
+    RefreshRequest, RefreshResponse, ReregisterFinishRequest, ReregisterStartRequest,
+    ReregisterStartResponse, SessionInfo, SyncResponse, Tombstone, TotpCodeRequest, TotpEnrollment,
+    Vault,
+};
 use serde::de::DeserializeOwned;
 use uuid::Uuid;
 
 use crate::error::Error;
 
+#[derive(Debug)]
+struct Tokens {
+    access_token: String,
+    refresh_token: String,
+}
+
 #[derive(Debug, Clone)]
 pub struct ApiClient {
     base_url: String,
-    access_token: String,
+    tokens: Arc<Mutex<Tokens>>,
     client: reqwest::blocking::Client,
 }
 
 #[allow(unused)]
 impl ApiClient {
-    pub fn new(base_url: String, access_token: String) -> Self {
+    pub fn new(base_url: String, access_token: String, refresh_token: String) -> Self {
         Self {
             base_url,
-            access_token,
+            tokens: Arc::new(Mutex::new(Tokens {
+                access_token,
+                refresh_token,
+            })),
             client: reqwest::blocking::Client::new(),
         }
     }
 
-    fn request_json<T: DeserializeOwned>(
+    /// Send an authenticated request. When the access token has expired,
+    /// it is refreshed and the request is sent once more.
+    fn send(
         &self,
         request: reqwest::blocking::RequestBuilder,
-    ) -> Result<T, Error> {
-        let response = request
-            .bearer_auth(&self.access_token)
-            .send()
-            .map_err(|e| Error::ApiError(e))?;
+    ) -> Result<reqwest::blocking::Response, Error> {
+        let retry = request.try_clone();
+        let access_token = self.tokens.lock().unwrap().access_token.clone();
 
-        let data = response
-            .error_for_status()
-            .map_err(|e| Error::ApiError(e))?
-            .json()
-            .map_err(|e| Error::ApiError(e))?;
+        let response = request.bearer_auth(&access_token).send()?;
+
+        match retry {
+            Some(retry) if response.status() == StatusCode::UNAUTHORIZED => {
+                let access_token = self.refresh()?;
+                Ok(retry.bearer_auth(access_token).send()?)
+            }
+            _ => Ok(response),
+        }
+    }
+
+    /// Rotate the tokens and return the new access token.
+    fn refresh(&self) -> Result<String, Error> {
+        let mut tokens = self.tokens.lock().unwrap();
+
+        let url = format!("{}/api/v1/auth/refresh", &self.base_url);
+        let response = self
+            .client
+            .post(url)
+            .json(&RefreshRequest {
+                refresh_token: tokens.refresh_token.clone(),
+            })
+            .send()?;
+        let response: RefreshResponse = Error::check(response)?.json()?;
+
+        tokens.access_token = response.access_token;
+        tokens.refresh_token = response.refresh_token;
+        Ok(tokens.access_token.clone())
+    }
 
-        Ok(data)
+    fn request_json<T: DeserializeOwned>(
+        &self,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> Result<T, Error> {
+        let response = self.send(request)?;
+        Ok(Error::check(response)?.json()?)
     }
 
     fn request_empty(&self, request: reqwest::blocking::RequestBuilder) -> Result<(), Error> {
-        request
-            .bearer_auth(&self.access_token)
-            .send()
-            .map_err(|e| Error::ApiError(e))?
-            .error_for_status()
-            .map_err(|e| Error::ApiError(e))?;
+        Error::check(self.send(request)?)?;
         Ok(())
     }
 
+    /// Revoke the session on the server, the tokens are useless afterwards.
+    pub fn logout(&self) -> Result<(), Error> {
+        let url = format!("{}/api/v1/auth/logout", &self.base_url);
+        self.request_empty(self.client.post(url))
+    }
+
+    pub fn profile(&self) -> Result<Profile, Error> {
+        let url = format!("{}/api/v1/me", &self.base_url);
+        self.request_json(self.client.get(url))
+    }
+
+    pub fn change_password_start(
+        &self,
+        request: &ChangePasswordStartRequest,
+    ) -> Result<ChangePasswordStartResponse, Error> {
+        let url = format!("{}/api/v1/me/password/start", &self.base_url);
+        self.request_json(self.client.post(url).json(request))
+    }
+
+    /// Store the new password and the re-encrypted vaults, returning the
+    /// updated vaults. Fails if a vault of the account is missing.
+    pub fn change_password_finish(
+        &self,
+        request: &ChangePasswordFinishRequest,
+    ) -> Result<Vec<Vault>, Error> {
+        let url = format!("{}/api/v1/me/password/finish", &self.base_url);
+        self.request_json(self.client.post(url).json(request))
+    }
+
+    pub fn reregister_start(
+        &self,
+        request: &ReregisterStartRequest,
+    ) -> Result<ReregisterStartResponse, Error> {
+        let url = format!("{}/api/v1/me/credential/start", &self.base_url);
+        self.request_json(self.client.post(url).json(request))
+    }
+
+    pub fn reregister_finish(&self, request: &ReregisterFinishRequest) -> Result<(), Error> {
+        let url = format!("{}/api/v1/me/credential/finish", &self.base_url);
+        self.request_empty(self.client.post(url).json(request))
+    }
+
+    pub fn email_change_start(
+        &self,
+        request: &EmailChangeStartRequest,
+    ) -> Result<LoginStartResponse, Error> {
+        let url = format!("{}/api/v1/me/email/start", &self.base_url);
+        self.request_json(self.client.post(url).json(request))
+    }
+
+    /// Mail a code to the new email, see [`ApiClient::confirm_email_change`].
+    pub fn request_email_change(&self, request: &EmailChangeRequest) -> Result<(), Error> {
+        let url = format!("{}/api/v1/me/email", &self.base_url);
+        self.request_empty(self.client.post(url).json(request))
+    }
+
+    pub fn confirm_email_change(&self, code: &str) -> Result<(), Error> {
+        let url = format!("{}/api/v1/me/email/confirm", &self.base_url);
+        let request = EmailChangeConfirmRequest {
+            code: code.trim().to_string(),
+        };
+        self.request_empty(self.client.post(url).json(&request))
+    }
+
+    pub fn delete_account_start(
+        &self,
+        request: &DeleteAccountStartRequest,
+    ) -> Result<LoginStartResponse, Error> {
+        let url = format!("{}/api/v1/me/delete/start", &self.base_url);
+        self.request_json(self.client.post(url).json(request))
+    }
+
+    /// Delete the account for good, the tokens are useless afterwards.
+    pub fn delete_account(
+        &self,
+        request: &DeleteAccountRequest,
+    ) -> Result<DeleteAccountResponse, Error> {
+        let url = format!("{}/api/v1/me", &self.base_url);
+        self.request_json(self.client.delete(url).json(request))
+    }
+
+    /// Generate a TOTP secret, see [`ApiClient::confirm_totp`].
+    pub fn enroll_totp(&self) -> Result<TotpEnrollment, Error> {
+        let url = format!("{}/api/v1/me/mfa/totp", &self.base_url);
+        self.request_json(self.client.post(url))
+    }
+
+    /// Enable TOTP with a first code, returning the recovery codes.
+    pub fn confirm_totp(&self, code: &str) -> Result<RecoveryCodes, Error> {
+        let url = format!("{}/api/v1/me/mfa/totp/confirm", &self.base_url);
+        let request = TotpCodeRequest {
+            code: code.trim().to_string(),
+        };
+        self.request_json(self.client.post(url).json(&request))
+    }
+
+    pub fn disable_totp(&self, code: &str) -> Result<(), Error> {
+        let url = format!("{}/api/v1/me/mfa/totp", &self.base_url);
+        let request = TotpCodeRequest {
+            code: code.trim().to_string(),
+        };
+        self.request_empty(self.client.delete(url).json(&request))
+    }
+
+    pub fn list_sessions(&self) -> Result<Vec<SessionInfo>, Error> {
+        let url = format!("{}/api/v1/sessions", &self.base_url);
+        self.request_json(self.client.get(url))
+    }
+
+    pub fn revoke_session(&self, id: &Uuid) -> Result<(), Error> {
+        let url = format!("{}/api/v1/sessions/{}", &self.base_url, id);
+        self.request_empty(self.client.delete(url))
+    }
+
+    /// Revoke every session but the one of this client.
+    pub fn revoke_other_sessions(&self) -> Result<(), Error> {
+        let url = format!("{}/api/v1/sessions", &self.base_url);
+        self.request_empty(self.client.delete(url))
+    }
+
     pub fn fetch_vaults(&self) -> Result<Vec<Vault>, Error> {
         let url = format!("{}/api/v1/vaults", &self.base_url);
         self.request_json(self.client.get(url))
@@ -151,9 +548,16 @@ impl ApiClient {
         self.request_json(self.client.post(url).json(vault))
     }
 
-    pub fn update_vault(&self, vault_id: Uuid, vault: &CreateVaultRequest) -> Result<Vault, Error> {
+    /// Create or update a vault. `revision` is the server revision the change
+    /// is based on, `0` if the vault was never synced.
+    pub fn update_vault(
+        &self,
+        vault_id: Uuid,
+        revision: i64,
+        vault: &CreateVaultRequest,
+    ) -> Result<Vault, Error> {
         let url = format!("{}/api/v1/vaults/{}", &self.base_url, vault_id);
-        self.request_json(self.client.put(url).json(vault))
+        self.request_json(if_match(self.client.put(url), revision).json(vault))
     }
 
     pub fn delete_vault(&self, id: &Uuid) -> Result<(), Error> {
@@ -187,13 +591,14 @@ impl ApiClient {
         &self,
         vault_id: &Uuid,
         record_id: &Uuid,
+        revision: i64,
         record: &CreateRecordRequest,
     ) -> Result<Record, Error> {
         let url = format!(
-            "{}/api/v1/vaults/{}/records{}",
+            "{}/api/v1/vaults/{}/records/{}",
             &self.base_url, vault_id, record_id
         );
-        self.request_json(self.client.put(url).json(record))
+        self.request_json(if_match(self.client.put(url), revision).json(record))
     }
 
     pub fn delete_record(&self, vault_id: &Uuid, record_id: &Uuid) -> Result<(), Error> {
@@ -203,4 +608,23 @@ impl ApiClient {
         );
         self.request_empty(self.client.delete(url))
     }
+
+    pub fn sync(&self, since: Option<i64>) -> Result<SyncResponse, Error> {
+        let url = match since {
+            Some(since) => format!("{}/api/v1/sync?since={}", &self.base_url, since),
+            None => format!("{}/api/v1/sync", &self.base_url),
+        };
+        self.request_json(self.client.get(url))
+    }
+}
+
+/// Only overwrite the server copy if it is still at `revision`.
+fn if_match(
+    request: reqwest::blocking::RequestBuilder,
+    revision: i64,
+) -> reqwest::blocking::RequestBuilder {
+    match revision {
+        0 => request,
+        revision => request.header(reqwest::header::IF_MATCH, format!("\"{}\"", revision)),
+    }
 }
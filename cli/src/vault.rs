@@ -14,6 +14,8 @@ pub struct EncryptedVault {
     pub encrypted_vsk: Vec<u8>,
     pub created_at: i64,
     pub updated_at: i64,
+    /// The server revision this vault is based on, `0` if it was never synced.
+    pub revision: i64,
 }
 
 impl EncryptedVault {
@@ -76,6 +78,7 @@ impl EncryptedVault {
             encrypted_vsk,
             created_at,
             updated_at,
+            revision: 0,
         }
     }
 }
@@ -130,12 +133,13 @@ pub fn list_vaults(conn: &Connection) -> Result<Vec<EncryptedVault>, Error> {
     let list = vaults
         .into_iter()
         .map(
-            |(id, enc_name, enc_vsk, created_at, updated_at)| EncryptedVault {
+            |(id, enc_name, enc_vsk, created_at, updated_at, revision)| EncryptedVault {
                 id: Uuid::parse_str(&id).unwrap(),
                 encrypted_name: enc_name,
                 encrypted_vsk: enc_vsk,
                 created_at,
                 updated_at,
+                revision,
             },
         )
         .collect();
@@ -1,55 +1,273 @@
-use sanctum_shared::models::{CreateRecordRequest, CreateVaultRequest, Record, Vault};
+use std::sync::Arc;
+
+use reqwest::{StatusCode, header::IF_MATCH};
+use sanctum_shared::models::{
+    ChangePasswordFinishRequest, ChangePasswordStartRequest, ChangePasswordStartResponse,
+    CreateRecordRequest, CreateVaultRequest, DeleteAccountRequest, DeleteAccountResponse,
+    DeleteAccountStartRequest, EmailChangeConfirmRequest, EmailChangeRequest,
+    EmailChangeStartRequest, LoginStartResponse, Profile, Record, RecoveryCodes, RefreshRequest,
+    RefreshResponse, ReregisterFinishRequest, ReregisterStartRequest, ReregisterStartResponse,
+    SessionInfo, SyncResponse, TotpCodeRequest, TotpEnrollment, Vault,
+};
 use serde::de::DeserializeOwned;
+use tokio::sync::Mutex;
 use uuid::Uuid;
 
 use crate::Error;
 
+/// The outcome of a conditional (`If-Match`) write.
+#[derive(Debug)]
+pub enum Write<T> {
+    Done(T),
+    /// The revision we sent was stale; this is the current server copy.
+    Conflict(T),
+}
+
+#[derive(Debug)]
+struct Tokens {
+    access_token: String,
+    refresh_token: String,
+}
+
 #[derive(Debug, Clone)]
 pub struct ApiClient {
     base_url: String,
-    access_token: String,
+    /// Shared between clones, and locked for the whole refresh,
+    /// so a refresh token is never used twice.
+    tokens: Arc<Mutex<Tokens>>,
     client: reqwest::Client,
 }
 
 #[allow(unused)]
 impl ApiClient {
-    pub fn new(base_url: String, access_token: String) -> Self {
+    pub fn new(base_url: String, access_token: String, refresh_token: String) -> Self {
         Self {
             base_url,
-            access_token,
+            tokens: Arc::new(Mutex::new(Tokens {
+                access_token,
+                refresh_token,
+            })),
             client: reqwest::Client::new(),
         }
     }
 
+    /// Send an authenticated request. When the access token has expired,
+    /// it is refreshed and the request is sent once more.
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response, Error> {
+        let retry = request.try_clone();
+        let access_token = self.tokens.lock().await.access_token.clone();
+
+        let response = request.bearer_auth(&access_token).send().await?;
+
+        match retry {
+            Some(retry) if response.status() == StatusCode::UNAUTHORIZED => {
+                let access_token = self.refresh(&access_token).await?;
+                Ok(retry.bearer_auth(access_token).send().await?)
+            }
+            _ => Ok(response),
+        }
+    }
+
+    /// Replace the `expired` access token and return the new one.
+    async fn refresh(&self, expired: &str) -> Result<String, Error> {
+        let mut tokens = self.tokens.lock().await;
+
+        // another request refreshed while we were waiting for the lock
+        if tokens.access_token != expired {
+            return Ok(tokens.access_token.clone());
+        }
+
+        let url = format!("{}/api/v1/auth/refresh", &self.base_url);
+        let response = self
+            .client
+            .post(url)
+            .json(&RefreshRequest {
+                refresh_token: tokens.refresh_token.clone(),
+            })
+            .send()
+            .await?;
+        let response: RefreshResponse = Error::check(response).await?.json().await?;
+
+        tokens.access_token = response.access_token;
+        tokens.refresh_token = response.refresh_token;
+        Ok(tokens.access_token.clone())
+    }
+
     async fn request_json<T: DeserializeOwned>(
         &self,
         request: reqwest::RequestBuilder,
     ) -> Result<T, Error> {
-        let response = request
-            .bearer_auth(&self.access_token)
-            .send()
+        let response = self.send(request).await?;
+        Ok(Error::check(response).await?.json().await?)
+    }
+
+    /// Send a write which is only applied if the server copy is still at
+    /// `revision` (`0` for resources the server has never seen).
+    async fn request_conditional<T: DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+        revision: i64,
+    ) -> Result<Write<T>, Error> {
+        let request = match revision {
+            0 => request,
+            revision => request.header(IF_MATCH, format!("\"{}\"", revision)),
+        };
+
+        let response = self.send(request).await?;
+
+        // a 409 is an error, the id is taken by someone else
+        if response.status() == StatusCode::PRECONDITION_FAILED {
+            let current = response.json().await?;
+            return Ok(Write::Conflict(current));
+        }
+
+        let data = Error::check(response).await?.json().await?;
+        Ok(Write::Done(data))
+    }
+
+    async fn request_empty(&self, request: reqwest::RequestBuilder) -> Result<(), Error> {
+        Error::check(self.send(request).await?).await?;
+        Ok(())
+    }
+
+    /// Revoke the session on the server, the tokens are useless afterwards.
+    pub async fn logout(&self) -> Result<(), Error> {
+        let url = format!("{}/api/v1/auth/logout", &self.base_url);
+        self.request_empty(self.client.post(url)).await
+    }
+
+    pub async fn profile(&self) -> Result<Profile, Error> {
+        let url = format!("{}/api/v1/me", &self.base_url);
+        self.request_json(self.client.get(url)).await
+    }
+
+    /// Replace the account settings, which have to be a JSON object.
+    pub async fn update_settings(&self, settings: &serde_json::Value) -> Result<(), Error> {
+        let url = format!("{}/api/v1/me/settings", &self.base_url);
+        self.request_empty(self.client.put(url).json(settings))
+            .await
+    }
+
+    pub async fn change_password_start(
+        &self,
+        request: &ChangePasswordStartRequest,
+    ) -> Result<ChangePasswordStartResponse, Error> {
+        let url = format!("{}/api/v1/me/password/start", &self.base_url);
+        self.request_json(self.client.post(url).json(request)).await
+    }
+
+    /// Store the new password and the re-encrypted vault keys, returning
+    /// the updated vaults. Fails with [`Error::Conflict`] when the vaults
+    /// don't match the ones on the server.
+    pub async fn change_password_finish(
+        &self,
+        request: &ChangePasswordFinishRequest,
+    ) -> Result<Vec<Vault>, Error> {
+        let url = format!("{}/api/v1/me/password/finish", &self.base_url);
+        let response = self.send(self.client.post(url).json(request)).await?;
+
+        if response.status() == StatusCode::CONFLICT {
+            return Err(Error::Conflict);
+        }
+
+        Ok(Error::check(response).await?.json().await?)
+    }
+
+    pub async fn reregister_start(
+        &self,
+        request: &ReregisterStartRequest,
+    ) -> Result<ReregisterStartResponse, Error> {
+        let url = format!("{}/api/v1/me/credential/start", &self.base_url);
+        self.request_json(self.client.post(url).json(request)).await
+    }
+
+    pub async fn reregister_finish(&self, request: &ReregisterFinishRequest) -> Result<(), Error> {
+        let url = format!("{}/api/v1/me/credential/finish", &self.base_url);
+        self.request_empty(self.client.post(url).json(request))
             .await
-            .map_err(|e| Error::ApiError(e))?;
+    }
 
-        let data = response
-            .error_for_status()
-            .map_err(|e| Error::ApiError(e))?
-            .json()
+    pub async fn email_change_start(
+        &self,
+        request: &EmailChangeStartRequest,
+    ) -> Result<LoginStartResponse, Error> {
+        let url = format!("{}/api/v1/me/email/start", &self.base_url);
+        self.request_json(self.client.post(url).json(request)).await
+    }
+
+    /// Mail a code to the new email, see [`ApiClient::confirm_email_change`].
+    pub async fn request_email_change(&self, request: &EmailChangeRequest) -> Result<(), Error> {
+        let url = format!("{}/api/v1/me/email", &self.base_url);
+        self.request_empty(self.client.post(url).json(request))
             .await
-            .map_err(|e| Error::ApiError(e))?;
+    }
 
-        Ok(data)
+    pub async fn confirm_email_change(&self, code: &str) -> Result<(), Error> {
+        let url = format!("{}/api/v1/me/email/confirm", &self.base_url);
+        let request = EmailChangeConfirmRequest {
+            code: code.trim().to_string(),
+        };
+        self.request_empty(self.client.post(url).json(&request))
+            .await
     }
 
-    async fn request_empty(&self, request: reqwest::RequestBuilder) -> Result<(), Error> {
-        request
-            .bearer_auth(&self.access_token)
-            .send()
+    pub async fn delete_account_start(
+        &self,
+        request: &DeleteAccountStartRequest,
+    ) -> Result<LoginStartResponse, Error> {
+        let url = format!("{}/api/v1/me/delete/start", &self.base_url);
+        self.request_json(self.client.post(url).json(request)).await
+    }
+
+    /// Delete the account for good, the tokens are useless afterwards.
+    pub async fn delete_account(
+        &self,
+        request: &DeleteAccountRequest,
+    ) -> Result<DeleteAccountResponse, Error> {
+        let url = format!("{}/api/v1/me", &self.base_url);
+        self.request_json(self.client.delete(url).json(request))
+            .await
+    }
+
+    /// Generate a TOTP secret, see [`ApiClient::confirm_totp`].
+    pub async fn enroll_totp(&self) -> Result<TotpEnrollment, Error> {
+        let url = format!("{}/api/v1/me/mfa/totp", &self.base_url);
+        self.request_json(self.client.post(url)).await
+    }
+
+    /// Enable TOTP with a first code, returning the recovery codes.
+    pub async fn confirm_totp(&self, code: &str) -> Result<RecoveryCodes, Error> {
+        let url = format!("{}/api/v1/me/mfa/totp/confirm", &self.base_url);
+        let request = TotpCodeRequest {
+            code: code.trim().to_string(),
+        };
+        self.request_json(self.client.post(url).json(&request))
             .await
-            .map_err(|e| Error::ApiError(e))?
-            .error_for_status()
-            .map_err(|e| Error::ApiError(e))?;
-        Ok(())
+    }
+
+    pub async fn disable_totp(&self, code: &str) -> Result<(), Error> {
+        let url = format!("{}/api/v1/me/mfa/totp", &self.base_url);
+        let request = TotpCodeRequest {
+            code: code.trim().to_string(),
+        };
+        self.request_empty(self.client.delete(url).json(&request))
+            .await
+    }
+
+    pub async fn list_sessions(&self) -> Result<Vec<SessionInfo>, Error> {
+        let url = format!("{}/api/v1/sessions", &self.base_url);
+        self.request_json(self.client.get(url)).await
+    }
+
+    pub async fn revoke_session(&self, id: &Uuid) -> Result<(), Error> {
+        let url = format!("{}/api/v1/sessions/{}", &self.base_url, id);
+        self.request_empty(self.client.delete(url)).await
+    }
+
+    /// Revoke every session but the one of this client.
+    pub async fn revoke_other_sessions(&self) -> Result<(), Error> {
+        let url = format!("{}/api/v1/sessions", &self.base_url);
+        self.request_empty(self.client.delete(url)).await
     }
 
     pub async fn fetch_vaults(&self) -> Result<Vec<Vault>, Error> {
@@ -67,8 +285,16 @@ impl ApiClient {
         self.request_json(self.client.post(url).json(vault)).await
     }
 
-    pub async fn update_vault(&self, vault: &CreateRecordRequest) -> Result<Vault, Error> {
-        todo!()
+    /// Create or update the vault with the given id, see [`Write`].
+    pub async fn update_vault(
+        &self,
+        id: &Uuid,
+        revision: i64,
+        vault: &CreateVaultRequest,
+    ) -> Result<Write<Vault>, Error> {
+        let url = format!("{}/api/v1/vaults/{}", &self.base_url, id);
+        self.request_conditional(self.client.put(url).json(vault), revision)
+            .await
     }
 
     pub async fn delete_vault(&self, id: &Uuid) -> Result<(), Error> {
@@ -98,8 +324,20 @@ impl ApiClient {
         self.request_json(self.client.post(url).json(record)).await
     }
 
-    pub async fn update_record(&self, vault_id: &Uuid, record_id: &Uuid) -> Result<Record, Error> {
-        todo!()
+    /// Create or update the record with the given id, see [`Write`].
+    pub async fn update_record(
+        &self,
+        vault_id: &Uuid,
+        record_id: &Uuid,
+        revision: i64,
+        record: &CreateRecordRequest,
+    ) -> Result<Write<Record>, Error> {
+        let url = format!(
+            "{}/api/v1/vaults/{}/records/{}",
+            &self.base_url, vault_id, record_id
+        );
+        self.request_conditional(self.client.put(url).json(record), revision)
+            .await
     }
 
     pub async fn delete_record(&self, vault_id: &Uuid, record_id: &Uuid) -> Result<(), Error> {
@@ -109,4 +347,12 @@ impl ApiClient {
         );
         self.request_empty(self.client.delete(url)).await
     }
+
+    pub async fn sync(&self, since: Option<i64>) -> Result<SyncResponse, Error> {
+        let url = match since {
+            Some(since) => format!("{}/api/v1/sync?since={}", &self.base_url, since),
+            None => format!("{}/api/v1/sync", &self.base_url),
+        };
+        self.request_json(self.client.get(url)).await
+    }
 }
@@ -2,15 +2,18 @@ use argon2::password_hash::rand_core::RngCore;
 use base64::{Engine, prelude::BASE64_STANDARD};
 use chacha20poly1305::aead::OsRng;
 use sanctum_shared::models::{
-    LoginFinishRequest, LoginFinishResponse, LoginStartRequest, LoginStartResponse,
-    RegistrationFinishRequest, RegistrationStartRequest, RegistrationStartResponse,
+    KdfParams, LoginFinishRequest, LoginResult, LoginStartRequest, LoginStartResponse, MfaMethod,
+    MfaVerifyRequest, RegistrationConfirmRequest, RegistrationFinishRequest,
+    RegistrationStartRequest, RegistrationStartResponse,
 };
 
+use crate::Error;
+
 #[allow(unused)]
 pub async fn register(email: &str, password: &str) -> Result<(), String> {
     let client = reqwest::Client::new();
 
-    let (state, message) = sanctum_shared::register::client_start(&password.as_bytes()).unwrap();
+    let (state, message) = sanctum_shared::register::client_start(password.as_bytes()).unwrap();
 
     let response = client
         .post("http://localhost:3000/api/v1/auth/register/start")
@@ -20,18 +23,21 @@ pub async fn register(email: &str, password: &str) -> Result<(), String> {
         })
         .send()
         .await
-        .unwrap();
+        .map_err(|e| e.to_string())?;
 
     if response.status() != 200 {
         dbg!(response);
         return Err("Registration start failed".to_string());
     }
 
-    let response = response.json::<RegistrationStartResponse>().await.unwrap();
+    let response = response
+        .json::<RegistrationStartResponse>()
+        .await
+        .map_err(|e| e.to_string())?;
 
     let server_message = BASE64_STANDARD.decode(response.server_start).unwrap();
     let message =
-        sanctum_shared::register::client_finish(&password.as_bytes(), &state, &server_message)
+        sanctum_shared::register::client_finish(password.as_bytes(), &state, &server_message)
             .unwrap();
 
     let salt = {
@@ -46,21 +52,69 @@ pub async fn register(email: &str, password: &str) -> Result<(), String> {
             email: email.to_string(),
             salt: BASE64_STANDARD.encode(salt),
             client_finish: BASE64_STANDARD.encode(message),
+            credential_id: response.credential_id,
+            kdf: KdfParams::default(),
         })
         .send()
         .await
-        .unwrap()
+        .map_err(|e| e.to_string())?
         .status();
 
-    dbg!(status);
+    // the account only exists once the code mailed to `email` is confirmed
+    if status != 202 {
+        return Err("Registration finish failed".to_string());
+    }
 
     Ok(())
 }
 
-pub async fn login(email: &str, password: &str) -> Result<LoginFinishResponse, String> {
+/// Create the account registered with [`register`], using the
+/// code that was mailed to its email address.
+pub async fn confirm_registration(code: &str) -> Result<(), String> {
     let client = reqwest::Client::new();
 
-    let (state, message) = sanctum_shared::login::client_start(password.as_bytes()).unwrap();
+    let status = client
+        .post("http://localhost:3000/api/v1/auth/register/confirm")
+        .json(&RegistrationConfirmRequest {
+            code: code.trim().to_string(),
+        })
+        .send()
+        .await
+        .map_err(|e| e.to_string())?
+        .status();
+
+    match status.as_u16() {
+        201 => Ok(()),
+        409 => Err("The account already exists".to_string()),
+        _ => Err("The confirmation code is invalid or expired".to_string()),
+    }
+}
+
+/// The answer to the challenge of an account with a second factor.
+#[derive(Debug, Clone)]
+pub enum SecondFactor {
+    /// A code from the authenticator app.
+    Totp(String),
+    /// One of the codes handed out when TOTP was enabled. It can't be
+    /// used again.
+    RecoveryCode(String),
+}
+
+/// Log in, passing the second factor on if the server asks for it.
+///
+/// Without a second factor, a login into an account that has one returns
+/// the challenge, and has to be started over with the code. Refused logins
+/// (a wrong password, a locked account) are [`Error::Api`].
+pub async fn login(
+    email: &str,
+    password: &str,
+    device_name: Option<&str>,
+    second_factor: Option<&SecondFactor>,
+) -> Result<LoginResult, Error> {
+    let client = reqwest::Client::new();
+
+    let (state, message) =
+        sanctum_shared::login::client_start(password.as_bytes()).map_err(|_| Error::CryptoError)?;
 
     let response = client
         .post("http://localhost:3000/api/v1/auth/login/start")
@@ -69,29 +123,58 @@ pub async fn login(email: &str, password: &str) -> Result<LoginFinishResponse, S
             client_start: BASE64_STANDARD.encode(message),
         })
         .send()
-        .await
-        .unwrap()
+        .await?;
+    let response = Error::check(response)
+        .await?
         .json::<LoginStartResponse>()
-        .await
-        .unwrap();
+        .await?;
 
-    let server_start = BASE64_STANDARD.decode(response.message).unwrap();
+    let server_start = BASE64_STANDARD
+        .decode(response.message)
+        .map_err(|_| Error::InvalidBase64)?;
 
+    // fails for a wrong password (or an unknown email) already
     let message_bytes =
-        sanctum_shared::login::client_finish(&password.as_bytes(), &state, &server_start).unwrap();
+        sanctum_shared::login::client_finish(password.as_bytes(), &state, &server_start)
+            .map_err(|_| Error::LoginFailed("Wrong email or password".to_string()))?;
 
     let response = client
         .post("http://localhost:3000/api/v1/auth/login/finish")
         .json(&LoginFinishRequest {
+            login_id: response.login_id,
             email: email.to_string(),
             client_finish: BASE64_STANDARD.encode(message_bytes),
+            device_name: device_name.map(str::to_string),
+            platform: Some(std::env::consts::OS.to_string()),
         })
         .send()
-        .await
-        .unwrap()
-        .json::<LoginFinishResponse>()
-        .await
-        .unwrap();
+        .await?;
+    let response = Error::check(response).await?.json::<LoginResult>().await?;
+
+    let (challenge, second_factor) = match (response, second_factor) {
+        (LoginResult::MfaRequired(challenge), Some(second_factor)) => (challenge, second_factor),
+        (response, _) => return Ok(response),
+    };
+
+    let (path, method, code) = match second_factor {
+        SecondFactor::Totp(code) => ("totp", MfaMethod::Totp, code),
+        SecondFactor::RecoveryCode(code) => ("recovery", MfaMethod::RecoveryCode, code),
+    };
+    if !challenge.methods.contains(&method) {
+        return Err(Error::LoginFailed(
+            "The account has no such second factor".to_string(),
+        ));
+    }
+
+    let response = client
+        .post(format!("http://localhost:3000/api/v1/auth/mfa/{}", path))
+        .json(&MfaVerifyRequest {
+            mfa_token: challenge.mfa_token,
+            code: code.trim().to_string(),
+        })
+        .send()
+        .await?;
+    let response = Error::check(response).await?.json().await?;
 
-    Ok(response)
+    Ok(LoginResult::Done(response))
 }
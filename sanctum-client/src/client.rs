@@ -1,23 +1,35 @@
+use std::sync::Arc;
+
+use argon2::password_hash::rand_core::RngCore;
 use base64::{Engine, prelude::BASE64_STANDARD};
 use chacha20poly1305::{ChaCha20Poly1305, KeyInit, aead::OsRng};
+use sanctum_shared::models::{
+    ChangePasswordFinishRequest, ChangePasswordStartRequest, DeleteAccountRequest,
+    DeleteAccountStartRequest, EmailChangeRequest, EmailChangeStartRequest, LoginResult, Profile,
+    ReregisterFinishRequest, ReregisterStartRequest, RewrappedVault, TotpEnrollment,
+};
 use secrecy::{ExposeSecret, SecretSlice};
-use std::sync::Arc;
-use std::time::Duration;
+use sled::Transactional;
+use sled::transaction::ConflictableTransactionError;
 use time::UtcDateTime;
-use tokio::time::sleep;
-use tokio::{sync::Mutex, task::JoinHandle};
+use tokio::{sync::oneshot, task::JoinHandle};
 use uuid::Uuid;
 use zeroize::Zeroize;
 
 use crate::crypto::{decrypt_vault, encrypt_vault};
-use crate::outbox::{Action, EntityType, OutboxEntry};
+use crate::merge::{RecordConflict, Resolver};
+use crate::outbox::{self, Action, EntityType, FailedChange, OutboxEntry};
+use crate::sync::SyncEngine;
 use crate::{
-    Config, Error,
+    Config, Error, SecondFactor,
     api::ApiClient,
     crypto::{decrypt_data, derive_key, encrypt_data},
     models::{EncryptedRecord, EncryptedVault, PlainRecord, PlainVault},
 };
 
+/// Where the local copy of the vaults is stored.
+const DB_PATH: &str = "data.sled.db";
+
 pub struct LockedClient {
     config: Config,
 }
@@ -27,68 +39,110 @@ impl LockedClient {
         Ok(Self { config })
     }
 
-    pub async fn login(mut self, email: &str, password: &str) -> Result<UnlockedClient, Error> {
-        let resp = crate::auth::login(email, password).await.unwrap();
-        let salt = BASE64_STANDARD.decode(&resp.salt).unwrap();
-        self.config.salt = salt;
+    /// Log in and unlock the vaults.
+    ///
+    /// Fails with [`Error::MfaRequired`] when the account has a second
+    /// factor and none was given, the login then has to be repeated with it.
+    pub async fn login(
+        &self,
+        email: &str,
+        password: &str,
+        second_factor: Option<SecondFactor>,
+    ) -> Result<UnlockedClient, Error> {
+        let resp = match crate::auth::login(
+            email,
+            password,
+            self.config.device_name.as_deref(),
+            second_factor.as_ref(),
+        )
+        .await?
+        {
+            LoginResult::Done(resp) => resp,
+            LoginResult::MfaRequired(challenge) => {
+                return Err(Error::MfaRequired(challenge.methods));
+            }
+        };
+        let mut config = self.config.clone();
+        let api_client = ApiClient::new(
+            self.config.api_base_url.clone(),
+            resp.access_token,
+            resp.refresh_token,
+        );
 
-        let master_key = derive_key(password, &self.config.salt)?;
-        let api_client = ApiClient::new(self.config.api_base_url.clone(), resp.access_token);
+        if resp.reregister {
+            // the server asks again on the next login if this fails
+            let _ = reregister(&api_client, password).await;
+        }
 
-        let db = sled::open("data.sled.db").unwrap();
-        let data_tree = db.open_tree("data").unwrap();
-        let outbox_tree = db.open_tree("outbox").unwrap();
+        // a fresh install only learns how to derive the master key here
+        let profile = api_client.profile().await?;
+        config.salt = b64_decode(&profile.salt)?;
+        config.kdf = profile.kdf;
 
-        Ok(UnlockedClient {
-            config: self.config,
-            api_client: Some(Arc::new(api_client)),
-            master_key: SecretSlice::new(Box::new(master_key)),
-            // store: LocalStore::open("data.sled.db").unwrap(),
-            db,
-            data_tree,
-            outbox_tree,
-            last_sync: Mutex::new(None),
-        })
+        let master_key = derive_key(password, &config.salt, &config.kdf)?;
+
+        UnlockedClient::open(config, Some(api_client), master_key)
     }
 
+    /// Start registering an account. It is created once the code mailed
+    /// to `email` is passed to [`LockedClient::confirm_registration`].
     pub async fn register(email: &str, password: &str) -> Result<(), Error> {
-        crate::auth::register(email, password).await.unwrap();
-        Ok(())
+        crate::auth::register(email, password)
+            .await
+            .map_err(Error::RegistrationFailed)
     }
 
-    pub fn unlock_offline(self, password: &str) -> Result<UnlockedClient, Error> {
-        let master_key = derive_key(password, &self.config.salt)?;
+    pub async fn confirm_registration(code: &str) -> Result<(), Error> {
+        crate::auth::confirm_registration(code)
+            .await
+            .map_err(Error::RegistrationFailed)
+    }
 
-        let db = sled::open("data.sled.db").unwrap();
-        let data_tree = db.open_tree("data").unwrap();
-        let outbox_tree = db.open_tree("outbox").unwrap();
+    pub fn unlock_offline(self, password: &str) -> Result<UnlockedClient, Error> {
+        let master_key = derive_key(password, &self.config.salt, &self.config.kdf)?;
 
-        Ok(UnlockedClient {
-            config: self.config,
-            api_client: None,
-            master_key: SecretSlice::new(Box::new(master_key)),
-            // store: LocalStore::open("data.sled.db").unwrap(),
-            db,
-            data_tree,
-            outbox_tree,
-            last_sync: Mutex::new(None),
-        })
+        UnlockedClient::open(self.config, None, master_key)
     }
 }
 
 pub struct UnlockedClient {
     config: Config,
-    api_client: Option<Arc<ApiClient>>,
     master_key: SecretSlice<u8>,
     // store: LocalStore,
     db: sled::Db,
     data_tree: sled::Tree,
     outbox_tree: sled::Tree,
-    last_sync: Mutex<Option<UtcDateTime>>,
+    sync: SyncEngine,
+    /// Stops the background sync when fired or dropped.
+    background_sync: std::sync::Mutex<Option<oneshot::Sender<()>>>,
 }
 
 impl UnlockedClient {
+    fn open(
+        config: Config,
+        api_client: Option<ApiClient>,
+        master_key: [u8; 32],
+    ) -> Result<Self, Error> {
+        let db = sled::open(DB_PATH)?;
+        let data_tree = db.open_tree("data")?;
+        let outbox_tree = db.open_tree("outbox")?;
+        let master_key = SecretSlice::new(Box::new(master_key));
+        let sync = SyncEngine::open(api_client, Resolver::new(master_key.clone()), &db)?;
+
+        Ok(UnlockedClient {
+            config,
+            master_key,
+            // store: LocalStore::open("data.sled.db").unwrap(),
+            db,
+            data_tree,
+            outbox_tree,
+            sync,
+            background_sync: std::sync::Mutex::new(None),
+        })
+    }
+
     pub fn lock(mut self) -> LockedClient {
+        let _ = self.stop_background_sync();
         self.master_key.zeroize();
 
         LockedClient {
@@ -96,6 +150,218 @@ impl UnlockedClient {
         }
     }
 
+    /// End the session on the server and lock the client.
+    pub async fn logout(self) -> Result<LockedClient, Error> {
+        if let Some(api_client) = self.sync.api_client() {
+            api_client.logout().await?;
+        }
+        Ok(self.lock())
+    }
+
+    /// Change the master password.
+    ///
+    /// Only the vault keys are encrypted with the master key, so they are
+    /// re-encrypted with the new one and stored on the server together with
+    /// the new OPAQUE password file, after proving the current `password`
+    /// once more. This needs a connection and fails with
+    /// [`Error::UnsyncedChanges`] if local changes can't be synced first,
+    /// as they were made with the old master key. All other sessions are
+    /// logged out by the server.
+    pub async fn change_password(
+        &mut self,
+        password: &str,
+        new_password: &str,
+    ) -> Result<(), Error> {
+        let Some(api_client) = self.sync.api_client() else {
+            return Err(Error::SyncInOfflineMode);
+        };
+
+        self.sync.sync_once().await?;
+        if self.sync.has_unsent_changes()? {
+            return Err(Error::UnsyncedChanges);
+        }
+
+        let salt = {
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            salt
+        };
+        let master_key =
+            SecretSlice::new(Box::new(derive_key(new_password, &salt, &self.config.kdf)?));
+
+        // the server's vaults, it refuses the change if one is missing
+        let mut vaults = Vec::new();
+        for vault in api_client.fetch_vaults().await? {
+            let vault_key = decrypt_data(
+                &b64_decode(&vault.encrypted_vault_key)?,
+                self.master_key.expose_secret(),
+            )?;
+            vaults.push(RewrappedVault {
+                id: vault.id,
+                encrypted_vault_key: b64_encode(&encrypt_data(
+                    &vault_key,
+                    master_key.expose_secret(),
+                )?),
+                encrypted_name: vault.encrypted_name,
+                revision: vault.revision,
+            });
+        }
+
+        let (login_state, login_message) = sanctum_shared::login::client_start(password.as_bytes())
+            .map_err(|_| Error::CryptoError)?;
+        let (state, message) = sanctum_shared::register::client_start(new_password.as_bytes())
+            .map_err(|_| Error::CryptoError)?;
+        let response = api_client
+            .change_password_start(&ChangePasswordStartRequest {
+                client_start: b64_encode(&message),
+                login_start: b64_encode(&login_message),
+            })
+            .await?;
+        let login_message = sanctum_shared::login::client_finish(
+            password.as_bytes(),
+            &login_state,
+            &b64_decode(&response.login.message)?,
+        )
+        .map_err(|_| Error::CryptoError)?;
+        let message = sanctum_shared::register::client_finish(
+            new_password.as_bytes(),
+            &state,
+            &b64_decode(&response.registration.server_start)?,
+        )
+        .map_err(|_| Error::CryptoError)?;
+
+        let vaults = api_client
+            .change_password_finish(&ChangePasswordFinishRequest {
+                login_id: response.login.login_id,
+                login_finish: b64_encode(&login_message),
+                client_finish: b64_encode(&message),
+                credential_id: response.registration.credential_id,
+                salt: b64_encode(&salt),
+                kdf: self.config.kdf.clone(),
+                vaults,
+            })
+            .await?;
+
+        for vault in vaults {
+            let vault = EncryptedVault::from(vault);
+            self.data_tree
+                .insert(format!("vault:{}", vault.id), serde_json::to_vec(&vault)?)?;
+        }
+        self.db.flush_async().await?;
+
+        self.sync.set_master_key(master_key.clone());
+        self.master_key.zeroize();
+        self.master_key = master_key;
+        self.config.salt = salt.to_vec();
+        Ok(())
+    }
+
+    /// The profile of the account, see [`Profile`].
+    pub async fn profile(&self) -> Result<Profile, Error> {
+        let Some(api_client) = self.sync.api_client() else {
+            return Err(Error::SyncInOfflineMode);
+        };
+        api_client.profile().await
+    }
+
+    /// Start changing the email of the account, which takes the `password`
+    /// once more. A code is mailed to the new address, see
+    /// [`UnlockedClient::confirm_email_change`].
+    pub async fn change_email(&self, email: &str, password: &str) -> Result<(), Error> {
+        let Some(api_client) = self.sync.api_client() else {
+            return Err(Error::SyncInOfflineMode);
+        };
+
+        let (state, message) = sanctum_shared::login::client_start(password.as_bytes())
+            .map_err(|_| Error::CryptoError)?;
+        let response = api_client
+            .email_change_start(&EmailChangeStartRequest {
+                client_start: b64_encode(&message),
+            })
+            .await?;
+        let message = sanctum_shared::login::client_finish(
+            password.as_bytes(),
+            &state,
+            &b64_decode(&response.message)?,
+        )
+        .map_err(|_| Error::CryptoError)?;
+
+        api_client
+            .request_email_change(&EmailChangeRequest {
+                email: email.to_string(),
+                login_id: response.login_id,
+                client_finish: b64_encode(&message),
+            })
+            .await
+    }
+
+    /// Change the email to the address `code` was mailed to.
+    pub async fn confirm_email_change(&self, code: &str) -> Result<(), Error> {
+        let Some(api_client) = self.sync.api_client() else {
+            return Err(Error::SyncInOfflineMode);
+        };
+        api_client.confirm_email_change(code).await
+    }
+
+    /// Generate a TOTP secret for the authenticator app. Logins only ask
+    /// for codes once one was passed to [`UnlockedClient::confirm_totp`].
+    pub async fn enable_totp(&self) -> Result<TotpEnrollment, Error> {
+        let Some(api_client) = self.sync.api_client() else {
+            return Err(Error::SyncInOfflineMode);
+        };
+        api_client.enroll_totp().await
+    }
+
+    /// Enable TOTP with a first code, returning the recovery codes.
+    pub async fn confirm_totp(&self, code: &str) -> Result<Vec<String>, Error> {
+        let Some(api_client) = self.sync.api_client() else {
+            return Err(Error::SyncInOfflineMode);
+        };
+        Ok(api_client.confirm_totp(code).await?.codes)
+    }
+
+    /// Disable TOTP, with a code from the authenticator or a recovery code.
+    pub async fn disable_totp(&self, code: &str) -> Result<(), Error> {
+        let Some(api_client) = self.sync.api_client() else {
+            return Err(Error::SyncInOfflineMode);
+        };
+        api_client.disable_totp(code).await
+    }
+
+    /// Delete the account on the server, after proving the password once
+    /// more, and then the local data.
+    pub async fn delete_account(self, password: &str) -> Result<(), Error> {
+        let Some(api_client) = self.sync.api_client() else {
+            return Err(Error::SyncInOfflineMode);
+        };
+
+        let (state, message) = sanctum_shared::login::client_start(password.as_bytes())
+            .map_err(|_| Error::CryptoError)?;
+        let response = api_client
+            .delete_account_start(&DeleteAccountStartRequest {
+                client_start: b64_encode(&message),
+            })
+            .await?;
+        let message = sanctum_shared::login::client_finish(
+            password.as_bytes(),
+            &state,
+            &b64_decode(&response.message)?,
+        )
+        .map_err(|_| Error::CryptoError)?;
+
+        api_client
+            .delete_account(&DeleteAccountRequest {
+                login_id: response.login_id,
+                client_finish: b64_encode(&message),
+            })
+            .await?;
+
+        // the background sync holds on to the database as well
+        let _ = self.stop_background_sync();
+        drop(self);
+        std::fs::remove_dir_all(DB_PATH).map_err(|e| Error::Storage(e.into()))
+    }
+
     // ------------------------------------------------------------------------------------
 
     pub fn list_vaults(&self) -> Vec<PlainVault> {
@@ -104,7 +370,7 @@ impl UnlockedClient {
             .map(|e| {
                 let (_, value) = e.unwrap();
                 let encrypted = serde_json::from_slice(&value).unwrap();
-                decrypt_vault(&encrypted, &self.master_key.expose_secret()).unwrap()
+                decrypt_vault(&encrypted, self.master_key.expose_secret()).unwrap()
             })
             .collect::<Vec<_>>()
     }
@@ -120,27 +386,20 @@ impl UnlockedClient {
             updated_at: UtcDateTime::now(),
         };
 
-        let encrypted = encrypt_vault(&plain, &self.master_key.expose_secret()).unwrap();
+        let encrypted = encrypt_vault(&plain, self.master_key.expose_secret()).unwrap();
         let outbox_entry = OutboxEntry::new(
             Action::Create,
             EntityType::Vault,
             serde_json::to_value(&encrypted).unwrap(),
         );
 
-        self.data_tree
-            .insert(
+        self.commit(
+            vec![(
                 format!("vault:{}", encrypted.id),
-                serde_json::to_vec(&encrypted).unwrap(),
-            )
-            .unwrap();
-        self.outbox_tree
-            .insert(
-                format!("outbox:{}", outbox_entry.id),
-                serde_json::to_vec(&outbox_entry).unwrap(),
-            )
-            .unwrap();
-
-        self.db.flush().unwrap();
+                Some(serde_json::to_vec(&encrypted)?),
+            )],
+            &outbox_entry,
+        )?;
 
         Ok(plain)
     }
@@ -155,7 +414,7 @@ impl UnlockedClient {
                 &BASE64_STANDARD
                     .decode(existing.encrypted_vault_key.clone())
                     .unwrap(),
-                &self.master_key.expose_secret(),
+                self.master_key.expose_secret(),
             )
             .unwrap();
 
@@ -170,16 +429,10 @@ impl UnlockedClient {
                 serde_json::to_value(&existing).unwrap(),
             );
 
-            self.data_tree
-                .insert(key, serde_json::to_vec(&existing).unwrap())
-                .unwrap();
-            self.outbox_tree
-                .insert(
-                    format!("outbox:{}", outbox_entry.id),
-                    serde_json::to_vec(&outbox_entry).unwrap(),
-                )
-                .unwrap();
-            self.db.flush().unwrap();
+            self.commit(
+                vec![(key, Some(serde_json::to_vec(&existing)?))],
+                &outbox_entry,
+            )?;
 
             Ok(PlainVault {
                 id: existing.id,
@@ -195,29 +448,29 @@ impl UnlockedClient {
     }
 
     pub fn delete_vault(&self, vault_id: Uuid) -> Result<(), Error> {
-        let vault = self
-            .data_tree
-            .remove(format!("vault:{}", vault_id))
-            .unwrap()
-            .unwrap();
-
-        let vault: EncryptedVault = serde_json::from_slice(&vault).unwrap();
+        let key = format!("vault:{}", vault_id);
+        let vault = self.data_tree.get(&key)?.ok_or(Error::NotFound)?;
+        let vault: EncryptedVault = serde_json::from_slice(&vault)?;
 
         let outbox_entry = OutboxEntry::new(
             Action::Delete,
             EntityType::Vault,
-            serde_json::to_value(&vault).unwrap(),
+            serde_json::to_value(&vault)?,
         );
 
-        self.outbox_tree
-            .insert(
-                format!("outbox:{}", outbox_entry.id),
-                serde_json::to_vec(&outbox_entry).unwrap(),
-            )
-            .unwrap();
+        // the server deletes the records of a vault along with it,
+        // so they only have to be removed locally
+        let mut writes = vec![(key, None)];
+        for record_key in self
+            .data_tree
+            .scan_prefix(format!("record:{}:", vault_id))
+            .keys()
+        {
+            let record_key = String::from_utf8_lossy(&record_key?).into_owned();
+            writes.push((record_key, None));
+        }
 
-        self.db.flush().unwrap();
-        Ok(())
+        self.commit(writes, &outbox_entry)
     }
 
     // ------------------------------------------------------------------------------------
@@ -229,7 +482,7 @@ impl UnlockedClient {
                 let ev: EncryptedVault = serde_json::from_slice(&v).unwrap();
                 decrypt_data(
                     &BASE64_STANDARD.decode(ev.encrypted_vault_key).unwrap(),
-                    &self.master_key.expose_secret(),
+                    self.master_key.expose_secret(),
                 )
                 .unwrap()
             }
@@ -278,7 +531,7 @@ impl UnlockedClient {
         let ev: EncryptedVault = serde_json::from_slice(&vault_item).unwrap();
         let vault_key = decrypt_data(
             &BASE64_STANDARD.decode(ev.encrypted_vault_key).unwrap(),
-            &self.master_key.expose_secret(),
+            self.master_key.expose_secret(),
         )
         .unwrap();
 
@@ -288,17 +541,25 @@ impl UnlockedClient {
             vault_id,
             encrypted_record_key: b64_encode(&encrypt_data(&record_key, &vault_key).unwrap()),
             encrypted_data_blob: b64_encode(&encrypt_data(data.as_bytes(), &record_key).unwrap()),
+            revision: 0,
+            base: None,
             created_at: UtcDateTime::now(),
             updated_at: UtcDateTime::now(),
         };
 
-        self.data_tree
-            .insert(
+        let outbox_entry = OutboxEntry::new(
+            Action::Create,
+            EntityType::Record,
+            serde_json::to_value(&encrypted)?,
+        );
+
+        self.commit(
+            vec![(
                 format!("record:{}:{}", vault_id, encrypted.id),
-                serde_json::to_vec(&encrypted).unwrap(),
-            )
-            .unwrap();
-        self.db.flush().unwrap();
+                Some(serde_json::to_vec(&encrypted)?),
+            )],
+            &outbox_entry,
+        )?;
 
         Ok(PlainRecord {
             id: encrypted.id,
@@ -325,26 +586,32 @@ impl UnlockedClient {
         let ev: EncryptedVault = serde_json::from_slice(&vault_item).unwrap();
         let vault_key = decrypt_data(
             &BASE64_STANDARD.decode(ev.encrypted_vault_key).unwrap(),
-            &self.master_key.expose_secret(),
+            self.master_key.expose_secret(),
         )
         .unwrap();
 
         let key = format!("record:{}:{}", vault_id, record_id);
-        let (record_key_bytes, created_at) = if let Ok(Some(value)) = self.data_tree.get(&key) {
-            let existing: EncryptedRecord = serde_json::from_slice(&value).unwrap();
-            let record_key = decrypt_data(
-                &BASE64_STANDARD
-                    .decode(existing.encrypted_record_key)
-                    .unwrap(),
-                &vault_key,
-            )
-            .unwrap();
-            (record_key, existing.created_at)
-        } else {
-            // create new record key
-            let rk = ChaCha20Poly1305::generate_key(&mut OsRng);
-            (rk.to_vec(), UtcDateTime::now())
-        };
+        let (record_key_bytes, revision, base, created_at) =
+            if let Ok(Some(value)) = self.data_tree.get(&key) {
+                let existing: EncryptedRecord = serde_json::from_slice(&value).unwrap();
+                let record_key = decrypt_data(
+                    &BASE64_STANDARD
+                        .decode(existing.encrypted_record_key)
+                        .unwrap(),
+                    &vault_key,
+                )
+                .unwrap();
+                (
+                    record_key,
+                    existing.revision,
+                    existing.base,
+                    existing.created_at,
+                )
+            } else {
+                // create new record key
+                let rk = ChaCha20Poly1305::generate_key(&mut OsRng);
+                (rk.to_vec(), 0, None, UtcDateTime::now())
+            };
 
         let encrypted = EncryptedRecord {
             id: record_id,
@@ -353,14 +620,22 @@ impl UnlockedClient {
             encrypted_data_blob: b64_encode(
                 &encrypt_data(data.as_bytes(), &record_key_bytes).unwrap(),
             ),
+            revision,
+            base,
             created_at,
             updated_at: UtcDateTime::now(),
         };
 
-        self.data_tree
-            .insert(key, serde_json::to_vec(&encrypted).unwrap())
-            .unwrap();
-        self.db.flush().unwrap();
+        let outbox_entry = OutboxEntry::new(
+            Action::Update,
+            EntityType::Record,
+            serde_json::to_value(&encrypted)?,
+        );
+
+        self.commit(
+            vec![(key, Some(serde_json::to_vec(&encrypted)?))],
+            &outbox_entry,
+        )?;
 
         Ok(PlainRecord {
             id: encrypted.id,
@@ -373,42 +648,157 @@ impl UnlockedClient {
     }
 
     pub fn delete_record(&self, vault_id: Uuid, record_id: Uuid) -> Result<(), Error> {
-        self.data_tree
-            .remove(format!("record:{}:{}", vault_id, record_id))
-            .unwrap();
-        self.db.flush().unwrap();
+        let key = format!("record:{}:{}", vault_id, record_id);
+        let Some(record) = self.data_tree.get(&key)? else {
+            return Ok(());
+        };
+        let record: EncryptedRecord = serde_json::from_slice(&record)?;
+
+        let outbox_entry = OutboxEntry::new(
+            Action::Delete,
+            EntityType::Record,
+            serde_json::to_value(&record)?,
+        );
+
+        self.commit(vec![(key, None)], &outbox_entry)
+    }
+
+    /// Apply the given writes (`None` removes the key) to the data tree and
+    /// queue the outbox entry describing them in a single transaction, so the
+    /// local state and the outbox can never get out of step.
+    fn commit(
+        &self,
+        writes: Vec<(String, Option<Vec<u8>>)>,
+        outbox_entry: &OutboxEntry,
+    ) -> Result<(), Error> {
+        let entry = serde_json::to_vec(outbox_entry)?;
+
+        (&self.data_tree, &self.outbox_tree)
+            .transaction(|(data, outbox)| {
+                for (key, value) in &writes {
+                    match value {
+                        Some(value) => data.insert(key.as_bytes(), value.as_slice())?,
+                        None => data.remove(key.as_bytes())?,
+                    };
+                }
+                outbox.insert(
+                    outbox::key(outbox.generate_id()?).as_bytes(),
+                    entry.as_slice(),
+                )?;
+                Ok::<_, ConflictableTransactionError>(())
+            })
+            .map_err(|e| match e {
+                sled::transaction::TransactionError::Abort(e)
+                | sled::transaction::TransactionError::Storage(e) => Error::Storage(e),
+            })?;
+
+        self.db.flush()?;
+        self.sync.outbox_changed();
         Ok(())
     }
 
     // ------------------------------------------------------------------------------------
 
+    /// Push all queued local changes to the server and pull everything that
+    /// changed remotely since the last sync.
     pub async fn sync_once(&self) -> Result<(), Error> {
-        let Some(api_client) = &self.api_client else {
-            return Err(Error::SyncInOfflineMode);
-        };
+        self.sync.sync_once().await
+    }
 
-        unimplemented!()
+    /// The time of the last successful sync, if there was one.
+    pub async fn last_sync(&self) -> Option<UtcDateTime> {
+        let cursor = self.sync.last_sync().await?;
+        UtcDateTime::from_unix_timestamp(cursor).ok()
     }
 
+    /// Start syncing in the background, see [`crate::sync::SYNC_INTERVAL`].
+    ///
+    /// The task runs until [`UnlockedClient::stop_background_sync`] or
+    /// [`UnlockedClient::lock`] is called, or the client is dropped.
     pub fn start_background_sync(&self) -> Result<JoinHandle<()>, Error> {
-        let Some(api_client) = &self.api_client else {
+        if !self.sync.is_online() {
             return Err(Error::SyncInOfflineMode);
-        };
+        }
+
+        let mut background_sync = self.background_sync.lock().unwrap();
+        if background_sync.as_ref().is_some_and(|tx| !tx.is_closed()) {
+            return Err(Error::BackgroundSyncRunning);
+        }
+
+        let (tx, rx) = oneshot::channel();
+        *background_sync = Some(tx);
+
+        Ok(tokio::spawn(self.sync.clone().run(rx)))
+    }
+
+    /// Call `handler` whenever a sync runs into edits from another device
+    /// that could not be merged with local ones, see [`RecordConflict`].
+    pub fn on_conflict(&self, handler: impl Fn(RecordConflict) + Send + Sync + 'static) {
+        self.sync.set_conflict_handler(Some(Arc::new(handler)));
+    }
 
-        // Implementation details
-        unimplemented!()
+    /// Call `handler` whenever a local change fails to sync again after
+    /// [`crate::outbox::MAX_ATTEMPTS`] attempts, see [`FailedChange`].
+    pub fn on_failed_change(&self, handler: impl Fn(FailedChange) + Send + Sync + 'static) {
+        self.sync.set_failed_change_handler(Some(Arc::new(handler)));
+    }
+
+    /// Give up on a local change that fails to sync, see [`FailedChange`].
+    /// The next sync brings back the server's version of the item.
+    pub async fn discard_change(&self, id: Uuid) -> Result<(), Error> {
+        self.sync.discard_change(id).await
     }
 
     pub fn stop_background_sync(&self) -> Result<(), Error> {
-        // Implementation details
-        unimplemented!()
+        if let Some(tx) = self.background_sync.lock().unwrap().take() {
+            // the task may already be gone
+            let _ = tx.send(());
+        }
+        Ok(())
     }
 }
 
+/// Register the password again under a credential id, for accounts whose
+/// password file is still bound to their email.
+async fn reregister(api_client: &ApiClient, password: &str) -> Result<(), Error> {
+    let (login_state, login_message) =
+        sanctum_shared::login::client_start(password.as_bytes()).map_err(|_| Error::CryptoError)?;
+    let (state, message) = sanctum_shared::register::client_start(password.as_bytes())
+        .map_err(|_| Error::CryptoError)?;
+    let response = api_client
+        .reregister_start(&ReregisterStartRequest {
+            client_start: b64_encode(&message),
+            login_start: b64_encode(&login_message),
+        })
+        .await?;
+    let login_message = sanctum_shared::login::client_finish(
+        password.as_bytes(),
+        &login_state,
+        &b64_decode(&response.login.message)?,
+    )
+    .map_err(|_| Error::CryptoError)?;
+    let message = sanctum_shared::register::client_finish(
+        password.as_bytes(),
+        &state,
+        &b64_decode(&response.registration.server_start)?,
+    )
+    .map_err(|_| Error::CryptoError)?;
+
+    api_client
+        .reregister_finish(&ReregisterFinishRequest {
+            login_id: response.login.login_id,
+            login_finish: b64_encode(&login_message),
+            client_finish: b64_encode(&message),
+        })
+        .await
+}
+
 fn b64_encode(data: &[u8]) -> String {
-    base64::encode(data)
+    BASE64_STANDARD.encode(data)
 }
 
 fn b64_decode(encoded: &str) -> Result<Vec<u8>, Error> {
-    base64::decode(encoded).map_err(|_| Error::InvalidBase64)
+    BASE64_STANDARD
+        .decode(encoded)
+        .map_err(|_| Error::InvalidBase64)
 }
@@ -1,7 +1,28 @@
+use sanctum_shared::models::KdfParams;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub api_base_url: String,
+    /// The salt of the master key, taken from the profile on every login.
     pub salt: Vec<u8>,
+    /// How the master key is derived, taken from the profile like the salt.
+    #[serde(default)]
+    pub kdf: KdfParams,
+    /// Shown in the list of sessions, see [`crate::LockedClient::login`].
+    #[serde(default)]
+    pub device_name: Option<String>,
+}
+
+impl Config {
+    /// The config of a client which has never logged in. It can't be
+    /// unlocked offline before the first login.
+    pub fn new(api_base_url: String) -> Self {
+        Self {
+            api_base_url,
+            salt: Vec::new(),
+            kdf: KdfParams::default(),
+            device_name: None,
+        }
+    }
 }
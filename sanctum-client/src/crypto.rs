@@ -1,9 +1,10 @@
-use argon2::Argon2;
+use argon2::{Algorithm, Argon2, Params, Version};
 use base64::{Engine, prelude::BASE64_STANDARD};
 use chacha20poly1305::{
-    AeadCore, ChaCha20Poly1305, Key, KeyInit, Nonce,
+    AeadCore, ChaCha20Poly1305, KeyInit, Nonce,
     aead::{Aead, OsRng},
 };
+use sanctum_shared::models::KdfParams;
 use secrecy::ExposeSecret;
 
 use crate::{
@@ -11,11 +12,13 @@ use crate::{
     models::{EncryptedVault, PlainVault},
 };
 
-pub fn derive_key(password: &str, salt: &[u8]) -> Result<[u8; 32], Error> {
+pub fn derive_key(password: &str, salt: &[u8], kdf: &KdfParams) -> Result<[u8; 32], Error> {
     let mut master_key_bytes = [0u8; 32];
-    let argon2 = Argon2::default();
+    let params = Params::new(kdf.memory_kib, kdf.iterations, kdf.parallelism, Some(32))
+        .map_err(Error::DeriveKey)?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
     argon2
-        .hash_password_into(&password.as_bytes(), &salt, &mut master_key_bytes)
+        .hash_password_into(password.as_bytes(), salt, &mut master_key_bytes)
         .map_err(Error::DeriveKey)?;
     Ok(master_key_bytes)
 }
@@ -45,13 +48,19 @@ pub fn decrypt_data(data: &[u8], key: &[u8]) -> Result<Vec<u8>, Error> {
 }
 
 pub fn encrypt_vault(plain: &PlainVault, master_key: &[u8]) -> Result<EncryptedVault, Error> {
-    let encrypted_name = encrypt_data(plain.name.expose_secret().as_bytes(), &master_key).unwrap();
-    let encrypted_vault_key = encrypt_data(plain.key.expose_secret(), &master_key).unwrap();
+    // the name is encrypted with the vault key, see `decrypt_vault`
+    let encrypted_name = encrypt_data(
+        plain.name.expose_secret().as_bytes(),
+        plain.key.expose_secret(),
+    )
+    .unwrap();
+    let encrypted_vault_key = encrypt_data(plain.key.expose_secret(), master_key).unwrap();
 
     Ok(EncryptedVault {
         id: plain.id,
         encrypted_name: b64_encode(&encrypted_name),
         encrypted_vault_key: b64_encode(&encrypted_vault_key),
+        revision: 0,
         created_at: plain.created_at,
         updated_at: plain.updated_at,
     })
@@ -80,3 +89,22 @@ fn b64_decode(encoded: &str) -> Result<Vec<u8>, Error> {
         .decode(encoded)
         .map_err(|_| Error::InvalidBase64)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_kdf_matches_argon2_default() {
+        // accounts registered before the KDF parameters were stored
+        // derived their master key with `Argon2::default()`
+        let salt = b"saltsaltsaltsalt";
+        let mut expected = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(b"password", salt, &mut expected)
+            .unwrap();
+
+        let key = derive_key("password", salt, &KdfParams::default()).unwrap();
+        assert_eq!(key, expected);
+    }
+}
@@ -1,20 +1,97 @@
+use reqwest::StatusCode;
+use sanctum_shared::models::{ErrorCode, ErrorResponse, MfaMethod};
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Failed to derive key")]
     DeriveKey(argon2::Error),
 
+    #[error("Login failed: {0}")]
+    LoginFailed(String),
+
+    #[error("Registration failed: {0}")]
+    RegistrationFailed(String),
+
+    /// Log in again with one of the methods as second factor.
+    #[error("The account asks for a second factor")]
+    MfaRequired(Vec<MfaMethod>),
+
     #[error("Sync is not allowed in offline mode")]
     SyncInOfflineMode,
 
-    #[error("API error")]
-    ApiError(#[from] reqwest::Error),
+    #[error("Background sync is already running")]
+    BackgroundSyncRunning,
+
+    #[error("Failed to reach the server: {0}")]
+    Http(#[from] reqwest::Error),
+
+    /// The server answered with an error, match on the `code`.
+    #[error("{message}")]
+    Api {
+        status: StatusCode,
+        code: ErrorCode,
+        message: String,
+        /// Identifies the request in the server logs.
+        request_id: Option<String>,
+    },
+
+    #[error("There are local changes which have not been synced yet")]
+    UnsyncedChanges,
 
     #[error("Not found")]
     NotFound,
 
+    #[error("The item was changed on another device")]
+    Conflict,
+
     #[error("Crypto error")]
     CryptoError,
 
     #[error("Invalid base64 data")]
     InvalidBase64,
+
+    #[error("Storage error")]
+    Storage(#[from] sled::Error),
+
+    #[error("Serialization error")]
+    Serialization(#[from] serde_json::Error),
+}
+
+impl Error {
+    /// The code of an error answer of the server.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Error::Api { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Pass a successful `response` on, and turn an error answer into
+    /// [`Error::Api`].
+    pub(crate) async fn check(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        let body = response.bytes().await?;
+        Err(match serde_json::from_slice::<ErrorResponse>(&body) {
+            Ok(error) => Error::Api {
+                status,
+                code: error.code,
+                message: error.message,
+                request_id: Some(error.request_id),
+            },
+            // e.g. from a proxy in front of the server
+            Err(_) => Error::Api {
+                status,
+                code: ErrorCode::Unknown,
+                message: status
+                    .canonical_reason()
+                    .unwrap_or("Request failed")
+                    .to_string(),
+                request_id: None,
+            },
+        })
+    }
 }
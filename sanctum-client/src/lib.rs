@@ -4,9 +4,14 @@ mod client;
 mod config;
 mod crypto;
 mod error;
+mod merge;
 mod models;
 mod outbox;
+pub mod sync;
 
+pub use auth::SecondFactor;
 pub use client::{LockedClient, UnlockedClient};
 pub use config::Config;
 pub use error::Error;
+pub use merge::RecordConflict;
+pub use outbox::{EntityType, FailedChange};
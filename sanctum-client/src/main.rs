@@ -2,13 +2,11 @@ use sanctum_client::{Config, LockedClient};
 
 #[tokio::main]
 async fn main() {
+    let email = "test@example.com";
     let password = "password";
-    let config = Config {
-        api_base_url: "http://localhost:3000".to_string(),
-        salt: vec![0; 32],
-    };
+    let config = Config::new("http://localhost:3000".to_string());
     let client = LockedClient::from_config(config).unwrap();
-    let client = client.unlock_offline(password).unwrap();
+    let client = client.login(email, password, None).await.unwrap();
 
     let vault = client.create_vault("Personal").unwrap();
     let record = client
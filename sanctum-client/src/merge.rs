@@ -0,0 +1,306 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, RwLock},
+};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use secrecy::{ExposeSecret, SecretSlice};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use crate::{
+    Error,
+    crypto::{decrypt_data, encrypt_data},
+    models::{EncryptedRecord, EncryptedVault},
+};
+
+/// Reported when a record was edited on this and another device, and
+/// the edits could not be merged.
+///
+/// The record itself keeps the server's value for every conflicting field,
+/// the local version is kept in full as a new record, the conflicted copy.
+#[derive(Debug, Clone)]
+pub struct RecordConflict {
+    pub vault_id: Uuid,
+    pub record_id: Uuid,
+    /// The id of the conflicted copy.
+    pub copy_id: Uuid,
+    /// The fields that were changed on both devices. Empty if the record
+    /// is not a JSON object and could only be compared as a whole.
+    pub fields: Vec<String>,
+}
+
+/// The result of [`Resolver::merge`].
+pub(crate) struct Merged {
+    /// The merged record, based on the server copy it was merged with.
+    pub record: EncryptedRecord,
+    /// The conflicting fields, `None` if the merge was clean.
+    pub conflicts: Option<Vec<String>>,
+}
+
+/// Merges records which were changed locally and on the server since
+/// the last sync.
+///
+/// This is the only part of the sync that ever sees plaintexts, which is
+/// why it holds on to a copy of the master key. The copy is shared between
+/// clones, so a new master key reaches the background sync as well.
+#[derive(Clone)]
+pub(crate) struct Resolver {
+    master_key: Arc<RwLock<SecretSlice<u8>>>,
+}
+
+impl Resolver {
+    pub fn new(master_key: SecretSlice<u8>) -> Self {
+        Self {
+            master_key: Arc::new(RwLock::new(master_key)),
+        }
+    }
+
+    /// Replace the master key, after the master password was changed.
+    pub fn set_master_key(&self, master_key: SecretSlice<u8>) {
+        *self.master_key.write().unwrap() = master_key;
+    }
+
+    /// Three-way merge `local` and `remote`, using the last synced version
+    /// of `local` as the common ancestor.
+    ///
+    /// The merged record is encrypted with the local record key, and so is
+    /// its base, the remote data, for the next merge to decrypt.
+    pub fn merge(
+        &self,
+        vault: &EncryptedVault,
+        local: &EncryptedRecord,
+        remote: &EncryptedRecord,
+    ) -> Result<Merged, Error> {
+        let vault_key = decrypt_data(
+            &b64_decode(&vault.encrypted_vault_key)?,
+            self.master_key.read().unwrap().expose_secret(),
+        )?;
+
+        let local_key = decrypt_data(&b64_decode(&local.encrypted_record_key)?, &vault_key)?;
+        let remote_key = decrypt_data(&b64_decode(&remote.encrypted_record_key)?, &vault_key)?;
+
+        let local_data = decrypt_text(&local.encrypted_data_blob, &local_key)?;
+        let remote_data = decrypt_text(&remote.encrypted_data_blob, &remote_key)?;
+        // the last synced version was encrypted with the local record key
+        let base_data = match &local.base {
+            Some(base) => Some(decrypt_text(base, &local_key)?),
+            None => None,
+        };
+
+        let (data, conflicts) = merge_data(base_data.as_deref(), &local_data, &remote_data);
+
+        Ok(Merged {
+            record: EncryptedRecord {
+                id: remote.id,
+                vault_id: remote.vault_id,
+                encrypted_record_key: local.encrypted_record_key.clone(),
+                encrypted_data_blob: b64_encode(&encrypt_data(data.as_bytes(), &local_key)?),
+                revision: remote.revision,
+                base: Some(b64_encode(&encrypt_data(
+                    remote_data.as_bytes(),
+                    &local_key,
+                )?)),
+                created_at: remote.created_at,
+                updated_at: local.updated_at.max(remote.updated_at),
+            },
+            conflicts,
+        })
+    }
+}
+
+/// Merge the record data `local` and `remote` with their common ancestor `base`.
+///
+/// JSON objects are merged field by field: a field changed on only one side
+/// takes that side's value. Anything else is compared as a whole. Conflicting
+/// values resolve to `remote`, the conflicting fields are returned alongside.
+pub(crate) fn merge_data(
+    base: Option<&str>,
+    local: &str,
+    remote: &str,
+) -> (String, Option<Vec<String>>) {
+    let objects = (
+        base.map(serde_json::from_str::<Map<String, Value>>)
+            .transpose(),
+        serde_json::from_str::<Map<String, Value>>(local),
+        serde_json::from_str::<Map<String, Value>>(remote),
+    );
+
+    let (Ok(base_fields), Ok(local_fields), Ok(remote_fields)) = objects else {
+        return match merge_value(base, Some(local), Some(remote)) {
+            Ok(value) => (value.unwrap_or(remote).to_string(), None),
+            Err(()) => (remote.to_string(), Some(vec![])),
+        };
+    };
+    let base_fields = base_fields.unwrap_or_default();
+
+    let names = base_fields
+        .keys()
+        .chain(local_fields.keys())
+        .chain(remote_fields.keys())
+        .collect::<BTreeSet<_>>();
+
+    let mut merged = Map::new();
+    let mut conflicts = Vec::new();
+
+    for name in names {
+        let value = match merge_value(
+            base_fields.get(name),
+            local_fields.get(name),
+            remote_fields.get(name),
+        ) {
+            Ok(value) => value,
+            Err(()) => {
+                conflicts.push(name.clone());
+                remote_fields.get(name)
+            }
+        };
+        if let Some(value) = value {
+            merged.insert(name.clone(), value.clone());
+        }
+    }
+
+    let conflicts = (!conflicts.is_empty()).then_some(conflicts);
+    (Value::Object(merged).to_string(), conflicts)
+}
+
+/// Merge a single value, `None` meaning absent. Fails if both sides
+/// changed it in different ways.
+fn merge_value<T: PartialEq>(
+    base: Option<T>,
+    local: Option<T>,
+    remote: Option<T>,
+) -> Result<Option<T>, ()> {
+    if local == remote || local == base {
+        Ok(remote)
+    } else if remote == base {
+        Ok(local)
+    } else {
+        Err(())
+    }
+}
+
+fn decrypt_text(encoded: &str, key: &[u8]) -> Result<String, Error> {
+    let data = decrypt_data(&b64_decode(encoded)?, key)?;
+    String::from_utf8(data).map_err(|_| Error::CryptoError)
+}
+
+fn b64_encode(data: &[u8]) -> String {
+    BASE64_STANDARD.encode(data)
+}
+
+fn b64_decode(encoded: &str) -> Result<Vec<u8>, Error> {
+    BASE64_STANDARD
+        .decode(encoded)
+        .map_err(|_| Error::InvalidBase64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &str) -> Value {
+        serde_json::from_str(data).unwrap()
+    }
+
+    fn encrypt(data: &str, key: &[u8]) -> String {
+        b64_encode(&encrypt_data(data.as_bytes(), key).unwrap())
+    }
+
+    fn record(data: &str, key: &[u8], vault_key: &[u8], revision: i64) -> EncryptedRecord {
+        EncryptedRecord {
+            id: Uuid::nil(),
+            vault_id: Uuid::nil(),
+            encrypted_record_key: b64_encode(&encrypt_data(key, vault_key).unwrap()),
+            encrypted_data_blob: encrypt(data, key),
+            revision,
+            base: None,
+            created_at: time::UtcDateTime::UNIX_EPOCH,
+            updated_at: time::UtcDateTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn merged_records_merge_again() {
+        let master_key = [1; 32];
+        let vault_key = [2; 32];
+        let (local_key, remote_key) = ([3; 32], [4; 32]);
+        let vault = EncryptedVault {
+            id: Uuid::nil(),
+            encrypted_vault_key: b64_encode(&encrypt_data(&vault_key, &master_key).unwrap()),
+            encrypted_name: String::new(),
+            revision: 1,
+            created_at: time::UtcDateTime::UNIX_EPOCH,
+            updated_at: time::UtcDateTime::UNIX_EPOCH,
+        };
+        let resolver = Resolver::new(master_key.to_vec().into());
+
+        let mut local = record(r#"{"a":2,"b":1}"#, &local_key, &vault_key, 1);
+        local.base = Some(encrypt(r#"{"a":1,"b":1}"#, &local_key));
+        let remote = record(r#"{"a":1,"b":2}"#, &remote_key, &vault_key, 2);
+        let merged = resolver.merge(&vault, &local, &remote).unwrap();
+        assert_eq!(merged.conflicts, None);
+
+        // pushing the merge lost against yet another remote edit
+        let remote = record(r#"{"a":1,"b":3}"#, &remote_key, &vault_key, 3);
+        let merged = resolver.merge(&vault, &merged.record, &remote).unwrap();
+        assert_eq!(merged.conflicts, None);
+
+        let key = decrypt_data(
+            &b64_decode(&merged.record.encrypted_record_key).unwrap(),
+            &vault_key,
+        )
+        .unwrap();
+        let data = decrypt_text(&merged.record.encrypted_data_blob, &key).unwrap();
+        assert_eq!(parse(&data), parse(r#"{"a":2,"b":3}"#));
+    }
+
+    #[test]
+    fn disjoint_edits_merge() {
+        let base = r#"{"username":"alice","url":"a.com","password":"x"}"#;
+        let local = r#"{"username":"bob","url":"a.com","password":"x"}"#;
+        let remote = r#"{"username":"alice","url":"b.com","password":"x","notes":"n"}"#;
+
+        let (merged, conflicts) = merge_data(Some(base), local, remote);
+
+        assert_eq!(conflicts, None);
+        assert_eq!(
+            parse(&merged),
+            parse(r#"{"username":"bob","url":"b.com","password":"x","notes":"n"}"#)
+        );
+    }
+
+    #[test]
+    fn deleted_field_stays_deleted() {
+        let base = r#"{"username":"alice","notes":"n"}"#;
+        let local = r#"{"username":"alice"}"#;
+        let remote = r#"{"username":"bob","notes":"n"}"#;
+
+        let (merged, conflicts) = merge_data(Some(base), local, remote);
+
+        assert_eq!(conflicts, None);
+        assert_eq!(parse(&merged), parse(r#"{"username":"bob"}"#));
+    }
+
+    #[test]
+    fn conflicting_edits_keep_remote_value() {
+        let base = r#"{"password":"x","url":"a.com"}"#;
+        let local = r#"{"password":"y","url":"b.com"}"#;
+        let remote = r#"{"password":"z","url":"a.com"}"#;
+
+        let (merged, conflicts) = merge_data(Some(base), local, remote);
+
+        assert_eq!(conflicts, Some(vec!["password".to_string()]));
+        assert_eq!(parse(&merged), parse(r#"{"password":"z","url":"b.com"}"#));
+    }
+
+    #[test]
+    fn non_objects_are_compared_as_a_whole() {
+        assert_eq!(merge_data(Some("a"), "b", "a"), ("b".to_string(), None));
+        assert_eq!(merge_data(Some("a"), "a", "c"), ("c".to_string(), None));
+        assert_eq!(
+            merge_data(Some("a"), "b", "c"),
+            ("c".to_string(), Some(vec![]))
+        );
+    }
+}
@@ -31,21 +31,33 @@ pub struct PlainRecord {
 //
 // These mirror the server-side shapes (they store base64-encoded
 // ciphertexts for encrypted fields).
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EncryptedVault {
     pub id: Uuid,
     pub encrypted_vault_key: String,
     pub encrypted_name: String,
+    /// The server revision this copy is based on, `0` if it was never synced.
+    #[serde(default)]
+    pub revision: i64,
     pub created_at: UtcDateTime,
     pub updated_at: UtcDateTime,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EncryptedRecord {
     pub id: Uuid,
     pub vault_id: Uuid,
     pub encrypted_record_key: String,
     pub encrypted_data_blob: String,
+    /// The server revision this copy is based on, `0` if it was never synced.
+    #[serde(default)]
+    pub revision: i64,
+    /// The `encrypted_data_blob` at `revision`, i.e. as last synced.
+    ///
+    /// It is the common ancestor when local changes have to be merged with
+    /// changes from another device, see [`crate::merge::merge_data`].
+    #[serde(default)]
+    pub base: Option<String>,
     pub created_at: UtcDateTime,
     pub updated_at: UtcDateTime,
 }
@@ -56,6 +68,7 @@ impl From<Vault> for EncryptedVault {
             id: value.id,
             encrypted_vault_key: value.encrypted_vault_key,
             encrypted_name: value.encrypted_name,
+            revision: value.revision,
             created_at: value.created_at,
             updated_at: value.updated_at,
         }
@@ -68,7 +81,9 @@ impl From<Record> for EncryptedRecord {
             id: value.id,
             vault_id: value.vault_id,
             encrypted_record_key: value.encrypted_record_key,
+            base: Some(value.encrypted_data_blob.clone()),
             encrypted_data_blob: value.encrypted_data_blob,
+            revision: value.revision,
             created_at: value.created_at,
             updated_at: value.updated_at,
         }
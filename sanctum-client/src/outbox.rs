@@ -41,6 +41,35 @@ pub struct OutboxEntry {
     pub status: OutboxStatus,
 }
 
+/// How often an entry is attempted before each further failure is
+/// reported as a [`FailedChange`]. It stays queued and is retried on every
+/// sync until it goes through or is discarded.
+pub const MAX_ATTEMPTS: u32 = 5;
+
+/// A local change the server rejected [`MAX_ATTEMPTS`] times or more.
+///
+/// Until it is discarded, the item keeps its local version and syncs
+/// don't overwrite it.
+#[derive(Debug, Clone)]
+pub struct FailedChange {
+    /// Pass it to [`crate::UnlockedClient::discard_change`] to drop the
+    /// change and get the server's version back.
+    pub id: Uuid,
+    pub entity_type: EntityType,
+    pub entity_id: Option<Uuid>,
+    pub attempts: u32,
+    /// Why the last attempt failed.
+    pub error: String,
+}
+
+/// The sled key of an outbox entry.
+///
+/// `seq` comes from [`sled::Db::generate_id`], it is zero-padded so that
+/// iterating the outbox tree yields the entries in the order they were queued.
+pub fn key(seq: u64) -> String {
+    format!("outbox:{:020}", seq)
+}
+
 impl OutboxEntry {
     pub fn new(action: Action, entity_type: EntityType, payload: Value) -> Self {
         Self {
@@ -53,9 +82,96 @@ impl OutboxEntry {
             status: OutboxStatus::Pending,
         }
     }
+
+    /// The id of the vault or record this entry refers to.
+    pub fn entity_id(&self) -> Option<Uuid> {
+        self.payload
+            .get("id")
+            .and_then(Value::as_str)
+            .and_then(|id| Uuid::parse_str(id).ok())
+    }
+
+    /// The item this entry refers to and, for records, their vault. The
+    /// entry can't go through while an earlier one for either failed.
+    pub fn depends_on(&self) -> impl Iterator<Item = Uuid> {
+        let vault_id = match self.entity_type {
+            EntityType::Vault => None,
+            EntityType::Record => self
+                .payload
+                .get("vault_id")
+                .and_then(Value::as_str)
+                .and_then(|id| Uuid::parse_str(id).ok()),
+        };
+        self.entity_id().into_iter().chain(vault_id)
+    }
+
+    /// The key of the item this entry refers to in the data tree.
+    pub fn data_key(&self) -> Option<String> {
+        let id = self.entity_id()?;
+        match self.entity_type {
+            EntityType::Vault => Some(format!("vault:{}", id)),
+            EntityType::Record => {
+                let vault_id = self.payload.get("vault_id")?.as_str()?;
+                Some(format!("record:{}:{}", vault_id, id))
+            }
+        }
+    }
+
+    /// Whether this entry still has to be sent to the server. Entries are
+    /// never given up on, however often they failed.
+    pub fn is_unsent(&self) -> bool {
+        self.status != OutboxStatus::Sent
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
+    use super::*;
+
+    #[test]
+    fn keys_sort_in_queue_order() {
+        let mut keys = vec![key(10), key(9), key(100), key(0)];
+        keys.sort();
+        assert_eq!(keys, vec![key(0), key(9), key(10), key(100)]);
+    }
+
+    #[test]
+    fn failed_entries_stay_unsent() {
+        let mut entry = OutboxEntry::new(Action::Update, EntityType::Vault, Value::Null);
+        entry.status = OutboxStatus::Failed;
+        entry.attempts = MAX_ATTEMPTS + 1;
+        assert!(entry.is_unsent());
+
+        entry.status = OutboxStatus::Sent;
+        assert!(!entry.is_unsent());
+    }
+
+    #[test]
+    fn records_depend_on_their_vault() {
+        let (id, vault_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let entry = OutboxEntry::new(
+            Action::Update,
+            EntityType::Record,
+            serde_json::json!({ "id": id, "vault_id": vault_id }),
+        );
+        assert_eq!(entry.depends_on().collect::<Vec<_>>(), vec![id, vault_id]);
+
+        let entry = OutboxEntry::new(
+            Action::Update,
+            EntityType::Vault,
+            serde_json::json!({ "id": vault_id }),
+        );
+        assert_eq!(entry.depends_on().collect::<Vec<_>>(), vec![vault_id]);
+    }
+
+    #[test]
+    fn entity_id_is_read_from_payload() {
+        let id = Uuid::new_v4();
+        let entry = OutboxEntry::new(
+            Action::Delete,
+            EntityType::Record,
+            serde_json::json!({ "id": id, "vault_id": Uuid::new_v4() }),
+        );
+        assert_eq!(entry.entity_id(), Some(id));
+    }
 }
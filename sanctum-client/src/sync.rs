@@ -0,0 +1,647 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use sanctum_shared::models::{
+    CreateRecordRequest, CreateVaultRequest, ErrorCode, Record, Tombstone,
+};
+use secrecy::SecretSlice;
+use tokio::sync::{Mutex, Notify, oneshot};
+use tokio::time::sleep;
+use uuid::Uuid;
+
+use crate::{
+    Error,
+    api::{ApiClient, Write},
+    merge::{RecordConflict, Resolver},
+    models::{EncryptedRecord, EncryptedVault},
+    outbox::{Action, EntityType, FailedChange, MAX_ATTEMPTS, OutboxEntry, OutboxStatus},
+};
+
+/// Key of the persisted sync cursor in the `meta` tree.
+const LAST_SYNC_KEY: &str = "last_sync";
+
+/// How long the background sync waits between two syncs
+/// when there are no local changes.
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The first delay after a failed background sync. It is doubled
+/// after each further failure, up to [`MAX_BACKOFF`].
+pub const MIN_BACKOFF: Duration = Duration::from_secs(5);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Called for every record conflict that could not be merged automatically.
+pub type ConflictHandler = Arc<dyn Fn(RecordConflict) + Send + Sync>;
+
+/// Called for every failed attempt of a change from its
+/// [`MAX_ATTEMPTS`]th on.
+pub type FailedChangeHandler = Arc<dyn Fn(FailedChange) + Send + Sync>;
+
+/// The part of the client that talks to the server.
+///
+/// Apart from the [`Resolver`] for conflicting edits it only ever handles
+/// ciphertexts, so it is cheap to clone into the background sync task.
+#[derive(Clone)]
+pub(crate) struct SyncEngine {
+    api_client: Option<Arc<ApiClient>>,
+    resolver: Resolver,
+    on_conflict: Arc<std::sync::Mutex<Option<ConflictHandler>>>,
+    on_failed_change: Arc<std::sync::Mutex<Option<FailedChangeHandler>>>,
+    db: sled::Db,
+    data_tree: sled::Tree,
+    outbox_tree: sled::Tree,
+    meta_tree: sled::Tree,
+    /// The cursor (in UNIX seconds) returned by the last successful sync.
+    ///
+    /// The lock is held for the whole duration of [`SyncEngine::sync_once`],
+    /// so there is never more than one sync running at a time.
+    last_sync: Arc<Mutex<Option<i64>>>,
+    /// Notified whenever a local change is queued in the outbox.
+    outbox_changed: Arc<Notify>,
+}
+
+impl SyncEngine {
+    pub fn open(
+        api_client: Option<ApiClient>,
+        resolver: Resolver,
+        db: &sled::Db,
+    ) -> Result<Self, Error> {
+        let meta_tree = db.open_tree("meta")?;
+
+        let last_sync = match meta_tree.get(LAST_SYNC_KEY)? {
+            Some(value) => Some(serde_json::from_slice(&value)?),
+            None => None,
+        };
+
+        Ok(Self {
+            api_client: api_client.map(Arc::new),
+            resolver,
+            on_conflict: Arc::new(std::sync::Mutex::new(None)),
+            on_failed_change: Arc::new(std::sync::Mutex::new(None)),
+            db: db.clone(),
+            data_tree: db.open_tree("data")?,
+            outbox_tree: db.open_tree("outbox")?,
+            meta_tree,
+            last_sync: Arc::new(Mutex::new(last_sync)),
+            outbox_changed: Arc::new(Notify::new()),
+        })
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.api_client.is_some()
+    }
+
+    pub fn api_client(&self) -> Option<&ApiClient> {
+        self.api_client.as_deref()
+    }
+
+    pub async fn last_sync(&self) -> Option<i64> {
+        *self.last_sync.lock().await
+    }
+
+    pub fn set_master_key(&self, master_key: SecretSlice<u8>) {
+        self.resolver.set_master_key(master_key);
+    }
+
+    /// Whether there are local changes which have not reached the server yet.
+    pub fn has_unsent_changes(&self) -> Result<bool, Error> {
+        Ok(!self.unsent_entity_ids()?.is_empty())
+    }
+
+    pub fn set_conflict_handler(&self, handler: Option<ConflictHandler>) {
+        *self.on_conflict.lock().unwrap() = handler;
+    }
+
+    pub fn set_failed_change_handler(&self, handler: Option<FailedChangeHandler>) {
+        *self.on_failed_change.lock().unwrap() = handler;
+    }
+
+    /// Drop the queued change with `id` and forget the sync cursor, so the
+    /// next sync pulls everything and the server's version of the item
+    /// replaces the local one.
+    pub async fn discard_change(&self, id: Uuid) -> Result<(), Error> {
+        let mut last_sync = self.last_sync.lock().await;
+
+        for item in self.outbox_tree.iter() {
+            let (key, value) = item?;
+            let entry: OutboxEntry = serde_json::from_slice(&value)?;
+            if entry.id == id {
+                self.outbox_tree.remove(key)?;
+                self.meta_tree.remove(LAST_SYNC_KEY)?;
+                self.db.flush_async().await?;
+                *last_sync = None;
+                return Ok(());
+            }
+        }
+
+        Err(Error::NotFound)
+    }
+
+    /// Wake up the background sync, if it is running.
+    pub fn outbox_changed(&self) {
+        self.outbox_changed.notify_one();
+    }
+
+    /// Push all queued local changes to the server and pull everything that
+    /// changed remotely since the last sync.
+    ///
+    /// Outbox entries are sent in the order they were queued. An entry that
+    /// fails holds back the later entries for the same item and those for
+    /// records of the same vault, which may depend on it, and is retried on
+    /// the next sync. From its [`MAX_ATTEMPTS`]th failure on, each one is
+    /// reported to the [`FailedChangeHandler`] as well.
+    ///
+    /// The pull runs either way; it leaves items with unsent changes alone.
+    /// The first error of the push is returned after it.
+    pub async fn sync_once(&self) -> Result<(), Error> {
+        let Some(api_client) = &self.api_client else {
+            return Err(Error::SyncInOfflineMode);
+        };
+
+        let mut last_sync = self.last_sync.lock().await;
+
+        let pushed = self.push(api_client).await;
+        let cursor = self.pull(api_client, *last_sync).await?;
+
+        self.meta_tree
+            .insert(LAST_SYNC_KEY, serde_json::to_vec(&cursor)?)?;
+        self.prune_outbox()?;
+        self.db.flush_async().await?;
+
+        *last_sync = Some(cursor);
+        pushed
+    }
+
+    /// Run [`SyncEngine::sync_once`] every [`SYNC_INTERVAL`], and right away
+    /// when a local change is queued, until `shutdown` fires or its sender
+    /// is dropped.
+    ///
+    /// API errors back off exponentially between [`MIN_BACKOFF`] and
+    /// [`MAX_BACKOFF`]; local changes don't cut a backoff short.
+    pub async fn run(self, mut shutdown: oneshot::Receiver<()>) {
+        let mut backoff = None;
+
+        loop {
+            let delay = tokio::select! {
+                _ = &mut shutdown => return,
+                result = self.sync_once() => match result {
+                    Err(Error::Http(_) | Error::Api { .. }) => {
+                        let delay = next_backoff(backoff);
+                        backoff = Some(delay);
+                        delay
+                    }
+                    _ => {
+                        backoff = None;
+                        SYNC_INTERVAL
+                    }
+                },
+            };
+
+            tokio::select! {
+                _ = &mut shutdown => return,
+                _ = sleep(delay) => {}
+                _ = self.outbox_changed.notified(), if backoff.is_none() => {}
+            }
+        }
+    }
+
+    async fn push(&self, api_client: &ApiClient) -> Result<(), Error> {
+        let keys = self
+            .outbox_tree
+            .iter()
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // what the server returned during this push, later entries
+        // for the same item were queued against an older revision
+        let mut synced = HashMap::new();
+        // the items and vaults of entries that failed
+        let mut blocked = HashSet::new();
+        let mut first_error = None;
+
+        for key in keys {
+            let Some(value) = self.outbox_tree.get(&key)? else {
+                continue;
+            };
+            let mut entry: OutboxEntry = serde_json::from_slice(&value)?;
+            if !entry.is_unsent() || entry.depends_on().any(|id| blocked.contains(&id)) {
+                continue;
+            }
+
+            entry.status = OutboxStatus::InFlight;
+            entry.attempts += 1;
+            self.outbox_tree.insert(&key, serde_json::to_vec(&entry)?)?;
+
+            let last_synced = entry.entity_id().and_then(|id| synced.get(&id).cloned());
+            let result = self.send(api_client, &entry, last_synced).await;
+
+            entry.status = match result {
+                Ok(_) => OutboxStatus::Sent,
+                Err(_) => OutboxStatus::Failed,
+            };
+            self.outbox_tree.insert(&key, serde_json::to_vec(&entry)?)?;
+
+            match result {
+                Ok(Some(last_synced)) => {
+                    if let Some(key) = entry.data_key() {
+                        self.set_synced(key, &last_synced)?;
+                    }
+                    if let Some(id) = entry.entity_id() {
+                        synced.insert(id, last_synced);
+                    }
+                }
+                Ok(None) => {}
+                // the other entries won't get through either
+                Err(e @ Error::Http(_)) => return Err(e),
+                Err(e) => {
+                    // the id is taken by another user's vault or another
+                    // vault's record, retrying won't help
+                    let taken =
+                        matches!(&e, Error::Api { status, .. } if *status == StatusCode::CONFLICT);
+                    if taken || entry.attempts >= MAX_ATTEMPTS {
+                        self.report_failed(&entry, &e);
+                    }
+                    blocked.extend(entry.entity_id());
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        first_error.map_or(Ok(()), Err)
+    }
+
+    /// Send a single outbox entry to the server.
+    ///
+    /// `last_synced` overrides what is stored in the payload. Returns what the
+    /// server now has, or `None` if the item was deleted.
+    async fn send(
+        &self,
+        api_client: &ApiClient,
+        entry: &OutboxEntry,
+        last_synced: Option<Synced>,
+    ) -> Result<Option<Synced>, Error> {
+        let result = match (&entry.entity_type, &entry.action) {
+            (EntityType::Vault, Action::Create | Action::Update) => {
+                let vault: EncryptedVault = serde_json::from_value(entry.payload.clone())?;
+                let request = CreateVaultRequest {
+                    encrypted_vault_key: vault.encrypted_vault_key,
+                    encrypted_name: vault.encrypted_name,
+                };
+                let revision = last_synced.map_or(vault.revision, |synced| synced.revision);
+                let written = match api_client
+                    .update_vault(&vault.id, revision, &request)
+                    .await?
+                {
+                    Write::Done(vault) => Write::Done(vault),
+                    // the name is the only thing edited locally, the vault
+                    // key is rewrapped by password changes and the server's
+                    // wrapping is the current one
+                    Write::Conflict(remote) => {
+                        let request = CreateVaultRequest {
+                            encrypted_vault_key: remote.encrypted_vault_key,
+                            ..request
+                        };
+                        api_client
+                            .update_vault(&vault.id, remote.revision, &request)
+                            .await?
+                    }
+                };
+                match written {
+                    Write::Done(vault) => Ok(Some(Synced {
+                        revision: vault.revision,
+                        base: None,
+                        encrypted_vault_key: Some(vault.encrypted_vault_key),
+                    })),
+                    // changed yet again in the meantime, try again on the next sync
+                    Write::Conflict(_) => Err(Error::Conflict),
+                }
+            }
+            (EntityType::Vault, Action::Delete) => {
+                let vault: EncryptedVault = serde_json::from_value(entry.payload.clone())?;
+                api_client.delete_vault(&vault.id).await.map(|_| None)
+            }
+            (EntityType::Record, Action::Create | Action::Update) => {
+                let mut record: EncryptedRecord = serde_json::from_value(entry.payload.clone())?;
+                if let Some(synced) = last_synced {
+                    record.revision = synced.revision;
+                    record.base = synced.base;
+                }
+                let written = match push_record(api_client, &record).await {
+                    // deleted on another device in the meantime, the edit
+                    // brings it back
+                    Err(e)
+                        if e.code() == Some(ErrorCode::RecordNotFound) && record.revision != 0 =>
+                    {
+                        record.revision = 0;
+                        record.base = None;
+                        push_record(api_client, &record).await?
+                    }
+                    written => written?,
+                };
+                match written {
+                    Write::Done(remote) => Ok(Some(Synced::from(remote))),
+                    Write::Conflict(remote) => self.resolve(api_client, record, remote).await,
+                }
+            }
+            (EntityType::Record, Action::Delete) => {
+                let record: EncryptedRecord = serde_json::from_value(entry.payload.clone())?;
+                api_client
+                    .delete_record(&record.vault_id, &record.id)
+                    .await
+                    .map(|_| None)
+            }
+        };
+
+        match result {
+            // whatever we wanted to delete is already gone
+            Err(Error::Api { status, .. })
+                if entry.action == Action::Delete && status == StatusCode::NOT_FOUND =>
+            {
+                Ok(None)
+            }
+            // deleted on another device, which takes its records with it,
+            // the pull removes the local copy
+            Err(e) if e.code() == Some(ErrorCode::VaultNotFound) => Ok(None),
+            result => result,
+        }
+    }
+
+    /// Merge a record that was changed both locally and on the server, and
+    /// push the result.
+    ///
+    /// When the edits conflict, the local version is uploaded as a new record
+    /// first, so it is never lost, and the conflict handler is called.
+    async fn resolve(
+        &self,
+        api_client: &ApiClient,
+        local: EncryptedRecord,
+        remote: Record,
+    ) -> Result<Option<Synced>, Error> {
+        let vault = self
+            .data_tree
+            .get(format!("vault:{}", local.vault_id))?
+            .ok_or(Error::NotFound)?;
+        let vault: EncryptedVault = serde_json::from_slice(&vault)?;
+
+        let remote = EncryptedRecord::from(remote);
+        let merged = self.resolver.merge(&vault, &local, &remote)?;
+
+        let conflict = match merged.conflicts {
+            Some(fields) => {
+                let copy = EncryptedRecord {
+                    id: Uuid::new_v4(),
+                    revision: 0,
+                    base: None,
+                    ..local.clone()
+                };
+                let Write::Done(uploaded) = push_record(api_client, &copy).await? else {
+                    return Err(Error::Conflict);
+                };
+                self.data_tree.insert(
+                    format!("record:{}:{}", copy.vault_id, copy.id),
+                    serde_json::to_vec(&EncryptedRecord {
+                        revision: uploaded.revision,
+                        base: Some(uploaded.encrypted_data_blob),
+                        ..copy.clone()
+                    })?,
+                )?;
+                Some(RecordConflict {
+                    vault_id: local.vault_id,
+                    record_id: local.id,
+                    copy_id: copy.id,
+                    fields,
+                })
+            }
+            None => None,
+        };
+
+        let Write::Done(uploaded) = push_record(api_client, &merged.record).await? else {
+            // changed yet again in the meantime, try again on the next sync
+            return Err(Error::Conflict);
+        };
+
+        let key = format!("record:{}:{}", local.vault_id, local.id);
+        let stored = self.data_tree.get(&key)?;
+        let stored: Option<EncryptedRecord> =
+            stored.map(|v| serde_json::from_slice(&v)).transpose()?;
+
+        // don't overwrite local edits made after this entry was queued
+        if stored.is_none_or(|stored| stored.encrypted_data_blob == local.encrypted_data_blob) {
+            let record = EncryptedRecord {
+                revision: uploaded.revision,
+                base: Some(uploaded.encrypted_data_blob.clone()),
+                ..merged.record
+            };
+            self.data_tree.insert(key, serde_json::to_vec(&record)?)?;
+        }
+
+        if let Some(conflict) = conflict {
+            let handler = self.on_conflict.lock().unwrap().clone();
+            if let Some(handler) = handler {
+                handler(conflict);
+            }
+        }
+
+        Ok(Some(Synced::from(uploaded)))
+    }
+
+    fn report_failed(&self, entry: &OutboxEntry, error: &Error) {
+        let handler = self.on_failed_change.lock().unwrap().clone();
+        if let Some(handler) = handler {
+            handler(FailedChange {
+                id: entry.id,
+                entity_type: entry.entity_type.clone(),
+                entity_id: entry.entity_id(),
+                attempts: entry.attempts,
+                error: error.to_string(),
+            });
+        }
+    }
+
+    /// Record what the server now has of the item stored under `key`.
+    fn set_synced(&self, key: String, synced: &Synced) -> Result<(), Error> {
+        self.data_tree.update_and_fetch(key, |value| {
+            let value = value?;
+            let Ok(mut item) = serde_json::from_slice::<serde_json::Value>(value) else {
+                return Some(value.to_vec());
+            };
+            item["revision"] = synced.revision.into();
+            if let Some(base) = &synced.base {
+                item["base"] = base.as_str().into();
+            }
+            if let Some(key) = &synced.encrypted_vault_key {
+                item["encrypted_vault_key"] = key.as_str().into();
+            }
+            serde_json::to_vec(&item).ok()
+        })?;
+        Ok(())
+    }
+
+    /// Apply the remote changes since `since` to the data tree and
+    /// return the new cursor.
+    async fn pull(&self, api_client: &ApiClient, since: Option<i64>) -> Result<i64, Error> {
+        let changes = api_client.sync(since).await?;
+
+        // local changes which have not reached the server yet win
+        let unsent = self.unsent_entity_ids()?;
+
+        if changes.full {
+            // deletions may be missing, so whatever the server doesn't
+            // have any more is gone
+            let known = changes
+                .vaults
+                .iter()
+                .map(|vault| vault.id)
+                .chain(changes.records.iter().map(|record| record.id))
+                .collect::<HashSet<_>>();
+            for key in self.data_tree.iter().keys() {
+                let key = key?;
+                let id = std::str::from_utf8(&key)
+                    .ok()
+                    .filter(|key| key.starts_with("vault:") || key.starts_with("record:"))
+                    .and_then(|key| key.rsplit(':').next())
+                    .and_then(|id| Uuid::parse_str(id).ok());
+                if let Some(id) = id
+                    && !known.contains(&id)
+                    && !unsent.contains(&id)
+                {
+                    self.data_tree.remove(key)?;
+                }
+            }
+        }
+
+        for tombstone in changes.tombstones {
+            match tombstone {
+                Tombstone::Vault { id, .. } if !unsent.contains(&id) => {
+                    self.data_tree.remove(format!("vault:{}", id))?;
+                    for record_key in self.data_tree.scan_prefix(format!("record:{}:", id)).keys() {
+                        self.data_tree.remove(record_key?)?;
+                    }
+                }
+                Tombstone::Record { id, vault_id, .. } if !unsent.contains(&id) => {
+                    self.data_tree
+                        .remove(format!("record:{}:{}", vault_id, id))?;
+                }
+                _ => {}
+            }
+        }
+
+        for vault in changes.vaults {
+            if unsent.contains(&vault.id) {
+                continue;
+            }
+            let vault = EncryptedVault::from(vault);
+            self.data_tree
+                .insert(format!("vault:{}", vault.id), serde_json::to_vec(&vault)?)?;
+        }
+
+        for record in changes.records {
+            if unsent.contains(&record.id) {
+                continue;
+            }
+            let record = EncryptedRecord::from(record);
+            self.data_tree.insert(
+                format!("record:{}:{}", record.vault_id, record.id),
+                serde_json::to_vec(&record)?,
+            )?;
+        }
+
+        Ok(changes.cursor)
+    }
+
+    fn unsent_entity_ids(&self) -> Result<HashSet<Uuid>, Error> {
+        let mut ids = HashSet::new();
+        for value in self.outbox_tree.iter().values() {
+            let entry: OutboxEntry = serde_json::from_slice(&value?)?;
+            if entry.is_unsent()
+                && let Some(id) = entry.entity_id()
+            {
+                ids.insert(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Remove all entries which made it to the server.
+    fn prune_outbox(&self) -> Result<(), Error> {
+        for item in self.outbox_tree.iter() {
+            let (key, value) = item?;
+            let entry: OutboxEntry = serde_json::from_slice(&value)?;
+            if entry.status == OutboxStatus::Sent {
+                self.outbox_tree.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn next_backoff(backoff: Option<Duration>) -> Duration {
+    backoff.map_or(MIN_BACKOFF, |delay| (delay * 2).min(MAX_BACKOFF))
+}
+
+/// What the server has of an item after it was pushed.
+#[derive(Clone)]
+struct Synced {
+    revision: i64,
+    /// The record data as stored on the server, `None` for vaults.
+    base: Option<String>,
+    /// The vault key as stored on the server, `None` for records.
+    encrypted_vault_key: Option<String>,
+}
+
+impl From<Record> for Synced {
+    fn from(record: Record) -> Self {
+        Self {
+            revision: record.revision,
+            base: Some(record.encrypted_data_blob),
+            encrypted_vault_key: None,
+        }
+    }
+}
+
+async fn push_record(
+    api_client: &ApiClient,
+    record: &EncryptedRecord,
+) -> Result<Write<Record>, Error> {
+    let request = CreateRecordRequest {
+        encrypted_record_key: record.encrypted_record_key.clone(),
+        encrypted_data_blob: record.encrypted_data_blob.clone(),
+    };
+    api_client
+        .update_record(&record.vault_id, &record.id, record.revision, &request)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut backoff = None;
+        let mut delays = Vec::new();
+        for _ in 0..8 {
+            let delay = next_backoff(backoff);
+            backoff = Some(delay);
+            delays.push(delay.as_secs());
+        }
+        assert_eq!(delays, vec![5, 10, 20, 40, 80, 160, 300, 300]);
+    }
+
+    #[tokio::test]
+    async fn run_stops_when_shutdown_is_dropped() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let resolver = Resolver::new(vec![0; 32].into());
+        let engine = SyncEngine::open(None, resolver, &db).unwrap();
+
+        let (tx, rx) = oneshot::channel();
+        let handle = tokio::spawn(engine.run(rx));
+        drop(tx);
+
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .expect("background sync did not stop")
+            .unwrap();
+    }
+}
@@ -1,6 +1,7 @@
 pub mod login;
 pub mod models;
 pub mod register;
+pub mod webauthn;
 
 use opaque_ke::{CipherSuite, argon2::Argon2};
 
@@ -8,18 +8,25 @@ use rand::rngs::OsRng;
 
 use crate::DefaultCipherSuite;
 
+/// Start the server side of a login.
+///
+/// Pass `None` as the `password_file` of an account that doesn't exist. The
+/// response is then indistinguishable from a real one, but the login can't
+/// be finished, just as with a wrong password.
 pub fn server_start(
     setup: &ServerSetup<DefaultCipherSuite>,
     account: &[u8],
-    password_file: &[u8],
+    password_file: Option<&[u8]>,
     client_start: &[u8],
 ) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
-    let password_file = ServerRegistration::<DefaultCipherSuite>::deserialize(password_file)?;
+    let password_file = password_file
+        .map(ServerRegistration::<DefaultCipherSuite>::deserialize)
+        .transpose()?;
 
     let login_start_result = ServerLogin::start(
         &mut OsRng,
         setup,
-        Some(password_file),
+        password_file,
         CredentialRequest::deserialize(client_start)?,
         account,
         ServerLoginParameters::default(),
@@ -35,7 +42,7 @@ pub fn server_finish(client_finish: &[u8], server_start: &[u8]) -> Result<(), Bo
     let start_state = ServerLogin::<DefaultCipherSuite>::deserialize(server_start)?;
 
     let _ = start_state.finish(
-        CredentialFinalization::deserialize(&client_finish)?,
+        CredentialFinalization::deserialize(client_finish)?,
         ServerLoginParameters::default(),
     )?;
 
@@ -50,7 +57,7 @@ pub fn client_start(password: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error
             login.state.serialize().to_vec(),
             login.message.serialize().to_vec(),
         )),
-        Err(err) => return Err(err.to_string().into()),
+        Err(err) => Err(err.to_string().into()),
     }
 }
 
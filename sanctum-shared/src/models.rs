@@ -2,6 +2,83 @@ use serde::{Deserialize, Serialize};
 use time::UtcDateTime;
 use uuid::Uuid;
 
+// ------------------------------------------
+//                  Errors
+// ------------------------------------------
+
+/// The body of every error response of the API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    /// What went wrong, for humans. Not meant to be matched on.
+    pub message: String,
+    /// Identifies the request in the server logs, also sent as the
+    /// `X-Request-Id` header.
+    pub request_id: String,
+}
+
+/// What went wrong, for clients to act on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request is malformed or invalid, see the message.
+    BadRequest,
+    /// The access token or the login is missing, invalid or expired.
+    Unauthorized,
+    /// The session may not do this, e.g. because it is not fresh enough.
+    Forbidden,
+    /// An admin disabled the account, it can't log in.
+    AccountDisabled,
+    /// There is no such endpoint.
+    NotFound,
+    VaultNotFound,
+    RecordNotFound,
+    SessionNotFound,
+    CredentialNotFound,
+    /// The request conflicts with the current state, e.g. an email address
+    /// that is taken or a second factor that is set up already.
+    Conflict,
+    PayloadTooLarge,
+    /// An update lacks the version it is based on.
+    PreconditionRequired,
+    /// Rate limited or locked out, retry after the `Retry-After` header.
+    TooManyRequests,
+    /// Something failed on the server, see its logs for the request id.
+    Internal,
+    /// A code this client does not know yet.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// A message for users.
+    pub fn description(self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "invalid request",
+            ErrorCode::Unauthorized => "not logged in",
+            ErrorCode::Forbidden => "not allowed",
+            ErrorCode::AccountDisabled => "account disabled",
+            ErrorCode::NotFound => "not found",
+            ErrorCode::VaultNotFound => "vault not found",
+            ErrorCode::RecordNotFound => "record not found",
+            ErrorCode::SessionNotFound => "session not found",
+            ErrorCode::CredentialNotFound => "security key not found",
+            ErrorCode::Conflict => "conflict",
+            ErrorCode::PayloadTooLarge => "too large",
+            ErrorCode::PreconditionRequired => "missing version",
+            ErrorCode::TooManyRequests => "too many requests",
+            ErrorCode::Internal => "server error",
+            ErrorCode::Unknown => "unknown error",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
 // ------------------------------------------
 //               Registration
 // ------------------------------------------
@@ -15,6 +92,9 @@ pub struct RegistrationStartRequest {
 #[derive(Serialize, Deserialize)]
 pub struct RegistrationStartResponse {
     pub server_start: String,
+    /// The OPAQUE credential identifier the registration was started
+    /// with. It has to be sent back to finish the registration.
+    pub credential_id: Uuid,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -24,6 +104,20 @@ pub struct RegistrationFinishRequest {
     pub email: String,
     pub salt: String,
     pub client_finish: String,
+    /// See [`RegistrationStartResponse::credential_id`].
+    pub credential_id: Uuid,
+    /// How the master key is derived from the password and `salt`.
+    #[serde(default)]
+    pub kdf: KdfParams,
+}
+
+/// Body of `POST /auth/register/confirm`.
+///
+/// `/auth/register/finish` only mails a code to the address, the account
+/// is created once that code comes back here.
+#[derive(Serialize, Deserialize)]
+pub struct RegistrationConfirmRequest {
+    pub code: String,
 }
 
 // ------------------------------------------
@@ -38,20 +132,407 @@ pub struct LoginStartRequest {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginStartResponse {
+    /// Identifies this login attempt, send it back in [`LoginFinishRequest::login_id`].
+    pub login_id: String,
     pub message: String,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct LoginFinishRequest {
+    pub login_id: String,
     pub email: String,
     pub client_finish: String,
+    /// A name for this device, shown in the list of sessions.
+    #[serde(default)]
+    pub device_name: Option<String>,
+    /// e.g. `linux` or `macos`, see [`std::env::consts::OS`].
+    #[serde(default)]
+    pub platform: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct LoginFinishResponse {
     pub access_token: String,
+    /// Exchanged for a new access token at `/auth/refresh`.
+    pub refresh_token: String,
+    /// Lifetime of the access token in seconds.
+    pub expires_in: i64,
     pub salt: String,
-    // TODO: add refresh_token and other nice stuff
+    /// The password file is still bound to the email. The client should
+    /// register the password again right away, see [`ReregisterStartRequest`].
+    #[serde(default)]
+    pub reregister: bool,
+}
+
+/// Response of `POST /auth/login/finish`.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LoginResult {
+    Done(LoginFinishResponse),
+    /// The account has a second factor. The tokens are only issued once
+    /// the challenge is answered, see [`MfaVerifyRequest`].
+    MfaRequired(MfaChallenge),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaChallenge {
+    /// Stands for the password check that succeeded, send it back in
+    /// [`MfaVerifyRequest::mfa_token`].
+    pub mfa_token: String,
+    /// The second factors the account has set up.
+    pub methods: Vec<MfaMethod>,
+    /// Seconds until the token expires and the login has to start over.
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MfaMethod {
+    /// A code from an authenticator app, at `/auth/mfa/totp`.
+    Totp,
+    /// One of the codes handed out when TOTP was enabled, at `/auth/mfa/recovery`.
+    RecoveryCode,
+    /// A security key or passkey, at `/auth/webauthn/login/start`.
+    Webauthn,
+}
+
+/// Body of `POST /auth/mfa/totp` and `POST /auth/mfa/recovery`,
+/// answered with a [`LoginFinishResponse`].
+#[derive(Serialize, Deserialize)]
+pub struct MfaVerifyRequest {
+    pub mfa_token: String,
+    pub code: String,
+}
+
+/// Body of `POST /auth/webauthn/login/start`, answered with [`WebauthnLoginOptions`].
+#[derive(Serialize, Deserialize)]
+pub struct WebauthnLoginStartRequest {
+    pub mfa_token: String,
+}
+
+/// The options for `navigator.credentials.get()`. Binary values are base64url.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnLoginOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    pub allow_credentials: Vec<String>,
+    /// In milliseconds.
+    pub timeout: u64,
+}
+
+/// Body of `POST /auth/webauthn/login/finish`, the
+/// `AuthenticatorAssertionResponse` in base64url. Answered with a
+/// [`LoginFinishResponse`].
+#[derive(Serialize, Deserialize)]
+pub struct WebauthnLoginFinishRequest {
+    pub mfa_token: String,
+    pub credential_id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// A new access token, and the refresh token replacing the one
+/// that was sent, which is no longer valid.
+#[derive(Serialize, Deserialize)]
+pub struct RefreshResponse {
+    pub access_token: String,
+    pub refresh_token: String,
+    /// Lifetime of the access token in seconds.
+    pub expires_in: i64,
+}
+
+// ------------------------------------------
+//                 Account
+// ------------------------------------------
+
+/// Response of `GET /me`, everything a client needs to set itself up
+/// after logging in.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Profile {
+    pub id: Uuid,
+    pub email: String,
+    pub created_at: UtcDateTime,
+    /// The salt the master key is derived with.
+    pub salt: String,
+    pub kdf: KdfParams,
+    /// Whatever the clients store there, see `PUT /me/settings`.
+    pub settings: serde_json::Value,
+    pub usage: StorageUsage,
+    /// Whether logins ask for a TOTP code, see `POST /me/mfa/totp`.
+    #[serde(default)]
+    pub totp_enabled: bool,
+}
+
+/// The Argon2id parameters the master key is derived with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// The parameters of `argon2::Argon2::default()`, which accounts
+    /// registered before the parameters were stored use.
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// What an account stores on the server, in ciphertext.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StorageUsage {
+    pub vaults: i64,
+    pub records: i64,
+    /// The size of all encrypted names, keys and records.
+    pub bytes: i64,
+}
+
+/// Body of `POST /me/password/start`, answered with a
+/// [`ChangePasswordStartResponse`].
+///
+/// Like deleting the account, changing the password takes a fresh OPAQUE
+/// login with the current password, a stolen access token alone is not
+/// enough.
+#[derive(Serialize, Deserialize)]
+pub struct ChangePasswordStartRequest {
+    /// Starts the registration of the new password.
+    pub client_start: String,
+    /// Starts the login with the current password.
+    pub login_start: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangePasswordStartResponse {
+    /// For the new password.
+    pub registration: RegistrationStartResponse,
+    /// For the current password.
+    pub login: LoginStartResponse,
+}
+
+/// Body of `POST /me/password/finish`.
+///
+/// `vaults` has to contain every vault of the user, re-encrypted under the
+/// new master key, otherwise the server answers `409 Conflict` and nothing
+/// changes. On success, all other sessions are logged out and the updated
+/// vaults are returned.
+#[derive(Serialize, Deserialize)]
+pub struct ChangePasswordFinishRequest {
+    /// See [`LoginStartResponse::login_id`].
+    pub login_id: String,
+    /// Finishes the login with the current password.
+    pub login_finish: String,
+    pub client_finish: String,
+    /// See [`RegistrationStartResponse::credential_id`].
+    pub credential_id: Uuid,
+    /// The salt of the new master key.
+    pub salt: String,
+    /// How the new master key is derived.
+    #[serde(default)]
+    pub kdf: KdfParams,
+    pub vaults: Vec<RewrappedVault>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RewrappedVault {
+    pub id: Uuid,
+    pub encrypted_vault_key: String,
+    /// Unchanged, unless the name is encrypted with the master key as well.
+    pub encrypted_name: String,
+    /// The revision the vault key was rewrapped from. When the vault was
+    /// changed since, the server answers `409 Conflict`.
+    pub revision: i64,
+}
+
+/// Body of `POST /me/credential/start`, answered with a
+/// [`ReregisterStartResponse`].
+///
+/// Registers the current password again under a stable credential
+/// identifier. Only allowed right after a login that asked for it, see
+/// [`LoginFinishResponse::reregister`], and with another OPAQUE login
+/// proving the password.
+#[derive(Serialize, Deserialize)]
+pub struct ReregisterStartRequest {
+    /// Starts the registration of the password.
+    pub client_start: String,
+    /// Starts the login with the password.
+    pub login_start: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReregisterStartResponse {
+    pub registration: RegistrationStartResponse,
+    pub login: LoginStartResponse,
+}
+
+/// Body of `POST /me/credential/finish`. The password file is bound to the
+/// credential id handed out with the [`ReregisterStartResponse`].
+#[derive(Serialize, Deserialize)]
+pub struct ReregisterFinishRequest {
+    /// See [`LoginStartResponse::login_id`].
+    pub login_id: String,
+    /// Finishes the login with the password.
+    pub login_finish: String,
+    pub client_finish: String,
+}
+
+/// Body of `POST /me/email`.
+///
+/// A code is mailed to the new address, the email only changes once it
+/// is passed to `/me/email/confirm`.
+#[derive(Serialize, Deserialize)]
+pub struct EmailChangeRequest {
+    pub email: String,
+    /// See [`LoginStartResponse::login_id`].
+    pub login_id: String,
+    /// Finishes the login started with [`EmailChangeStartRequest`].
+    pub client_finish: String,
+}
+
+/// Body of `POST /me/email/start`, answered with a [`LoginStartResponse`].
+///
+/// Changing the email takes a fresh OPAQUE login, like deleting the
+/// account, which is finished along with the [`EmailChangeRequest`].
+#[derive(Serialize, Deserialize)]
+pub struct EmailChangeStartRequest {
+    pub client_start: String,
+}
+
+/// Body of `POST /me/email/confirm`.
+#[derive(Serialize, Deserialize)]
+pub struct EmailChangeConfirmRequest {
+    pub code: String,
+}
+
+/// Body of `POST /me/delete/start`, answered with a [`LoginStartResponse`].
+///
+/// Deleting the account takes a fresh OPAQUE login, a stolen access
+/// token alone is not enough.
+#[derive(Serialize, Deserialize)]
+pub struct DeleteAccountStartRequest {
+    pub client_start: String,
+}
+
+/// Body of `DELETE /me`.
+#[derive(Serialize, Deserialize)]
+pub struct DeleteAccountRequest {
+    /// See [`LoginStartResponse::login_id`].
+    pub login_id: String,
+    pub client_finish: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeleteAccountResponse {
+    pub id: Uuid,
+    pub deleted_at: UtcDateTime,
+}
+
+/// Response of `POST /me/mfa/totp`.
+///
+/// The secret is only used for logins once a code generated from it was
+/// sent to `/me/mfa/totp/confirm`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    /// The secret in base32, for authenticators that take it typed in.
+    pub secret: String,
+    /// An `otpauth://` URI, usually shown as a QR code.
+    pub uri: String,
+}
+
+/// Body of `POST /me/mfa/totp/confirm` and `DELETE /me/mfa/totp`.
+#[derive(Serialize, Deserialize)]
+pub struct TotpCodeRequest {
+    /// A TOTP code. Disabling TOTP takes a recovery code as well.
+    pub code: String,
+}
+
+/// Single-use codes that replace a TOTP code, see [`MfaMethod::RecoveryCode`].
+/// They are shown once, the server only keeps their hashes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    pub codes: Vec<String>,
+}
+
+/// Response of `POST /auth/webauthn/register/start`, the options for
+/// `navigator.credentials.create()`. Binary values are base64url.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnRegistrationOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    pub rp_name: String,
+    /// The user handle the authenticator stores with the credential.
+    pub user_id: String,
+    pub user_name: String,
+    /// COSE ids of the accepted key algorithms, in order of preference.
+    pub algorithms: Vec<i64>,
+    /// The credentials of the user, so an authenticator is not registered twice.
+    pub exclude_credentials: Vec<String>,
+    /// In milliseconds.
+    pub timeout: u64,
+}
+
+/// Body of `POST /auth/webauthn/register/finish`, the
+/// `AuthenticatorAttestationResponse` in base64url.
+#[derive(Serialize, Deserialize)]
+pub struct WebauthnRegistrationRequest {
+    /// Shown in the list of credentials.
+    pub name: String,
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+/// A security key or passkey registered as second factor.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnCredential {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: UtcDateTime,
+    pub last_used_at: Option<UtcDateTime>,
+}
+
+/// Body of `POST /auth/webauthn/credentials/delete/start`, answered with a
+/// [`LoginStartResponse`].
+///
+/// Removing a second factor takes a fresh OPAQUE login, like deleting the
+/// account, which is finished along with the [`WebauthnDeleteRequest`].
+#[derive(Serialize, Deserialize)]
+pub struct WebauthnDeleteStartRequest {
+    pub client_start: String,
+}
+
+/// Body of `DELETE /auth/webauthn/credentials/{credential_id}`.
+#[derive(Serialize, Deserialize)]
+pub struct WebauthnDeleteRequest {
+    /// See [`LoginStartResponse::login_id`].
+    pub login_id: String,
+    pub client_finish: String,
+}
+
+// ------------------------------------------
+//                 Sessions
+// ------------------------------------------
+
+/// A device the user is logged in on.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+    pub created_at: UtcDateTime,
+    /// The last time the session was used to log in or refresh its token.
+    pub last_seen_at: UtcDateTime,
+    /// Whether this is the session making the request.
+    pub current: bool,
 }
 
 // ------------------------------------------
@@ -64,6 +545,8 @@ pub struct Vault {
     pub user_id: Uuid,
     pub encrypted_vault_key: String,
     pub encrypted_name: String,
+    /// Bumped on every update, see [`CreateVaultRequest`].
+    pub revision: i64,
     pub created_at: UtcDateTime,
     pub updated_at: UtcDateTime,
 }
@@ -72,19 +555,66 @@ pub struct Vault {
 pub struct Record {
     pub id: Uuid,
     pub vault_id: Uuid,
+    pub encrypted_record_key: String,
     pub encrypted_data_blob: String,
+    /// Bumped on every update, see [`CreateRecordRequest`].
+    pub revision: i64,
     pub created_at: UtcDateTime,
     pub updated_at: UtcDateTime,
 }
 
+/// Body of `POST /vaults` and `PUT /vaults/{vault_id}`.
+///
+/// A `PUT` which updates an existing vault has to send the revision it
+/// last saw as `If-Match: "<revision>"`. When the vault was changed in the
+/// meantime, the server answers `412 Precondition Failed` with its current
+/// copy. `409 Conflict` means the id is taken by another user's vault.
 #[derive(Serialize, Deserialize)]
 pub struct CreateVaultRequest {
     pub encrypted_vault_key: String,
     pub encrypted_name: String,
 }
 
+/// Body of `POST /vaults/{vault_id}/records` and
+/// `PUT /vaults/{vault_id}/records/{record_id}`.
+///
+/// Updates carry the expected revision in `If-Match`, see [`CreateVaultRequest`].
 #[derive(Serialize, Deserialize)]
 pub struct CreateRecordRequest {
-    // pub encrypted_record_key: String,
+    pub encrypted_record_key: String,
     pub encrypted_data_blob: String,
 }
+
+// ------------------------------------------
+//                  Sync
+// ------------------------------------------
+
+#[derive(Serialize, Deserialize)]
+pub struct SyncResponse {
+    /// The server time (in UNIX seconds) at which this change set was
+    /// taken. Pass it as `since` on the next sync.
+    pub cursor: i64,
+    /// Whether this is everything the account has rather than the changes
+    /// since the cursor, because there was none or it is older than the
+    /// server keeps deletions. Clients should drop what they have synced
+    /// before and is not in here.
+    #[serde(default)]
+    pub full: bool,
+    pub vaults: Vec<Vault>,
+    pub records: Vec<Record>,
+    /// Deletions since the cursor. Clients should apply these before
+    /// the upserts above, since an id may have been deleted and re-created.
+    pub tombstones: Vec<Tombstone>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Tombstone {
+    /// A deleted vault. All records of the vault are gone as well.
+    Vault { id: Uuid, deleted_at: UtcDateTime },
+    Record {
+        id: Uuid,
+        vault_id: Uuid,
+        deleted_at: UtcDateTime,
+    },
+}
@@ -38,7 +38,7 @@ pub fn client_start(password: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Box<dyn std::
             start.state.serialize().to_vec(),
             start.message.serialize().to_vec(),
         )),
-        Err(err) => return Err(err.to_string().into()),
+        Err(err) => Err(err.to_string().into()),
     }
 }
 
@@ -0,0 +1,279 @@
+//! The relying party side of WebAuthn (Level 2), as far as a second
+//! factor needs it.
+//!
+//! Only the `none` attestation is asked for, so attestation statements are
+//! not verified: the server doesn't care which make of authenticator a
+//! credential comes from, only that later assertions are signed by it.
+//! Credentials are ES256 (P-256) or EdDSA (Ed25519) keys.
+
+use std::fmt;
+
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use ciborium::Value;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// The COSE algorithm ids of the supported credentials, in order of preference.
+pub const ES256: i64 = -7;
+pub const EDDSA: i64 = -8;
+pub const ALGORITHMS: [i64; 2] = [ES256, EDDSA];
+
+/// Authenticator data flags.
+const USER_PRESENT: u8 = 0x01;
+const ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Some part of the response could not be parsed.
+    Malformed(&'static str),
+    /// The client data is for another ceremony, challenge or origin.
+    ClientData(&'static str),
+    /// The authenticator data is for another relying party, or the
+    /// user was not present.
+    AuthenticatorData(&'static str),
+    UnsupportedAlgorithm(i64),
+    InvalidSignature,
+    /// The signature counter went backwards, the credential may have
+    /// been cloned.
+    CounterRegressed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Malformed(what) => write!(f, "malformed {}", what),
+            Error::ClientData(what) => write!(f, "client data: {}", what),
+            Error::AuthenticatorData(what) => write!(f, "authenticator data: {}", what),
+            Error::UnsupportedAlgorithm(alg) => write!(f, "unsupported algorithm {}", alg),
+            Error::InvalidSignature => write!(f, "invalid signature"),
+            Error::CounterRegressed => write!(f, "signature counter went backwards"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Where the ceremonies are expected to come from.
+pub struct RelyingParty<'a> {
+    /// A domain, e.g. `sanctum.example.com`.
+    pub id: &'a str,
+    /// The origin the client data has to name, e.g. `https://sanctum.example.com`.
+    pub origin: &'a str,
+}
+
+/// A credential created by [`verify_registration`].
+#[derive(Debug)]
+pub struct Credential {
+    /// The id the authenticator knows the credential by.
+    pub id: Vec<u8>,
+    /// The public key, as COSE key in CBOR.
+    pub public_key: Vec<u8>,
+    pub algorithm: i64,
+    pub sign_count: u32,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+/// Verify the response to `navigator.credentials.create()`.
+///
+/// `client_data_json` and `attestation_object` are the raw bytes of the
+/// `AuthenticatorAttestationResponse`.
+pub fn verify_registration(
+    rp: &RelyingParty,
+    challenge: &[u8],
+    client_data_json: &[u8],
+    attestation_object: &[u8],
+) -> Result<Credential, Error> {
+    check_client_data(rp, "webauthn.create", challenge, client_data_json)?;
+
+    let attestation: Value = ciborium::from_reader(attestation_object)
+        .map_err(|_| Error::Malformed("attestation object"))?;
+    let auth_data = map_get(&attestation, &Value::Text("authData".into()))
+        .and_then(Value::as_bytes)
+        .ok_or(Error::Malformed("attestation object"))?;
+
+    let (flags, sign_count) = check_authenticator_data(rp, auth_data)?;
+    if flags & ATTESTED_CREDENTIAL_DATA == 0 {
+        return Err(Error::AuthenticatorData("no credential"));
+    }
+
+    // the aaguid (16 bytes) is skipped, it names the make of authenticator
+    let rest = auth_data
+        .get(37 + 16..)
+        .ok_or(Error::Malformed("credential data"))?;
+    let (length, rest) = rest
+        .split_first_chunk::<2>()
+        .ok_or(Error::Malformed("credential data"))?;
+    let length = u16::from_be_bytes(*length) as usize;
+    if rest.len() < length {
+        return Err(Error::Malformed("credential data"));
+    }
+    let (id, key_and_extensions) = rest.split_at(length);
+
+    // extensions may follow the key, what is left after reading it
+    let mut rest = key_and_extensions;
+    let public_key: Value =
+        ciborium::from_reader(&mut rest).map_err(|_| Error::Malformed("public key"))?;
+    let public_key_bytes = key_and_extensions[..key_and_extensions.len() - rest.len()].to_vec();
+
+    let algorithm = cose_int(&public_key, 3).ok_or(Error::Malformed("public key"))?;
+    // parsing the key checks that it is one we can verify with
+    PublicKey::from_cose(&public_key)?;
+
+    Ok(Credential {
+        id: id.to_vec(),
+        public_key: public_key_bytes,
+        algorithm,
+        sign_count,
+    })
+}
+
+/// Verify the response to `navigator.credentials.get()` for a credential
+/// from [`verify_registration`], and return its new signature counter.
+pub fn verify_assertion(
+    rp: &RelyingParty,
+    challenge: &[u8],
+    public_key: &[u8],
+    sign_count: u32,
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+) -> Result<u32, Error> {
+    check_client_data(rp, "webauthn.get", challenge, client_data_json)?;
+    let (_, new_sign_count) = check_authenticator_data(rp, authenticator_data)?;
+
+    let public_key: Value =
+        ciborium::from_reader(public_key).map_err(|_| Error::Malformed("public key"))?;
+    let mut signed = authenticator_data.to_vec();
+    signed.extend_from_slice(&Sha256::digest(client_data_json));
+    PublicKey::from_cose(&public_key)?.verify(&signed, signature)?;
+
+    // authenticators without a counter always send 0
+    if (sign_count != 0 || new_sign_count != 0) && new_sign_count <= sign_count {
+        return Err(Error::CounterRegressed);
+    }
+    Ok(new_sign_count)
+}
+
+fn check_client_data(
+    rp: &RelyingParty,
+    kind: &str,
+    challenge: &[u8],
+    client_data_json: &[u8],
+) -> Result<(), Error> {
+    let client_data: ClientData =
+        serde_json::from_slice(client_data_json).map_err(|_| Error::Malformed("client data"))?;
+
+    if client_data.kind != kind {
+        return Err(Error::ClientData("wrong type"));
+    }
+    let sent_challenge = BASE64_URL_SAFE_NO_PAD
+        .decode(client_data.challenge)
+        .map_err(|_| Error::Malformed("client data"))?;
+    if sent_challenge != challenge {
+        return Err(Error::ClientData("wrong challenge"));
+    }
+    if client_data.origin != rp.origin {
+        return Err(Error::ClientData("wrong origin"));
+    }
+    Ok(())
+}
+
+/// Check the fixed part of the authenticator data, and return its flags
+/// and signature counter.
+fn check_authenticator_data(rp: &RelyingParty, auth_data: &[u8]) -> Result<(u8, u32), Error> {
+    if auth_data.len() < 37 {
+        return Err(Error::Malformed("authenticator data"));
+    }
+    if auth_data[..32] != *Sha256::digest(rp.id.as_bytes()) {
+        return Err(Error::AuthenticatorData("wrong relying party"));
+    }
+    let flags = auth_data[32];
+    if flags & USER_PRESENT == 0 {
+        return Err(Error::AuthenticatorData("user not present"));
+    }
+    let sign_count = u32::from_be_bytes(auth_data[33..37].try_into().unwrap());
+    Ok((flags, sign_count))
+}
+
+enum PublicKey {
+    Es256(p256::ecdsa::VerifyingKey),
+    EdDsa(ed25519_dalek::VerifyingKey),
+}
+
+impl PublicKey {
+    fn from_cose(key: &Value) -> Result<Self, Error> {
+        const MALFORMED: Error = Error::Malformed("public key");
+
+        let algorithm = cose_int(key, 3).ok_or(MALFORMED)?;
+        let x = map_get(key, &Value::from(-2))
+            .and_then(Value::as_bytes)
+            .ok_or(MALFORMED)?;
+
+        match (algorithm, cose_int(key, 1), cose_int(key, -1)) {
+            // EC2 on P-256
+            (ES256, Some(2), Some(1)) => {
+                let y = map_get(key, &Value::from(-3))
+                    .and_then(Value::as_bytes)
+                    .ok_or(MALFORMED)?;
+                if x.len() != 32 || y.len() != 32 {
+                    return Err(MALFORMED);
+                }
+                let point = p256::EncodedPoint::from_affine_coordinates(
+                    x.as_slice().into(),
+                    y.as_slice().into(),
+                    false,
+                );
+                p256::ecdsa::VerifyingKey::from_encoded_point(&point)
+                    .map(PublicKey::Es256)
+                    .map_err(|_| MALFORMED)
+            }
+            // OKP on Ed25519
+            (EDDSA, Some(1), Some(6)) => {
+                let x: &[u8; 32] = x.as_slice().try_into().map_err(|_| MALFORMED)?;
+                ed25519_dalek::VerifyingKey::from_bytes(x)
+                    .map(PublicKey::EdDsa)
+                    .map_err(|_| MALFORMED)
+            }
+            (ES256 | EDDSA, _, _) => Err(MALFORMED),
+            (algorithm, _, _) => Err(Error::UnsupportedAlgorithm(algorithm)),
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), Error> {
+        use p256::ecdsa::signature::Verifier;
+
+        match self {
+            // ES256 signatures are DER encoded
+            PublicKey::Es256(key) => {
+                let signature = p256::ecdsa::Signature::from_der(signature)
+                    .map_err(|_| Error::InvalidSignature)?;
+                key.verify(message, &signature)
+            }
+            PublicKey::EdDsa(key) => {
+                let signature = ed25519_dalek::Signature::from_slice(signature)
+                    .map_err(|_| Error::InvalidSignature)?;
+                key.verify(message, &signature)
+            }
+        }
+        .map_err(|_| Error::InvalidSignature)
+    }
+}
+
+fn map_get<'a>(map: &'a Value, key: &Value) -> Option<&'a Value> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| value)
+}
+
+fn cose_int(map: &Value, key: i64) -> Option<i64> {
+    let value = map_get(map, &Value::from(key))?.as_integer()?;
+    i64::try_from(value).ok()
+}
@@ -16,14 +16,14 @@ fn both() {
     let password_file = {
         // client
         let (client_state, message) =
-            sanctum_shared::register::client_start(&password.as_bytes()).unwrap();
+            sanctum_shared::register::client_start(password.as_bytes()).unwrap();
         // server
         let server_message =
-            sanctum_shared::register::server_start(&setup, &email.as_bytes(), &message).unwrap();
+            sanctum_shared::register::server_start(&setup, email.as_bytes(), &message).unwrap();
 
         // client
         let client_message = sanctum_shared::register::client_finish(
-            &password.as_bytes(),
+            password.as_bytes(),
             &client_state,
             &server_message,
         )
@@ -48,20 +48,64 @@ fn both() {
 
     // client
     let (client_state, client_message) =
-        sanctum_shared::login::client_start(&password.as_bytes()).unwrap();
+        sanctum_shared::login::client_start(password.as_bytes()).unwrap();
     // server
     let (server_state, server_message) = sanctum_shared::login::server_start(
         &setup,
-        &email.as_bytes(),
-        &password_file,
+        email.as_bytes(),
+        Some(&password_file),
         &client_message,
     )
     .unwrap();
 
     // client
     let client_message =
-        sanctum_shared::login::client_finish(&password.as_bytes(), &client_state, &server_message)
+        sanctum_shared::login::client_finish(password.as_bytes(), &client_state, &server_message)
             .unwrap();
     // server
     sanctum_shared::login::server_finish(&client_message, &server_state).unwrap();
 }
+
+#[test]
+fn unknown_account() {
+    let setup = ServerSetup::<DefaultCipherSuite>::new(&mut OsRng);
+
+    let (client_state, client_message) = sanctum_shared::login::client_start(b"password").unwrap();
+    // the server answers as if the account existed
+    let (_, server_message) =
+        sanctum_shared::login::server_start(&setup, b"nobody@example.com", None, &client_message)
+            .unwrap();
+
+    // but no password can finish the login
+    assert!(
+        sanctum_shared::login::client_finish(b"password", &client_state, &server_message).is_err()
+    );
+}
+
+#[test]
+fn password_file_is_bound_to_identifier() {
+    let setup = ServerSetup::<DefaultCipherSuite>::new(&mut OsRng);
+    let password = b"password";
+
+    let (client_state, message) = sanctum_shared::register::client_start(password).unwrap();
+    let server_message =
+        sanctum_shared::register::server_start(&setup, b"old@example.com", &message).unwrap();
+    let client_message =
+        sanctum_shared::register::client_finish(password, &client_state, &server_message).unwrap();
+    let password_file = sanctum_shared::register::server_finish(&client_message).unwrap();
+
+    // a password file registered under the email breaks when the email
+    // changes, which is why accounts get a stable credential id
+    let (client_state, client_message) = sanctum_shared::login::client_start(password).unwrap();
+    let (_, server_message) = sanctum_shared::login::server_start(
+        &setup,
+        b"new@example.com",
+        Some(&password_file),
+        &client_message,
+    )
+    .unwrap();
+
+    assert!(
+        sanctum_shared::login::client_finish(password, &client_state, &server_message).is_err()
+    );
+}
@@ -0,0 +1,236 @@
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use ciborium::Value;
+use p256::ecdsa::{Signature, SigningKey, signature::Signer};
+use rand::{RngCore, rngs::OsRng};
+use sha2::{Digest, Sha256};
+
+use sanctum_shared::webauthn::{self, Error, RelyingParty};
+
+const RP: RelyingParty = RelyingParty {
+    id: "sanctum.example.com",
+    origin: "https://sanctum.example.com",
+};
+
+/// A platform authenticator in software, with a single ES256 credential.
+struct SoftwareAuthenticator {
+    key: SigningKey,
+    credential_id: Vec<u8>,
+    sign_count: u32,
+}
+
+/// What the browser would hand the server, in raw bytes.
+struct Assertion {
+    client_data_json: Vec<u8>,
+    authenticator_data: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl SoftwareAuthenticator {
+    fn new() -> Self {
+        let mut credential_id = vec![0u8; 16];
+        OsRng.fill_bytes(&mut credential_id);
+        Self {
+            key: SigningKey::random(&mut OsRng),
+            credential_id,
+            sign_count: 0,
+        }
+    }
+
+    fn client_data(kind: &str, challenge: &[u8], origin: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "type": kind,
+            "challenge": BASE64_URL_SAFE_NO_PAD.encode(challenge),
+            "origin": origin,
+            "crossOrigin": false,
+        }))
+        .unwrap()
+    }
+
+    fn authenticator_data(&mut self, rp_id: &str, flags: u8) -> Vec<u8> {
+        self.sign_count += 1;
+        let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend_from_slice(&self.sign_count.to_be_bytes());
+        data
+    }
+
+    fn cose_key(&self) -> Vec<u8> {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let key = Value::Map(vec![
+            (Value::from(1), Value::from(2)),
+            (Value::from(3), Value::from(webauthn::ES256)),
+            (Value::from(-1), Value::from(1)),
+            (Value::from(-2), Value::Bytes(point.x().unwrap().to_vec())),
+            (Value::from(-3), Value::Bytes(point.y().unwrap().to_vec())),
+        ]);
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&key, &mut encoded).unwrap();
+        encoded
+    }
+
+    /// `navigator.credentials.create()`, returning the client data
+    /// and the attestation object.
+    fn create(&mut self, challenge: &[u8], origin: &str) -> (Vec<u8>, Vec<u8>) {
+        // user present, attested credential data
+        let mut auth_data = self.authenticator_data(RP.id, 0x41);
+        auth_data.extend_from_slice(&[0u8; 16]);
+        auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(&self.credential_id);
+        auth_data.extend_from_slice(&self.cose_key());
+
+        let attestation = Value::Map(vec![
+            (Value::from("fmt"), Value::from("none")),
+            (Value::from("attStmt"), Value::Map(Vec::new())),
+            (Value::from("authData"), Value::Bytes(auth_data)),
+        ]);
+        let mut attestation_object = Vec::new();
+        ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+
+        (
+            Self::client_data("webauthn.create", challenge, origin),
+            attestation_object,
+        )
+    }
+
+    /// `navigator.credentials.get()`.
+    fn get(&mut self, challenge: &[u8], origin: &str) -> Assertion {
+        let client_data_json = Self::client_data("webauthn.get", challenge, origin);
+        let authenticator_data = self.authenticator_data(RP.id, 0x01);
+
+        let mut signed = authenticator_data.clone();
+        signed.extend_from_slice(&Sha256::digest(&client_data_json));
+        let signature: Signature = self.key.sign(&signed);
+
+        Assertion {
+            client_data_json,
+            authenticator_data,
+            signature: signature.to_der().as_bytes().to_vec(),
+        }
+    }
+}
+
+fn challenge() -> [u8; 32] {
+    let mut challenge = [0u8; 32];
+    OsRng.fill_bytes(&mut challenge);
+    challenge
+}
+
+fn verify(
+    credential: &webauthn::Credential,
+    challenge: &[u8],
+    assertion: &Assertion,
+) -> Result<u32, Error> {
+    webauthn::verify_assertion(
+        &RP,
+        challenge,
+        &credential.public_key,
+        credential.sign_count,
+        &assertion.client_data_json,
+        &assertion.authenticator_data,
+        &assertion.signature,
+    )
+}
+
+#[test]
+fn register_and_authenticate() {
+    let mut authenticator = SoftwareAuthenticator::new();
+
+    let registration_challenge = challenge();
+    let (client_data_json, attestation_object) =
+        authenticator.create(&registration_challenge, RP.origin);
+    let mut credential = webauthn::verify_registration(
+        &RP,
+        &registration_challenge,
+        &client_data_json,
+        &attestation_object,
+    )
+    .unwrap();
+
+    assert_eq!(credential.id, authenticator.credential_id);
+    assert_eq!(credential.algorithm, webauthn::ES256);
+    assert_eq!(credential.sign_count, 1);
+
+    for _ in 0..2 {
+        let login_challenge = challenge();
+        let assertion = authenticator.get(&login_challenge, RP.origin);
+        credential.sign_count = verify(&credential, &login_challenge, &assertion).unwrap();
+    }
+    assert_eq!(credential.sign_count, 3);
+}
+
+#[test]
+fn registration_is_bound_to_challenge_and_origin() {
+    let mut authenticator = SoftwareAuthenticator::new();
+    let registration_challenge = challenge();
+
+    let (client_data_json, attestation_object) =
+        authenticator.create(&registration_challenge, RP.origin);
+    assert_eq!(
+        webauthn::verify_registration(&RP, &challenge(), &client_data_json, &attestation_object)
+            .unwrap_err(),
+        Error::ClientData("wrong challenge")
+    );
+
+    let (client_data_json, attestation_object) =
+        authenticator.create(&registration_challenge, "https://evil.example.com");
+    assert_eq!(
+        webauthn::verify_registration(
+            &RP,
+            &registration_challenge,
+            &client_data_json,
+            &attestation_object
+        )
+        .unwrap_err(),
+        Error::ClientData("wrong origin")
+    );
+}
+
+#[test]
+fn assertion_rejects_tampering_and_replay() {
+    let mut authenticator = SoftwareAuthenticator::new();
+    let registration_challenge = challenge();
+    let (client_data_json, attestation_object) =
+        authenticator.create(&registration_challenge, RP.origin);
+    let mut credential = webauthn::verify_registration(
+        &RP,
+        &registration_challenge,
+        &client_data_json,
+        &attestation_object,
+    )
+    .unwrap();
+
+    let login_challenge = challenge();
+    let assertion = authenticator.get(&login_challenge, RP.origin);
+
+    // an answer to another challenge
+    assert_eq!(
+        verify(&credential, &challenge(), &assertion).unwrap_err(),
+        Error::ClientData("wrong challenge")
+    );
+
+    // authenticator data changed after signing
+    let mut tampered = Assertion {
+        client_data_json: assertion.client_data_json.clone(),
+        authenticator_data: assertion.authenticator_data.clone(),
+        signature: assertion.signature.clone(),
+    };
+    tampered.authenticator_data[36] ^= 0x01;
+    assert_eq!(
+        verify(&credential, &login_challenge, &tampered).unwrap_err(),
+        Error::InvalidSignature
+    );
+
+    // the same assertion twice, as from a cloned authenticator
+    credential.sign_count = verify(&credential, &login_challenge, &assertion).unwrap();
+    assert_eq!(
+        verify(&credential, &login_challenge, &assertion).unwrap_err(),
+        Error::CounterRegressed
+    );
+
+    // an assertion made by some other key
+    let other = SoftwareAuthenticator::new().get(&login_challenge, RP.origin);
+    assert_eq!(
+        verify(&credential, &login_challenge, &other).unwrap_err(),
+        Error::InvalidSignature
+    );
+}
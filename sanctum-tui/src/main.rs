@@ -1,4 +1,4 @@
-use sanctum_client::{Config, LockedClient};
+use sanctum_client::{Config, Error, LockedClient, SecondFactor};
 
 #[tokio::main]
 async fn main() {
@@ -7,12 +7,25 @@ async fn main() {
 
     LockedClient::register(email, password).await.unwrap();
 
-    let config = Config {
-        api_base_url: "https://sanctum.lucalewin.dev".to_string(),
-        salt: vec![0; 32],
-    };
+    println!("Confirmation code:");
+    let mut code = String::new();
+    std::io::stdin().read_line(&mut code).unwrap();
+    LockedClient::confirm_registration(&code).await.unwrap();
+
+    let config = Config::new("https://sanctum.lucalewin.dev".to_string());
     let locked = LockedClient::from_config(config).unwrap();
-    let client = locked.login(email, password).await.unwrap();
+    let client = match locked.login(email, password, None).await {
+        Err(Error::MfaRequired(_)) => {
+            println!("Authenticator code:");
+            let mut code = String::new();
+            std::io::stdin().read_line(&mut code).unwrap();
+            locked
+                .login(email, password, Some(SecondFactor::Totp(code)))
+                .await
+                .unwrap()
+        }
+        client => client.unwrap(),
+    };
 
     let personal_vault = client.create_vault("Personal").unwrap();
     let _ = client
@@ -0,0 +1,3 @@
+DROP TABLE tombstones;
+
+ALTER TABLE records DROP COLUMN encrypted_record_key;
@@ -0,0 +1,19 @@
+-- `create_record` already writes the wrapped record key, but the
+-- initial schema never had a column for it.
+ALTER TABLE records ADD COLUMN encrypted_record_key TEXT NOT NULL DEFAULT '';
+ALTER TABLE records ALTER COLUMN encrypted_record_key DROP DEFAULT;
+
+-- Deleted vaults and records are remembered here so that clients
+-- which were offline can learn about the deletion on their next sync.
+CREATE TABLE tombstones (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+
+    entity_type TEXT NOT NULL CHECK (entity_type IN ('vault', 'record')),
+    entity_id UUID NOT NULL,
+    vault_id UUID NOT NULL,
+
+    deleted_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX tombstones_user_id_deleted_at_idx ON tombstones (user_id, deleted_at);
@@ -0,0 +1,2 @@
+ALTER TABLE records DROP COLUMN revision;
+ALTER TABLE vaults DROP COLUMN revision;
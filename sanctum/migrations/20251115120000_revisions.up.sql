@@ -0,0 +1,5 @@
+-- Every write to a vault or record bumps its revision. Clients send the
+-- revision they last saw with each update, so a stale write is rejected
+-- instead of silently overwriting a change made on another device.
+ALTER TABLE vaults ADD COLUMN revision BIGINT NOT NULL DEFAULT 1;
+ALTER TABLE records ADD COLUMN revision BIGINT NOT NULL DEFAULT 1;
@@ -0,0 +1,18 @@
+-- One row per login. The refresh token is rotated on every use, only its
+-- SHA-256 hash is stored.
+CREATE TABLE sessions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+
+    refresh_token_hash TEXT NOT NULL UNIQUE,
+    -- the token replaced by the last rotation, presenting it again
+    -- means it was stolen and revokes the session
+    previous_token_hash TEXT UNIQUE,
+
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
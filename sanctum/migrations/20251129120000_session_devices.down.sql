@@ -0,0 +1,2 @@
+ALTER TABLE sessions DROP COLUMN platform;
+ALTER TABLE sessions DROP COLUMN device_name;
@@ -0,0 +1,4 @@
+-- What the client told us about itself at login, so users
+-- can tell their sessions apart when revoking one.
+ALTER TABLE sessions ADD COLUMN device_name TEXT;
+ALTER TABLE sessions ADD COLUMN platform TEXT;
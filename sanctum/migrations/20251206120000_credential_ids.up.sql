@@ -0,0 +1,4 @@
+-- The OPAQUE credential identifier, so the email can change without
+-- invalidating the password file. Accounts registered before still use
+-- their email, until they re-register on their next login.
+ALTER TABLE users ADD COLUMN credential_id UUID UNIQUE;
@@ -0,0 +1,4 @@
+ALTER TABLE users DROP COLUMN settings;
+ALTER TABLE users DROP COLUMN kdf_parallelism;
+ALTER TABLE users DROP COLUMN kdf_iterations;
+ALTER TABLE users DROP COLUMN kdf_memory_kib;
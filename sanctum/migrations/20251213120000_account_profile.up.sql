@@ -0,0 +1,10 @@
+-- The Argon2id parameters the master key is derived with, next to its
+-- salt. The defaults are those of the `argon2` crate, which every
+-- account registered so far was using.
+ALTER TABLE users ADD COLUMN kdf_memory_kib INTEGER NOT NULL DEFAULT 19456;
+ALTER TABLE users ADD COLUMN kdf_iterations INTEGER NOT NULL DEFAULT 2;
+ALTER TABLE users ADD COLUMN kdf_parallelism INTEGER NOT NULL DEFAULT 1;
+
+-- Client preferences synced between devices, the server doesn't look
+-- inside.
+ALTER TABLE users ADD COLUMN settings JSONB NOT NULL DEFAULT '{}';
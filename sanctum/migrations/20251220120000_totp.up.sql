@@ -0,0 +1,20 @@
+-- The TOTP seed, encrypted with the server's MFA key. It is only in use
+-- once `totp_enabled_at` is set, before that the enrollment is pending.
+ALTER TABLE users ADD COLUMN totp_secret TEXT;
+ALTER TABLE users ADD COLUMN totp_enabled_at TIMESTAMPTZ;
+-- the time step of the last accepted code, so no code works twice
+ALTER TABLE users ADD COLUMN totp_last_step BIGINT;
+
+-- Single-use codes for when the authenticator is lost. Only their
+-- SHA-256 hash is stored.
+CREATE TABLE recovery_codes (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+
+    code_hash TEXT NOT NULL,
+
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
@@ -0,0 +1,20 @@
+-- Security keys and passkeys registered as second factor. Only the public
+-- key is stored, as COSE key.
+CREATE TABLE webauthn_credentials (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+
+    -- the id the authenticator knows the credential by
+    credential_id BYTEA NOT NULL UNIQUE,
+    public_key BYTEA NOT NULL,
+    algorithm INTEGER NOT NULL,
+    -- the signature counter of the last assertion, it never goes back
+    -- unless the credential was cloned
+    sign_count BIGINT NOT NULL DEFAULT 0,
+    name TEXT NOT NULL,
+
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMPTZ
+);
+
+CREATE INDEX webauthn_credentials_user_id_idx ON webauthn_credentials (user_id);
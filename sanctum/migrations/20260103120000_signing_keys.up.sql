@@ -0,0 +1,14 @@
+-- The Ed25519 keys access tokens are signed with, published as JWKS.
+CREATE TABLE signing_keys (
+    -- the RFC 7638 thumbprint of the public key, the `kid` of the tokens
+    kid TEXT PRIMARY KEY,
+    -- the seed of the private key, encrypted with a key derived from
+    -- JWT_SECRET and bound to the kid
+    private_key TEXT NOT NULL,
+    public_key BYTEA NOT NULL,
+
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- tokens are signed with the latest key that is active, a rotated key
+    -- is published for a while before so verifiers know it in time
+    active_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Set by an admin, see `sanctum users disable`. A disabled account can't
+-- log in and its sessions are revoked.
+ALTER TABLE users ADD COLUMN disabled_at TIMESTAMPTZ;
@@ -0,0 +1,120 @@
+-- The schema of the Postgres migrations one level up, for the embedded
+-- SQLite store. Ids are 16 byte blobs, timestamps are RFC 3339 text in
+-- UTC with a fixed number of digits, so they sort as text.
+
+CREATE TABLE users (
+    id BLOB PRIMARY KEY,
+
+    email TEXT NOT NULL UNIQUE,
+    password_file TEXT NOT NULL,
+    salt TEXT NOT NULL,
+    credential_id BLOB UNIQUE,
+
+    kdf_memory_kib INTEGER NOT NULL,
+    kdf_iterations INTEGER NOT NULL,
+    kdf_parallelism INTEGER NOT NULL,
+
+    settings TEXT NOT NULL DEFAULT '{}',
+
+    totp_secret TEXT,
+    totp_enabled_at TEXT,
+    totp_last_step INTEGER,
+
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+CREATE TABLE vaults (
+    id BLOB PRIMARY KEY,
+    user_id BLOB NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+
+    encrypted_vault_key TEXT NOT NULL,
+    encrypted_name TEXT NOT NULL,
+    revision INTEGER NOT NULL DEFAULT 1,
+
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+CREATE INDEX vaults_user_id_idx ON vaults (user_id);
+
+CREATE TABLE records (
+    id BLOB PRIMARY KEY,
+    vault_id BLOB NOT NULL REFERENCES vaults(id) ON DELETE CASCADE,
+
+    encrypted_record_key TEXT NOT NULL,
+    encrypted_data_blob TEXT NOT NULL,
+    revision INTEGER NOT NULL DEFAULT 1,
+
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+CREATE INDEX records_vault_id_idx ON records (vault_id);
+
+CREATE TABLE tombstones (
+    id BLOB PRIMARY KEY,
+    user_id BLOB NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+
+    entity_type TEXT NOT NULL CHECK (entity_type IN ('vault', 'record')),
+    entity_id BLOB NOT NULL,
+    vault_id BLOB NOT NULL,
+
+    deleted_at TEXT NOT NULL
+);
+
+CREATE INDEX tombstones_user_id_deleted_at_idx ON tombstones (user_id, deleted_at);
+
+CREATE TABLE sessions (
+    id BLOB PRIMARY KEY,
+    user_id BLOB NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+
+    refresh_token_hash TEXT NOT NULL UNIQUE,
+    previous_token_hash TEXT UNIQUE,
+    device_name TEXT,
+    platform TEXT,
+
+    created_at TEXT NOT NULL,
+    last_used_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    revoked_at TEXT
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
+
+CREATE TABLE recovery_codes (
+    id BLOB PRIMARY KEY,
+    user_id BLOB NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+
+    code_hash TEXT NOT NULL,
+
+    created_at TEXT NOT NULL,
+    used_at TEXT
+);
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
+
+CREATE TABLE webauthn_credentials (
+    id BLOB PRIMARY KEY,
+    user_id BLOB NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+
+    credential_id BLOB NOT NULL UNIQUE,
+    public_key BLOB NOT NULL,
+    algorithm INTEGER NOT NULL,
+    sign_count INTEGER NOT NULL DEFAULT 0,
+    name TEXT NOT NULL,
+
+    created_at TEXT NOT NULL,
+    last_used_at TEXT
+);
+
+CREATE INDEX webauthn_credentials_user_id_idx ON webauthn_credentials (user_id);
+
+CREATE TABLE signing_keys (
+    kid TEXT PRIMARY KEY,
+    private_key TEXT NOT NULL,
+    public_key BLOB NOT NULL,
+
+    created_at TEXT NOT NULL,
+    active_at TEXT NOT NULL
+);
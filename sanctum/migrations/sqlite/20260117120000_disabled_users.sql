@@ -0,0 +1,2 @@
+-- See the Postgres migration of the same name.
+ALTER TABLE users ADD COLUMN disabled_at TEXT;
@@ -0,0 +1,667 @@
+use axum::{
+    Json, Router,
+    extract::State,
+    http::StatusCode,
+    routing::{get, post, put},
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use sanctum_shared::models::{
+    ChangePasswordFinishRequest, ChangePasswordStartRequest, ChangePasswordStartResponse,
+    DeleteAccountRequest, DeleteAccountResponse, DeleteAccountStartRequest,
+    EmailChangeConfirmRequest, EmailChangeRequest, EmailChangeStartRequest, LoginStartResponse,
+    Profile, RegistrationStartResponse, ReregisterFinishRequest, ReregisterStartRequest,
+    ReregisterStartResponse, Vault,
+};
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime, UtcDateTime};
+use uuid::Uuid;
+
+use crate::{
+    AppStateRef,
+    auth::{check_kdf, credential_identifier},
+    error::ApiError,
+    middleware::{Claims, Session},
+    rate_limit, session,
+    store::{EmailChangeOutcome, PasswordChange, PasswordChangeOutcome, User},
+    util::{generate_token, hash_token, is_valid_email, normalize_email},
+};
+
+pub fn routes() -> Router<AppStateRef> {
+    Router::new()
+        .route("/me/password/start", post(change_password_start))
+        .route("/me/password/finish", post(change_password_finish))
+        .route("/me/credential/start", post(reregister_start))
+        .route("/me/credential/finish", post(reregister_finish))
+        .route("/me/email/start", post(email_change_start))
+        .route("/me/email", post(request_email_change))
+        .route("/me/email/confirm", post(confirm_email_change))
+        .route("/me/delete/start", post(delete_account_start))
+        .route("/me", get(profile).delete(delete_account))
+        .route("/me/settings", put(update_settings))
+}
+
+/// How long after the login a session may register the password again.
+const REREGISTER_WINDOW: Duration = Duration::minutes(5);
+
+/// How long an email change code is valid.
+const EMAIL_CHANGE_CODE_TTL: u64 = 24 * 60 * 60;
+
+/// How long the server state of a login proving the password is kept.
+const PROOF_STATE_TTL: u64 = 60;
+
+/// A login proving the password, between its two steps, see [`start_proof`].
+#[derive(Serialize, Deserialize)]
+struct ProofState {
+    user_id: Uuid,
+    server_state: String,
+}
+
+/// An email change waiting for the new address to be confirmed.
+#[derive(Serialize, Deserialize)]
+struct PendingEmailChange {
+    user_id: Uuid,
+    email: String,
+}
+
+/// GET /me
+///
+/// The profile of the current user, see [`Profile`].
+async fn profile(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+) -> Result<Json<Profile>, ApiError> {
+    let user = state
+        .store
+        .user(user_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+    let usage = state.store.storage_usage(user_id).await?;
+
+    Ok(Json(Profile {
+        id: user.id,
+        email: user.email,
+        created_at: user.created_at.to_utc(),
+        salt: user.salt,
+        kdf: user.kdf,
+        settings: user.settings,
+        usage,
+        totp_enabled: user.totp_enabled_at.is_some(),
+    }))
+}
+
+/// Largest settings object stored, in bytes of JSON.
+const MAX_SETTINGS_SIZE: usize = 16 * 1024;
+
+/// PUT /me/settings
+///
+/// Replace the settings of the current user. They have to be a JSON object.
+///
+/// - returns 204 No Content when stored
+/// - returns 400 Bad Request when not an object
+/// - returns 413 Payload Too Large beyond [`MAX_SETTINGS_SIZE`]
+async fn update_settings(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+    Json(settings): Json<serde_json::Value>,
+) -> Result<StatusCode, ApiError> {
+    if !settings.is_object() {
+        return Err(ApiError::BadRequest("Settings must be an object"));
+    }
+    if settings.to_string().len() > MAX_SETTINGS_SIZE {
+        return Err(ApiError::PayloadTooLarge("Settings are too large"));
+    }
+
+    state.store.update_settings(user_id, &settings).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /me/password/start
+///
+/// Start an OPAQUE registration for the new master password, and the
+/// login that proves the current one for [`change_password_finish`].
+async fn change_password_start(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+    Json(payload): Json<ChangePasswordStartRequest>,
+) -> Result<Json<ChangePasswordStartResponse>, ApiError> {
+    let user = state
+        .store
+        .user(user_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+
+    let login = start_proof(&state, &user, "password", &payload.login_start).await?;
+    // accounts still bound to their email get a credential id along the way
+    let credential_id = user.credential_id.unwrap_or_else(Uuid::new_v4);
+    let registration = registration_start(&state, credential_id, &payload.client_start)?;
+
+    Ok(Json(ChangePasswordStartResponse {
+        registration,
+        login,
+    }))
+}
+
+/// POST /me/password/finish
+///
+/// Replace the OPAQUE password file and salt, and the vault keys wrapped
+/// with the old master key, all in one transaction. Every other session
+/// is revoked, they still hold the old master key.
+///
+/// - returns 200 OK with the updated vaults
+/// - returns 401 Unauthorized for a wrong password or an unknown login id
+/// - returns 409 Conflict when `vaults` doesn't match the user's vaults or
+///   their revisions, or the credential id doesn't match the user's
+/// - returns 429 Too Many Requests while the account is locked
+async fn change_password_finish(
+    State(state): State<AppStateRef>,
+    claims: Claims,
+    Json(payload): Json<ChangePasswordFinishRequest>,
+) -> Result<Json<Vec<Vault>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| ApiError::Unauthorized)?;
+    let session_id = Uuid::parse_str(&claims.sid).map_err(|_| ApiError::Unauthorized)?;
+
+    let email = state
+        .store
+        .user(user_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?
+        .email;
+    finish_proof(
+        &state,
+        user_id,
+        &email,
+        "password",
+        &payload.login_id,
+        &payload.login_finish,
+    )
+    .await?;
+
+    let client_finish = BASE64_STANDARD
+        .decode(payload.client_finish)
+        .map_err(|_| ApiError::BadRequest("Invalid base64"))?;
+    let password_file = sanctum_shared::register::server_finish(&client_finish)
+        .map_err(|_| ApiError::BadRequest("Invalid registration upload"))?;
+    check_kdf(&payload.kdf)?;
+
+    let outcome = state
+        .store
+        .change_password(
+            user_id,
+            session_id,
+            &PasswordChange {
+                password_file: BASE64_STANDARD.encode(password_file),
+                salt: payload.salt,
+                credential_id: payload.credential_id,
+                kdf: payload.kdf,
+                vaults: payload.vaults,
+            },
+        )
+        .await?;
+
+    let (vaults, revoked) = match outcome {
+        PasswordChangeOutcome::Changed {
+            vaults,
+            revoked_sessions,
+        } => (vaults, revoked_sessions),
+        // a vault would be left encrypted with the old master key
+        PasswordChangeOutcome::VaultMismatch => {
+            return Err(ApiError::Conflict("Every vault has to be rewrapped"));
+        }
+        // the rewrap would overwrite another device's change
+        PasswordChangeOutcome::StaleVault => {
+            return Err(ApiError::Conflict(
+                "A vault was changed in the meantime, rewrap it again",
+            ));
+        }
+        // not the credential id handed out by `change_password_start`
+        PasswordChangeOutcome::CredentialMismatch => {
+            return Err(ApiError::Conflict(
+                "The password was changed in the meantime",
+            ));
+        }
+    };
+
+    for session_id in revoked {
+        session::deny_session(&state, session_id).await?;
+    }
+
+    Ok(Json(vaults))
+}
+
+/// POST /me/credential/start
+///
+/// Start registering the current password again under a credential id,
+/// for accounts that still use their email as OPAQUE identifier, and the
+/// login that proves the password for [`reregister_finish`]. Only allowed
+/// for [`REREGISTER_WINDOW`] after the login.
+///
+/// - returns 409 Conflict when the account already has a credential id
+async fn reregister_start(
+    State(state): State<AppStateRef>,
+    claims: Claims,
+    Json(payload): Json<ReregisterStartRequest>,
+) -> Result<Json<ReregisterStartResponse>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| ApiError::Unauthorized)?;
+    check_fresh_session(&state, &claims).await?;
+
+    let user = state
+        .store
+        .user(user_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+    if user.credential_id.is_some() {
+        return Err(ApiError::Conflict(
+            "The account has a credential id already",
+        ));
+    }
+
+    let login = start_proof(&state, &user, "reregister", &payload.login_start).await?;
+    let registration = registration_start(&state, Uuid::new_v4(), &payload.client_start)?;
+
+    // the finish request names the login, not the credential id
+    state
+        .cache
+        .set_ex(
+            &format!("reregister_credential_{}", login.login_id),
+            &registration.credential_id.to_string(),
+            PROOF_STATE_TTL,
+        )
+        .await?;
+
+    Ok(Json(ReregisterStartResponse {
+        registration,
+        login,
+    }))
+}
+
+/// POST /me/credential/finish
+///
+/// Replace the password file bound to the email with the one bound to
+/// the credential id. The password itself, and with it the salt and the
+/// vault keys, stays the same.
+///
+/// - returns 204 No Content when the password file was replaced
+/// - returns 401 Unauthorized for a wrong password or an unknown login id
+/// - returns 409 Conflict when the account already has a credential id
+/// - returns 429 Too Many Requests while the account is locked
+async fn reregister_finish(
+    State(state): State<AppStateRef>,
+    claims: Claims,
+    Json(payload): Json<ReregisterFinishRequest>,
+) -> Result<StatusCode, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| ApiError::Unauthorized)?;
+    check_fresh_session(&state, &claims).await?;
+
+    let email = state
+        .store
+        .user(user_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?
+        .email;
+    let credential_id = state
+        .cache
+        .get_del(&format!("reregister_credential_{}", payload.login_id))
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+    let credential_id = Uuid::parse_str(&credential_id).map_err(ApiError::internal)?;
+    finish_proof(
+        &state,
+        user_id,
+        &email,
+        "reregister",
+        &payload.login_id,
+        &payload.login_finish,
+    )
+    .await?;
+
+    let client_finish = BASE64_STANDARD
+        .decode(payload.client_finish)
+        .map_err(|_| ApiError::BadRequest("Invalid base64"))?;
+    let password_file = sanctum_shared::register::server_finish(&client_finish)
+        .map_err(|_| ApiError::BadRequest("Invalid registration upload"))?;
+
+    let updated = state
+        .store
+        .reregister(
+            user_id,
+            &BASE64_STANDARD.encode(password_file),
+            credential_id,
+        )
+        .await?;
+
+    match updated {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::Conflict(
+            "The account has a credential id already",
+        )),
+    }
+}
+
+/// POST /me/email/start
+///
+/// Start the OPAQUE login that proves the password for
+/// [`request_email_change`].
+async fn email_change_start(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+    Json(payload): Json<EmailChangeStartRequest>,
+) -> Result<Json<LoginStartResponse>, ApiError> {
+    let user = state
+        .store
+        .user(user_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+
+    start_proof(&state, &user, "email", &payload.client_start)
+        .await
+        .map(Json)
+}
+
+/// POST /me/email
+///
+/// Mail a code to the new address after finishing the login started with
+/// [`email_change_start`], the code changes the email once passed to
+/// [`confirm_email_change`]. Like the registration, this answers
+/// `202 Accepted` whether or not the address is already taken, only its
+/// owner is told.
+///
+/// - returns 401 Unauthorized for a wrong password or an unknown login id
+/// - returns 429 Too Many Requests while the account is locked
+async fn request_email_change(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+    Json(payload): Json<EmailChangeRequest>,
+) -> Result<StatusCode, ApiError> {
+    let email = normalize_email(&payload.email);
+    if !is_valid_email(&email) {
+        return Err(ApiError::BadRequest("Invalid email address"));
+    }
+
+    let current = state
+        .store
+        .user(user_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?
+        .email;
+    finish_proof(
+        &state,
+        user_id,
+        &current,
+        "email",
+        &payload.login_id,
+        &payload.client_finish,
+    )
+    .await?;
+
+    if state.store.user_by_email(&email).await?.is_some() {
+        state.mailer.send_account_exists(&email).await?;
+        return Ok(StatusCode::ACCEPTED);
+    }
+
+    let pending = PendingEmailChange { user_id, email };
+    let code = generate_token();
+
+    state
+        .cache
+        .set_ex(
+            &format!("pending_email_change_{}", hash_token(&code)),
+            &serde_json::to_string(&pending).map_err(ApiError::internal)?,
+            EMAIL_CHANGE_CODE_TTL,
+        )
+        .await?;
+
+    state
+        .mailer
+        .send_email_change_code(&pending.email, &code)
+        .await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// POST /me/email/confirm
+///
+/// Change the email to the address the code was mailed to. The OPAQUE
+/// password file is bound to the credential id, so it stays valid.
+///
+/// - returns 204 No Content when the email was changed
+/// - returns 400 Bad Request when the code is unknown, expired or was
+///   requested by another account
+/// - returns 409 Conflict when the address was taken in the meantime, or
+///   the account still has to register its password again, see
+///   [`reregister_start`]
+async fn confirm_email_change(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+    Json(payload): Json<EmailChangeConfirmRequest>,
+) -> Result<StatusCode, ApiError> {
+    let pending = state
+        .cache
+        .get_del(&format!(
+            "pending_email_change_{}",
+            hash_token(&payload.code)
+        ))
+        .await?
+        .ok_or(ApiError::BadRequest("Invalid or expired code"))?;
+    let pending: PendingEmailChange = serde_json::from_str(&pending).map_err(ApiError::internal)?;
+
+    if pending.user_id != user_id {
+        return Err(ApiError::BadRequest("Invalid or expired code"));
+    }
+
+    let previous = match state.store.change_email(user_id, &pending.email).await? {
+        EmailChangeOutcome::Changed { previous } => previous,
+        EmailChangeOutcome::UnknownUser => return Err(ApiError::Unauthorized),
+        // the password file would no longer match the email
+        EmailChangeOutcome::NoCredentialId => {
+            return Err(ApiError::Conflict(
+                "Re-register the credential before changing the email",
+            ));
+        }
+        EmailChangeOutcome::Taken => {
+            return Err(ApiError::Conflict("The email address is taken"));
+        }
+    };
+
+    state
+        .mailer
+        .send_email_changed(&previous, &pending.email)
+        .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /me/delete/start
+///
+/// Start the OPAQUE login that proves the password for [`delete_account`].
+/// It counts towards the lockout of the account like any other login.
+async fn delete_account_start(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+    Json(payload): Json<DeleteAccountStartRequest>,
+) -> Result<Json<LoginStartResponse>, ApiError> {
+    let user = state
+        .store
+        .user(user_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+
+    start_proof(&state, &user, "delete", &payload.client_start)
+        .await
+        .map(Json)
+}
+
+/// DELETE /me
+///
+/// Delete the account for good, after finishing the login started with
+/// [`delete_account_start`]. Vaults, records, tombstones and sessions go
+/// with it, every session is logged out, and what the cache holds about the
+/// account is forgotten.
+///
+/// - returns 200 OK with the id of the deleted account
+/// - returns 401 Unauthorized for a wrong password or an unknown login id
+/// - returns 429 Too Many Requests while the account is locked
+async fn delete_account(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+    Json(payload): Json<DeleteAccountRequest>,
+) -> Result<Json<DeleteAccountResponse>, ApiError> {
+    let email = state
+        .store
+        .user(user_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?
+        .email;
+
+    finish_proof(
+        &state,
+        user_id,
+        &email,
+        "delete",
+        &payload.login_id,
+        &payload.client_finish,
+    )
+    .await?;
+
+    // vaults, records, tombstones and sessions go with the account
+    let sessions = state.store.delete_user(user_id).await?;
+
+    for session_id in sessions {
+        session::deny_session(&state, session_id).await?;
+    }
+    rate_limit::forget_account(&*state.cache, &email).await?;
+
+    tracing::info!("Deleted account {}", user_id);
+
+    Ok(Json(DeleteAccountResponse {
+        id: user_id,
+        deleted_at: UtcDateTime::now(),
+    }))
+}
+
+/// Start an OPAQUE login with the current password, for an `action` that
+/// has to prove it. It counts towards the lockout of the account like any
+/// other login.
+pub(crate) async fn start_proof(
+    state: &AppStateRef,
+    user: &User,
+    action: &str,
+    client_start: &str,
+) -> Result<LoginStartResponse, ApiError> {
+    rate_limit::check_lockout(&*state.cache, &user.email).await?;
+    rate_limit::check(
+        &*state.cache,
+        &rate_limit::account_key(&user.email),
+        rate_limit::LOGIN_ATTEMPTS_PER_ACCOUNT,
+    )
+    .await?;
+
+    let password_file = BASE64_STANDARD
+        .decode(&user.password_file)
+        .map_err(ApiError::internal)?;
+    let client_start = BASE64_STANDARD
+        .decode(client_start)
+        .map_err(|_| ApiError::BadRequest("Invalid base64"))?;
+
+    let (server_state, message) = sanctum_shared::login::server_start(
+        &state.server_setup,
+        &credential_identifier(user.credential_id, &user.email),
+        Some(&password_file),
+        &client_start,
+    )
+    .map_err(|_| ApiError::BadRequest("Invalid login request"))?;
+
+    let login_id = generate_token();
+    let proof_state = ProofState {
+        user_id: user.id,
+        server_state: BASE64_STANDARD.encode(server_state),
+    };
+    state
+        .cache
+        .set_ex(
+            &format!("{}_state_{}", action, login_id),
+            &serde_json::to_string(&proof_state).map_err(ApiError::internal)?,
+            PROOF_STATE_TTL,
+        )
+        .await?;
+
+    Ok(LoginStartResponse {
+        login_id,
+        message: BASE64_STANDARD.encode(message),
+    })
+}
+
+/// Finish the login started with [`start_proof`] for the same `action`,
+/// failing with 401 Unauthorized unless it proves the password.
+pub(crate) async fn finish_proof(
+    state: &AppStateRef,
+    user_id: Uuid,
+    email: &str,
+    action: &str,
+    login_id: &str,
+    client_finish: &str,
+) -> Result<(), ApiError> {
+    rate_limit::check_lockout(&*state.cache, email).await?;
+
+    let proof_state = state
+        .cache
+        .get_del(&format!("{}_state_{}", action, login_id))
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+    let proof_state: ProofState = serde_json::from_str(&proof_state).map_err(ApiError::internal)?;
+
+    // the login was started by another account
+    if proof_state.user_id != user_id {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let client_finish = BASE64_STANDARD
+        .decode(client_finish)
+        .map_err(|_| ApiError::BadRequest("Invalid base64"))?;
+    let server_state = BASE64_STANDARD
+        .decode(proof_state.server_state)
+        .map_err(ApiError::internal)?;
+
+    if sanctum_shared::login::server_finish(&client_finish, &server_state).is_err() {
+        rate_limit::record_failure(&*state.cache, email).await?;
+        return Err(ApiError::Unauthorized);
+    }
+    Ok(())
+}
+
+/// Start an OPAQUE registration under `credential_id`.
+fn registration_start(
+    state: &AppStateRef,
+    credential_id: Uuid,
+    client_start: &str,
+) -> Result<RegistrationStartResponse, ApiError> {
+    let client_start = BASE64_STANDARD
+        .decode(client_start)
+        .map_err(|_| ApiError::BadRequest("Invalid base64"))?;
+    let server_start = sanctum_shared::register::server_start(
+        &state.server_setup,
+        credential_id.as_bytes(),
+        &client_start,
+    )
+    .map_err(|_| ApiError::BadRequest("Invalid registration request"))?;
+
+    Ok(RegistrationStartResponse {
+        server_start: BASE64_STANDARD.encode(server_start),
+        credential_id,
+    })
+}
+
+/// Reject sessions older than [`REREGISTER_WINDOW`].
+async fn check_fresh_session(state: &AppStateRef, claims: &Claims) -> Result<(), ApiError> {
+    let session_id = Uuid::parse_str(&claims.sid).map_err(|_| ApiError::Unauthorized)?;
+
+    let created_at = state
+        .store
+        .session(session_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?
+        .created_at;
+
+    if created_at < OffsetDateTime::now_utc() - REREGISTER_WINDOW {
+        return Err(ApiError::Forbidden("Log in again to do this"));
+    }
+    Ok(())
+}
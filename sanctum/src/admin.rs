@@ -0,0 +1,329 @@
+//! The admin commands of the `sanctum` binary, next to running the server.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use clap::Subcommand;
+
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use uuid::Uuid;
+
+use crate::{
+    cache::{self, Cache},
+    config::Config,
+    jwt, rate_limit,
+    server_setup::{self, SetupError},
+    session,
+    store::{Store, User},
+    util::normalize_email,
+};
+
+#[derive(Subcommand)]
+pub enum SetupCommand {
+    /// Create a new server setup. Accounts registered with another setup
+    /// can't log in with it
+    Generate {
+        /// Where to write it, `-` for stdout. Defaults to server_setup.path
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Replace an existing file
+        #[arg(long)]
+        force: bool,
+    },
+    /// Show the public key and fingerprint of the configured setup, to
+    /// check replicas share one
+    Inspect,
+    /// Store the configured setup again, encrypted with server_setup.key or
+    /// unencrypted without one
+    Rekey {
+        /// The key the setup is encrypted with now, if it isn't server_setup.key
+        #[arg(long, env = "SANCTUM_SERVER_SETUP_OLD_KEY", hide_env_values = true)]
+        old_key: Option<String>,
+        /// Where to write it, `-` for stdout. Defaults to replacing server_setup.path
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum KeysCommand {
+    /// List the signing keys, newest first
+    List,
+    /// Create a new signing key. Running servers publish it right away and
+    /// sign with it once every replica knows it
+    Rotate,
+}
+
+#[derive(Subcommand)]
+pub enum UsersCommand {
+    /// List the accounts with how many vaults and records they have
+    List,
+    /// Keep an account from logging in and log it out everywhere
+    Disable {
+        /// The email or id of the account
+        user: String,
+    },
+    /// Let a disabled account log in again
+    Enable {
+        /// The email or id of the account
+        user: String,
+    },
+    /// Revoke every session of an account
+    Logout {
+        /// The email or id of the account
+        user: String,
+    },
+    /// Delete an account with its vaults and records
+    Delete {
+        /// The email or id of the account
+        user: String,
+        /// Don't ask for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+pub async fn migrate(config: &Config) -> Result<(), String> {
+    let store = crate::get_store(config).await?;
+    crate::prepare_schema(&*store, true).await?;
+    eprintln!("The database is up to date");
+    Ok(())
+}
+
+pub fn setup(config: &Config, command: SetupCommand) -> Result<(), SetupError> {
+    let key = server_setup::key(&config.server_setup)?;
+
+    match command {
+        SetupCommand::Generate { output, force } => {
+            let setup = opaque_ke::ServerSetup::new(&mut rand::rngs::OsRng);
+            let output = output.unwrap_or_else(|| config.server_setup.path.clone());
+            write(&output, &server_setup::encode(&setup, key.as_ref()), force)?;
+            eprintln!("fingerprint: {}", server_setup::fingerprint(&setup));
+        }
+        SetupCommand::Inspect => {
+            let loaded = server_setup::load(&config.server_setup)?.ok_or_else(|| {
+                SetupError::Invalid(format!(
+                    "{} does not exist",
+                    config.server_setup.path.display()
+                ))
+            })?;
+            match &config.server_setup.data {
+                Some(_) => println!("source:      server_setup.data"),
+                None => println!("source:      {}", config.server_setup.path.display()),
+            }
+            println!("encrypted:   {}", loaded.encrypted);
+            println!("public key:  {}", server_setup::public_key(&loaded.setup));
+            println!("fingerprint: {}", server_setup::fingerprint(&loaded.setup));
+        }
+        SetupCommand::Rekey { old_key, output } => {
+            let mut old = config.server_setup.clone();
+            if old_key.is_some() {
+                old.key = old_key;
+            }
+            let loaded = server_setup::load(&old)?.ok_or_else(|| {
+                SetupError::Invalid(format!("{} does not exist", old.path.display()))
+            })?;
+            let output = output.unwrap_or_else(|| config.server_setup.path.clone());
+            write(
+                &output,
+                &server_setup::encode(&loaded.setup, key.as_ref()),
+                true,
+            )?;
+            eprintln!("fingerprint: {}", server_setup::fingerprint(&loaded.setup));
+        }
+    }
+    Ok(())
+}
+
+fn write(output: &Path, content: &str, overwrite: bool) -> Result<(), SetupError> {
+    if output.as_os_str() == "-" {
+        println!("{}", content);
+        return Ok(());
+    }
+    server_setup::write(output, content, overwrite)?;
+    eprintln!("Wrote the server setup to {}", output.display());
+    Ok(())
+}
+
+pub async fn keys(config: &Config, command: KeysCommand) -> Result<(), String> {
+    let store = crate::get_store(config).await?;
+
+    match command {
+        KeysCommand::List => {
+            let now = OffsetDateTime::now_utc();
+            let keys = store.signing_keys().await.map_err(|e| e.to_string())?;
+            let active = keys.iter().position(|key| key.active_at <= now);
+            for (i, key) in keys.iter().enumerate() {
+                let state = match active {
+                    Some(active) if i == active => "active",
+                    Some(active) if i > active => "replaced",
+                    _ => "pending",
+                };
+                println!(
+                    "{}  {:<8}  created {}  active from {}",
+                    key.kid,
+                    state,
+                    key.created_at.format(&Rfc3339).unwrap_or_default(),
+                    key.active_at.format(&Rfc3339).unwrap_or_default()
+                );
+            }
+        }
+        KeysCommand::Rotate => {
+            // the key is encrypted with the secret, a missing one would
+            // leave it readable to anyone with the database
+            let secret = config.checked_jwt_secret().map_err(|e| e.to_string())?;
+            let keys = jwt::SigningKeys::new(secret);
+            let active_at = OffsetDateTime::now_utc() + jwt::PUBLISH_DELAY;
+            let kid = keys
+                .create(&*store, active_at)
+                .await
+                .map_err(|e| e.to_string())?;
+            eprintln!(
+                "Created signing key {}, tokens are signed with it from {}",
+                kid,
+                active_at.format(&Rfc3339).unwrap_or_default()
+            );
+        }
+    }
+    Ok(())
+}
+
+pub async fn users(config: &Config, command: UsersCommand) -> Result<(), String> {
+    let store = crate::get_store(config).await?;
+
+    match command {
+        UsersCommand::List => {
+            let users = store.user_summaries().await.map_err(|e| e.to_string())?;
+            for user in users {
+                let state = match user.disabled_at {
+                    Some(_) => "disabled",
+                    None => "active",
+                };
+                println!(
+                    "{}  {:<8}  {:>4} vaults  {:>6} records  created {}  {}",
+                    user.id,
+                    state,
+                    user.vaults,
+                    user.records,
+                    user.created_at.format(&Rfc3339).unwrap_or_default(),
+                    user.email
+                );
+            }
+        }
+        UsersCommand::Disable { user } => {
+            let user = find_user(&*store, &user).await?;
+            let sessions = store
+                .set_disabled(user.id, true)
+                .await
+                .map_err(|e| e.to_string())?;
+            deny_sessions(config, &sessions).await?;
+            eprintln!(
+                "Disabled {} and revoked {} sessions",
+                user.email,
+                sessions.len()
+            );
+        }
+        UsersCommand::Enable { user } => {
+            let user = find_user(&*store, &user).await?;
+            store
+                .set_disabled(user.id, false)
+                .await
+                .map_err(|e| e.to_string())?;
+            eprintln!("Enabled {}", user.email);
+        }
+        UsersCommand::Logout { user } => {
+            let user = find_user(&*store, &user).await?;
+            let sessions = store
+                .revoke_sessions(user.id)
+                .await
+                .map_err(|e| e.to_string())?;
+            deny_sessions(config, &sessions).await?;
+            eprintln!("Revoked {} sessions of {}", sessions.len(), user.email);
+        }
+        UsersCommand::Delete { user, yes } => {
+            let user = find_user(&*store, &user).await?;
+            let usage = store
+                .storage_usage(user.id)
+                .await
+                .map_err(|e| e.to_string())?;
+            let question = format!(
+                "Delete {} with {} vaults and {} records? This can't be undone",
+                user.email, usage.vaults, usage.records
+            );
+            if !yes && !confirm(&question)? {
+                return Err("Aborted".to_string());
+            }
+
+            // vaults, records, tombstones and sessions go with the account
+            let sessions = store
+                .delete_user(user.id)
+                .await
+                .map_err(|e| e.to_string())?;
+            deny_sessions(config, &sessions).await?;
+            if let Some(cache) = shared_cache(config).await? {
+                rate_limit::forget_account(&*cache, &user.email)
+                    .await
+                    .map_err(|_| "Failed to clear the rate limits of the account".to_string())?;
+            }
+            eprintln!("Deleted {}", user.email);
+        }
+    }
+    Ok(())
+}
+
+/// The account with the id or email `user`.
+async fn find_user(store: &dyn Store, user: &str) -> Result<User, String> {
+    let found = match Uuid::parse_str(user) {
+        Ok(id) => store.user(id).await,
+        Err(_) => store.user_by_email(&normalize_email(user)).await,
+    };
+    found
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("There is no account {}", user))
+}
+
+/// The cache the servers share, `None` when they each keep their own.
+async fn shared_cache(config: &Config) -> Result<Option<Arc<dyn Cache>>, String> {
+    let Some(url) = config.redis_url.as_deref() else {
+        return Ok(None);
+    };
+    cache::connect(Some(url))
+        .await
+        .map(Some)
+        .map_err(|e| format!("Failed to connect to Redis: {}", e))
+}
+
+/// Reject the access tokens of revoked sessions, which the servers only
+/// learn about through a shared cache. Without, they stay valid until
+/// they expire.
+async fn deny_sessions(config: &Config, sessions: &[Uuid]) -> Result<(), String> {
+    if sessions.is_empty() {
+        return Ok(());
+    }
+    let Some(cache) = shared_cache(config).await? else {
+        eprintln!(
+            "No redis_url set, access tokens already issued stay valid for up to {} seconds",
+            config.tokens.access_token_ttl
+        );
+        return Ok(());
+    };
+    for session_id in sessions {
+        session::deny(&*cache, *session_id, config.tokens.access_token_ttl)
+            .await
+            .map_err(|e| format!("Failed to revoke access tokens: {}", e))?;
+    }
+    Ok(())
+}
+
+fn confirm(question: &str) -> Result<bool, String> {
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush().map_err(|e| e.to_string())?;
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .map_err(|e| e.to_string())?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
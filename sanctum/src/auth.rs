@@ -2,193 +2,369 @@
 
 use axum::extract::State;
 use axum::http::StatusCode;
-use axum::routing::post;
+use axum::routing::{delete, get, post};
 use axum::{Json, Router};
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
-use jsonwebtoken::{EncodingKey, Header};
-use redis::AsyncTypedCommands;
 use sanctum_shared::models::{
-    LoginFinishRequest, LoginFinishResponse, LoginStartRequest, LoginStartResponse,
-    RegistrationFinishRequest, RegistrationStartRequest, RegistrationStartResponse,
+    KdfParams, LoginFinishRequest, LoginFinishResponse, LoginResult, LoginStartRequest,
+    LoginStartResponse, RegistrationConfirmRequest, RegistrationFinishRequest,
+    RegistrationStartRequest, RegistrationStartResponse,
 };
-use serde::Deserialize;
-use time::{Duration, OffsetDateTime};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::AppStateRef;
-use crate::middleware::Claims;
-use crate::util::normalize_email;
+use crate::error::ApiError;
+use crate::middleware::ClientIp;
+use crate::rate_limit;
+use crate::store::NewUser;
+use crate::util::{generate_token, hash_token, is_valid_email, normalize_email};
+use crate::{mfa, session, webauthn};
 
 pub fn routes() -> Router<AppStateRef> {
     Router::new()
         .route("/register/start", post(register_start))
         .route("/register/finish", post(register_finish))
+        .route("/register/confirm", post(register_confirm))
         .route("/login/start", post(login_start))
         .route("/login/finish", post(login_finish))
+        .route("/mfa/totp", post(mfa::login_totp))
+        .route("/mfa/recovery", post(mfa::login_recovery))
+        .route("/webauthn/register/start", post(webauthn::register_start))
+        .route("/webauthn/register/finish", post(webauthn::register_finish))
+        .route("/webauthn/credentials", get(webauthn::list_credentials))
+        .route(
+            "/webauthn/credentials/delete/start",
+            post(webauthn::delete_credential_start),
+        )
+        .route(
+            "/webauthn/credentials/{credential_id}",
+            delete(webauthn::delete_credential),
+        )
+        .route("/webauthn/login/start", post(webauthn::login_start))
+        .route("/webauthn/login/finish", post(webauthn::login_finish))
+        .route("/refresh", post(session::refresh))
+        .route("/logout", post(session::logout))
 }
 
+/// Registration must not reveal whether an email is already taken, so
+/// neither of the steps looks at existing accounts. Whether the account is
+/// actually created is only told to the owner of the email, see
+/// [`register_finish`].
 pub async fn register_start(
     State(state): State<AppStateRef>,
     Json(payload): Json<RegistrationStartRequest>,
-) -> Result<Json<RegistrationStartResponse>, StatusCode> {
-    // check if the email is already registered
-    let user = sqlx::query!("SELECT id FROM users WHERE email = $1", payload.email)
-        .fetch_optional(&state.db)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    if user.is_some() {
-        return Err(StatusCode::BAD_REQUEST);
-    }
-
+) -> Result<Json<RegistrationStartResponse>, ApiError> {
     // start the OPAQUE registration process
+    let credential_id = Uuid::new_v4();
     let decoded_client_start = BASE64_STANDARD
         .decode(payload.client_start)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|_| ApiError::BadRequest("Invalid base64"))?;
     let server_start = sanctum_shared::register::server_start(
         &state.server_setup,
-        normalize_email(&payload.email).as_bytes(),
+        credential_id.as_bytes(),
         &decoded_client_start,
     )
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .map_err(|_| ApiError::BadRequest("Invalid registration request"))?;
     let encoded_server_start = BASE64_STANDARD.encode(server_start);
 
     // Return the OPAQUE server start response
     Ok(Json(RegistrationStartResponse {
         server_start: encoded_server_start,
+        credential_id,
     }))
 }
 
+/// The OPAQUE credential identifier of an account.
+///
+/// Accounts registered before there were credential ids use their email,
+/// until they register their password again, see `account::reregister_start`.
+pub fn credential_identifier(credential_id: Option<Uuid>, email: &str) -> Vec<u8> {
+    match credential_id {
+        Some(credential_id) => credential_id.as_bytes().to_vec(),
+        None => email.as_bytes().to_vec(),
+    }
+}
+
+/// How long a registration code is valid.
+const REGISTRATION_CODE_TTL: u64 = 24 * 60 * 60;
+
+/// A registration waiting for its email to be confirmed.
+#[derive(Serialize, Deserialize)]
+struct PendingRegistration {
+    email: String,
+    salt: String,
+    password_file: String,
+    credential_id: Uuid,
+    #[serde(default)]
+    kdf: KdfParams,
+}
+
+/// Refuse KDF parameters weaker than the defaults, or so expensive
+/// that clients on small devices could never unlock the account.
+pub fn check_kdf(kdf: &KdfParams) -> Result<(), ApiError> {
+    let minimum = KdfParams::default();
+    let valid = (minimum.memory_kib..=4 * 1024 * 1024).contains(&kdf.memory_kib)
+        && (1..=100).contains(&kdf.iterations)
+        && (1..=64).contains(&kdf.parallelism)
+        && kdf.memory_kib >= 8 * kdf.parallelism;
+
+    if !valid {
+        return Err(ApiError::BadRequest("Unsupported KDF parameters"));
+    }
+    Ok(())
+}
+
+/// Mail a confirmation code to new accounts, and a warning to the owner
+/// of an existing one. Both cases answer `202 Accepted`.
 pub async fn register_finish(
     State(state): State<AppStateRef>,
     Json(payload): Json<RegistrationFinishRequest>,
-) -> Result<StatusCode, StatusCode> {
+) -> Result<StatusCode, ApiError> {
     let decoded_client_finish = BASE64_STANDARD
         .decode(payload.client_finish)
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+        .map_err(|_| ApiError::BadRequest("Invalid base64"))?;
     let password_file = sanctum_shared::register::server_finish(&decoded_client_finish)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let encoded_password_file = BASE64_STANDARD.encode(password_file);
-
-    sqlx::query!(
-        "INSERT INTO users (email, salt, password_file) VALUES ($1, $2, $3)",
-        normalize_email(&payload.email),
-        payload.salt,
-        encoded_password_file
-    )
-    .execute(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|_| ApiError::BadRequest("Invalid registration upload"))?;
+    let email = normalize_email(&payload.email);
+    if !is_valid_email(&email) {
+        return Err(ApiError::BadRequest("Invalid email address"));
+    }
+    check_kdf(&payload.kdf)?;
+
+    if state.store.user_by_email(&email).await?.is_some() {
+        state.mailer.send_account_exists(&email).await?;
+        return Ok(StatusCode::ACCEPTED);
+    }
+
+    let pending = PendingRegistration {
+        email,
+        salt: payload.salt,
+        password_file: BASE64_STANDARD.encode(password_file),
+        credential_id: payload.credential_id,
+        kdf: payload.kdf,
+    };
+    let code = generate_token();
+
+    state
+        .cache
+        .set_ex(
+            &format!("pending_registration_{}", hash_token(&code)),
+            &serde_json::to_string(&pending).map_err(ApiError::internal)?,
+            REGISTRATION_CODE_TTL,
+        )
+        .await?;
+
+    state
+        .mailer
+        .send_registration_code(&pending.email, &code)
+        .await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Create the account a registration code was mailed for.
+///
+/// - returns 201 Created when the account was created
+/// - returns 400 Bad Request when the code is unknown or expired
+/// - returns 409 Conflict when the email was registered in the meantime
+///   (or the credential id is taken, which only a broken client can cause)
+pub async fn register_confirm(
+    State(state): State<AppStateRef>,
+    Json(payload): Json<RegistrationConfirmRequest>,
+) -> Result<StatusCode, ApiError> {
+    let pending = state
+        .cache
+        .get_del(&format!(
+            "pending_registration_{}",
+            hash_token(&payload.code)
+        ))
+        .await?
+        .ok_or(ApiError::BadRequest("Invalid or expired code"))?;
+    let pending: PendingRegistration =
+        serde_json::from_str(&pending).map_err(ApiError::internal)?;
 
-    Ok(StatusCode::CREATED)
+    let created = state
+        .store
+        .create_user(&NewUser {
+            email: pending.email,
+            salt: pending.salt,
+            password_file: pending.password_file,
+            credential_id: pending.credential_id,
+            kdf: pending.kdf,
+        })
+        .await?;
+
+    match created {
+        true => Ok(StatusCode::CREATED),
+        false => Err(ApiError::Conflict("The email address is taken")),
+    }
 }
 
 // ------------------------------------------------------------------------------------------------
 //                                             Login
 // ------------------------------------------------------------------------------------------------
 
+/// A login between its two steps.
+#[derive(Serialize, Deserialize)]
+struct LoginState {
+    email: String,
+    server_state: String,
+}
+
+/// Unknown emails get a fake OPAQUE response, so they can't be told apart
+/// from existing accounts. The login then fails in [`login_finish`] the same
+/// way as with a wrong password.
 pub async fn login_start(
     State(state): State<AppStateRef>,
+    ClientIp(ip): ClientIp,
     Json(payload): Json<LoginStartRequest>,
-) -> Result<Json<LoginStartResponse>, StatusCode> {
-    let user = sqlx::query!(
-        "SELECT id, email, password_file FROM users WHERE email = $1",
-        payload.email
+) -> Result<Json<LoginStartResponse>, ApiError> {
+    let email = normalize_email(&payload.email);
+
+    rate_limit::check(
+        &*state.cache,
+        &format!("rate_login_ip_{}", ip),
+        rate_limit::LOGIN_REQUESTS_PER_IP,
+    )
+    .await?;
+    rate_limit::check_lockout(&*state.cache, &email).await?;
+    rate_limit::check(
+        &*state.cache,
+        &rate_limit::account_key(&email),
+        rate_limit::LOGIN_ATTEMPTS_PER_ACCOUNT,
     )
-    .fetch_one(&state.db)
-    .await
-    .map_err(|_| StatusCode::NOT_FOUND)?;
+    .await?;
 
-    let password_file = BASE64_STANDARD
-        .decode(user.password_file)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let user = state.store.user_by_email(&email).await?;
+
+    let identifier = credential_identifier(user.as_ref().and_then(|u| u.credential_id), &email);
+    let password_file = user
+        .map(|user| BASE64_STANDARD.decode(user.password_file))
+        .transpose()
+        .map_err(ApiError::internal)?;
     let client_start = BASE64_STANDARD
         .decode(payload.client_start)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|_| ApiError::BadRequest("Invalid base64"))?;
 
     let (server_state, message) = sanctum_shared::login::server_start(
         &state.server_setup,
-        //user.id.as_bytes(),
-        user.email.as_bytes(),
-        &password_file,
+        &identifier,
+        password_file.as_deref(),
         &client_start,
     )
-    .map_err(|_| StatusCode::BAD_REQUEST)?;
+    .map_err(|_| ApiError::BadRequest("Invalid login request"))?;
 
-    // save server state in cache
-    let mut redis = state.redis.clone();
-    let encoded_server_state = BASE64_STANDARD.encode(server_state);
-    redis
-        .set_ex(format!("login_state_{}", user.id), encoded_server_state, 60)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // save server state in cache, for unknown emails too. Every attempt
+    // gets its own id, so logins on several devices don't get in each
+    // other's way
+    let login_id = generate_token();
+    let login_state = LoginState {
+        email,
+        server_state: BASE64_STANDARD.encode(server_state),
+    };
+    state
+        .cache
+        .set_ex(
+            &format!("login_state_{}", login_id),
+            &serde_json::to_string(&login_state).map_err(ApiError::internal)?,
+            state.config.tokens.login_state_ttl,
+        )
+        .await?;
 
     Ok(Json(LoginStartResponse {
+        login_id,
         message: BASE64_STANDARD.encode(message),
     }))
 }
 
+/// - returns 401 Unauthorized for a wrong password and unknown emails alike
+/// - returns 403 Forbidden when the account is disabled
+/// - returns 429 Too Many Requests while the account is locked after
+///   too many failures
+/// - returns an [`MfaChallenge`](sanctum_shared::models::MfaChallenge)
+///   instead of the tokens when the account has a second factor
 pub async fn login_finish(
     State(state): State<AppStateRef>,
+    ClientIp(ip): ClientIp,
     Json(payload): Json<LoginFinishRequest>,
-) -> Result<Json<LoginFinishResponse>, StatusCode> {
-    // get the user details from the database
-    let user = sqlx::query!(
-        "SELECT * FROM users WHERE email = $1",
-        normalize_email(&payload.email)
-    )
-    .fetch_optional(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<Json<LoginResult>, ApiError> {
+    let email = normalize_email(&payload.email);
 
-    let Some(user) = user else {
-        return Err(StatusCode::NOT_FOUND);
-    };
+    rate_limit::check(
+        &*state.cache,
+        &format!("rate_login_ip_{}", ip),
+        rate_limit::LOGIN_REQUESTS_PER_IP,
+    )
+    .await?;
+    rate_limit::check_lockout(&*state.cache, &email).await?;
 
     // get login_state from cache
     let login_state = state
-        .redis
-        .clone()
-        .get_del(format!("login_state_{}", user.id))
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-        .ok_or(StatusCode::NOT_FOUND)?;
+        .cache
+        .get_del(&format!("login_state_{}", payload.login_id))
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+    let login_state: LoginState = serde_json::from_str(&login_state).map_err(ApiError::internal)?;
+
+    // the attempt was started for another account
+    if login_state.email != email {
+        return Err(ApiError::Unauthorized);
+    }
 
     // decode the payload data
     let client_finish = BASE64_STANDARD
         .decode(payload.client_finish)
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+        .map_err(|_| ApiError::BadRequest("Invalid base64"))?;
     let server_start = BASE64_STANDARD
-        .decode(login_state)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    // finish the OPAQUE login process
-    let server_finish = sanctum_shared::login::server_finish(&client_finish, &server_start)
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
-
-    // generate a JWT
-    let now = OffsetDateTime::now_utc();
-    let expires = now + Duration::days(7);
-    let claims = Claims {
-        sub: user.id.to_string(),
-        exp: expires.unix_timestamp() as u64,
-        iat: now.unix_timestamp() as u64,
-        iss: "https://sanctum.lucalewin.dev".into(),
-        nbf: now.unix_timestamp() as u64,
-        aud: "https://sanctum.lucalewin.dev".into(),
-        jti: uuid::Uuid::new_v4().to_string(),
-    };
+        .decode(login_state.server_state)
+        .map_err(ApiError::internal)?;
 
-    let token = jsonwebtoken::encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(&state.jwt_secret.as_bytes()),
+    // finish the OPAQUE login process, this never succeeds for unknown emails
+    if sanctum_shared::login::server_finish(&client_finish, &server_start).is_err() {
+        rate_limit::record_failure(&*state.cache, &email).await?;
+        return Err(ApiError::Unauthorized);
+    }
+
+    // get the user details from the database
+    let user = state
+        .store
+        .user_by_email(&email)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+    if user.disabled_at.is_some() {
+        return Err(ApiError::AccountDisabled);
+    }
+
+    // the failures are only cleared once the second factor passed as well
+    let methods = mfa::methods(&state, user.id).await?;
+    if !methods.is_empty() {
+        let challenge = mfa::challenge(
+            &state,
+            user.id,
+            &email,
+            payload.device_name,
+            payload.platform,
+            methods,
+        )
+        .await?;
+        return Ok(Json(LoginResult::MfaRequired(challenge)));
+    }
+    rate_limit::clear_failures(&*state.cache, &email).await?;
+
+    let tokens = session::create(
+        &state,
+        user.id,
+        payload.device_name.as_deref(),
+        payload.platform.as_deref(),
     )
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .await?;
 
-    Ok(Json(LoginFinishResponse {
-        access_token: token,
+    Ok(Json(LoginResult::Done(LoginFinishResponse {
+        access_token: tokens.access_token,
+        refresh_token: tokens.refresh_token,
+        expires_in: tokens.expires_in,
         salt: user.salt,
-    }))
+        reregister: user.credential_id.is_none(),
+    })))
 }
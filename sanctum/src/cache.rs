@@ -0,0 +1,315 @@
+//! Short-lived state: pending logins and registrations, rate limit
+//! counters and revoked tokens.
+//!
+//! Handlers only talk to a [`Cache`]. With `redis_url` set it is Redis,
+//! shared by every replica. Without, it is kept in the memory of the
+//! process, which is enough for a single server and for tests, but is lost
+//! on restart and not shared between replicas.
+//!
+//! Every entry expires, times are in seconds.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use redis::{AsyncTypedCommands, IntegerReplyOrNoOp, RedisError, aio::ConnectionManager};
+
+pub type Result<T> = std::result::Result<T, CacheError>;
+
+/// A failed operation of a [`Cache`] backend.
+#[derive(Debug, thiserror::Error)]
+pub enum CacheError {
+    #[error("Redis: {0}")]
+    Redis(#[from] RedisError),
+}
+
+#[async_trait::async_trait]
+pub trait Cache: Send + Sync {
+    /// Store `value` under `key` for `ttl` seconds, replacing what was there.
+    async fn set_ex(&self, key: &str, value: &str, ttl: u64) -> Result<()>;
+
+    async fn get(&self, key: &str) -> Result<Option<String>>;
+
+    /// Remove `key` and return its value. Of concurrent calls only one
+    /// gets the value, so it can be used up exactly once.
+    async fn get_del(&self, key: &str) -> Result<Option<String>>;
+
+    /// Remove the keys, returning how many of them there were.
+    async fn del(&self, keys: &[String]) -> Result<u64>;
+
+    /// Whether any of the keys is there.
+    async fn exists(&self, keys: &[String]) -> Result<bool>;
+
+    /// The seconds until `key` expires, `None` when it is not there.
+    async fn ttl(&self, key: &str) -> Result<Option<u64>>;
+
+    /// Increment the counter at `key` and expire it `ttl` seconds from now.
+    async fn incr(&self, key: &str, ttl: u64) -> Result<u64>;
+
+    /// Increment the counter at `key`, which is started with a lifetime of
+    /// `window` seconds. Returns the count and the seconds left.
+    async fn incr_in_window(&self, key: &str, window: u64) -> Result<(u64, u64)>;
+}
+
+/// Use Redis at `url`, or the memory of the process without one.
+pub async fn connect(url: Option<&str>) -> Result<Arc<dyn Cache>> {
+    match url {
+        Some(url) => Ok(Arc::new(RedisCache::connect(url).await?)),
+        None => Ok(Arc::new(MemoryCache::new())),
+    }
+}
+
+// ------------------------------------------------------------------------------------------------
+//                                             Redis
+// ------------------------------------------------------------------------------------------------
+
+pub struct RedisCache {
+    redis: ConnectionManager,
+}
+
+impl RedisCache {
+    pub async fn connect(url: &str) -> Result<Self> {
+        let client = redis::Client::open(url)?;
+        Ok(Self {
+            redis: ConnectionManager::new(client).await?,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Cache for RedisCache {
+    async fn set_ex(&self, key: &str, value: &str, ttl: u64) -> Result<()> {
+        Ok(self.redis.clone().set_ex(key, value, ttl).await?)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.redis.clone().get(key).await?)
+    }
+
+    async fn get_del(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.redis.clone().get_del(key).await?)
+    }
+
+    async fn del(&self, keys: &[String]) -> Result<u64> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        Ok(self.redis.clone().del(keys).await? as u64)
+    }
+
+    async fn exists(&self, keys: &[String]) -> Result<bool> {
+        let found: u64 = redis::cmd("EXISTS")
+            .arg(keys)
+            .query_async(&mut self.redis.clone())
+            .await?;
+        Ok(found > 0)
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<u64>> {
+        match self.redis.clone().ttl(key).await? {
+            IntegerReplyOrNoOp::IntegerReply(ttl) if ttl > 0 => Ok(Some(ttl as u64)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn incr(&self, key: &str, ttl: u64) -> Result<u64> {
+        let (count,): (u64,) = redis::pipe()
+            .atomic()
+            .incr(key, 1)
+            .expire(key, ttl as i64)
+            .ignore()
+            .query_async(&mut self.redis.clone())
+            .await?;
+        Ok(count)
+    }
+
+    async fn incr_in_window(&self, key: &str, window: u64) -> Result<(u64, u64)> {
+        // the window starts with the first increment
+        let (count, ttl): (u64, i64) = redis::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(key)
+            .arg(0)
+            .arg("EX")
+            .arg(window)
+            .arg("NX")
+            .ignore()
+            .incr(key, 1)
+            .ttl(key)
+            .query_async(&mut self.redis.clone())
+            .await?;
+        Ok((count, ttl.max(0) as u64))
+    }
+}
+
+// ------------------------------------------------------------------------------------------------
+//                                            Memory
+// ------------------------------------------------------------------------------------------------
+
+/// Expired entries are dropped when they are read, and all of them every
+/// this many writes, so keys that are never read again don't pile up.
+const SWEEP_INTERVAL: u64 = 1024;
+
+#[derive(Default)]
+pub struct MemoryCache {
+    entries: DashMap<String, Entry>,
+    writes: AtomicU64,
+}
+
+struct Entry {
+    value: String,
+    expires_at: Instant,
+}
+
+impl Entry {
+    fn is_live(&self, now: Instant) -> bool {
+        self.expires_at > now
+    }
+}
+
+impl MemoryCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn live(&self, key: &str) -> Option<dashmap::mapref::one::Ref<'_, String, Entry>> {
+        self.entries
+            .get(key)
+            .filter(|entry| entry.is_live(Instant::now()))
+    }
+
+    fn remove(&self, key: &str) -> Option<String> {
+        self.entries
+            .remove(key)
+            .filter(|(_, entry)| entry.is_live(Instant::now()))
+            .map(|(_, entry)| entry.value)
+    }
+
+    /// Increment the counter at `key`. `ttl` is told whether the counter
+    /// was live, and returns the new lifetime if it gets one.
+    fn increment(&self, key: &str, ttl: impl Fn(bool) -> Option<u64>) -> (u64, u64) {
+        let now = Instant::now();
+        let result = {
+            let mut entry = self.entries.entry(key.to_string()).or_insert(Entry {
+                value: String::new(),
+                expires_at: now,
+            });
+            let live = entry.is_live(now);
+            let count = match live {
+                true => entry.value.parse::<u64>().unwrap_or(0) + 1,
+                false => 1,
+            };
+            entry.value = count.to_string();
+            if let Some(ttl) = ttl(live) {
+                entry.expires_at = now + Duration::from_secs(ttl);
+            }
+            (count, seconds_left(&entry, now))
+        };
+        self.wrote();
+        result
+    }
+
+    fn wrote(&self) {
+        if self.writes.fetch_add(1, Ordering::Relaxed) % SWEEP_INTERVAL == SWEEP_INTERVAL - 1 {
+            let now = Instant::now();
+            self.entries.retain(|_, entry| entry.is_live(now));
+        }
+    }
+}
+
+/// Rounded up, so live entries never have 0 seconds left.
+fn seconds_left(entry: &Entry, now: Instant) -> u64 {
+    let left = entry.expires_at.saturating_duration_since(now);
+    left.as_millis().div_ceil(1000) as u64
+}
+
+#[async_trait::async_trait]
+impl Cache for MemoryCache {
+    async fn set_ex(&self, key: &str, value: &str, ttl: u64) -> Result<()> {
+        self.entries.insert(
+            key.to_string(),
+            Entry {
+                value: value.to_string(),
+                expires_at: Instant::now() + Duration::from_secs(ttl),
+            },
+        );
+        self.wrote();
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.live(key).map(|entry| entry.value.clone()))
+    }
+
+    async fn get_del(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.remove(key))
+    }
+
+    async fn del(&self, keys: &[String]) -> Result<u64> {
+        Ok(keys.iter().filter_map(|key| self.remove(key)).count() as u64)
+    }
+
+    async fn exists(&self, keys: &[String]) -> Result<bool> {
+        Ok(keys.iter().any(|key| self.live(key).is_some()))
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<u64>> {
+        Ok(self
+            .live(key)
+            .map(|entry| seconds_left(&entry, Instant::now())))
+    }
+
+    async fn incr(&self, key: &str, ttl: u64) -> Result<u64> {
+        Ok(self.increment(key, |_| Some(ttl)).0)
+    }
+
+    async fn incr_in_window(&self, key: &str, window: u64) -> Result<(u64, u64)> {
+        Ok(self.increment(key, |live| (!live).then_some(window)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn values_can_be_taken_once() {
+        let cache = MemoryCache::new();
+        cache.set_ex("a", "1", 60).await.unwrap();
+
+        assert_eq!(cache.get("a").await.unwrap().as_deref(), Some("1"));
+        assert_eq!(cache.get_del("a").await.unwrap().as_deref(), Some("1"));
+        assert_eq!(cache.get_del("a").await.unwrap(), None);
+        assert!(!cache.exists(&["a".to_string()]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_gone() {
+        let cache = MemoryCache::new();
+        cache.set_ex("a", "1", 60).await.unwrap();
+        cache.entries.get_mut("a").unwrap().expires_at = Instant::now();
+
+        assert_eq!(cache.get("a").await.unwrap(), None);
+        assert_eq!(cache.ttl("a").await.unwrap(), None);
+        assert_eq!(cache.del(&["a".to_string()]).await.unwrap(), 0);
+        assert_eq!(cache.incr("a", 60).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn windows_keep_their_expiry() {
+        let cache = MemoryCache::new();
+
+        assert_eq!(cache.incr_in_window("a", 60).await.unwrap(), (1, 60));
+        cache.entries.get_mut("a").unwrap().expires_at -= Duration::from_secs(30);
+        assert_eq!(cache.incr_in_window("a", 60).await.unwrap(), (2, 30));
+
+        // a sliding expiry is pushed back
+        assert_eq!(cache.incr("a", 60).await.unwrap(), 3);
+        assert_eq!(cache.ttl("a").await.unwrap(), Some(60));
+    }
+}
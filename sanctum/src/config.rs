@@ -0,0 +1,567 @@
+//! The server configuration.
+//!
+//! Read from a TOML file (see `sanctum.example.toml`), then overridden by
+//! environment variables, then validated. Every setting but the URLs and
+//! secrets has a default, so the file is optional.
+//!
+//! | setting                       | environment variable           |
+//! |-------------------------------|--------------------------------|
+//! | `bind`                        | `SANCTUM_BIND`                 |
+//! | `public_url`                  | `SANCTUM_PUBLIC_URL`           |
+//! | `log_format`                  | `SANCTUM_LOG_FORMAT`           |
+//! | `trusted_proxies`             | `SANCTUM_TRUSTED_PROXIES`      |
+//! | `server_setup.path`           | `SANCTUM_SERVER_SETUP`         |
+//! | `server_setup.data`           | `SANCTUM_SERVER_SETUP_DATA`    |
+//! | `server_setup.key`            | `SANCTUM_SERVER_SETUP_KEY`     |
+//! | `database_url`                | `DATABASE_URL`                 |
+//! | `migrate`                     | `SANCTUM_MIGRATE`              |
+//! | `redis_url`                   | `REDIS_URL`                    |
+//! | `tombstone_retention`         | `SANCTUM_TOMBSTONE_RETENTION`  |
+//! | `jwt_secret`                  | `JWT_SECRET`                   |
+//! | `mfa_secret`                  | `MFA_SECRET`                   |
+//! | `tokens.access_token_ttl`     | `SANCTUM_ACCESS_TOKEN_TTL`     |
+//! | `tokens.refresh_token_ttl`    | `SANCTUM_REFRESH_TOKEN_TTL`    |
+//! | `tokens.login_state_ttl`      | `SANCTUM_LOGIN_STATE_TTL`      |
+//! | `tokens.signing_key_rotation` | `SANCTUM_SIGNING_KEY_ROTATION` |
+//! | `mail.from`                   | `MAIL_FROM`                    |
+//! | `mail.smtp_url`               | `SMTP_URL`                     |
+//! | `mail.dir`                    | `MAIL_DIR`                     |
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use time::Duration;
+use url::Url;
+
+/// Where the config is read from when no path is given.
+pub const DEFAULT_PATH: &str = "sanctum.toml";
+
+/// Shortest accepted secret, in bytes.
+const MIN_SECRET_LENGTH: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: Box<toml::de::Error>,
+    },
+    #[error("invalid value for {name}: {message}")]
+    Invalid { name: &'static str, message: String },
+    #[error("{0} is required, set it in the config file or the environment")]
+    Missing(&'static str),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable, over several lines.
+    Pretty,
+    /// Human readable, a line per event.
+    #[default]
+    Compact,
+    /// A JSON object per line, for log collectors.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "compact" => Ok(LogFormat::Compact),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected pretty, compact or json".to_string()),
+        }
+    }
+}
+
+/// The addresses of the reverse proxies in front of the server, as IPs or
+/// networks, e.g. `10.0.0.0/8`. Requests from them are attributed to the
+/// client named in their `Forwarded` or `X-Forwarded-For` header.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "Vec<String>")]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+}
+
+impl TryFrom<Vec<String>> for TrustedProxies {
+    type Error = String;
+
+    fn try_from(values: Vec<String>) -> Result<Self, Self::Error> {
+        values
+            .iter()
+            .map(|value| {
+                let value = value.trim();
+                value
+                    .parse()
+                    .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("{} is not an IP address or network", value))
+            })
+            .collect::<Result<_, _>>()
+            .map(TrustedProxies)
+    }
+}
+
+/// A comma separated list, for the environment.
+impl FromStr for TrustedProxies {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|value| !value.trim().is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+            .try_into()
+    }
+}
+
+/// Lifetimes, in seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenConfig {
+    /// How long an access token is valid. Revoking a session only takes
+    /// effect for its access tokens through the deny list in the cache,
+    /// which therefore has to keep entries for this long.
+    pub access_token_ttl: u64,
+    /// How long a session may go unused before it has to log in again.
+    pub refresh_token_ttl: u64,
+    /// How long the server state of a login is kept between its two steps.
+    pub login_state_ttl: u64,
+    /// How long access tokens are signed with a key before it is rotated,
+    /// see `jwt.rs`. 0 only rotates with `sanctum keys rotate`.
+    pub signing_key_rotation: u64,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        Self {
+            access_token_ttl: 15 * 60,
+            refresh_token_ttl: 30 * 24 * 60 * 60,
+            login_state_ttl: 60,
+            signing_key_rotation: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl TokenConfig {
+    pub fn access_token_ttl(&self) -> Duration {
+        Duration::seconds(self.access_token_ttl as i64)
+    }
+
+    pub fn refresh_token_ttl(&self) -> Duration {
+        Duration::seconds(self.refresh_token_ttl as i64)
+    }
+
+    pub fn signing_key_rotation(&self) -> Duration {
+        Duration::seconds(self.signing_key_rotation as i64)
+    }
+}
+
+/// Where the OPAQUE `ServerSetup` is kept, see `server_setup.rs`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSetupConfig {
+    /// The file the setup is read from, and created in on the first start.
+    pub path: PathBuf,
+    /// The setup itself, as written to the file. Takes precedence over
+    /// `path`, for deployments that hand out secrets through the environment.
+    pub data: Option<String>,
+    /// Encrypts the setup, 32 bytes in base64.
+    pub key: Option<String>,
+}
+
+impl Default for ServerSetupConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from(".state.safe"),
+            data: None,
+            key: None,
+        }
+    }
+}
+
+/// See `mail.rs`. Mails go out over SMTP if `smtp_url` is set, are
+/// written to `dir` otherwise, and only logged without either.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub from: String,
+    pub smtp_url: Option<String>,
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            from: "Sanctum <no-reply@localhost>".to_string(),
+            smtp_url: None,
+            dir: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: SocketAddr,
+    /// The URL clients reach the server at. It is the issuer and audience
+    /// of the access tokens, and the WebAuthn relying party.
+    pub public_url: Option<Url>,
+    pub log_format: LogFormat,
+    pub trusted_proxies: TrustedProxies,
+    pub server_setup: ServerSetupConfig,
+    pub database_url: Option<String>,
+    /// Apply missing migrations on start. Without, the server refuses to
+    /// start until they are applied with `sanctum migrate`.
+    pub migrate: bool,
+    /// Without it the short-lived state is kept in memory, see `cache.rs`.
+    pub redis_url: Option<String>,
+    /// How long, in seconds, deletions are kept for clients to sync them.
+    /// Clients which last synced before get everything again, see `sync.rs`.
+    pub tombstone_retention: u64,
+    /// Encrypts the keys access tokens are signed with, see `jwt.rs`.
+    pub jwt_secret: Option<String>,
+    /// Encrypts the TOTP secrets, see `mfa.rs`.
+    pub mfa_secret: Option<String>,
+    pub tokens: TokenConfig,
+    pub mail: MailConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            public_url: None,
+            log_format: LogFormat::default(),
+            trusted_proxies: TrustedProxies::default(),
+            server_setup: ServerSetupConfig::default(),
+            database_url: None,
+            migrate: true,
+            redis_url: None,
+            tombstone_retention: 90 * 24 * 60 * 60,
+            jwt_secret: None,
+            mfa_secret: None,
+            tokens: TokenConfig::default(),
+            mail: MailConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Read the config file at `path`, or [`DEFAULT_PATH`] if it exists,
+    /// and apply the environment. The server has to [`Config::validate`]
+    /// the result, the admin commands only need parts of it.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut config = match path {
+            Some(path) => Self::read(path)?,
+            None if Path::new(DEFAULT_PATH).exists() => Self::read(Path::new(DEFAULT_PATH))?,
+            None => Self::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+        toml::from_str(&content).map_err(|source| ConfigError::Parse {
+            path: path.to_owned(),
+            source: Box::new(source),
+        })
+    }
+
+    /// Override settings with the environment variables that are set,
+    /// see the table in the module docs.
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        fn parse<T: FromStr>(name: &'static str, value: String) -> Result<T, ConfigError>
+        where
+            T::Err: std::fmt::Display,
+        {
+            value.parse().map_err(|e: T::Err| ConfigError::Invalid {
+                name,
+                message: e.to_string(),
+            })
+        }
+
+        if let Some(value) = var("SANCTUM_BIND") {
+            self.bind = parse("SANCTUM_BIND", value)?;
+        }
+        if let Some(value) = var("SANCTUM_PUBLIC_URL") {
+            self.public_url = Some(parse("SANCTUM_PUBLIC_URL", value)?);
+        }
+        if let Some(value) = var("SANCTUM_LOG_FORMAT") {
+            self.log_format = parse("SANCTUM_LOG_FORMAT", value)?;
+        }
+        if let Some(value) = var("SANCTUM_TRUSTED_PROXIES") {
+            self.trusted_proxies = parse("SANCTUM_TRUSTED_PROXIES", value)?;
+        }
+        if let Some(value) = var("SANCTUM_SERVER_SETUP") {
+            self.server_setup.path = PathBuf::from(value);
+        }
+        if let Some(value) = var("SANCTUM_MIGRATE") {
+            self.migrate = parse("SANCTUM_MIGRATE", value)?;
+        }
+        if let Some(value) = var("SANCTUM_TOMBSTONE_RETENTION") {
+            self.tombstone_retention = parse("SANCTUM_TOMBSTONE_RETENTION", value)?;
+        }
+        if let Some(value) = var("SANCTUM_ACCESS_TOKEN_TTL") {
+            self.tokens.access_token_ttl = parse("SANCTUM_ACCESS_TOKEN_TTL", value)?;
+        }
+        if let Some(value) = var("SANCTUM_REFRESH_TOKEN_TTL") {
+            self.tokens.refresh_token_ttl = parse("SANCTUM_REFRESH_TOKEN_TTL", value)?;
+        }
+        if let Some(value) = var("SANCTUM_LOGIN_STATE_TTL") {
+            self.tokens.login_state_ttl = parse("SANCTUM_LOGIN_STATE_TTL", value)?;
+        }
+        if let Some(value) = var("SANCTUM_SIGNING_KEY_ROTATION") {
+            self.tokens.signing_key_rotation = parse("SANCTUM_SIGNING_KEY_ROTATION", value)?;
+        }
+
+        self.database_url = var("DATABASE_URL").or(self.database_url.take());
+        self.redis_url = var("REDIS_URL").or(self.redis_url.take());
+        self.server_setup.data = var("SANCTUM_SERVER_SETUP_DATA").or(self.server_setup.data.take());
+        self.server_setup.key = var("SANCTUM_SERVER_SETUP_KEY").or(self.server_setup.key.take());
+        self.jwt_secret = var("JWT_SECRET").or(self.jwt_secret.take());
+        self.mfa_secret = var("MFA_SECRET").or(self.mfa_secret.take());
+        if let Some(value) = var("MAIL_FROM") {
+            self.mail.from = value;
+        }
+        self.mail.smtp_url = var("SMTP_URL").or(self.mail.smtp_url.take());
+        self.mail.dir = var("MAIL_DIR").map(PathBuf::from).or(self.mail.dir.take());
+
+        Ok(())
+    }
+
+    /// Check everything the server needs is set and sane.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |name, message: &str| ConfigError::Invalid {
+            name,
+            message: message.to_string(),
+        };
+
+        let public_url = self
+            .public_url
+            .as_ref()
+            .ok_or(ConfigError::Missing("public_url"))?;
+        if !matches!(public_url.scheme(), "http" | "https") || public_url.host_str().is_none() {
+            return Err(invalid("public_url", "must be an http or https URL"));
+        }
+        if public_url.path() != "/"
+            || public_url.query().is_some()
+            || public_url.fragment().is_some()
+            || !public_url.username().is_empty()
+        {
+            return Err(invalid("public_url", "must be an origin, without a path"));
+        }
+
+        self.database_url()?;
+        self.checked_jwt_secret()?;
+        check_secret("mfa_secret", self.mfa_secret.as_deref())?;
+
+        if self.tokens.access_token_ttl == 0 || self.tokens.login_state_ttl == 0 {
+            return Err(invalid("tokens", "lifetimes must not be 0"));
+        }
+        if self.tokens.refresh_token_ttl < self.tokens.access_token_ttl {
+            return Err(invalid(
+                "tokens.refresh_token_ttl",
+                "must not be shorter than the access token lifetime",
+            ));
+        }
+        if self.tombstone_retention == 0 {
+            return Err(invalid("tombstone_retention", "must not be 0"));
+        }
+        Ok(())
+    }
+
+    /// The origin of [`Config::public_url`], e.g. `https://sanctum.example.com`.
+    pub fn issuer(&self) -> &str {
+        // validated to be an origin, which serializes with a trailing slash
+        self.public_url().as_str().trim_end_matches('/')
+    }
+
+    /// The host name of [`Config::public_url`].
+    pub fn public_host(&self) -> &str {
+        self.public_url().host_str().unwrap_or_default()
+    }
+
+    fn public_url(&self) -> &Url {
+        self.public_url
+            .as_ref()
+            .expect("validated in Config::validate")
+    }
+
+    pub fn tombstone_retention(&self) -> Duration {
+        Duration::seconds(self.tombstone_retention as i64)
+    }
+
+    pub fn database_url(&self) -> Result<&str, ConfigError> {
+        self.database_url
+            .as_deref()
+            .ok_or(ConfigError::Missing("database_url"))
+    }
+
+    pub fn jwt_secret(&self) -> &str {
+        self.jwt_secret.as_deref().unwrap_or_default()
+    }
+
+    /// The JWT secret, for commands that don't [`Config::validate`] the
+    /// whole config but encrypt signing keys with it.
+    pub fn checked_jwt_secret(&self) -> Result<&str, ConfigError> {
+        check_secret("jwt_secret", self.jwt_secret.as_deref())
+    }
+
+    pub fn mfa_secret(&self) -> &str {
+        self.mfa_secret.as_deref().unwrap_or_default()
+    }
+}
+
+fn check_secret<'a>(name: &'static str, secret: Option<&'a str>) -> Result<&'a str, ConfigError> {
+    let secret = secret.ok_or(ConfigError::Missing(name))?;
+    if secret.len() < MIN_SECRET_LENGTH {
+        return Err(ConfigError::Invalid {
+            name,
+            message: "must be at least 32 bytes long".to_string(),
+        });
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+        move |name| vars.get(name).cloned()
+    }
+
+    const EXAMPLE: &str = r#"
+        bind = "127.0.0.1:8080"
+        public_url = "https://sanctum.example.com"
+        log_format = "json"
+        trusted_proxies = ["10.0.0.0/8", "::1"]
+        database_url = "postgresql://localhost/sanctum"
+        redis_url = "redis://localhost"
+        jwt_secret = "0123456789abcdef0123456789abcdef"
+        mfa_secret = "fedcba9876543210fedcba9876543210"
+
+        [tokens]
+        access_token_ttl = 300
+    "#;
+
+    #[test]
+    fn file_is_read_and_validated() {
+        let config: Config = toml::from_str(EXAMPLE).unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.bind, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.issuer(), "https://sanctum.example.com");
+        assert_eq!(config.public_host(), "sanctum.example.com");
+        assert_eq!(config.tokens.access_token_ttl, 300);
+        assert!(config.trusted_proxies.contains("10.1.2.3".parse().unwrap()));
+        assert!(config.trusted_proxies.contains("::1".parse().unwrap()));
+        assert!(
+            !config
+                .trusted_proxies
+                .contains("192.168.0.1".parse().unwrap())
+        );
+        // the rest keeps its defaults
+        assert_eq!(config.tokens.login_state_ttl, 60);
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let mut config: Config = toml::from_str(EXAMPLE).unwrap();
+        config
+            .apply_env(env(&[
+                ("SANCTUM_BIND", "0.0.0.0:9000"),
+                ("SANCTUM_ACCESS_TOKEN_TTL", "60"),
+                ("SANCTUM_MIGRATE", "false"),
+                ("SANCTUM_TRUSTED_PROXIES", "127.0.0.1, 172.16.0.0/12"),
+                ("REDIS_URL", "redis://cache"),
+            ]))
+            .unwrap();
+
+        assert_eq!(config.bind, "0.0.0.0:9000".parse().unwrap());
+        assert_eq!(config.tokens.access_token_ttl, 60);
+        assert!(!config.migrate);
+        assert!(
+            config
+                .trusted_proxies
+                .contains("172.17.0.2".parse().unwrap())
+        );
+        assert!(!config.trusted_proxies.contains("10.1.2.3".parse().unwrap()));
+        assert_eq!(config.redis_url.as_deref(), Some("redis://cache"));
+        assert_eq!(
+            config.database_url().unwrap(),
+            "postgresql://localhost/sanctum"
+        );
+
+        let error = config
+            .apply_env(env(&[("SANCTUM_LOG_FORMAT", "xml")]))
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            ConfigError::Invalid {
+                name: "SANCTUM_LOG_FORMAT",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let config = Config::default();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::Missing("public_url"))
+        ));
+
+        let mut config: Config = toml::from_str(EXAMPLE).unwrap();
+        config.jwt_secret = Some("short".to_string());
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::Invalid {
+                name: "jwt_secret",
+                ..
+            })
+        ));
+
+        assert!(config.checked_jwt_secret().is_err());
+        assert!(matches!(
+            Config::default().checked_jwt_secret(),
+            Err(ConfigError::Missing("jwt_secret"))
+        ));
+
+        config = toml::from_str(EXAMPLE).unwrap();
+        config.public_url = Some("https://example.com/sanctum".parse().unwrap());
+        assert!(config.validate().is_err());
+
+        assert!(toml::from_str::<Config>("unknown = 1").is_err());
+        assert!(toml::from_str::<Config>(r#"trusted_proxies = ["proxy"]"#).is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! The errors of the API.
+//!
+//! Handlers fail with an [`ApiError`], which is answered with an
+//! [`ErrorResponse`]: a code from [`ErrorCode`] for clients to act on, a
+//! message, and the id of the request. Internal errors are logged where
+//! they happen, with the request id, and only reported as such.
+//!
+//! [`request_id`] gives every request its id and turns the errors axum
+//! answers with on its own, e.g. for a malformed body, into the same shape.
+
+use std::fmt::Debug;
+
+use axum::{
+    Json,
+    extract::Request,
+    http::{HeaderName, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sanctum_shared::models::{ErrorCode, ErrorResponse};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::cache::CacheError;
+
+pub static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest request id accepted from a client, or a proxy in front.
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+/// Largest body of a rejection by axum that is passed on as message.
+const MAX_REJECTION_LENGTH: usize = 1024;
+
+tokio::task_local! {
+    static CURRENT_REQUEST_ID: String;
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request is malformed or invalid, and what is wrong with it.
+    BadRequest(&'static str),
+    Unauthorized,
+    Forbidden(&'static str),
+    AccountDisabled,
+    VaultNotFound,
+    RecordNotFound,
+    SessionNotFound,
+    CredentialNotFound,
+    Conflict(&'static str),
+    PayloadTooLarge(&'static str),
+    PreconditionRequired(&'static str),
+    /// Retry after the given number of seconds.
+    TooManyRequests(u64),
+    /// The cause is logged already.
+    Internal,
+}
+
+impl ApiError {
+    /// Log an unexpected error and report it as internal.
+    #[track_caller]
+    pub fn internal(error: impl Debug) -> Self {
+        let location = std::panic::Location::caller();
+        tracing::error!("Internal error at {}: {:?}", location, error);
+        ApiError::Internal
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) | ApiError::AccountDisabled => StatusCode::FORBIDDEN,
+            ApiError::VaultNotFound
+            | ApiError::RecordNotFound
+            | ApiError::SessionNotFound
+            | ApiError::CredentialNotFound => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::PreconditionRequired(_) => StatusCode::PRECONDITION_REQUIRED,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ApiError::BadRequest(_) => ErrorCode::BadRequest,
+            ApiError::Unauthorized => ErrorCode::Unauthorized,
+            ApiError::Forbidden(_) => ErrorCode::Forbidden,
+            ApiError::AccountDisabled => ErrorCode::AccountDisabled,
+            ApiError::VaultNotFound => ErrorCode::VaultNotFound,
+            ApiError::RecordNotFound => ErrorCode::RecordNotFound,
+            ApiError::SessionNotFound => ErrorCode::SessionNotFound,
+            ApiError::CredentialNotFound => ErrorCode::CredentialNotFound,
+            ApiError::Conflict(_) => ErrorCode::Conflict,
+            ApiError::PayloadTooLarge(_) => ErrorCode::PayloadTooLarge,
+            ApiError::PreconditionRequired(_) => ErrorCode::PreconditionRequired,
+            ApiError::TooManyRequests(_) => ErrorCode::TooManyRequests,
+            ApiError::Internal => ErrorCode::Internal,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::Forbidden(message)
+            | ApiError::Conflict(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::PreconditionRequired(message) => message.to_string(),
+            ApiError::TooManyRequests(retry_after) => {
+                format!("Too many requests, retry in {} seconds", retry_after)
+            }
+            _ => {
+                let mut message = self.code().description().to_string();
+                message[..1].make_ascii_uppercase();
+                message
+            }
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = error_response(self.status(), self.code(), self.message());
+        if let ApiError::TooManyRequests(retry_after) = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    #[track_caller]
+    fn from(error: sqlx::Error) -> Self {
+        ApiError::internal(error)
+    }
+}
+
+impl From<CacheError> for ApiError {
+    #[track_caller]
+    fn from(error: CacheError) -> Self {
+        ApiError::internal(error)
+    }
+}
+
+fn error_response(status: StatusCode, code: ErrorCode, message: String) -> Response {
+    let request_id = CURRENT_REQUEST_ID
+        .try_with(|id| id.clone())
+        .unwrap_or_default();
+    let body = ErrorResponse {
+        code,
+        message,
+        request_id,
+    };
+    (status, Json(body)).into_response()
+}
+
+/// Give the request an id, taken from the `X-Request-Id` header if there
+/// is one, log everything about the request with it and send it back.
+pub async fn request_id(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH)
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let span = tracing::info_span!("request", id = %id);
+    let response = CURRENT_REQUEST_ID
+        .scope(id.clone(), async {
+            let response = next.run(request).await;
+            into_error_response(response).await
+        })
+        .instrument(span)
+        .await;
+
+    let mut response = response;
+    if let Ok(id) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID.clone(), id);
+    }
+    response
+}
+
+/// Turn an error response that is not an [`ErrorResponse`] yet, like the
+/// rejections of axum's extractors, into one.
+async fn into_error_response(response: Response) -> Response {
+    let status = response.status();
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/json"));
+    if !(status.is_client_error() || status.is_server_error()) || is_json {
+        return response;
+    }
+
+    let code = match status {
+        StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+        StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+        StatusCode::NOT_FOUND => ErrorCode::NotFound,
+        StatusCode::CONFLICT => ErrorCode::Conflict,
+        StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+        StatusCode::TOO_MANY_REQUESTS => ErrorCode::TooManyRequests,
+        status if status.is_server_error() => ErrorCode::Internal,
+        _ => ErrorCode::BadRequest,
+    };
+
+    let (parts, body) = response.into_parts();
+    let message = match axum::body::to_bytes(body, MAX_REJECTION_LENGTH).await {
+        Ok(body) if !body.is_empty() => String::from_utf8_lossy(&body).into_owned(),
+        _ => status
+            .canonical_reason()
+            .unwrap_or("Request failed")
+            .to_string(),
+    };
+
+    let mut response = error_response(status, code, message);
+    for (name, value) in parts.headers {
+        if let Some(name) = name
+            && name != header::CONTENT_TYPE
+            && name != header::CONTENT_LENGTH
+        {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+/// The answer to requests for unknown endpoints.
+pub async fn fallback() -> Response {
+    error_response(
+        StatusCode::NOT_FOUND,
+        ErrorCode::NotFound,
+        "No such endpoint".to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, body::Body, routing::get};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn send(router: Router, request: Request) -> (StatusCode, Option<String>, ErrorResponse) {
+        let response = router
+            .layer(axum::middleware::from_fn(request_id))
+            .fallback(fallback)
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = response.status();
+        let id = response
+            .headers()
+            .get(&REQUEST_ID)
+            .map(|id| id.to_str().unwrap().to_string());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, id, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn errors_carry_code_and_request_id() {
+        let router =
+            Router::new().route("/", get(|| async { Err::<(), _>(ApiError::VaultNotFound) }));
+        let request = Request::builder()
+            .uri("/")
+            .header(&REQUEST_ID, "the-request")
+            .body(Body::empty())
+            .unwrap();
+
+        let (status, id, body) = send(router, request).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(id.as_deref(), Some("the-request"));
+        assert_eq!(body.code, ErrorCode::VaultNotFound);
+        assert_eq!(body.message, "Vault not found");
+        assert_eq!(body.request_id, "the-request");
+    }
+
+    #[tokio::test]
+    async fn rejections_and_unknown_routes_become_error_responses() {
+        let router =
+            Router::new().route("/", axum::routing::post(|_: Json<ErrorResponse>| async {}));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("{}"))
+            .unwrap();
+        let (status, id, body) = send(router.clone(), request).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body.code, ErrorCode::BadRequest);
+        assert!(body.message.contains("missing field"));
+        assert_eq!(Some(body.request_id), id);
+
+        let request = Request::builder().uri("/nope").body(Body::empty()).unwrap();
+        let (status, _, body) = send(router, request).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body.code, ErrorCode::NotFound);
+    }
+}
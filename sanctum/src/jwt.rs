@@ -0,0 +1,393 @@
+//! The keys access tokens are signed with.
+//!
+//! Tokens are signed with Ed25519 (`EdDSA`) and name their key in the
+//! `kid` header, so other services can verify them with the public keys
+//! from `/.well-known/jwks.json` instead of sharing a secret.
+//!
+//! The keys live in the database, their private halves encrypted with a
+//! key derived from `jwt_secret`, so every replica signs with the same
+//! ones. Keys are rotated with overlap:
+//!
+//! - a new key is published [`PUBLISH_DELAY`] before tokens are signed with
+//!   it, long enough for every replica and every cached JWKS to know it
+//! - a replaced key stays published until the last token signed with it
+//!   expired, then it is deleted
+//!
+//! Every replica reloads the keys each [`RELOAD_INTERVAL`], rotates them
+//! once the active key is older than `tokens.signing_key_rotation`, and
+//! drops the keys no token can be signed with anymore.
+
+use std::{collections::HashMap, sync::RwLock};
+
+use axum::{Json, Router, http::header, response::IntoResponse, routing::get};
+use base64::{
+    Engine,
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+};
+use chacha20poly1305::{
+    AeadCore, ChaCha20Poly1305, KeyInit,
+    aead::{Aead, Payload},
+};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, ThumbprintHash,
+    },
+};
+use rand::{RngCore, rngs::OsRng};
+use serde::{Serialize, de::DeserializeOwned};
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    AppStateRef,
+    config::Config,
+    error::ApiError,
+    store::{self, Store},
+};
+
+/// How long a new key is published before tokens are signed with it.
+/// Longer than [`RELOAD_INTERVAL`] and the cache lifetime of the JWKS.
+pub const PUBLISH_DELAY: Duration = Duration::minutes(10);
+
+/// How often the keys are reloaded from the database.
+const RELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How long clients may cache the JWKS, in seconds.
+const JWKS_MAX_AGE: u64 = 5 * 60;
+
+/// The DER prefix of an Ed25519 private key in PKCS #8 (RFC 8410), the
+/// 32 byte seed follows.
+const PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+pub fn routes() -> Router<AppStateRef> {
+    Router::new().route("/.well-known/jwks.json", get(jwks))
+}
+
+/// GET /.well-known/jwks.json
+///
+/// The public keys of all tokens that may still be valid, and of the key
+/// that is next.
+async fn jwks(state: axum::extract::State<AppStateRef>) -> impl IntoResponse {
+    (
+        [(
+            header::CACHE_CONTROL,
+            format!("public, max-age={}", JWKS_MAX_AGE),
+        )],
+        Json(state.signing_keys.jwks()),
+    )
+}
+
+/// The keys loaded from the database.
+#[derive(Default)]
+struct KeySet {
+    /// The key tokens are signed with now.
+    signing: Option<(String, EncodingKey)>,
+    verifying: HashMap<String, DecodingKey>,
+    jwks: Vec<Jwk>,
+}
+
+pub struct SigningKeys {
+    /// Encrypts the private keys in the database.
+    secret: [u8; 32],
+    keys: RwLock<KeySet>,
+}
+
+impl SigningKeys {
+    pub fn new(jwt_secret: &str) -> Self {
+        Self {
+            secret: Sha256::digest(jwt_secret).into(),
+            keys: RwLock::new(KeySet::default()),
+        }
+    }
+
+    /// Sign the claims with the active key.
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, ApiError> {
+        let keys = self.keys.read().expect("poisoned");
+        let Some((kid, key)) = &keys.signing else {
+            tracing::error!("There is no signing key to issue tokens with");
+            return Err(ApiError::Internal);
+        };
+
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(kid.clone());
+        jsonwebtoken::encode(&header, claims, key).map_err(|e| {
+            tracing::error!("Failed to sign token: {:?}", e);
+            ApiError::Internal
+        })
+    }
+
+    /// Verify a token signed with one of the published keys.
+    pub fn verify<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        validation: &Validation,
+    ) -> Result<T, jsonwebtoken::errors::Error> {
+        use jsonwebtoken::errors::ErrorKind;
+
+        let kid = jsonwebtoken::decode_header(token)?
+            .kid
+            .ok_or(ErrorKind::InvalidToken)?;
+        let keys = self.keys.read().expect("poisoned");
+        let key = keys
+            .verifying
+            .get(&kid)
+            .ok_or(ErrorKind::InvalidKeyFormat)?;
+        jsonwebtoken::decode(token, key, validation).map(|data| data.claims)
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self.keys.read().expect("poisoned").jwks.clone(),
+        }
+    }
+
+    /// Load the published keys from the database.
+    pub async fn reload(
+        &self,
+        store: &dyn Store,
+        access_token_ttl: Duration,
+    ) -> Result<(), sqlx::Error> {
+        let now = OffsetDateTime::now_utc();
+        let rows = store.signing_keys().await?;
+
+        let mut keys = KeySet::default();
+        // keys replaced by an active key longer ago than tokens live are unused
+        let mut replaced_at: Option<OffsetDateTime> = None;
+        for row in rows {
+            if replaced_at.is_some_and(|at| at + access_token_ttl < now) {
+                break;
+            }
+
+            if row.active_at <= now && keys.signing.is_none() {
+                match self.decrypt(&row.kid, &row.private_key) {
+                    Some(seed) => {
+                        let mut der = PKCS8_PREFIX.to_vec();
+                        der.extend_from_slice(&seed);
+                        keys.signing = Some((row.kid.clone(), EncodingKey::from_ed_der(&der)));
+                    }
+                    None => tracing::error!(
+                        "Failed to decrypt signing key {}, is JWT_SECRET right?",
+                        row.kid
+                    ),
+                }
+            }
+            if row.active_at <= now {
+                replaced_at = Some(row.active_at);
+            }
+
+            keys.verifying
+                .insert(row.kid.clone(), DecodingKey::from_ed_der(&row.public_key));
+            keys.jwks.push(jwk(&row.kid, &row.public_key));
+        }
+
+        *self.keys.write().expect("poisoned") = keys;
+        Ok(())
+    }
+
+    /// Create a new key that is used from `active_at` on.
+    pub async fn create(
+        &self,
+        store: &dyn Store,
+        active_at: OffsetDateTime,
+    ) -> Result<String, sqlx::Error> {
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+        let public_key = ed25519_dalek::SigningKey::from_bytes(&seed)
+            .verifying_key()
+            .to_bytes();
+        let kid = jwk("", &public_key).thumbprint(ThumbprintHash::SHA256);
+
+        store
+            .add_signing_key(&store::SigningKey {
+                private_key: self.encrypt(&kid, &seed),
+                public_key: public_key.to_vec(),
+                created_at: OffsetDateTime::now_utc(),
+                active_at,
+                kid: kid.clone(),
+            })
+            .await?;
+        Ok(kid)
+    }
+
+    /// Make sure there is a key to sign with, rotate it when it is due and
+    /// delete the keys no valid token was signed with, then reload.
+    pub async fn maintain(&self, store: &dyn Store, config: &Config) -> Result<(), sqlx::Error> {
+        let now = OffsetDateTime::now_utc();
+        let access_token_ttl = config.tokens.access_token_ttl();
+        let latest = store.signing_keys().await?.first().map(|key| key.active_at);
+
+        match latest {
+            None => {
+                let kid = self.create(store, now).await?;
+                tracing::info!("Created signing key {}", kid);
+            }
+            Some(latest)
+                if config.tokens.signing_key_rotation != 0
+                    && latest + config.tokens.signing_key_rotation() <= now =>
+            {
+                let kid = self.create(store, now + PUBLISH_DELAY).await?;
+                tracing::info!("Rotating to signing key {}", kid);
+            }
+            Some(_) => {}
+        }
+
+        let deleted = store
+            .delete_replaced_signing_keys(now - access_token_ttl)
+            .await?;
+        for kid in deleted {
+            tracing::info!("Deleted signing key {}", kid);
+        }
+
+        self.reload(store, access_token_ttl).await
+    }
+
+    fn encrypt(&self, kid: &str, seed: &[u8; 32]) -> String {
+        let cipher = ChaCha20Poly1305::new((&self.secret).into());
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: seed,
+                    aad: kid.as_bytes(),
+                },
+            )
+            .expect("encryption can't fail for short messages");
+
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend_from_slice(&ciphertext);
+        BASE64_STANDARD.encode(encrypted)
+    }
+
+    fn decrypt(&self, kid: &str, encrypted: &str) -> Option<Vec<u8>> {
+        let encrypted = BASE64_STANDARD.decode(encrypted).ok()?;
+        if encrypted.len() < 12 {
+            return None;
+        }
+        let (nonce, ciphertext) = encrypted.split_at(12);
+        ChaCha20Poly1305::new((&self.secret).into())
+            .decrypt(
+                nonce.into(),
+                Payload {
+                    msg: ciphertext,
+                    aad: kid.as_bytes(),
+                },
+            )
+            .ok()
+    }
+}
+
+/// Keep the keys of this replica current, see the module docs.
+pub fn spawn_maintenance(state: AppStateRef) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = state
+                .signing_keys
+                .maintain(&*state.store, &state.config)
+                .await
+            {
+                tracing::error!("Failed to maintain signing keys: {:?}", e);
+            }
+        }
+    });
+}
+
+fn jwk(kid: &str, public_key: &[u8]) -> Jwk {
+    Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(KeyAlgorithm::EdDSA),
+            key_id: (!kid.is_empty()).then(|| kid.to_string()),
+            ..Default::default()
+        },
+        algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+            key_type: OctetKeyPairType::OctetKeyPair,
+            curve: EllipticCurve::Ed25519,
+            x: BASE64_URL_SAFE_NO_PAD.encode(public_key),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, serde::Deserialize)]
+    struct Claims {
+        sub: String,
+        exp: u64,
+    }
+
+    /// A key set with a single key, the way `reload` builds it.
+    fn key_set(keys: &SigningKeys) -> (String, Vec<u8>) {
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+        let public_key = ed25519_dalek::SigningKey::from_bytes(&seed)
+            .verifying_key()
+            .to_bytes();
+        let kid = jwk("", &public_key).thumbprint(ThumbprintHash::SHA256);
+        let encrypted = keys.encrypt(&kid, &seed);
+
+        let mut der = PKCS8_PREFIX.to_vec();
+        der.extend_from_slice(&keys.decrypt(&kid, &encrypted).unwrap());
+        let mut set = keys.keys.write().unwrap();
+        set.signing = Some((kid.clone(), EncodingKey::from_ed_der(&der)));
+        set.verifying
+            .insert(kid.clone(), DecodingKey::from_ed_der(&public_key));
+        set.jwks.push(jwk(&kid, &public_key));
+        (kid, public_key.to_vec())
+    }
+
+    #[test]
+    fn tokens_verify_with_the_published_key() {
+        let keys = SigningKeys::new("0123456789abcdef0123456789abcdef");
+        let (kid, _) = key_set(&keys);
+
+        let claims = Claims {
+            sub: "user".to_string(),
+            exp: (OffsetDateTime::now_utc() + Duration::minutes(5)).unix_timestamp() as u64,
+        };
+        let token = keys.sign(&claims).unwrap();
+        assert_eq!(
+            jsonwebtoken::decode_header(&token).unwrap().kid.unwrap(),
+            kid
+        );
+
+        // as another service would, with nothing but the JWKS
+        let jwks: JwkSet =
+            serde_json::from_str(&serde_json::to_string(&keys.jwks()).unwrap()).unwrap();
+        let key = DecodingKey::from_jwk(jwks.find(&kid).unwrap()).unwrap();
+        let mut validation = Validation::new(Algorithm::EdDSA);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        let decoded = jsonwebtoken::decode::<Claims>(&token, &key, &validation).unwrap();
+        assert_eq!(decoded.claims.sub, "user");
+
+        // a key another server made is unknown
+        let other = SigningKeys::new("0123456789abcdef0123456789abcdef");
+        key_set(&other);
+        let forged = other.sign(&claims).unwrap();
+        assert!(keys.verify::<Claims>(&forged, &validation).is_err());
+        assert!(keys.verify::<Claims>(&token, &validation).is_ok());
+    }
+
+    #[test]
+    fn private_keys_are_bound_to_secret_and_kid() {
+        let keys = SigningKeys::new("0123456789abcdef0123456789abcdef");
+        let encrypted = keys.encrypt("kid", &[1u8; 32]);
+
+        assert_eq!(keys.decrypt("kid", &encrypted).unwrap(), [1u8; 32]);
+        assert!(keys.decrypt("other", &encrypted).is_none());
+        assert!(
+            SigningKeys::new("fedcba9876543210fedcba9876543210")
+                .decrypt("kid", &encrypted)
+                .is_none()
+        );
+    }
+}
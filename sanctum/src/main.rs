@@ -1,101 +1,245 @@
+mod account;
+mod admin;
 mod auth;
+mod cache;
+mod config;
+mod error;
+mod jwt;
+mod mail;
+mod mfa;
 mod middleware;
+mod rate_limit;
+mod server_setup;
+mod session;
+mod store;
+mod sync;
 mod util;
 mod vault;
+mod webauthn;
 
-use std::{
-    fs::File,
-    io::{Read, Write},
-    path::Path,
-    sync::Arc,
-};
+use std::{net::SocketAddr, path::PathBuf, process::ExitCode, sync::Arc};
 
 use axum::Router;
-use base64::{Engine, prelude::BASE64_STANDARD};
+use clap::{Parser, Subcommand};
 use opaque_ke::ServerSetup;
-use rand::rngs::OsRng;
 use sanctum_shared::DefaultCipherSuite;
-use sqlx::PgPool;
+use sha2::{Digest, Sha256};
 use tokio::net::TcpListener;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+use crate::config::{Config, LogFormat};
+
+#[derive(Parser)]
+#[command(version, about = "The Sanctum server")]
+struct Args {
+    /// The config file, `sanctum.toml` is read if it exists
+    #[arg(short, long, env = "SANCTUM_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the server, the default
+    Serve {
+        /// Create a new server setup when it is missing although there are
+        /// accounts, which then can't log in anymore
+        #[arg(long)]
+        force_new_server_setup: bool,
+    },
+    /// Apply the database migrations the binary comes with
+    Migrate,
+    /// Manage the OPAQUE server setup
+    #[command(subcommand)]
+    Setup(admin::SetupCommand),
+    /// Manage the keys access tokens are signed with
+    #[command(subcommand)]
+    Keys(admin::KeysCommand),
+    /// Manage the accounts
+    #[command(subcommand)]
+    Users(admin::UsersCommand),
+}
+
 struct AppState {
+    config: Config,
     server_setup: ServerSetup<DefaultCipherSuite>,
-    db: PgPool,
-    redis: redis::aio::ConnectionManager,
-    jwt_secret: String,
+    store: Arc<dyn store::Store>,
+    cache: Arc<dyn cache::Cache>,
+    signing_keys: jwt::SigningKeys,
+    /// Encrypts the TOTP secrets, see `mfa.rs`.
+    mfa_key: [u8; 32],
+    mailer: mail::Mailer,
 }
 type AppStateRef = std::sync::Arc<AppState>;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     dotenvy::dotenv().ok();
-    setup_logging();
+    let args = Args::parse();
+
+    let config = match Config::load(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    setup_logging(config.log_format);
+
+    let result = match args.command.unwrap_or(Command::Serve {
+        force_new_server_setup: false,
+    }) {
+        Command::Serve {
+            force_new_server_setup,
+        } => serve(config, force_new_server_setup).await,
+        Command::Migrate => admin::migrate(&config).await,
+        Command::Setup(command) => admin::setup(&config, command).map_err(|e| e.to_string()),
+        Command::Keys(command) => admin::keys(&config, command).await,
+        Command::Users(command) => admin::users(&config, command).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            tracing::error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn serve(config: Config, force_new_server_setup: bool) -> Result<(), String> {
+    config.validate().map_err(|e| e.to_string())?;
+    let store = get_store(&config).await?;
+    prepare_schema(&*store, config.migrate).await?;
+    let server_setup =
+        server_setup::load_or_create(&config.server_setup, &*store, force_new_server_setup)
+            .await
+            .map_err(|e| e.to_string())?;
+    let cache = get_cache(&config).await?;
 
-    let server_setup = get_server_setup();
-    let redis = get_redis().await;
-    let db = get_db().await;
+    let signing_keys = jwt::SigningKeys::new(config.jwt_secret());
+    signing_keys
+        .maintain(&*store, &config)
+        .await
+        .map_err(|e| format!("Failed to load the signing keys: {}", e))?;
+    let mailer = mail::Mailer::from_config(&config.mail).map_err(|e| e.to_string())?;
 
+    let bind = config.bind;
     let state = AppState {
+        mfa_key: Sha256::digest(config.mfa_secret()).into(),
+        config,
         server_setup,
-        db,
-        redis,
-        jwt_secret: std::env::var("JWT_SECRET").expect("JWT_SECRET must be set"),
+        store,
+        cache,
+        signing_keys,
+        mailer,
     };
+    let state = Arc::new(state);
+    jwt::spawn_maintenance(state.clone());
+    sync::spawn_cleanup(state.clone());
 
     let api_v1 = Router::new()
         .nest("/auth", auth::routes())
-        .merge(vault::routes());
+        .merge(account::routes())
+        .merge(mfa::routes())
+        .merge(session::routes())
+        .merge(vault::routes())
+        .merge(sync::routes());
 
     let app = Router::new()
         .nest("/api/v1", api_v1)
+        .merge(jwt::routes())
+        .fallback(error::fallback)
         .layer(TraceLayer::new_for_http())
-        .with_state(Arc::new(state));
+        .layer(axum::middleware::from_fn(error::request_id))
+        .with_state(state);
 
-    tracing::info!("Starting server on http://0.0.0.0:3000");
+    let listener = TcpListener::bind(bind)
+        .await
+        .map_err(|e| format!("Failed to bind to {}: {}", bind, e))?;
+    tracing::info!("Starting server on http://{}", bind);
 
-    let listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .map_err(|e| format!("Server failed: {}", e))
 }
 
-// FIXME: remove all the unwraps: when an error
-// occurs, just generate a new ServerSetup
-fn get_server_setup() -> ServerSetup<DefaultCipherSuite> {
-    if std::fs::exists(Path::new(".state.safe")).unwrap() {
-        let mut file = File::open(".state.safe").unwrap();
-        let mut content = String::new();
-        file.read_to_string(&mut content).unwrap();
-        let setup = ServerSetup::deserialize(&BASE64_STANDARD.decode(content).unwrap()).unwrap();
-        setup
-    } else {
-        let setup = ServerSetup::new(&mut OsRng);
-        let mut file = File::create(".state.safe").unwrap();
-        let content = BASE64_STANDARD.encode(setup.serialize());
-        file.write_all(&content.as_bytes()).unwrap();
-        setup
+fn setup_logging(format: LogFormat) {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "tower_http=debug,axum::rejection=trace,sanctum=trace".into());
+    let registry = tracing_subscriber::registry().with(filter);
+
+    match format {
+        LogFormat::Pretty => registry
+            .with(tracing_subscriber::fmt::layer().pretty())
+            .init(),
+        LogFormat::Compact => registry
+            .with(tracing_subscriber::fmt::layer().without_time())
+            .init(),
+        LogFormat::Json => registry
+            .with(tracing_subscriber::fmt::layer().json())
+            .init(),
     }
 }
 
-fn setup_logging() {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "tower_http=debug,axum::rejection=trace,sanctum=trace".into()),
-        )
-        .with(tracing_subscriber::fmt::layer().without_time())
-        .init();
+async fn get_cache(config: &Config) -> Result<Arc<dyn cache::Cache>, String> {
+    if config.redis_url.is_none() {
+        tracing::warn!(
+            "No redis_url set, keeping logins in progress, rate limits and revoked tokens \
+            in memory. They are lost on restart and not shared with other instances"
+        );
+    }
+    cache::connect(config.redis_url.as_deref())
+        .await
+        .map_err(|e| format!("Failed to connect to Redis: {}", e))
 }
 
-async fn get_redis() -> redis::aio::ConnectionManager {
-    let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
-    let redis_client = redis::Client::open(redis_url).unwrap();
-    redis::aio::ConnectionManager::new(redis_client)
+async fn get_store(config: &Config) -> Result<Arc<dyn store::Store>, String> {
+    store::connect(config.database_url().map_err(|e| e.to_string())?)
         .await
-        .unwrap()
+        .map_err(|e| format!("Failed to connect to the database: {}", e))
 }
 
-async fn get_db() -> PgPool {
-    let db_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgPool::connect(&db_url).await.unwrap()
+/// Check the schema of the database is the one the binary expects, after
+/// applying the missing migrations if `migrate` is set.
+async fn prepare_schema(store: &dyn store::Store, migrate: bool) -> Result<(), String> {
+    let versions = |versions: &[i64]| {
+        versions
+            .iter()
+            .map(i64::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let state = store
+        .schema_state()
+        .await
+        .map_err(|e| format!("Failed to check the database schema: {}", e))?;
+    if !state.unknown.is_empty() {
+        return Err(format!(
+            "The database was migrated by a newer version of the server, \
+            this one doesn't know migrations {}",
+            versions(&state.unknown)
+        ));
+    }
+    if state.pending.is_empty() {
+        return Ok(());
+    }
+    if !migrate {
+        return Err(format!(
+            "The database lacks migrations {}, apply them with `sanctum migrate`",
+            versions(&state.pending)
+        ));
+    }
+
+    tracing::info!("Applying migrations {}", versions(&state.pending));
+    store
+        .migrate()
+        .await
+        .map_err(|e| format!("Failed to migrate the database: {}", e))
 }
@@ -0,0 +1,591 @@
+//! TOTP (RFC 6238) as a second factor for the login, with recovery codes
+//! for when the authenticator is lost.
+//!
+//! With TOTP enabled, [`auth::login_finish`](crate::auth::login_finish)
+//! hands out an MFA token instead of a session. The token only stands for
+//! the password check, it is exchanged for the session together with a
+//! code at `/auth/mfa/totp` or `/auth/mfa/recovery`.
+//!
+//! The TOTP secrets are encrypted with the server's MFA key, so a leaked
+//! database alone does not give them away.
+
+use std::net::IpAddr;
+
+use axum::{Json, Router, extract::State, http::StatusCode, routing::post};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit,
+    aead::{Aead, AeadCore, Payload},
+};
+use hmac::{Hmac, Mac};
+use rand::{Rng, RngCore, rngs::OsRng};
+use sanctum_shared::models::{
+    LoginFinishResponse, MfaChallenge, MfaMethod, MfaVerifyRequest, RecoveryCodes, TotpCodeRequest,
+    TotpEnrollment,
+};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::{
+    AppStateRef,
+    error::ApiError,
+    middleware::{ClientIp, Session},
+    rate_limit, session,
+    util::{generate_token, hash_token},
+};
+
+pub fn routes() -> Router<AppStateRef> {
+    Router::new()
+        .route("/me/mfa/totp", post(enroll_totp).delete(disable_totp))
+        .route("/me/mfa/totp/confirm", post(confirm_totp))
+}
+
+/// The issuer shown by authenticator apps.
+const ISSUER: &str = "Sanctum";
+
+/// Length of a TOTP secret in bytes, the 160 bits RFC 4226 recommends.
+const SECRET_LENGTH: usize = 20;
+const DIGITS: u32 = 6;
+/// The TOTP time step in seconds.
+const STEP: u64 = 30;
+/// Codes of this many steps before and after the current one are
+/// accepted as well, for clocks that are a little off.
+const SKEW: u64 = 1;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+/// Characters of a recovery code, without the dash in the middle.
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+/// How long an MFA token is valid.
+const MFA_TOKEN_TTL: u64 = 5 * 60;
+/// Wrong codes an MFA token survives, the login has to start over after.
+const MFA_ATTEMPTS: u64 = 5;
+
+/// A login waiting for its second factor.
+#[derive(Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub user_id: Uuid,
+    pub email: String,
+    device_name: Option<String>,
+    platform: Option<String>,
+}
+
+/// The second factors of the user, none if the password is enough to log in.
+pub async fn methods(state: &AppStateRef, user_id: Uuid) -> Result<Vec<MfaMethod>, ApiError> {
+    let Some(user) = state.store.user(user_id).await? else {
+        return Ok(Vec::new());
+    };
+
+    let mut methods = Vec::new();
+    if user.totp_enabled_at.is_some() {
+        methods.extend([MfaMethod::Totp, MfaMethod::RecoveryCode]);
+    }
+    if !state
+        .store
+        .webauthn_credential_ids(user_id)
+        .await?
+        .is_empty()
+    {
+        methods.push(MfaMethod::Webauthn);
+    }
+    Ok(methods)
+}
+
+/// Remember a login whose password was right, and issue the token it
+/// continues with.
+pub async fn challenge(
+    state: &AppStateRef,
+    user_id: Uuid,
+    email: &str,
+    device_name: Option<String>,
+    platform: Option<String>,
+    methods: Vec<MfaMethod>,
+) -> Result<MfaChallenge, ApiError> {
+    let mfa_token = generate_token();
+    let pending = PendingLogin {
+        user_id,
+        email: email.to_owned(),
+        device_name,
+        platform,
+    };
+
+    state
+        .cache
+        .set_ex(
+            &pending_key(&mfa_token),
+            &serde_json::to_string(&pending).map_err(ApiError::internal)?,
+            MFA_TOKEN_TTL,
+        )
+        .await?;
+
+    Ok(MfaChallenge {
+        mfa_token,
+        methods,
+        expires_in: MFA_TOKEN_TTL as i64,
+    })
+}
+
+/// POST /auth/mfa/totp
+///
+/// Finish a login with a TOTP code, see [`challenge`].
+///
+/// - returns 401 Unauthorized for a wrong code or an unknown MFA token
+/// - wrong codes count towards the lockout of the account
+pub async fn login_totp(
+    State(state): State<AppStateRef>,
+    ClientIp(ip): ClientIp,
+    Json(payload): Json<MfaVerifyRequest>,
+) -> Result<Json<LoginFinishResponse>, ApiError> {
+    let pending = pending_login(&state, ip, &payload.mfa_token).await?;
+
+    if !use_totp_code(&state, pending.user_id, &payload.code).await? {
+        return Err(reject_code(&state, &payload.mfa_token, &pending).await);
+    }
+    finish_login(&state, &payload.mfa_token, pending).await
+}
+
+/// POST /auth/mfa/recovery
+///
+/// Finish a login with a recovery code, which can't be used again.
+///
+/// - returns 401 Unauthorized for a wrong code or an unknown MFA token
+pub async fn login_recovery(
+    State(state): State<AppStateRef>,
+    ClientIp(ip): ClientIp,
+    Json(payload): Json<MfaVerifyRequest>,
+) -> Result<Json<LoginFinishResponse>, ApiError> {
+    let pending = pending_login(&state, ip, &payload.mfa_token).await?;
+
+    if !use_recovery_code(&state, pending.user_id, &payload.code).await? {
+        return Err(reject_code(&state, &payload.mfa_token, &pending).await);
+    }
+    finish_login(&state, &payload.mfa_token, pending).await
+}
+
+/// POST /me/mfa/totp
+///
+/// Generate a new TOTP secret. It replaces any earlier one that was never
+/// confirmed, logins only ask for it after [`confirm_totp`].
+///
+/// - returns 409 Conflict when TOTP is already enabled
+async fn enroll_totp(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+) -> Result<Json<TotpEnrollment>, ApiError> {
+    let mut secret = [0u8; SECRET_LENGTH];
+    OsRng.fill_bytes(&mut secret);
+
+    let email = state
+        .store
+        .set_totp_secret(user_id, &encrypt_secret(&state.mfa_key, user_id, &secret))
+        .await?
+        .ok_or(ApiError::Conflict("TOTP is enabled already"))?;
+
+    let secret = base32_encode(&secret);
+    let uri = format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP}",
+        issuer = ISSUER,
+        account = percent_encode(&email),
+    );
+
+    Ok(Json(TotpEnrollment { secret, uri }))
+}
+
+/// POST /me/mfa/totp/confirm
+///
+/// Enable TOTP with a first code from the authenticator, which proves it
+/// was set up right. Returns the recovery codes, replacing earlier ones.
+///
+/// - returns 400 Bad Request for a wrong code
+/// - returns 409 Conflict when there is no secret to confirm
+async fn confirm_totp(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+    Json(payload): Json<TotpCodeRequest>,
+) -> Result<Json<RecoveryCodes>, ApiError> {
+    let user = state
+        .store
+        .user(user_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+
+    let (encrypted, secret) = match (user.totp_secret, user.totp_enabled_at) {
+        (Some(encrypted), None) => {
+            let secret = decrypt_secret(&state.mfa_key, user_id, &encrypted)?;
+            (encrypted, secret)
+        }
+        _ => return Err(ApiError::Conflict("TOTP is not being set up")),
+    };
+    let step = verify_totp(&secret, &payload.code, unix_time(), None)
+        .ok_or(ApiError::BadRequest("Invalid code"))?;
+
+    let codes = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect::<Vec<_>>();
+    let hashes = codes
+        .iter()
+        .map(|code| hash_token(&normalize_recovery_code(code)))
+        .collect::<Vec<_>>();
+
+    // a concurrent enrollment may have replaced the secret in the meantime
+    let enabled = state
+        .store
+        .enable_totp(user_id, &encrypted, step as i64, &hashes)
+        .await?;
+    if !enabled {
+        return Err(ApiError::Conflict("TOTP is not being set up"));
+    }
+
+    Ok(Json(RecoveryCodes { codes }))
+}
+
+/// DELETE /me/mfa/totp
+///
+/// Disable TOTP, with a code from the authenticator or a recovery code.
+///
+/// - returns 400 Bad Request for a wrong code
+/// - returns 409 Conflict when TOTP is not enabled
+async fn disable_totp(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+    Json(payload): Json<TotpCodeRequest>,
+) -> Result<StatusCode, ApiError> {
+    if !methods(&state, user_id).await?.contains(&MfaMethod::Totp) {
+        return Err(ApiError::Conflict("TOTP is not enabled"));
+    }
+
+    let valid = use_totp_code(&state, user_id, &payload.code).await?
+        || use_recovery_code(&state, user_id, &payload.code).await?;
+    if !valid {
+        return Err(ApiError::BadRequest("Invalid code"));
+    }
+
+    state.store.disable_totp(user_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Look up the login an MFA token stands for, after the same checks as
+/// the password step of the login.
+pub async fn pending_login(
+    state: &AppStateRef,
+    ip: IpAddr,
+    mfa_token: &str,
+) -> Result<PendingLogin, ApiError> {
+    rate_limit::check(
+        &*state.cache,
+        &format!("rate_login_ip_{}", ip),
+        rate_limit::LOGIN_REQUESTS_PER_IP,
+    )
+    .await?;
+
+    let pending = state
+        .cache
+        .get(&pending_key(mfa_token))
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+    let pending: PendingLogin = serde_json::from_str(&pending).map_err(ApiError::internal)?;
+
+    rate_limit::check_lockout(&*state.cache, &pending.email).await?;
+    Ok(pending)
+}
+
+/// Count a wrong code, against the token and the account.
+pub async fn reject_code(state: &AppStateRef, mfa_token: &str, pending: &PendingLogin) -> ApiError {
+    let attempts_key = format!("mfa_attempts_{}", hash_token(mfa_token));
+
+    let counted = async {
+        rate_limit::record_failure(&*state.cache, &pending.email).await?;
+
+        let attempts = state.cache.incr(&attempts_key, MFA_TOKEN_TTL).await?;
+
+        if attempts >= MFA_ATTEMPTS {
+            state
+                .cache
+                .del(&[pending_key(mfa_token), attempts_key.clone()])
+                .await?;
+        }
+        Ok::<_, ApiError>(())
+    };
+
+    match counted.await {
+        Ok(()) => ApiError::Unauthorized,
+        Err(error) => error,
+    }
+}
+
+/// Consume the MFA token and start the session.
+pub async fn finish_login(
+    state: &AppStateRef,
+    mfa_token: &str,
+    pending: PendingLogin,
+) -> Result<Json<LoginFinishResponse>, ApiError> {
+    // only one request may win the token
+    let deleted = state
+        .cache
+        .del(&[
+            pending_key(mfa_token),
+            format!("mfa_attempts_{}", hash_token(mfa_token)),
+        ])
+        .await?;
+    if deleted == 0 {
+        return Err(ApiError::Unauthorized);
+    }
+    rate_limit::clear_failures(&*state.cache, &pending.email).await?;
+
+    let user = state
+        .store
+        .user(pending.user_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+
+    let tokens = session::create(
+        state,
+        pending.user_id,
+        pending.device_name.as_deref(),
+        pending.platform.as_deref(),
+    )
+    .await?;
+
+    Ok(Json(LoginFinishResponse {
+        access_token: tokens.access_token,
+        refresh_token: tokens.refresh_token,
+        expires_in: tokens.expires_in,
+        salt: user.salt,
+        reregister: user.credential_id.is_none(),
+    }))
+}
+
+/// Check a TOTP code of a user with TOTP enabled. A code is only accepted
+/// once, neither it nor any earlier one works again afterwards.
+async fn use_totp_code(state: &AppStateRef, user_id: Uuid, code: &str) -> Result<bool, ApiError> {
+    let user = state
+        .store
+        .user(user_id)
+        .await?
+        .filter(|user| user.totp_enabled_at.is_some());
+
+    let Some((secret, last_step)) = user.and_then(|u| Some((u.totp_secret?, u.totp_last_step)))
+    else {
+        return Ok(false);
+    };
+    let secret = decrypt_secret(&state.mfa_key, user_id, &secret)?;
+    let Some(step) = verify_totp(&secret, code, unix_time(), last_step.map(|s| s as u64)) else {
+        return Ok(false);
+    };
+
+    // a concurrent request may have used the same code in the meantime
+    Ok(state.store.use_totp_step(user_id, step as i64).await?)
+}
+
+/// Check a recovery code, and mark it as used.
+async fn use_recovery_code(
+    state: &AppStateRef,
+    user_id: Uuid,
+    code: &str,
+) -> Result<bool, ApiError> {
+    Ok(state
+        .store
+        .use_recovery_code(user_id, &hash_token(&normalize_recovery_code(code)))
+        .await?)
+}
+
+fn pending_key(mfa_token: &str) -> String {
+    format!("mfa_pending_{}", hash_token(mfa_token))
+}
+
+fn unix_time() -> u64 {
+    OffsetDateTime::now_utc().unix_timestamp() as u64
+}
+
+/// The HOTP value (RFC 4226) of `secret` for `counter`.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // dynamic truncation
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    value % 10u32.pow(DIGITS)
+}
+
+/// Check `code` against the TOTP values around the time `now`, and return
+/// the time step it matched. Steps up to `last_step` are not accepted.
+fn verify_totp(secret: &[u8], code: &str, now: u64, last_step: Option<u64>) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = now / STEP;
+    (current.saturating_sub(SKEW)..=current + SKEW)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| {
+            let expected = format!("{:0width$}", hotp(secret, *step), width = DIGITS as usize);
+            bool::from(expected.as_bytes().ct_eq(code.as_bytes()))
+        })
+}
+
+/// Encrypt a TOTP secret for the `totp_secret` column. The user id is
+/// bound to the ciphertext, so secrets can't be moved between accounts.
+fn encrypt_secret(key: &[u8; 32], user_id: Uuid, secret: &[u8]) -> String {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: secret,
+                aad: user_id.as_bytes(),
+            },
+        )
+        .expect("encryption can't fail for short messages");
+
+    let mut encrypted = nonce.to_vec();
+    encrypted.extend_from_slice(&ciphertext);
+    BASE64_STANDARD.encode(encrypted)
+}
+
+fn decrypt_secret(key: &[u8; 32], user_id: Uuid, encrypted: &str) -> Result<Vec<u8>, ApiError> {
+    let encrypted = BASE64_STANDARD
+        .decode(encrypted)
+        .map_err(ApiError::internal)?;
+    if encrypted.len() < 12 {
+        return Err(ApiError::Internal);
+    }
+    let (nonce, ciphertext) = encrypted.split_at(12);
+
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(
+            nonce.into(),
+            Payload {
+                msg: ciphertext,
+                aad: user_id.as_bytes(),
+            },
+        )
+        .map_err(|_| {
+            tracing::error!("Failed to decrypt the TOTP secret of {}", user_id);
+            ApiError::Internal
+        })
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Base32 (RFC 4648) without padding, the way authenticators take secrets.
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer = 0u16;
+    let mut bits = 0;
+
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+/// A recovery code like `k3nq7-xw2dp`.
+fn generate_recovery_code() -> String {
+    let mut code = String::with_capacity(RECOVERY_CODE_LENGTH + 1);
+    for i in 0..RECOVERY_CODE_LENGTH {
+        if i == RECOVERY_CODE_LENGTH / 2 {
+            code.push('-');
+        }
+        let c = BASE32_ALPHABET[OsRng.gen_range(0..BASE32_ALPHABET.len())];
+        code.push(c.to_ascii_lowercase() as char);
+    }
+    code
+}
+
+/// Recovery codes are typed in by hand, so case and dashes don't matter.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Percent-encode the account name in the `otpauth://` label.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'@' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the SHA-1 secret of the test vectors in RFC 6238, appendix B
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn totp_matches_rfc_vectors() {
+        // the RFC lists 8 digits, these are the last 6 of them
+        assert_eq!(verify_totp(RFC_SECRET, "287082", 59, None), Some(1));
+        assert_eq!(
+            verify_totp(RFC_SECRET, "081804", 1111111109, None),
+            Some(37037036)
+        );
+        assert_eq!(
+            verify_totp(RFC_SECRET, "050471", 1111111111, None),
+            Some(37037037)
+        );
+    }
+
+    #[test]
+    fn totp_rejects_used_and_distant_steps() {
+        assert_eq!(verify_totp(RFC_SECRET, "287082", 59, Some(1)), None);
+        assert_eq!(verify_totp(RFC_SECRET, "287082", 59 + 3 * STEP, None), None);
+        assert_eq!(verify_totp(RFC_SECRET, "287083", 59, None), None);
+        assert_eq!(verify_totp(RFC_SECRET, "28708", 59, None), None);
+    }
+
+    #[test]
+    fn base32_matches_rfc_vectors() {
+        assert_eq!(base32_encode(b""), "");
+        assert_eq!(base32_encode(b"f"), "MY");
+        assert_eq!(base32_encode(b"foob"), "MZXW6YQ");
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+    }
+
+    #[test]
+    fn secret_is_bound_to_user() {
+        let key = [7u8; 32];
+        let user_id = Uuid::new_v4();
+        let encrypted = encrypt_secret(&key, user_id, RFC_SECRET);
+
+        assert_eq!(
+            decrypt_secret(&key, user_id, &encrypted).unwrap(),
+            RFC_SECRET
+        );
+        assert!(decrypt_secret(&key, Uuid::new_v4(), &encrypted).is_err());
+    }
+
+    #[test]
+    fn recovery_codes_are_normalized() {
+        let code = generate_recovery_code();
+        assert_eq!(code.len(), RECOVERY_CODE_LENGTH + 1);
+        assert_eq!(
+            normalize_recovery_code(&code.to_uppercase().replace('-', " ")),
+            normalize_recovery_code(&code)
+        );
+    }
+}
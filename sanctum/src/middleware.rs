@@ -1,20 +1,20 @@
+use std::net::{IpAddr, SocketAddr};
+
 use axum::{
     RequestPartsExt,
-    extract::{FromRequestParts, Path},
-    http::StatusCode,
+    extract::{ConnectInfo, FromRequestParts, Path},
+    http::{HeaderMap, header},
 };
 use axum_extra::{
     TypedHeader,
     headers::{Authorization, authorization::Bearer},
 };
-use jsonwebtoken::{DecodingKey, Validation};
+use jsonwebtoken::{Algorithm, Validation};
 use sanctum_shared::models::Vault;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::AppStateRef;
-
-pub const ISSUER: &str = "https://sanctum.lucalewin.dev";
+use crate::{AppStateRef, config::TrustedProxies, error::ApiError, session};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
@@ -25,12 +25,13 @@ pub struct Claims {
     pub aud: String,
     pub jti: String,
     pub nbf: u64,
+    /// The id of the session (see `session.rs`) the token was issued for.
+    pub sid: String,
 }
 
-pub struct Session(pub Uuid);
-
-impl FromRequestParts<AppStateRef> for Session {
-    type Rejection = StatusCode;
+/// The claims of a valid access token whose session was not revoked.
+impl FromRequestParts<AppStateRef> for Claims {
+    type Rejection = ApiError;
 
     async fn from_request_parts(
         parts: &mut axum::http::request::Parts,
@@ -40,61 +41,271 @@ impl FromRequestParts<AppStateRef> for Session {
         let TypedHeader(Authorization(bearer)) = parts
             .extract::<TypedHeader<Authorization<Bearer>>>()
             .await
-            .map_err(|_| StatusCode::UNAUTHORIZED)?;
+            .map_err(|_| ApiError::Unauthorized)?;
 
         // Define JWT validation rules
         let validation = {
-            let mut validation = Validation::default();
+            let mut validation = Validation::new(Algorithm::EdDSA);
             validation.set_required_spec_claims(&["exp", "nbf", "aud", "iss", "sub"]);
-            validation.set_issuer(&[ISSUER]);
-            validation.set_audience(&[ISSUER]);
+            validation.set_issuer(&[state.config.issuer()]);
+            validation.set_audience(&[state.config.issuer()]);
             validation
         };
 
         // Decode and validate the JWT token
-        let claims = jsonwebtoken::decode::<Claims>(
-            bearer.token(),
-            &DecodingKey::from_secret(state.jwt_secret.as_bytes()),
-            &validation,
-        )
-        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+        let claims = state
+            .signing_keys
+            .verify::<Claims>(bearer.token(), &validation)
+            .map_err(|_| ApiError::Unauthorized)?;
+
+        // reject tokens of sessions that were logged out
+        let revoked = state
+            .cache
+            .exists(&[
+                session::revoked_token_key(&claims.jti),
+                session::revoked_session_key(&claims.sid),
+            ])
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to check for revoked tokens: {:?}", e);
+                ApiError::Internal
+            })?;
+        if revoked {
+            return Err(ApiError::Unauthorized);
+        }
+
+        Ok(claims)
+    }
+}
+
+pub struct Session(pub Uuid);
+
+impl FromRequestParts<AppStateRef> for Session {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &AppStateRef,
+    ) -> Result<Self, Self::Rejection> {
+        let claims = Claims::from_request_parts(parts, state).await?;
 
         // extract the user ID from the JWT claims
-        let user_id = Uuid::parse_str(&claims.claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+        let user_id = Uuid::parse_str(&claims.sub).map_err(|_| ApiError::Unauthorized)?;
         Ok(Session(user_id))
     }
 }
 
 pub struct OwnedVault(pub Vault);
 
+/// The path parameters [`OwnedVault`] cares about. Any other
+/// parameters of the route (e.g. `record_id`) are ignored.
+#[derive(Deserialize)]
+struct VaultPath {
+    vault_id: Uuid,
+}
+
 impl axum::extract::FromRequestParts<AppStateRef> for OwnedVault {
-    type Rejection = StatusCode;
+    type Rejection = ApiError;
 
     async fn from_request_parts(
         parts: &mut axum::http::request::Parts,
         state: &AppStateRef,
     ) -> Result<Self, Self::Rejection> {
         // 1. Extract the vault_id from the URL path
-        let Path(vault_id) = parts
-            .extract::<Path<Uuid>>()
+        let Path(VaultPath { vault_id }) = parts
+            .extract::<Path<VaultPath>>()
             .await
-            .map_err(|_| StatusCode::BAD_REQUEST)?;
+            .map_err(|_| ApiError::BadRequest("Invalid vault id"))?;
 
         // 2. Extract the user_id from the session
         let Session(user_id) = Session::from_request_parts(parts, state).await?;
 
         // 3. Perform the ownership check query
-        let vault = sqlx::query_as!(
-            Vault,
-            "SELECT * FROM vaults WHERE id = $1 AND user_id = $2",
-            vault_id,
-            user_id
-        )
-        .fetch_optional(&state.db)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-        .ok_or(StatusCode::NOT_FOUND)?;
+        let vault = state
+            .store
+            .vault(user_id, vault_id)
+            .await?
+            .ok_or(ApiError::VaultNotFound)?;
 
         Ok(OwnedVault(vault))
     }
 }
+
+/// The revision a client expects a vault or record to be at, taken from the
+/// `If-Match` header (e.g. `If-Match: "3"`). `None` when the header is missing.
+pub struct IfMatch(pub Option<i64>);
+
+impl<S: Send + Sync> FromRequestParts<S> for IfMatch {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(header::IF_MATCH) else {
+            return Ok(IfMatch(None));
+        };
+
+        let revision = value
+            .to_str()
+            .map_err(|_| ApiError::BadRequest("Invalid If-Match header"))?
+            .trim()
+            .trim_start_matches("W/")
+            .trim_matches('"')
+            .parse()
+            .map_err(|_| ApiError::BadRequest("Invalid If-Match header"))?;
+
+        Ok(IfMatch(Some(revision)))
+    }
+}
+
+/// The address of the client, which logins are rate limited by.
+///
+/// That is the peer address, unless the peer is one of the `trusted_proxies`.
+/// Then it is the last address in the `Forwarded` header (or, without one,
+/// `X-Forwarded-For`) that is not a trusted proxy itself; the addresses
+/// before it were sent by the client and may be made up.
+pub struct ClientIp(pub IpAddr);
+
+impl FromRequestParts<AppStateRef> for ClientIp {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &AppStateRef,
+    ) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = parts
+            .extract::<ConnectInfo<SocketAddr>>()
+            .await
+            .map_err(ApiError::internal)?;
+
+        Ok(ClientIp(client_ip(
+            peer.ip(),
+            &parts.headers,
+            &state.config.trusted_proxies,
+        )))
+    }
+}
+
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &TrustedProxies) -> IpAddr {
+    if !trusted.contains(peer) {
+        return peer;
+    }
+
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values(header::FORWARDED);
+    let hops = if forwarded.is_empty() {
+        values(header::HeaderName::from_static("x-forwarded-for"))
+            .into_iter()
+            .map(parse_ip)
+            .collect::<Vec<_>>()
+    } else {
+        forwarded.into_iter().map(forwarded_for).collect()
+    };
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        // an unknown or obfuscated hop ends what can be traced
+        let Some(ip) = hop else { break };
+        client = ip;
+        if !trusted.contains(ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// The `for` address of an element of a `Forwarded` header, e.g.
+/// `for="[2001:db8::1]:4711";proto=https`.
+fn forwarded_for(element: &str) -> Option<IpAddr> {
+    element.split(';').find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("for")
+            .then(|| parse_ip(value.trim().trim_matches('"')))?
+    })
+}
+
+/// An IP address, optionally with a port and IPv6 addresses in brackets.
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    value
+        .parse()
+        .or_else(|_| value.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| value.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn headers_of_untrusted_peers_are_ignored() {
+        let trusted = TrustedProxies::default();
+        let headers = headers(&[("x-forwarded-for", "192.0.2.1")]);
+        assert_eq!(
+            client_ip(ip("198.51.100.7"), &headers, &trusted),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn client_is_the_last_untrusted_hop() {
+        let trusted: TrustedProxies = "10.0.0.0/8".parse().unwrap();
+
+        let forwarded_for = headers(&[("x-forwarded-for", "203.0.113.9, 192.0.2.1, 10.0.0.2")]);
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &forwarded_for, &trusted),
+            ip("192.0.2.1")
+        );
+
+        let forwarded = headers(&[
+            (
+                "forwarded",
+                r#"for=203.0.113.9, for="[2001:db8::1]:4711";proto=https"#,
+            ),
+            ("forwarded", "for=10.0.0.2"),
+            ("x-forwarded-for", "192.0.2.1"),
+        ]);
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &forwarded, &trusted),
+            ip("2001:db8::1")
+        );
+    }
+
+    #[test]
+    fn untraceable_hops_stop_at_the_proxy() {
+        let trusted: TrustedProxies = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &HeaderMap::new(), &trusted),
+            ip("10.0.0.1")
+        );
+
+        let headers = headers(&[("forwarded", "for=192.0.2.1, for=unknown")]);
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, &trusted),
+            ip("10.0.0.1")
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Brute-force protection for the login, counted in the [`Cache`].
+//!
+//! Requests are counted in fixed windows per client IP (see
+//! [`crate::middleware::ClientIp`]) and per account.
+//! Failed logins additionally lock the account for a time that doubles
+//! with every failure, see [`lockout_duration`].
+
+use crate::{cache::Cache, error::ApiError};
+
+/// Login requests (both steps) a single IP may send per [`WINDOW`].
+pub const LOGIN_REQUESTS_PER_IP: u64 = 20;
+/// Login attempts a single account may receive per [`WINDOW`].
+pub const LOGIN_ATTEMPTS_PER_ACCOUNT: u64 = 10;
+/// The length of a rate limit window in seconds.
+pub const WINDOW: u64 = 60;
+
+/// Failed logins that are allowed before the account is locked.
+const FREE_FAILURES: u64 = 3;
+/// The first lockout in seconds, doubled with every further failure.
+const MIN_LOCKOUT: u64 = 5;
+const MAX_LOCKOUT: u64 = 15 * 60;
+/// How long failed logins are remembered after the last one.
+const FAILURES_TTL: u64 = 24 * 60 * 60;
+
+/// Count a request against `key`, and reject it if there were more
+/// than `limit` in the current window.
+pub async fn check(cache: &dyn Cache, key: &str, limit: u64) -> Result<(), ApiError> {
+    let (count, ttl) = cache.incr_in_window(key, WINDOW).await.map_err(|e| {
+        tracing::error!("Failed to count request: {:?}", e);
+        ApiError::Internal
+    })?;
+
+    if count > limit {
+        return Err(ApiError::TooManyRequests(ttl.max(1)));
+    }
+    Ok(())
+}
+
+/// Reject the request if the account is locked after failed logins.
+pub async fn check_lockout(cache: &dyn Cache, account: &str) -> Result<(), ApiError> {
+    match cache.ttl(&lockout_key(account)).await? {
+        Some(ttl) => Err(ApiError::TooManyRequests(ttl)),
+        None => Ok(()),
+    }
+}
+
+/// Remember a failed login, and lock the account if there were too many.
+pub async fn record_failure(cache: &dyn Cache, account: &str) -> Result<(), ApiError> {
+    let failures = cache.incr(&failures_key(account), FAILURES_TTL).await?;
+
+    if let Some(lockout) = lockout_duration(failures) {
+        tracing::warn!("Locking {} for {}s after failed logins", account, lockout);
+        cache.set_ex(&lockout_key(account), "1", lockout).await?;
+    }
+    Ok(())
+}
+
+/// Forget the failed logins of an account after a successful one.
+pub async fn clear_failures(cache: &dyn Cache, account: &str) -> Result<(), ApiError> {
+    cache.del(&[failures_key(account)]).await?;
+    Ok(())
+}
+
+/// Forget everything about an account, after it was deleted.
+pub async fn forget_account(cache: &dyn Cache, account: &str) -> Result<(), ApiError> {
+    cache
+        .del(&[
+            failures_key(account),
+            lockout_key(account),
+            account_key(account),
+        ])
+        .await?;
+    Ok(())
+}
+
+/// The key counting the login attempts of an account, see [`check`].
+pub fn account_key(account: &str) -> String {
+    format!("rate_login_account_{}", account)
+}
+
+/// How long an account is locked after its `failures`th failed login.
+fn lockout_duration(failures: u64) -> Option<u64> {
+    let exponent = failures.checked_sub(FREE_FAILURES + 1)?;
+    let lockout = MIN_LOCKOUT.saturating_mul(1 << exponent.min(32));
+    Some(lockout.min(MAX_LOCKOUT))
+}
+
+fn failures_key(account: &str) -> String {
+    format!("login_failures_{}", account)
+}
+
+fn lockout_key(account: &str) -> String {
+    format!("login_lockout_{}", account)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockout_doubles_up_to_max() {
+        let lockouts = (1..=12).map(lockout_duration).collect::<Vec<_>>();
+        assert_eq!(
+            lockouts,
+            vec![
+                None,
+                None,
+                None,
+                Some(5),
+                Some(10),
+                Some(20),
+                Some(40),
+                Some(80),
+                Some(160),
+                Some(320),
+                Some(640),
+                Some(900)
+            ]
+        );
+    }
+}
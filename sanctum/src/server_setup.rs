@@ -0,0 +1,288 @@
+//! Storage of the OPAQUE `ServerSetup`.
+//!
+//! The setup holds the server's long-term key and the OPRF seed every
+//! registration was made with. Losing or replacing it makes the password
+//! file of every account useless, so it is only ever created on purpose:
+//! on the first start of a server without accounts, or by
+//! `sanctum setup generate`. Replicas share one setup by pointing
+//! `server_setup.path` at the same file or passing it as `server_setup.data`.
+//!
+//! A setup is stored as base64 text, the way `.state.safe` always was.
+//! With `server_setup.key` set it is encrypted with ChaCha20-Poly1305 and
+//! stored as `encrypted:` followed by the base64 of nonce and ciphertext.
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chacha20poly1305::{
+    AeadCore, ChaCha20Poly1305, KeyInit,
+    aead::{Aead, Payload},
+};
+use opaque_ke::ServerSetup;
+use rand::rngs::OsRng;
+use sanctum_shared::DefaultCipherSuite;
+use sha2::{Digest, Sha256};
+
+use crate::{config::ServerSetupConfig, store::Store};
+
+const ENCRYPTED_PREFIX: &str = "encrypted:";
+
+/// Binds the ciphertext to its use.
+const AAD: &[u8] = b"sanctum server setup";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SetupError {
+    #[error("failed to read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("failed to write {path}: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("{0} exists already")]
+    Exists(PathBuf),
+    #[error("invalid server_setup.key: must be 32 bytes in base64")]
+    InvalidKey,
+    #[error("the server setup is encrypted, but server_setup.key is not set")]
+    KeyMissing,
+    #[error("failed to decrypt the server setup, server_setup.key is wrong")]
+    Decrypt,
+    #[error("invalid server setup: {0}")]
+    Invalid(String),
+    #[error(
+        "no server setup at {0}, but there are accounts that need the one they registered with. \
+        Restore it, or start with --force-new-server-setup to make every account unusable"
+    )]
+    Lost(PathBuf),
+    #[error("failed to count the accounts: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+/// A setup as loaded from its storage.
+pub struct Loaded {
+    pub setup: ServerSetup<DefaultCipherSuite>,
+    pub encrypted: bool,
+}
+
+/// The setup for the server to run with.
+///
+/// A missing setup is only created when there are no accounts yet, or
+/// with `force`.
+pub async fn load_or_create(
+    config: &ServerSetupConfig,
+    store: &dyn Store,
+    force: bool,
+) -> Result<ServerSetup<DefaultCipherSuite>, SetupError> {
+    if let Some(loaded) = load(config)? {
+        if !loaded.encrypted && config.key.is_some() {
+            tracing::warn!(
+                "The server setup is not encrypted although server_setup.key is set, \
+                encrypt it with `sanctum setup rekey`"
+            );
+        }
+        return Ok(loaded.setup);
+    }
+
+    if store.has_users().await? {
+        if !force {
+            return Err(SetupError::Lost(config.path.clone()));
+        }
+        tracing::warn!("Replacing the lost server setup, existing accounts can't log in anymore");
+    }
+
+    tracing::info!("Creating a new server setup in {}", config.path.display());
+    let setup = ServerSetup::new(&mut OsRng);
+    write(&config.path, &encode(&setup, key(config)?.as_ref()), false)?;
+    Ok(setup)
+}
+
+/// Load the setup from `server_setup.data`, or from `server_setup.path`
+/// if that exists.
+pub fn load(config: &ServerSetupConfig) -> Result<Option<Loaded>, SetupError> {
+    let content = match &config.data {
+        Some(data) => data.clone(),
+        None => match std::fs::read_to_string(&config.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(source) => {
+                return Err(SetupError::Read {
+                    path: config.path.clone(),
+                    source,
+                });
+            }
+        },
+    };
+    decode(&content, key(config)?.as_ref()).map(Some)
+}
+
+/// The key-encryption key in `server_setup.key`, if set.
+pub fn key(config: &ServerSetupConfig) -> Result<Option<[u8; 32]>, SetupError> {
+    config
+        .key
+        .as_ref()
+        .map(|key| {
+            BASE64_STANDARD
+                .decode(key.trim())
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .ok_or(SetupError::InvalidKey)
+        })
+        .transpose()
+}
+
+/// The text a setup is stored as, encrypted if there is a `key`.
+pub fn encode(setup: &ServerSetup<DefaultCipherSuite>, key: Option<&[u8; 32]>) -> String {
+    let serialized = setup.serialize();
+    let Some(key) = key else {
+        return BASE64_STANDARD.encode(serialized);
+    };
+
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &serialized,
+                aad: AAD,
+            },
+        )
+        .expect("encryption can't fail for short messages");
+
+    let mut encrypted = nonce.to_vec();
+    encrypted.extend_from_slice(&ciphertext);
+    format!("{}{}", ENCRYPTED_PREFIX, BASE64_STANDARD.encode(encrypted))
+}
+
+/// Parse a setup stored by [`encode`].
+pub fn decode(content: &str, key: Option<&[u8; 32]>) -> Result<Loaded, SetupError> {
+    let content = content.trim();
+    let invalid = |e: &dyn std::fmt::Display| SetupError::Invalid(e.to_string());
+
+    let (serialized, encrypted) = match content.strip_prefix(ENCRYPTED_PREFIX) {
+        Some(encrypted) => {
+            let key = key.ok_or(SetupError::KeyMissing)?;
+            let encrypted = BASE64_STANDARD.decode(encrypted).map_err(|e| invalid(&e))?;
+            if encrypted.len() < 12 {
+                return Err(SetupError::Invalid("too short".to_string()));
+            }
+            let (nonce, ciphertext) = encrypted.split_at(12);
+            let serialized = ChaCha20Poly1305::new(key.into())
+                .decrypt(
+                    nonce.into(),
+                    Payload {
+                        msg: ciphertext,
+                        aad: AAD,
+                    },
+                )
+                .map_err(|_| SetupError::Decrypt)?;
+            (serialized, true)
+        }
+        None => (
+            BASE64_STANDARD.decode(content).map_err(|e| invalid(&e))?,
+            false,
+        ),
+    };
+
+    let setup = ServerSetup::deserialize(&serialized).map_err(|e| invalid(&e))?;
+    Ok(Loaded { setup, encrypted })
+}
+
+/// Write a stored setup to `path`, readable by the owner only. An existing
+/// file is only replaced with `overwrite`, and never left half written.
+pub fn write(path: &Path, content: &str, overwrite: bool) -> Result<(), SetupError> {
+    let error = |path: &Path, source: std::io::Error| match source.kind() {
+        std::io::ErrorKind::AlreadyExists => SetupError::Exists(path.to_owned()),
+        _ => SetupError::Write {
+            path: path.to_owned(),
+            source,
+        },
+    };
+    let create = |path: &Path| {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()
+    };
+
+    if !overwrite {
+        return create(path).map_err(|e| error(path, e));
+    }
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".new");
+    let temporary = PathBuf::from(temporary);
+    create(&temporary)
+        .and_then(|()| std::fs::rename(&temporary, path))
+        .map_err(|e| {
+            let _ = std::fs::remove_file(&temporary);
+            error(path, e)
+        })
+}
+
+/// Identifies a setup without giving it away, so replicas can be checked
+/// to share one.
+pub fn fingerprint(setup: &ServerSetup<DefaultCipherSuite>) -> String {
+    Sha256::digest(setup.serialize())[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The public key clients see in every login, in base64.
+pub fn public_key(setup: &ServerSetup<DefaultCipherSuite>) -> String {
+    BASE64_STANDARD.encode(setup.keypair().public().serialize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding_round_trips() {
+        let setup = ServerSetup::<DefaultCipherSuite>::new(&mut OsRng);
+        let key = [7u8; 32];
+
+        let plain = decode(&encode(&setup, None), None).unwrap();
+        assert!(!plain.encrypted);
+        assert_eq!(fingerprint(&plain.setup), fingerprint(&setup));
+
+        let stored = encode(&setup, Some(&key));
+        assert!(stored.starts_with(ENCRYPTED_PREFIX));
+        let encrypted = decode(&stored, Some(&key)).unwrap();
+        assert!(encrypted.encrypted);
+        assert_eq!(fingerprint(&encrypted.setup), fingerprint(&setup));
+
+        assert!(matches!(decode(&stored, None), Err(SetupError::KeyMissing)));
+        assert!(matches!(
+            decode(&stored, Some(&[8u8; 32])),
+            Err(SetupError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn existing_setup_is_not_overwritten() {
+        let path = std::env::temp_dir().join(format!("sanctum-setup-{}", uuid::Uuid::new_v4()));
+
+        write(&path, "first", false).unwrap();
+        assert!(matches!(
+            write(&path, "second", false),
+            Err(SetupError::Exists(_))
+        ));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first");
+
+        write(&path, "second", true).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,282 @@
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{delete, get},
+};
+use sanctum_shared::models::{RefreshRequest, RefreshResponse, SessionInfo};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::{
+    AppStateRef,
+    cache::{self, Cache},
+    error::ApiError,
+    middleware::{Claims, Session},
+    store::NewSession,
+    util::{generate_token, hash_token},
+};
+
+pub fn routes() -> Router<AppStateRef> {
+    Router::new()
+        .route(
+            "/sessions",
+            get(list_sessions).delete(revoke_other_sessions),
+        )
+        .route("/sessions/{session_id}", delete(revoke_session))
+}
+
+/// Longest device name or platform stored for a session, anything
+/// beyond is cut off.
+const MAX_DEVICE_INFO_LENGTH: usize = 100;
+
+/// The cache key marking an access token as revoked.
+pub fn revoked_token_key(jti: &str) -> String {
+    format!("revoked_token_{}", jti)
+}
+
+/// The cache key marking all access tokens of a session as revoked.
+pub fn revoked_session_key(session_id: &str) -> String {
+    format!("revoked_session_{}", session_id)
+}
+
+/// Start a new session for the user and issue its first pair of tokens.
+///
+/// `device_name` and `platform` are whatever the client claims to be. The
+/// user is mailed about logins from devices that never had a session.
+/// Disabled accounts get none.
+pub async fn create(
+    state: &AppStateRef,
+    user_id: Uuid,
+    device_name: Option<&str>,
+    platform: Option<&str>,
+) -> Result<RefreshResponse, ApiError> {
+    let refresh_token = generate_token();
+    let truncate = |value: &str| {
+        value
+            .chars()
+            .take(MAX_DEVICE_INFO_LENGTH)
+            .collect::<String>()
+    };
+
+    let device_name = device_name.map(truncate);
+    let platform = platform.map(truncate);
+
+    let user = state
+        .store
+        .user(user_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+    if user.disabled_at.is_some() {
+        return Err(ApiError::AccountDisabled);
+    }
+    let email = user.email;
+    let history = state
+        .store
+        .device_history(user_id, device_name.as_deref(), platform.as_deref())
+        .await?;
+
+    let session_id = state
+        .store
+        .create_session(&NewSession {
+            user_id,
+            refresh_token_hash: hash_token(&refresh_token),
+            expires_at: OffsetDateTime::now_utc() + state.config.tokens.refresh_token_ttl(),
+            device_name: device_name.clone(),
+            platform: platform.clone(),
+        })
+        .await?;
+
+    // the first login is from the device the account was created on
+    if history.has_sessions && !history.known_device {
+        let state = state.clone();
+        tokio::spawn(async move {
+            state
+                .mailer
+                .send_new_device(&email, device_name.as_deref(), platform.as_deref())
+                .await;
+        });
+    }
+
+    Ok(RefreshResponse {
+        access_token: issue_access_token(state, user_id, session_id)?,
+        refresh_token,
+        expires_in: state.config.tokens.access_token_ttl().whole_seconds(),
+    })
+}
+
+/// POST /auth/refresh
+///
+/// Exchange a refresh token for a new access token. The refresh token is
+/// rotated, the one sent with the request can't be used again.
+///
+/// - returns 401 Unauthorized when the token is unknown, expired or revoked
+/// - a token that was already rotated revokes the whole session, as
+///   someone else must have used it first
+pub async fn refresh(
+    State(state): State<AppStateRef>,
+    Json(payload): Json<RefreshRequest>,
+) -> Result<Json<RefreshResponse>, ApiError> {
+    let hash = hash_token(&payload.refresh_token);
+    let refresh_token = generate_token();
+    let session = state
+        .store
+        .rotate_refresh_token(
+            &hash,
+            &hash_token(&refresh_token),
+            OffsetDateTime::now_utc() + state.config.tokens.refresh_token_ttl(),
+        )
+        .await?;
+
+    let Some(session) = session else {
+        // a rotated token must never come back
+        let reused = state.store.revoke_rotated(&hash).await?;
+
+        if let Some(session_id) = reused {
+            tracing::warn!("Refresh token of session {} was reused", session_id);
+            deny_session(&state, session_id).await?;
+        }
+
+        return Err(ApiError::Unauthorized);
+    };
+
+    Ok(Json(RefreshResponse {
+        access_token: issue_access_token(&state, session.user_id, session.id)?,
+        refresh_token,
+        expires_in: state.config.tokens.access_token_ttl().whole_seconds(),
+    }))
+}
+
+/// POST /auth/logout
+///
+/// Revoke the session of the access token and the token itself.
+pub async fn logout(
+    State(state): State<AppStateRef>,
+    claims: Claims,
+) -> Result<StatusCode, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| ApiError::Unauthorized)?;
+    let session_id = Uuid::parse_str(&claims.sid).map_err(|_| ApiError::Unauthorized)?;
+
+    state.store.revoke_session(user_id, session_id).await?;
+
+    state
+        .cache
+        .set_ex(
+            &revoked_token_key(&claims.jti),
+            "1",
+            state.config.tokens.access_token_ttl,
+        )
+        .await?;
+    deny_session(&state, session_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /sessions
+///
+/// List the active sessions of the current user, most recently used first.
+async fn list_sessions(
+    State(state): State<AppStateRef>,
+    claims: Claims,
+) -> Result<Json<Vec<SessionInfo>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| ApiError::Unauthorized)?;
+
+    let sessions = state
+        .store
+        .active_sessions(user_id)
+        .await?
+        .into_iter()
+        .map(|row| SessionInfo {
+            current: row.id.to_string() == claims.sid,
+            id: row.id,
+            device_name: row.device_name,
+            platform: row.platform,
+            created_at: row.created_at.to_utc(),
+            last_seen_at: row.last_used_at.to_utc(),
+        })
+        .collect();
+
+    Ok(Json(sessions))
+}
+
+/// DELETE /sessions/{session_id}
+///
+/// Revoke one of the current user's sessions, e.g. of a lost device.
+///
+/// - returns 204 No Content when revoked
+/// - returns 404 Not Found when there is no such active session
+async fn revoke_session(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+    Path(session_id): Path<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    if !state.store.revoke_session(user_id, session_id).await? {
+        return Err(ApiError::SessionNotFound);
+    }
+
+    deny_session(&state, session_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// DELETE /sessions
+///
+/// Revoke every session of the current user except the one making the request.
+async fn revoke_other_sessions(
+    State(state): State<AppStateRef>,
+    claims: Claims,
+) -> Result<StatusCode, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| ApiError::Unauthorized)?;
+    let session_id = Uuid::parse_str(&claims.sid).map_err(|_| ApiError::Unauthorized)?;
+
+    let revoked = state
+        .store
+        .revoke_other_sessions(user_id, session_id)
+        .await?;
+
+    for session_id in revoked {
+        deny_session(&state, session_id).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Reject the access tokens that are still out there for a revoked session.
+pub async fn deny_session(state: &AppStateRef, session_id: Uuid) -> Result<(), ApiError> {
+    deny(
+        &*state.cache,
+        session_id,
+        state.config.tokens.access_token_ttl,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to revoke access tokens: {:?}", e);
+        ApiError::Internal
+    })
+}
+
+/// Mark the access tokens of the session as revoked for `ttl` seconds, the
+/// longest they are valid.
+pub async fn deny(cache: &dyn Cache, session_id: Uuid, ttl: u64) -> cache::Result<()> {
+    cache
+        .set_ex(&revoked_session_key(&session_id.to_string()), "1", ttl)
+        .await
+}
+
+fn issue_access_token(
+    state: &AppStateRef,
+    user_id: Uuid,
+    session_id: Uuid,
+) -> Result<String, ApiError> {
+    let now = OffsetDateTime::now_utc();
+    let claims = Claims {
+        sub: user_id.to_string(),
+        exp: (now + state.config.tokens.access_token_ttl()).unix_timestamp() as u64,
+        iat: now.unix_timestamp() as u64,
+        iss: state.config.issuer().into(),
+        nbf: now.unix_timestamp() as u64,
+        aud: state.config.issuer().into(),
+        jti: Uuid::new_v4().to_string(),
+        sid: session_id.to_string(),
+    };
+
+    state.signing_keys.sign(&claims)
+}
@@ -0,0 +1,527 @@
+//! Where the server keeps accounts, vaults, records and sessions.
+//!
+//! Handlers only talk to a [`Store`], so the server can run on Postgres,
+//! or as a single binary on an embedded SQLite file. Which one is picked
+//! by the scheme of `database_url`, see [`connect`].
+//!
+//! Ids are generated by the server rather than the database, and every
+//! write that has to check something first is a single call, so each
+//! backend can make it atomic in its own way.
+
+mod postgres;
+mod sqlite;
+
+use std::{collections::HashMap, sync::Arc};
+
+use sanctum_shared::models::{
+    KdfParams, Record, RewrappedVault, StorageUsage, Tombstone, Vault, WebauthnCredential,
+};
+use sqlx::migrate::{Migrate, MigrateError, Migrator};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+pub use postgres::PgStore;
+pub use sqlite::SqliteStore;
+
+pub type Result<T> = std::result::Result<T, sqlx::Error>;
+
+/// Open the store `url` points at, `postgres://` or `sqlite://`.
+pub async fn connect(url: &str) -> Result<Arc<dyn Store>> {
+    if url.starts_with("sqlite:") {
+        Ok(Arc::new(SqliteStore::connect(url).await?))
+    } else {
+        Ok(Arc::new(PgStore::connect(url).await?))
+    }
+}
+
+#[async_trait::async_trait]
+pub trait Store: Send + Sync {
+    // ------------------------------------------
+    //                  Users
+    // ------------------------------------------
+
+    /// Whether any account exists.
+    async fn has_users(&self) -> Result<bool>;
+
+    async fn user(&self, id: Uuid) -> Result<Option<User>>;
+
+    async fn user_by_email(&self, email: &str) -> Result<Option<User>>;
+
+    /// Create an account, `false` when the email or credential id is taken.
+    async fn create_user(&self, user: &NewUser) -> Result<bool>;
+
+    async fn update_settings(&self, user_id: Uuid, settings: &serde_json::Value) -> Result<()>;
+
+    /// Replace the password file and the vault keys, and revoke every
+    /// session but `session_id`.
+    async fn change_password(
+        &self,
+        user_id: Uuid,
+        session_id: Uuid,
+        change: &PasswordChange,
+    ) -> Result<PasswordChangeOutcome>;
+
+    /// Store the password file registered under `credential_id`, `false`
+    /// when the account has a credential id already.
+    async fn reregister(
+        &self,
+        user_id: Uuid,
+        password_file: &str,
+        credential_id: Uuid,
+    ) -> Result<bool>;
+
+    async fn change_email(&self, user_id: Uuid, email: &str) -> Result<EmailChangeOutcome>;
+
+    /// Delete the account with everything it owns, returning the sessions
+    /// that were still active.
+    async fn delete_user(&self, user_id: Uuid) -> Result<Vec<Uuid>>;
+
+    async fn storage_usage(&self, user_id: Uuid) -> Result<StorageUsage>;
+
+    /// Every account with how much it stores, oldest first.
+    async fn user_summaries(&self) -> Result<Vec<UserSummary>>;
+
+    /// Disable or enable the account. Disabling revokes its sessions,
+    /// which are returned.
+    async fn set_disabled(&self, user_id: Uuid, disabled: bool) -> Result<Vec<Uuid>>;
+
+    // ------------------------------------------
+    //                  TOTP
+    // ------------------------------------------
+
+    /// Store a new TOTP secret and return the email, `None` when TOTP is
+    /// enabled already.
+    async fn set_totp_secret(&self, user_id: Uuid, secret: &str) -> Result<Option<String>>;
+
+    /// Enable the pending `secret`, with `step` as the last used time step,
+    /// and replace the recovery codes. `false` when the secret changed.
+    async fn enable_totp(
+        &self,
+        user_id: Uuid,
+        secret: &str,
+        step: i64,
+        recovery_code_hashes: &[String],
+    ) -> Result<bool>;
+
+    /// Remove the TOTP secret and the recovery codes.
+    async fn disable_totp(&self, user_id: Uuid) -> Result<()>;
+
+    /// Record `step` as the last used time step, `false` when it or a
+    /// later one was used already.
+    async fn use_totp_step(&self, user_id: Uuid, step: i64) -> Result<bool>;
+
+    /// Mark the unused recovery code with `code_hash` as used.
+    async fn use_recovery_code(&self, user_id: Uuid, code_hash: &str) -> Result<bool>;
+
+    // ------------------------------------------
+    //                 WebAuthn
+    // ------------------------------------------
+
+    /// The ids the authenticators know the user's credentials by.
+    async fn webauthn_credential_ids(&self, user_id: Uuid) -> Result<Vec<Vec<u8>>>;
+
+    async fn webauthn_credentials(&self, user_id: Uuid) -> Result<Vec<WebauthnCredential>>;
+
+    async fn webauthn_credential(
+        &self,
+        user_id: Uuid,
+        credential_id: &[u8],
+    ) -> Result<Option<StoredCredential>>;
+
+    async fn add_webauthn_credential(
+        &self,
+        credential: &NewCredential,
+    ) -> Result<WebauthnCredential>;
+
+    async fn delete_webauthn_credential(&self, user_id: Uuid, id: Uuid) -> Result<bool>;
+
+    /// Store the signature counter of an assertion, `false` when it is not
+    /// beyond the stored one.
+    async fn update_sign_count(&self, id: Uuid, sign_count: i64) -> Result<bool>;
+
+    // ------------------------------------------
+    //                 Sessions
+    // ------------------------------------------
+
+    async fn create_session(&self, session: &NewSession) -> Result<Uuid>;
+
+    async fn session(&self, id: Uuid) -> Result<Option<Session>>;
+
+    /// The sessions that are neither revoked nor expired, most recently
+    /// used first.
+    async fn active_sessions(&self, user_id: Uuid) -> Result<Vec<Session>>;
+
+    /// Whether the user had sessions before, and one on this device.
+    async fn device_history(
+        &self,
+        user_id: Uuid,
+        device_name: Option<&str>,
+        platform: Option<&str>,
+    ) -> Result<DeviceHistory>;
+
+    /// Replace the refresh token with `hash` of an active session, unless
+    /// the account is disabled.
+    async fn rotate_refresh_token(
+        &self,
+        hash: &str,
+        new_hash: &str,
+        expires_at: OffsetDateTime,
+    ) -> Result<Option<Session>>;
+
+    /// Revoke the session whose refresh token with `hash` was rotated
+    /// already, if it is still active.
+    async fn revoke_rotated(&self, hash: &str) -> Result<Option<Uuid>>;
+
+    /// `false` when the user has no such active session.
+    async fn revoke_session(&self, user_id: Uuid, id: Uuid) -> Result<bool>;
+
+    /// Revoke every session of the user but `keep`, returning them.
+    async fn revoke_other_sessions(&self, user_id: Uuid, keep: Uuid) -> Result<Vec<Uuid>>;
+
+    /// Revoke every session of the user, returning them.
+    async fn revoke_sessions(&self, user_id: Uuid) -> Result<Vec<Uuid>>;
+
+    // ------------------------------------------
+    //                  Vaults
+    // ------------------------------------------
+
+    /// The user's vaults, or only those updated at or after `since`, see
+    /// [`Store::changes`].
+    async fn vaults(&self, user_id: Uuid, since: Option<OffsetDateTime>) -> Result<Vec<Vault>>;
+
+    async fn vault(&self, user_id: Uuid, id: Uuid) -> Result<Option<Vault>>;
+
+    async fn create_vault(&self, user_id: Uuid, id: Uuid, vault: &VaultData) -> Result<Vault>;
+
+    /// Create the vault with `id`, or update it if it was last seen at
+    /// `revision`.
+    async fn put_vault(
+        &self,
+        user_id: Uuid,
+        id: Uuid,
+        vault: &VaultData,
+        revision: Option<i64>,
+    ) -> Result<Put<Vault>>;
+
+    /// Delete the vault and leave a tombstone, `false` when there was none.
+    async fn delete_vault(&self, user_id: Uuid, id: Uuid) -> Result<bool>;
+
+    // ------------------------------------------
+    //                  Records
+    // ------------------------------------------
+
+    /// The records of a vault, or only those updated at or after `since`,
+    /// see [`Store::changes`].
+    async fn records(&self, vault_id: Uuid, since: Option<OffsetDateTime>) -> Result<Vec<Record>>;
+
+    async fn record(&self, vault_id: Uuid, id: Uuid) -> Result<Option<Record>>;
+
+    async fn create_record(&self, vault_id: Uuid, id: Uuid, record: &RecordData) -> Result<Record>;
+
+    /// Create the record with `id`, or update it if it was last seen at
+    /// `revision`.
+    async fn put_record(
+        &self,
+        vault_id: Uuid,
+        id: Uuid,
+        record: &RecordData,
+        revision: Option<i64>,
+    ) -> Result<Put<Record>>;
+
+    /// Delete the record and leave a tombstone, `false` when there was none.
+    async fn delete_record(&self, vault: &Vault, id: Uuid) -> Result<bool>;
+
+    /// Everything of the user created, updated or deleted since `since`,
+    /// read at one point in time, and the cursor to pass as `since` next.
+    ///
+    /// Like every `since` filter this includes changes made at exactly
+    /// `since`: cursors are in whole seconds, so a change in the same second
+    /// as the cursor is sent twice rather than never.
+    ///
+    /// The cursor comes from the database clock, and is never later than a
+    /// change that wasn't committed yet when reading.
+    async fn changes(&self, user_id: Uuid, since: OffsetDateTime) -> Result<Changes>;
+
+    /// Delete the tombstones of deletions before `before`, returning how
+    /// many there were.
+    async fn delete_tombstones(&self, before: OffsetDateTime) -> Result<u64>;
+
+    // ------------------------------------------
+    //               Signing keys
+    // ------------------------------------------
+
+    /// All keys, the latest to become active first.
+    async fn signing_keys(&self) -> Result<Vec<SigningKey>>;
+
+    async fn add_signing_key(&self, key: &SigningKey) -> Result<()>;
+
+    /// Delete the keys replaced by one that became active before `before`,
+    /// returning their kids.
+    async fn delete_replaced_signing_keys(&self, before: OffsetDateTime) -> Result<Vec<String>>;
+
+    // ------------------------------------------
+    //                  Schema
+    // ------------------------------------------
+
+    /// Compare the migrations applied to the database with those embedded
+    /// in the binary.
+    async fn schema_state(&self) -> Result<SchemaState>;
+
+    /// Apply the migrations embedded in the binary that are missing. The
+    /// database is locked meanwhile, so several servers can start at once.
+    async fn migrate(&self) -> Result<()>;
+}
+
+/// An account, with everything the handlers need to know about it.
+#[derive(Debug)]
+pub struct User {
+    pub id: Uuid,
+    pub email: String,
+    /// The OPAQUE password file, in base64.
+    pub password_file: String,
+    pub salt: String,
+    /// `None` for accounts still registered under their email.
+    pub credential_id: Option<Uuid>,
+    pub kdf: KdfParams,
+    pub settings: serde_json::Value,
+    /// Encrypted with the MFA key, see `mfa.rs`.
+    pub totp_secret: Option<String>,
+    pub totp_enabled_at: Option<OffsetDateTime>,
+    pub totp_last_step: Option<i64>,
+    /// Disabled accounts can't log in.
+    pub disabled_at: Option<OffsetDateTime>,
+    pub created_at: OffsetDateTime,
+}
+
+/// An account as the admin commands list it.
+pub struct UserSummary {
+    pub id: Uuid,
+    pub email: String,
+    pub vaults: i64,
+    pub records: i64,
+    pub disabled_at: Option<OffsetDateTime>,
+    pub created_at: OffsetDateTime,
+}
+
+pub struct NewUser {
+    pub email: String,
+    pub salt: String,
+    pub password_file: String,
+    pub credential_id: Uuid,
+    pub kdf: KdfParams,
+}
+
+pub struct PasswordChange {
+    pub password_file: String,
+    pub salt: String,
+    pub credential_id: Uuid,
+    pub kdf: KdfParams,
+    pub vaults: Vec<RewrappedVault>,
+}
+
+pub enum PasswordChangeOutcome {
+    Changed {
+        vaults: Vec<Vault>,
+        revoked_sessions: Vec<Uuid>,
+    },
+    /// The rewrapped vaults are not exactly the user's vaults.
+    VaultMismatch,
+    /// A vault was changed after it was rewrapped.
+    StaleVault,
+    /// The account has another credential id, the password was changed
+    /// in the meantime.
+    CredentialMismatch,
+}
+
+pub enum EmailChangeOutcome {
+    Changed {
+        previous: String,
+    },
+    UnknownUser,
+    /// The account still has to register its password under a
+    /// credential id, the password file is bound to the email.
+    NoCredentialId,
+    Taken,
+}
+
+/// What [`Store::webauthn_credential`] needs to verify an assertion.
+pub struct StoredCredential {
+    pub id: Uuid,
+    /// The COSE key.
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+}
+
+pub struct NewCredential {
+    pub user_id: Uuid,
+    pub credential_id: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub algorithm: i32,
+    pub sign_count: i64,
+    pub name: String,
+}
+
+pub struct NewSession {
+    pub user_id: Uuid,
+    pub refresh_token_hash: String,
+    pub expires_at: OffsetDateTime,
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+    pub created_at: OffsetDateTime,
+    pub last_used_at: OffsetDateTime,
+}
+
+pub struct DeviceHistory {
+    pub has_sessions: bool,
+    pub known_device: bool,
+}
+
+/// The encrypted content of a vault.
+pub struct VaultData {
+    pub encrypted_name: String,
+    pub encrypted_vault_key: String,
+}
+
+/// The encrypted content of a record.
+pub struct RecordData {
+    pub encrypted_record_key: String,
+    pub encrypted_data_blob: String,
+}
+
+/// The outcome of [`Store::put_vault`] and [`Store::put_record`].
+pub enum Put<T> {
+    Created(T),
+    /// Updated, or left alone when nothing changed.
+    Updated(T),
+    /// The update is based on an older revision than this current copy, or
+    /// the create lost the race against another create of the same id.
+    Stale(T),
+    /// An update without the revision it is based on.
+    RevisionRequired,
+    /// An update of a vault or record that was deleted in the meantime.
+    Gone,
+    /// The id is used by another user's vault, or a record in another vault.
+    Taken,
+}
+
+/// What a sync returns, see `sync.rs`.
+pub struct Changes {
+    /// See [`Store::changes`].
+    pub cursor: OffsetDateTime,
+    pub vaults: Vec<Vault>,
+    pub records: Vec<Record>,
+    pub tombstones: Vec<Tombstone>,
+}
+
+/// A key access tokens are signed with, see `jwt.rs`.
+pub struct SigningKey {
+    pub kid: String,
+    /// The encrypted seed of the private key.
+    pub private_key: String,
+    pub public_key: Vec<u8>,
+    pub created_at: OffsetDateTime,
+    pub active_at: OffsetDateTime,
+}
+
+/// How the schema of the database relates to the binary.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SchemaState {
+    /// The versions of the migrations that are not applied yet.
+    pub pending: Vec<i64>,
+    /// The versions of applied migrations the binary doesn't know, the
+    /// database was migrated by a newer version of the server.
+    pub unknown: Vec<i64>,
+}
+
+/// [`Store::schema_state`] for a database migrated with `migrator`. Fails
+/// when a migration failed halfway or was changed after it was applied.
+async fn schema_state<DB>(migrator: &Migrator, db: &sqlx::Pool<DB>) -> Result<SchemaState>
+where
+    DB: sqlx::Database,
+    DB::Connection: Migrate,
+{
+    let mut conn = db.acquire().await?;
+    // servers starting at the same time would create the table twice
+    conn.lock().await?;
+    let state = read_schema_state(migrator, &mut *conn).await;
+    conn.unlock().await?;
+    state
+}
+
+async fn read_schema_state(migrator: &Migrator, conn: &mut impl Migrate) -> Result<SchemaState> {
+    conn.ensure_migrations_table().await?;
+    if let Some(version) = conn.dirty_version().await? {
+        return Err(MigrateError::Dirty(version).into());
+    }
+
+    let applied = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|m| (m.version, m.checksum))
+        .collect::<HashMap<_, _>>();
+    let mut state = SchemaState::default();
+    for migration in migrator.iter() {
+        if migration.migration_type.is_down_migration() {
+            continue;
+        }
+        match applied.get(&migration.version) {
+            Some(checksum) if *checksum != migration.checksum => {
+                return Err(MigrateError::VersionMismatch(migration.version).into());
+            }
+            Some(_) => {}
+            None => state.pending.push(migration.version),
+        }
+    }
+    state.unknown = applied
+        .into_keys()
+        .filter(|version| !migrator.version_exists(*version))
+        .collect();
+    state.unknown.sort();
+    Ok(state)
+}
+
+/// Whether an update of a row at `current` revision has to stop short, and
+/// with what. `unchanged` updates always succeed, they are retries.
+fn check_update<T>(
+    existing: T,
+    current: i64,
+    unchanged: bool,
+    revision: Option<i64>,
+) -> std::result::Result<T, Put<T>> {
+    if unchanged {
+        return Err(Put::Updated(existing));
+    }
+    match revision {
+        None => Err(Put::RevisionRequired),
+        Some(revision) if revision != current => Err(Put::Stale(existing)),
+        Some(_) => Ok(existing),
+    }
+}
+
+fn tombstone(
+    entity_type: &str,
+    entity_id: Uuid,
+    vault_id: Uuid,
+    deleted_at: OffsetDateTime,
+) -> Tombstone {
+    match entity_type {
+        "vault" => Tombstone::Vault {
+            id: entity_id,
+            deleted_at: deleted_at.to_utc(),
+        },
+        _ => Tombstone::Record {
+            id: entity_id,
+            vault_id,
+            deleted_at: deleted_at.to_utc(),
+        },
+    }
+}
@@ -0,0 +1,1176 @@
+use std::collections::{HashMap, HashSet};
+
+use sanctum_shared::models::{KdfParams, Record, StorageUsage, Vault, WebauthnCredential};
+use sqlx::{PgPool, migrate::Migrator};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use super::{
+    Changes, DeviceHistory, EmailChangeOutcome, NewCredential, NewSession, NewUser, PasswordChange,
+    PasswordChangeOutcome, Put, RecordData, Result, SchemaState, Session, SigningKey, Store,
+    StoredCredential, User, UserSummary, VaultData, check_update, tombstone,
+};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+pub struct PgStore {
+    db: PgPool,
+}
+
+impl PgStore {
+    pub async fn connect(url: &str) -> Result<Self> {
+        Ok(Self::new(PgPool::connect(url).await?))
+    }
+
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+/// Fetch the [`User`] matching `$condition`.
+macro_rules! fetch_user {
+    ($db:expr, $condition:literal, $value:expr) => {
+        sqlx::query!(
+            "SELECT
+                id, email, password_file, salt, credential_id,
+                kdf_memory_kib, kdf_iterations, kdf_parallelism, settings,
+                totp_secret, totp_enabled_at, totp_last_step, disabled_at, created_at
+            FROM users WHERE "
+                + $condition,
+            $value
+        )
+        .fetch_optional($db)
+        .await
+        .map(|row| {
+            row.map(|row| User {
+                id: row.id,
+                email: row.email,
+                password_file: row.password_file,
+                salt: row.salt,
+                credential_id: row.credential_id,
+                kdf: KdfParams {
+                    memory_kib: row.kdf_memory_kib as u32,
+                    iterations: row.kdf_iterations as u32,
+                    parallelism: row.kdf_parallelism as u32,
+                },
+                settings: row.settings,
+                totp_secret: row.totp_secret,
+                totp_enabled_at: row.totp_enabled_at,
+                totp_last_step: row.totp_last_step,
+                disabled_at: row.disabled_at,
+                created_at: row.created_at,
+            })
+        })
+    };
+}
+
+#[async_trait::async_trait]
+impl Store for PgStore {
+    async fn has_users(&self) -> Result<bool> {
+        sqlx::query_scalar!(r#"SELECT EXISTS (SELECT 1 FROM users) AS "exists!""#)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    async fn user(&self, id: Uuid) -> Result<Option<User>> {
+        fetch_user!(&self.db, "id = $1", id)
+    }
+
+    async fn user_by_email(&self, email: &str) -> Result<Option<User>> {
+        fetch_user!(&self.db, "email = $1", email)
+    }
+
+    async fn create_user(&self, user: &NewUser) -> Result<bool> {
+        let created = sqlx::query!(
+            "INSERT INTO users (
+                id, email, salt, password_file, credential_id,
+                kdf_memory_kib, kdf_iterations, kdf_parallelism
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT DO NOTHING",
+            Uuid::new_v4(),
+            user.email,
+            user.salt,
+            user.password_file,
+            user.credential_id,
+            user.kdf.memory_kib as i32,
+            user.kdf.iterations as i32,
+            user.kdf.parallelism as i32
+        )
+        .execute(&self.db)
+        .await?
+        .rows_affected();
+
+        Ok(created == 1)
+    }
+
+    async fn update_settings(&self, user_id: Uuid, settings: &serde_json::Value) -> Result<()> {
+        sqlx::query!(
+            "UPDATE users SET settings = $1, updated_at = now() WHERE id = $2",
+            settings,
+            user_id
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn change_password(
+        &self,
+        user_id: Uuid,
+        session_id: Uuid,
+        change: &PasswordChange,
+    ) -> Result<PasswordChangeOutcome> {
+        let mut tx = self.db.begin().await?;
+
+        // lock the vaults, so none can be created, changed or deleted in the
+        // meantime
+        let revisions = sqlx::query!(
+            "SELECT id, revision FROM vaults WHERE user_id = $1 FOR UPDATE",
+            user_id
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|row| (row.id, row.revision))
+        .collect::<HashMap<_, _>>();
+
+        let rewrapped = change.vaults.iter().map(|v| v.id).collect::<HashSet<_>>();
+        if rewrapped != revisions.keys().copied().collect()
+            || rewrapped.len() != change.vaults.len()
+        {
+            return Ok(PasswordChangeOutcome::VaultMismatch);
+        }
+        if change
+            .vaults
+            .iter()
+            .any(|vault| revisions[&vault.id] != vault.revision)
+        {
+            return Ok(PasswordChangeOutcome::StaleVault);
+        }
+
+        let updated = sqlx::query!(
+            "UPDATE users
+            SET
+                password_file = $1,
+                salt = $2,
+                credential_id = $3,
+                kdf_memory_kib = $5,
+                kdf_iterations = $6,
+                kdf_parallelism = $7,
+                updated_at = now()
+            WHERE id = $4
+                AND (credential_id IS NULL OR credential_id = $3)",
+            change.password_file,
+            change.salt,
+            change.credential_id,
+            user_id,
+            change.kdf.memory_kib as i32,
+            change.kdf.iterations as i32,
+            change.kdf.parallelism as i32
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        if updated == 0 {
+            return Ok(PasswordChangeOutcome::CredentialMismatch);
+        }
+
+        let mut vaults = Vec::with_capacity(change.vaults.len());
+        for vault in &change.vaults {
+            let updated = sqlx::query_as!(
+                Vault,
+                "UPDATE vaults
+                SET
+                    encrypted_vault_key = $1,
+                    encrypted_name = $2,
+                    revision = revision + 1,
+                    updated_at = now()
+                WHERE id = $3
+                RETURNING *",
+                vault.encrypted_vault_key,
+                vault.encrypted_name,
+                vault.id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            vaults.push(updated);
+        }
+
+        let revoked_sessions = sqlx::query_scalar!(
+            "UPDATE sessions SET revoked_at = now()
+            WHERE user_id = $1
+                AND id <> $2
+                AND revoked_at IS NULL
+            RETURNING id",
+            user_id,
+            session_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(PasswordChangeOutcome::Changed {
+            vaults,
+            revoked_sessions,
+        })
+    }
+
+    async fn reregister(
+        &self,
+        user_id: Uuid,
+        password_file: &str,
+        credential_id: Uuid,
+    ) -> Result<bool> {
+        let updated = sqlx::query!(
+            "UPDATE users
+            SET password_file = $1, credential_id = $2, updated_at = now()
+            WHERE id = $3 AND credential_id IS NULL",
+            password_file,
+            credential_id,
+            user_id
+        )
+        .execute(&self.db)
+        .await?
+        .rows_affected();
+
+        Ok(updated == 1)
+    }
+
+    async fn change_email(&self, user_id: Uuid, email: &str) -> Result<EmailChangeOutcome> {
+        let mut tx = self.db.begin().await?;
+
+        let Some(user) = sqlx::query!(
+            "SELECT email, credential_id FROM users WHERE id = $1 FOR UPDATE",
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(EmailChangeOutcome::UnknownUser);
+        };
+
+        if user.credential_id.is_none() {
+            return Ok(EmailChangeOutcome::NoCredentialId);
+        }
+
+        let updated = sqlx::query!(
+            "UPDATE users SET email = $1, updated_at = now() WHERE id = $2",
+            email,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await;
+        match updated {
+            Err(e)
+                if e.as_database_error()
+                    .is_some_and(|e| e.is_unique_violation()) =>
+            {
+                return Ok(EmailChangeOutcome::Taken);
+            }
+            updated => updated?,
+        };
+
+        tx.commit().await?;
+        Ok(EmailChangeOutcome::Changed {
+            previous: user.email,
+        })
+    }
+
+    async fn delete_user(&self, user_id: Uuid) -> Result<Vec<Uuid>> {
+        let mut tx = self.db.begin().await?;
+
+        let sessions = sqlx::query_scalar!(
+            "SELECT id FROM sessions WHERE user_id = $1 AND revoked_at IS NULL",
+            user_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        // vaults, records, tombstones and sessions cascade
+        sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(sessions)
+    }
+
+    async fn storage_usage(&self, user_id: Uuid) -> Result<StorageUsage> {
+        let usage = sqlx::query!(
+            r#"SELECT
+                (SELECT count(*) FROM vaults WHERE user_id = $1) AS "vaults!",
+                (SELECT count(*) FROM records r
+                    JOIN vaults v ON v.id = r.vault_id
+                    WHERE v.user_id = $1) AS "records!",
+                (SELECT coalesce(sum(
+                    octet_length(encrypted_vault_key) + octet_length(encrypted_name)
+                ), 0) FROM vaults WHERE user_id = $1)
+                + (SELECT coalesce(sum(
+                    octet_length(r.encrypted_record_key) + octet_length(r.encrypted_data_blob)
+                ), 0) FROM records r
+                    JOIN vaults v ON v.id = r.vault_id
+                    WHERE v.user_id = $1) AS "bytes!""#,
+            user_id
+        )
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(StorageUsage {
+            vaults: usage.vaults,
+            records: usage.records,
+            bytes: usage.bytes,
+        })
+    }
+
+    async fn user_summaries(&self) -> Result<Vec<UserSummary>> {
+        sqlx::query_as!(
+            UserSummary,
+            r#"SELECT
+                u.id, u.email,
+                (SELECT count(*) FROM vaults WHERE user_id = u.id) AS "vaults!",
+                (SELECT count(*) FROM records r
+                    JOIN vaults v ON v.id = r.vault_id
+                    WHERE v.user_id = u.id) AS "records!",
+                u.disabled_at, u.created_at
+            FROM users u
+            ORDER BY u.created_at"#
+        )
+        .fetch_all(&self.db)
+        .await
+    }
+
+    async fn set_disabled(&self, user_id: Uuid, disabled: bool) -> Result<Vec<Uuid>> {
+        let mut tx = self.db.begin().await?;
+
+        // an account that is disabled already keeps its date
+        sqlx::query!(
+            "UPDATE users
+            SET
+                disabled_at = CASE WHEN $2 THEN coalesce(disabled_at, now()) END,
+                updated_at = now()
+            WHERE id = $1",
+            user_id,
+            disabled
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let sessions = match disabled {
+            true => {
+                sqlx::query_scalar!(
+                    "UPDATE sessions SET revoked_at = now()
+                    WHERE user_id = $1
+                        AND revoked_at IS NULL
+                    RETURNING id",
+                    user_id
+                )
+                .fetch_all(&mut *tx)
+                .await?
+            }
+            false => Vec::new(),
+        };
+
+        tx.commit().await?;
+        Ok(sessions)
+    }
+
+    async fn set_totp_secret(&self, user_id: Uuid, secret: &str) -> Result<Option<String>> {
+        sqlx::query_scalar!(
+            "UPDATE users SET totp_secret = $2, updated_at = now()
+            WHERE id = $1 AND totp_enabled_at IS NULL
+            RETURNING email",
+            user_id,
+            secret
+        )
+        .fetch_optional(&self.db)
+        .await
+    }
+
+    async fn enable_totp(
+        &self,
+        user_id: Uuid,
+        secret: &str,
+        step: i64,
+        recovery_code_hashes: &[String],
+    ) -> Result<bool> {
+        let mut tx = self.db.begin().await?;
+
+        let enabled = sqlx::query!(
+            "UPDATE users SET totp_enabled_at = now(), totp_last_step = $3, updated_at = now()
+            WHERE id = $1 AND totp_secret = $2 AND totp_enabled_at IS NULL",
+            user_id,
+            secret,
+            step
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if enabled == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        let ids = recovery_code_hashes
+            .iter()
+            .map(|_| Uuid::new_v4())
+            .collect::<Vec<_>>();
+        sqlx::query!(
+            "INSERT INTO recovery_codes (id, user_id, code_hash)
+            SELECT id, $1, code_hash FROM UNNEST($2::uuid[], $3::text[]) AS c(id, code_hash)",
+            user_id,
+            &ids,
+            recovery_code_hashes
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn disable_totp(&self, user_id: Uuid) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+
+        sqlx::query!(
+            "UPDATE users
+            SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL, updated_at = now()
+            WHERE id = $1",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await
+    }
+
+    async fn use_totp_step(&self, user_id: Uuid, step: i64) -> Result<bool> {
+        let updated = sqlx::query!(
+            "UPDATE users SET totp_last_step = $2
+            WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)",
+            user_id,
+            step
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(updated.rows_affected() == 1)
+    }
+
+    async fn use_recovery_code(&self, user_id: Uuid, code_hash: &str) -> Result<bool> {
+        let updated = sqlx::query!(
+            "UPDATE recovery_codes SET used_at = now()
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL",
+            user_id,
+            code_hash
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(updated.rows_affected() == 1)
+    }
+
+    async fn webauthn_credential_ids(&self, user_id: Uuid) -> Result<Vec<Vec<u8>>> {
+        sqlx::query_scalar!(
+            "SELECT credential_id FROM webauthn_credentials WHERE user_id = $1",
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await
+    }
+
+    async fn webauthn_credentials(&self, user_id: Uuid) -> Result<Vec<WebauthnCredential>> {
+        let credentials = sqlx::query!(
+            "SELECT id, name, created_at, last_used_at FROM webauthn_credentials
+            WHERE user_id = $1
+            ORDER BY created_at",
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|row| WebauthnCredential {
+            id: row.id,
+            name: row.name,
+            created_at: row.created_at.to_utc(),
+            last_used_at: row.last_used_at.map(|t| t.to_utc()),
+        })
+        .collect();
+
+        Ok(credentials)
+    }
+
+    async fn webauthn_credential(
+        &self,
+        user_id: Uuid,
+        credential_id: &[u8],
+    ) -> Result<Option<StoredCredential>> {
+        sqlx::query_as!(
+            StoredCredential,
+            "SELECT id, public_key, sign_count FROM webauthn_credentials
+            WHERE user_id = $1 AND credential_id = $2",
+            user_id,
+            credential_id
+        )
+        .fetch_optional(&self.db)
+        .await
+    }
+
+    async fn add_webauthn_credential(
+        &self,
+        credential: &NewCredential,
+    ) -> Result<WebauthnCredential> {
+        let row = sqlx::query!(
+            "INSERT INTO webauthn_credentials
+                (id, user_id, credential_id, public_key, algorithm, sign_count, name)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id, created_at",
+            Uuid::new_v4(),
+            credential.user_id,
+            credential.credential_id,
+            credential.public_key,
+            credential.algorithm,
+            credential.sign_count,
+            credential.name
+        )
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(WebauthnCredential {
+            id: row.id,
+            name: credential.name.clone(),
+            created_at: row.created_at.to_utc(),
+            last_used_at: None,
+        })
+    }
+
+    async fn delete_webauthn_credential(&self, user_id: Uuid, id: Uuid) -> Result<bool> {
+        let deleted = sqlx::query!(
+            "DELETE FROM webauthn_credentials WHERE id = $1 AND user_id = $2",
+            id,
+            user_id
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(deleted.rows_affected() == 1)
+    }
+
+    async fn update_sign_count(&self, id: Uuid, sign_count: i64) -> Result<bool> {
+        let updated = sqlx::query!(
+            "UPDATE webauthn_credentials SET sign_count = $2, last_used_at = now()
+            WHERE id = $1 AND (sign_count < $2 OR $2 = 0)",
+            id,
+            sign_count
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(updated.rows_affected() == 1)
+    }
+
+    async fn create_session(&self, session: &NewSession) -> Result<Uuid> {
+        sqlx::query_scalar!(
+            "INSERT INTO sessions
+                (id, user_id, refresh_token_hash, expires_at, device_name, platform)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id",
+            Uuid::new_v4(),
+            session.user_id,
+            session.refresh_token_hash,
+            session.expires_at,
+            session.device_name,
+            session.platform
+        )
+        .fetch_one(&self.db)
+        .await
+    }
+
+    async fn session(&self, id: Uuid) -> Result<Option<Session>> {
+        sqlx::query_as!(
+            Session,
+            "SELECT id, user_id, device_name, platform, created_at, last_used_at
+            FROM sessions WHERE id = $1",
+            id
+        )
+        .fetch_optional(&self.db)
+        .await
+    }
+
+    async fn active_sessions(&self, user_id: Uuid) -> Result<Vec<Session>> {
+        sqlx::query_as!(
+            Session,
+            "SELECT id, user_id, device_name, platform, created_at, last_used_at
+            FROM sessions
+            WHERE user_id = $1
+                AND revoked_at IS NULL
+                AND expires_at > now()
+            ORDER BY last_used_at DESC",
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await
+    }
+
+    async fn device_history(
+        &self,
+        user_id: Uuid,
+        device_name: Option<&str>,
+        platform: Option<&str>,
+    ) -> Result<DeviceHistory> {
+        // a device counts as known once it had a session, even a revoked one
+        let history = sqlx::query!(
+            r#"SELECT
+                EXISTS(SELECT 1 FROM sessions WHERE user_id = $1) AS "has_sessions!",
+                EXISTS(
+                    SELECT 1 FROM sessions
+                    WHERE user_id = $1
+                        AND device_name IS NOT DISTINCT FROM $2
+                        AND platform IS NOT DISTINCT FROM $3
+                ) AS "known_device!""#,
+            user_id,
+            device_name,
+            platform
+        )
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(DeviceHistory {
+            has_sessions: history.has_sessions,
+            known_device: history.known_device,
+        })
+    }
+
+    async fn rotate_refresh_token(
+        &self,
+        hash: &str,
+        new_hash: &str,
+        expires_at: OffsetDateTime,
+    ) -> Result<Option<Session>> {
+        sqlx::query_as!(
+            Session,
+            "UPDATE sessions
+            SET
+                previous_token_hash = refresh_token_hash,
+                refresh_token_hash = $2,
+                last_used_at = now(),
+                expires_at = $3
+            WHERE refresh_token_hash = $1
+                AND revoked_at IS NULL
+                AND expires_at > now()
+                AND user_id IN (SELECT id FROM users WHERE disabled_at IS NULL)
+            RETURNING id, user_id, device_name, platform, created_at, last_used_at",
+            hash,
+            new_hash,
+            expires_at
+        )
+        .fetch_optional(&self.db)
+        .await
+    }
+
+    async fn revoke_rotated(&self, hash: &str) -> Result<Option<Uuid>> {
+        sqlx::query_scalar!(
+            "UPDATE sessions
+            SET revoked_at = now()
+            WHERE previous_token_hash = $1
+                AND revoked_at IS NULL
+            RETURNING id",
+            hash
+        )
+        .fetch_optional(&self.db)
+        .await
+    }
+
+    async fn revoke_session(&self, user_id: Uuid, id: Uuid) -> Result<bool> {
+        let revoked = sqlx::query!(
+            "UPDATE sessions SET revoked_at = now()
+            WHERE id = $1
+                AND user_id = $2
+                AND revoked_at IS NULL",
+            id,
+            user_id
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(revoked.rows_affected() == 1)
+    }
+
+    async fn revoke_other_sessions(&self, user_id: Uuid, keep: Uuid) -> Result<Vec<Uuid>> {
+        sqlx::query_scalar!(
+            "UPDATE sessions SET revoked_at = now()
+            WHERE user_id = $1
+                AND id <> $2
+                AND revoked_at IS NULL
+            RETURNING id",
+            user_id,
+            keep
+        )
+        .fetch_all(&self.db)
+        .await
+    }
+
+    async fn revoke_sessions(&self, user_id: Uuid) -> Result<Vec<Uuid>> {
+        sqlx::query_scalar!(
+            "UPDATE sessions SET revoked_at = now()
+            WHERE user_id = $1
+                AND revoked_at IS NULL
+            RETURNING id",
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await
+    }
+
+    async fn vaults(&self, user_id: Uuid, since: Option<OffsetDateTime>) -> Result<Vec<Vault>> {
+        sqlx::query_as!(
+            Vault,
+            "SELECT * FROM vaults
+            WHERE user_id = $1
+                AND ($2::timestamptz IS NULL OR updated_at >= $2)",
+            user_id,
+            since
+        )
+        .fetch_all(&self.db)
+        .await
+    }
+
+    async fn vault(&self, user_id: Uuid, id: Uuid) -> Result<Option<Vault>> {
+        sqlx::query_as!(
+            Vault,
+            "SELECT * FROM vaults WHERE id = $1 AND user_id = $2",
+            id,
+            user_id
+        )
+        .fetch_optional(&self.db)
+        .await
+    }
+
+    async fn create_vault(&self, user_id: Uuid, id: Uuid, vault: &VaultData) -> Result<Vault> {
+        sqlx::query_as!(
+            Vault,
+            "INSERT INTO vaults
+                (id, user_id, encrypted_name, encrypted_vault_key)
+            VALUES ($1, $2, $3, $4)
+            RETURNING *",
+            id,
+            user_id,
+            vault.encrypted_name,
+            vault.encrypted_vault_key
+        )
+        .fetch_one(&self.db)
+        .await
+    }
+
+    async fn put_vault(
+        &self,
+        user_id: Uuid,
+        id: Uuid,
+        vault: &VaultData,
+        revision: Option<i64>,
+    ) -> Result<Put<Vault>> {
+        let mut tx = self.db.begin().await?;
+
+        let existing = sqlx::query_as!(Vault, "SELECT * FROM vaults WHERE id = $1 FOR UPDATE", id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        let Some(existing) = existing else {
+            // only creates come without a revision, updates must not bring
+            // back what another device deleted
+            if revision.is_some() {
+                return Ok(Put::Gone);
+            }
+            let created = sqlx::query_as!(
+                Vault,
+                "INSERT INTO vaults
+                    (id, user_id, encrypted_name, encrypted_vault_key)
+                VALUES ($1, $2, $3, $4)
+                RETURNING *",
+                id,
+                user_id,
+                vault.encrypted_name,
+                vault.encrypted_vault_key
+            )
+            .fetch_one(&mut *tx)
+            .await;
+            let created = match created {
+                // a concurrent create of the same id won the race, answer as
+                // if it had come first
+                Err(e)
+                    if e.as_database_error()
+                        .is_some_and(|e| e.is_unique_violation()) =>
+                {
+                    tx.rollback().await?;
+                    let existing = sqlx::query_as!(Vault, "SELECT * FROM vaults WHERE id = $1", id)
+                        .fetch_optional(&self.db)
+                        .await?;
+                    return Ok(match existing {
+                        Some(existing) if existing.user_id == user_id => Put::Stale(existing),
+                        _ => Put::Taken,
+                    });
+                }
+                created => created?,
+            };
+
+            tx.commit().await?;
+            return Ok(Put::Created(created));
+        };
+
+        if existing.user_id != user_id {
+            return Ok(Put::Taken);
+        }
+        let unchanged = existing.encrypted_name == vault.encrypted_name
+            && existing.encrypted_vault_key == vault.encrypted_vault_key;
+        let current = existing.revision;
+        if let Err(outcome) = check_update(existing, current, unchanged, revision) {
+            return Ok(outcome);
+        }
+
+        let updated = sqlx::query_as!(
+            Vault,
+            "UPDATE vaults
+            SET
+                encrypted_name = $1,
+                encrypted_vault_key = $2,
+                revision = revision + 1,
+                updated_at = now()
+            WHERE id = $3
+            RETURNING *",
+            vault.encrypted_name,
+            vault.encrypted_vault_key,
+            id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(Put::Updated(updated))
+    }
+
+    async fn delete_vault(&self, user_id: Uuid, id: Uuid) -> Result<bool> {
+        let mut tx = self.db.begin().await?;
+
+        let deleted = sqlx::query!(
+            "DELETE FROM vaults WHERE id = $1 AND user_id = $2 RETURNING id",
+            id,
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if deleted.is_some() {
+            sqlx::query!(
+                "INSERT INTO tombstones (id, user_id, entity_type, entity_id, vault_id)
+                VALUES ($1, $2, 'vault', $3, $3)",
+                Uuid::new_v4(),
+                user_id,
+                id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(deleted.is_some())
+    }
+
+    async fn records(&self, vault_id: Uuid, since: Option<OffsetDateTime>) -> Result<Vec<Record>> {
+        sqlx::query_as!(
+            Record,
+            "SELECT * FROM records
+            WHERE vault_id = $1
+                AND ($2::timestamptz IS NULL OR updated_at >= $2)",
+            vault_id,
+            since
+        )
+        .fetch_all(&self.db)
+        .await
+    }
+
+    async fn record(&self, vault_id: Uuid, id: Uuid) -> Result<Option<Record>> {
+        sqlx::query_as!(
+            Record,
+            "SELECT * FROM records WHERE vault_id = $1 AND id = $2",
+            vault_id,
+            id
+        )
+        .fetch_optional(&self.db)
+        .await
+    }
+
+    async fn create_record(&self, vault_id: Uuid, id: Uuid, record: &RecordData) -> Result<Record> {
+        sqlx::query_as!(
+            Record,
+            "INSERT INTO records
+                (id, vault_id, encrypted_record_key, encrypted_data_blob)
+            VALUES ($1, $2, $3, $4)
+            RETURNING *",
+            id,
+            vault_id,
+            record.encrypted_record_key,
+            record.encrypted_data_blob
+        )
+        .fetch_one(&self.db)
+        .await
+    }
+
+    async fn put_record(
+        &self,
+        vault_id: Uuid,
+        id: Uuid,
+        record: &RecordData,
+        revision: Option<i64>,
+    ) -> Result<Put<Record>> {
+        let mut tx = self.db.begin().await?;
+
+        let existing =
+            sqlx::query_as!(Record, "SELECT * FROM records WHERE id = $1 FOR UPDATE", id)
+                .fetch_optional(&mut *tx)
+                .await?;
+
+        let Some(existing) = existing else {
+            // only creates come without a revision, updates must not bring
+            // back what another device deleted
+            if revision.is_some() {
+                return Ok(Put::Gone);
+            }
+            let created = sqlx::query_as!(
+                Record,
+                "INSERT INTO records
+                    (id, vault_id, encrypted_record_key, encrypted_data_blob)
+                VALUES ($1, $2, $3, $4)
+                RETURNING *",
+                id,
+                vault_id,
+                record.encrypted_record_key,
+                record.encrypted_data_blob
+            )
+            .fetch_one(&mut *tx)
+            .await;
+            let created = match created {
+                // a concurrent create of the same id won the race, answer as
+                // if it had come first
+                Err(e)
+                    if e.as_database_error()
+                        .is_some_and(|e| e.is_unique_violation()) =>
+                {
+                    tx.rollback().await?;
+                    let existing =
+                        sqlx::query_as!(Record, "SELECT * FROM records WHERE id = $1", id)
+                            .fetch_optional(&self.db)
+                            .await?;
+                    return Ok(match existing {
+                        Some(existing) if existing.vault_id == vault_id => Put::Stale(existing),
+                        _ => Put::Taken,
+                    });
+                }
+                created => created?,
+            };
+
+            tx.commit().await?;
+            return Ok(Put::Created(created));
+        };
+
+        // records can not be moved between vaults (or users)
+        if existing.vault_id != vault_id {
+            return Ok(Put::Taken);
+        }
+        let unchanged = existing.encrypted_record_key == record.encrypted_record_key
+            && existing.encrypted_data_blob == record.encrypted_data_blob;
+        let current = existing.revision;
+        if let Err(outcome) = check_update(existing, current, unchanged, revision) {
+            return Ok(outcome);
+        }
+
+        let updated = sqlx::query_as!(
+            Record,
+            "UPDATE records
+            SET
+                encrypted_record_key = $1,
+                encrypted_data_blob = $2,
+                revision = revision + 1,
+                updated_at = now()
+            WHERE id = $3
+            RETURNING *",
+            record.encrypted_record_key,
+            record.encrypted_data_blob,
+            id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(Put::Updated(updated))
+    }
+
+    async fn delete_record(&self, vault: &Vault, id: Uuid) -> Result<bool> {
+        let mut tx = self.db.begin().await?;
+
+        let deleted = sqlx::query!(
+            "DELETE FROM records WHERE vault_id = $1 AND id = $2 RETURNING id",
+            vault.id,
+            id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if deleted.is_some() {
+            sqlx::query!(
+                "INSERT INTO tombstones (id, user_id, entity_type, entity_id, vault_id)
+                VALUES ($1, $2, 'record', $3, $4)",
+                Uuid::new_v4(),
+                vault.user_id,
+                id,
+                vault.id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(deleted.is_some())
+    }
+
+    async fn changes(&self, user_id: Uuid, since: OffsetDateTime) -> Result<Changes> {
+        // rows are stamped with `now()`, the start of the transaction writing
+        // them, which may commit after we read. So the cursor is the start of
+        // the oldest transaction still running, taken before the snapshot
+        let cursor = sqlx::query_scalar!(
+            r#"
+            SELECT LEAST(
+                now(),
+                (SELECT min(xact_start) FROM pg_stat_activity WHERE datname = current_database())
+            ) AS "cursor!"
+            "#
+        )
+        .fetch_one(&self.db)
+        .await?;
+
+        let mut tx = self
+            .db
+            .begin_with("BEGIN ISOLATION LEVEL REPEATABLE READ")
+            .await?;
+
+        let vaults = sqlx::query_as!(
+            Vault,
+            r#"
+            SELECT * FROM vaults
+            WHERE user_id = $1
+                AND updated_at >= $2
+            "#,
+            user_id,
+            since
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let records = sqlx::query_as!(
+            Record,
+            r#"
+            SELECT r.* FROM records r
+            JOIN vaults v ON v.id = r.vault_id
+            WHERE v.user_id = $1
+                AND r.updated_at >= $2
+            "#,
+            user_id,
+            since
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let tombstones = sqlx::query!(
+            r#"
+            SELECT entity_type, entity_id, vault_id, deleted_at FROM tombstones
+            WHERE user_id = $1
+                AND deleted_at >= $2
+            ORDER BY deleted_at
+            "#,
+            user_id,
+            since
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|row| {
+            tombstone(
+                &row.entity_type,
+                row.entity_id,
+                row.vault_id,
+                row.deleted_at,
+            )
+        })
+        .collect();
+
+        tx.commit().await?;
+        Ok(Changes {
+            cursor,
+            vaults,
+            records,
+            tombstones,
+        })
+    }
+
+    async fn delete_tombstones(&self, before: OffsetDateTime) -> Result<u64> {
+        let result = sqlx::query!("DELETE FROM tombstones WHERE deleted_at < $1", before)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn signing_keys(&self) -> Result<Vec<SigningKey>> {
+        sqlx::query_as!(
+            SigningKey,
+            "SELECT kid, private_key, public_key, created_at, active_at FROM signing_keys
+            ORDER BY active_at DESC"
+        )
+        .fetch_all(&self.db)
+        .await
+    }
+
+    async fn add_signing_key(&self, key: &SigningKey) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO signing_keys (kid, private_key, public_key, created_at, active_at)
+            VALUES ($1, $2, $3, $4, $5)",
+            key.kid,
+            key.private_key,
+            key.public_key,
+            key.created_at,
+            key.active_at
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_replaced_signing_keys(&self, before: OffsetDateTime) -> Result<Vec<String>> {
+        sqlx::query_scalar!(
+            "DELETE FROM signing_keys AS old
+            WHERE EXISTS (
+                SELECT 1 FROM signing_keys AS new
+                WHERE new.active_at > old.active_at AND new.active_at < $1
+            )
+            RETURNING kid",
+            before
+        )
+        .fetch_all(&self.db)
+        .await
+    }
+
+    async fn schema_state(&self) -> Result<SchemaState> {
+        super::schema_state(&MIGRATOR, &self.db).await
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        MIGRATOR.run(&self.db).await?;
+        Ok(())
+    }
+}
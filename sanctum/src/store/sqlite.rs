@@ -0,0 +1,1710 @@
+//! The store in an SQLite file, for a server without a database server.
+//!
+//! Queries are checked at runtime here, the macros only check against the
+//! Postgres schema. The schema is in `migrations/sqlite`, with the same
+//! versions as the Postgres migrations it matches.
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    time::Duration,
+};
+
+use sanctum_shared::models::{KdfParams, Record, StorageUsage, Vault, WebauthnCredential};
+use sqlx::{
+    SqlitePool,
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+};
+use time::{OffsetDateTime, UtcOffset};
+use uuid::Uuid;
+
+use super::{
+    Changes, DeviceHistory, EmailChangeOutcome, NewCredential, NewSession, NewUser, PasswordChange,
+    PasswordChangeOutcome, Put, RecordData, Result, SchemaState, Session, SigningKey, Store,
+    StoredCredential, User, UserSummary, VaultData, check_update, tombstone,
+};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// How long a write waits for another one to finish.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct SqliteStore {
+    db: SqlitePool,
+}
+
+impl SqliteStore {
+    /// Open the database at `url`, creating it if needed.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .foreign_keys(true)
+            .busy_timeout(BUSY_TIMEOUT);
+
+        // every connection to an in-memory database gets a database of its own
+        let in_memory = url.contains(":memory:") || url.contains("mode=memory");
+        let db = if in_memory {
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+                .connect_with(options)
+                .await?
+        } else {
+            SqlitePool::connect_with(options.journal_mode(SqliteJournalMode::Wal)).await?
+        };
+
+        Ok(Self { db })
+    }
+
+    /// A transaction that takes the write lock right away, so it can't
+    /// fail to get it after reading.
+    async fn begin(&self) -> Result<sqlx::Transaction<'static, sqlx::Sqlite>> {
+        self.db.begin_with("BEGIN IMMEDIATE").await
+    }
+}
+
+/// The text a timestamp is stored as: RFC 3339 in UTC, always with
+/// microseconds, so that timestamps sort as text.
+fn timestamp(time: OffsetDateTime) -> String {
+    let time = time.to_offset(UtcOffset::UTC);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        time.year(),
+        time.month() as u8,
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second(),
+        time.microsecond()
+    )
+}
+
+fn now() -> String {
+    timestamp(OffsetDateTime::now_utc())
+}
+
+#[derive(sqlx::FromRow)]
+struct UserRow {
+    id: Uuid,
+    email: String,
+    password_file: String,
+    salt: String,
+    credential_id: Option<Uuid>,
+    kdf_memory_kib: i64,
+    kdf_iterations: i64,
+    kdf_parallelism: i64,
+    settings: sqlx::types::Json<serde_json::Value>,
+    totp_secret: Option<String>,
+    totp_enabled_at: Option<OffsetDateTime>,
+    totp_last_step: Option<i64>,
+    disabled_at: Option<OffsetDateTime>,
+    created_at: OffsetDateTime,
+}
+
+impl From<UserRow> for User {
+    fn from(row: UserRow) -> Self {
+        User {
+            id: row.id,
+            email: row.email,
+            password_file: row.password_file,
+            salt: row.salt,
+            credential_id: row.credential_id,
+            kdf: KdfParams {
+                memory_kib: row.kdf_memory_kib as u32,
+                iterations: row.kdf_iterations as u32,
+                parallelism: row.kdf_parallelism as u32,
+            },
+            settings: row.settings.0,
+            totp_secret: row.totp_secret,
+            totp_enabled_at: row.totp_enabled_at,
+            totp_last_step: row.totp_last_step,
+            disabled_at: row.disabled_at,
+            created_at: row.created_at,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct VaultRow {
+    id: Uuid,
+    user_id: Uuid,
+    encrypted_vault_key: String,
+    encrypted_name: String,
+    revision: i64,
+    created_at: OffsetDateTime,
+    updated_at: OffsetDateTime,
+}
+
+impl From<VaultRow> for Vault {
+    fn from(row: VaultRow) -> Self {
+        Vault {
+            id: row.id,
+            user_id: row.user_id,
+            encrypted_vault_key: row.encrypted_vault_key,
+            encrypted_name: row.encrypted_name,
+            revision: row.revision,
+            created_at: row.created_at.to_utc(),
+            updated_at: row.updated_at.to_utc(),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct RecordRow {
+    id: Uuid,
+    vault_id: Uuid,
+    encrypted_record_key: String,
+    encrypted_data_blob: String,
+    revision: i64,
+    created_at: OffsetDateTime,
+    updated_at: OffsetDateTime,
+}
+
+impl From<RecordRow> for Record {
+    fn from(row: RecordRow) -> Self {
+        Record {
+            id: row.id,
+            vault_id: row.vault_id,
+            encrypted_record_key: row.encrypted_record_key,
+            encrypted_data_blob: row.encrypted_data_blob,
+            revision: row.revision,
+            created_at: row.created_at.to_utc(),
+            updated_at: row.updated_at.to_utc(),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct SessionRow {
+    id: Uuid,
+    user_id: Uuid,
+    device_name: Option<String>,
+    platform: Option<String>,
+    created_at: OffsetDateTime,
+    last_used_at: OffsetDateTime,
+}
+
+impl From<SessionRow> for Session {
+    fn from(row: SessionRow) -> Self {
+        Session {
+            id: row.id,
+            user_id: row.user_id,
+            device_name: row.device_name,
+            platform: row.platform,
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct CredentialRow {
+    id: Uuid,
+    name: String,
+    created_at: OffsetDateTime,
+    last_used_at: Option<OffsetDateTime>,
+}
+
+impl From<CredentialRow> for WebauthnCredential {
+    fn from(row: CredentialRow) -> Self {
+        WebauthnCredential {
+            id: row.id,
+            name: row.name,
+            created_at: row.created_at.to_utc(),
+            last_used_at: row.last_used_at.map(|t| t.to_utc()),
+        }
+    }
+}
+
+const VAULT_COLUMNS: &str =
+    "id, user_id, encrypted_vault_key, encrypted_name, revision, created_at, updated_at";
+const RECORD_COLUMNS: &str =
+    "id, vault_id, encrypted_record_key, encrypted_data_blob, revision, created_at, updated_at";
+const SESSION_COLUMNS: &str = "id, user_id, device_name, platform, created_at, last_used_at";
+
+#[async_trait::async_trait]
+impl Store for SqliteStore {
+    async fn has_users(&self) -> Result<bool> {
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM users)")
+            .fetch_one(&self.db)
+            .await
+    }
+
+    async fn user(&self, id: Uuid) -> Result<Option<User>> {
+        let row = sqlx::query_as::<_, UserRow>("SELECT * FROM users WHERE id = ?1")
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(row.map(User::from))
+    }
+
+    async fn user_by_email(&self, email: &str) -> Result<Option<User>> {
+        let row = sqlx::query_as::<_, UserRow>("SELECT * FROM users WHERE email = ?1")
+            .bind(email)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(row.map(User::from))
+    }
+
+    async fn create_user(&self, user: &NewUser) -> Result<bool> {
+        let now = now();
+        let created = sqlx::query(
+            "INSERT INTO users (
+                id, email, salt, password_file, credential_id,
+                kdf_memory_kib, kdf_iterations, kdf_parallelism,
+                created_at, updated_at
+            )
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9)
+            ON CONFLICT DO NOTHING",
+        )
+        .bind(Uuid::new_v4())
+        .bind(&user.email)
+        .bind(&user.salt)
+        .bind(&user.password_file)
+        .bind(user.credential_id)
+        .bind(user.kdf.memory_kib)
+        .bind(user.kdf.iterations)
+        .bind(user.kdf.parallelism)
+        .bind(now)
+        .execute(&self.db)
+        .await?
+        .rows_affected();
+
+        Ok(created == 1)
+    }
+
+    async fn update_settings(&self, user_id: Uuid, settings: &serde_json::Value) -> Result<()> {
+        sqlx::query("UPDATE users SET settings = ?1, updated_at = ?2 WHERE id = ?3")
+            .bind(sqlx::types::Json(settings))
+            .bind(now())
+            .bind(user_id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn change_password(
+        &self,
+        user_id: Uuid,
+        session_id: Uuid,
+        change: &PasswordChange,
+    ) -> Result<PasswordChangeOutcome> {
+        let mut tx = self.begin().await?;
+        let now = now();
+
+        let revisions =
+            sqlx::query_as::<_, (Uuid, i64)>("SELECT id, revision FROM vaults WHERE user_id = ?1")
+                .bind(user_id)
+                .fetch_all(&mut *tx)
+                .await?
+                .into_iter()
+                .collect::<HashMap<_, _>>();
+
+        let rewrapped = change.vaults.iter().map(|v| v.id).collect::<HashSet<_>>();
+        if rewrapped != revisions.keys().copied().collect()
+            || rewrapped.len() != change.vaults.len()
+        {
+            return Ok(PasswordChangeOutcome::VaultMismatch);
+        }
+        if change
+            .vaults
+            .iter()
+            .any(|vault| revisions[&vault.id] != vault.revision)
+        {
+            return Ok(PasswordChangeOutcome::StaleVault);
+        }
+
+        let updated = sqlx::query(
+            "UPDATE users
+            SET
+                password_file = ?1,
+                salt = ?2,
+                credential_id = ?3,
+                kdf_memory_kib = ?5,
+                kdf_iterations = ?6,
+                kdf_parallelism = ?7,
+                updated_at = ?8
+            WHERE id = ?4
+                AND (credential_id IS NULL OR credential_id = ?3)",
+        )
+        .bind(&change.password_file)
+        .bind(&change.salt)
+        .bind(change.credential_id)
+        .bind(user_id)
+        .bind(change.kdf.memory_kib)
+        .bind(change.kdf.iterations)
+        .bind(change.kdf.parallelism)
+        .bind(&now)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        if updated == 0 {
+            return Ok(PasswordChangeOutcome::CredentialMismatch);
+        }
+
+        let mut vaults = Vec::with_capacity(change.vaults.len());
+        for vault in &change.vaults {
+            let updated = sqlx::query_as::<_, VaultRow>(&format!(
+                "UPDATE vaults
+                SET
+                    encrypted_vault_key = ?1,
+                    encrypted_name = ?2,
+                    revision = revision + 1,
+                    updated_at = ?3
+                WHERE id = ?4
+                RETURNING {VAULT_COLUMNS}"
+            ))
+            .bind(&vault.encrypted_vault_key)
+            .bind(&vault.encrypted_name)
+            .bind(&now)
+            .bind(vault.id)
+            .fetch_one(&mut *tx)
+            .await?;
+            vaults.push(updated.into());
+        }
+
+        let revoked_sessions = sqlx::query_scalar(
+            "UPDATE sessions SET revoked_at = ?3
+            WHERE user_id = ?1
+                AND id <> ?2
+                AND revoked_at IS NULL
+            RETURNING id",
+        )
+        .bind(user_id)
+        .bind(session_id)
+        .bind(&now)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(PasswordChangeOutcome::Changed {
+            vaults,
+            revoked_sessions,
+        })
+    }
+
+    async fn reregister(
+        &self,
+        user_id: Uuid,
+        password_file: &str,
+        credential_id: Uuid,
+    ) -> Result<bool> {
+        let updated = sqlx::query(
+            "UPDATE users
+            SET password_file = ?1, credential_id = ?2, updated_at = ?3
+            WHERE id = ?4 AND credential_id IS NULL",
+        )
+        .bind(password_file)
+        .bind(credential_id)
+        .bind(now())
+        .bind(user_id)
+        .execute(&self.db)
+        .await?
+        .rows_affected();
+
+        Ok(updated == 1)
+    }
+
+    async fn change_email(&self, user_id: Uuid, email: &str) -> Result<EmailChangeOutcome> {
+        let mut tx = self.begin().await?;
+
+        let Some(user) = sqlx::query_as::<_, UserRow>("SELECT * FROM users WHERE id = ?1")
+            .bind(user_id)
+            .fetch_optional(&mut *tx)
+            .await?
+        else {
+            return Ok(EmailChangeOutcome::UnknownUser);
+        };
+
+        if user.credential_id.is_none() {
+            return Ok(EmailChangeOutcome::NoCredentialId);
+        }
+
+        let updated = sqlx::query("UPDATE users SET email = ?1, updated_at = ?2 WHERE id = ?3")
+            .bind(email)
+            .bind(now())
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await;
+        match updated {
+            Err(e)
+                if e.as_database_error()
+                    .is_some_and(|e| e.is_unique_violation()) =>
+            {
+                return Ok(EmailChangeOutcome::Taken);
+            }
+            updated => updated?,
+        };
+
+        tx.commit().await?;
+        Ok(EmailChangeOutcome::Changed {
+            previous: user.email,
+        })
+    }
+
+    async fn delete_user(&self, user_id: Uuid) -> Result<Vec<Uuid>> {
+        let mut tx = self.begin().await?;
+
+        let sessions =
+            sqlx::query_scalar("SELECT id FROM sessions WHERE user_id = ?1 AND revoked_at IS NULL")
+                .bind(user_id)
+                .fetch_all(&mut *tx)
+                .await?;
+
+        // vaults, records, tombstones and sessions cascade
+        sqlx::query("DELETE FROM users WHERE id = ?1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(sessions)
+    }
+
+    async fn storage_usage(&self, user_id: Uuid) -> Result<StorageUsage> {
+        let (vaults, records, bytes) = sqlx::query_as(
+            "SELECT
+                (SELECT count(*) FROM vaults WHERE user_id = ?1),
+                (SELECT count(*) FROM records r
+                    JOIN vaults v ON v.id = r.vault_id
+                    WHERE v.user_id = ?1),
+                (SELECT coalesce(sum(
+                    length(CAST(encrypted_vault_key AS BLOB))
+                    + length(CAST(encrypted_name AS BLOB))
+                ), 0) FROM vaults WHERE user_id = ?1)
+                + (SELECT coalesce(sum(
+                    length(CAST(r.encrypted_record_key AS BLOB))
+                    + length(CAST(r.encrypted_data_blob AS BLOB))
+                ), 0) FROM records r
+                    JOIN vaults v ON v.id = r.vault_id
+                    WHERE v.user_id = ?1)",
+        )
+        .bind(user_id)
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(StorageUsage {
+            vaults,
+            records,
+            bytes,
+        })
+    }
+
+    async fn user_summaries(&self) -> Result<Vec<UserSummary>> {
+        let rows = sqlx::query_as::<
+            _,
+            (
+                Uuid,
+                String,
+                i64,
+                i64,
+                Option<OffsetDateTime>,
+                OffsetDateTime,
+            ),
+        >(
+            "SELECT
+                u.id, u.email,
+                (SELECT count(*) FROM vaults WHERE user_id = u.id),
+                (SELECT count(*) FROM records r
+                    JOIN vaults v ON v.id = r.vault_id
+                    WHERE v.user_id = u.id),
+                u.disabled_at, u.created_at
+            FROM users u
+            ORDER BY u.created_at",
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(
+                |(id, email, vaults, records, disabled_at, created_at)| UserSummary {
+                    id,
+                    email,
+                    vaults,
+                    records,
+                    disabled_at,
+                    created_at,
+                },
+            )
+            .collect())
+    }
+
+    async fn set_disabled(&self, user_id: Uuid, disabled: bool) -> Result<Vec<Uuid>> {
+        let mut tx = self.begin().await?;
+        let now = now();
+
+        // an account that is disabled already keeps its date
+        sqlx::query(
+            "UPDATE users
+            SET
+                disabled_at = CASE WHEN ?2 THEN coalesce(disabled_at, ?3) END,
+                updated_at = ?3
+            WHERE id = ?1",
+        )
+        .bind(user_id)
+        .bind(disabled)
+        .bind(&now)
+        .execute(&mut *tx)
+        .await?;
+
+        let sessions = match disabled {
+            true => {
+                sqlx::query_scalar(
+                    "UPDATE sessions SET revoked_at = ?2
+                    WHERE user_id = ?1
+                        AND revoked_at IS NULL
+                    RETURNING id",
+                )
+                .bind(user_id)
+                .bind(&now)
+                .fetch_all(&mut *tx)
+                .await?
+            }
+            false => Vec::new(),
+        };
+
+        tx.commit().await?;
+        Ok(sessions)
+    }
+
+    async fn set_totp_secret(&self, user_id: Uuid, secret: &str) -> Result<Option<String>> {
+        sqlx::query_scalar(
+            "UPDATE users SET totp_secret = ?2, updated_at = ?3
+            WHERE id = ?1 AND totp_enabled_at IS NULL
+            RETURNING email",
+        )
+        .bind(user_id)
+        .bind(secret)
+        .bind(now())
+        .fetch_optional(&self.db)
+        .await
+    }
+
+    async fn enable_totp(
+        &self,
+        user_id: Uuid,
+        secret: &str,
+        step: i64,
+        recovery_code_hashes: &[String],
+    ) -> Result<bool> {
+        let mut tx = self.begin().await?;
+        let now = now();
+
+        let enabled = sqlx::query(
+            "UPDATE users SET totp_enabled_at = ?4, totp_last_step = ?3, updated_at = ?4
+            WHERE id = ?1 AND totp_secret = ?2 AND totp_enabled_at IS NULL",
+        )
+        .bind(user_id)
+        .bind(secret)
+        .bind(step)
+        .bind(&now)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if enabled == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        for code_hash in recovery_code_hashes {
+            sqlx::query(
+                "INSERT INTO recovery_codes (id, user_id, code_hash, created_at)
+                VALUES (?1, ?2, ?3, ?4)",
+            )
+            .bind(Uuid::new_v4())
+            .bind(user_id)
+            .bind(code_hash)
+            .bind(&now)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn disable_totp(&self, user_id: Uuid) -> Result<()> {
+        let mut tx = self.begin().await?;
+
+        sqlx::query(
+            "UPDATE users
+            SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL, updated_at = ?2
+            WHERE id = ?1",
+        )
+        .bind(user_id)
+        .bind(now())
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await
+    }
+
+    async fn use_totp_step(&self, user_id: Uuid, step: i64) -> Result<bool> {
+        let updated = sqlx::query(
+            "UPDATE users SET totp_last_step = ?2
+            WHERE id = ?1 AND (totp_last_step IS NULL OR totp_last_step < ?2)",
+        )
+        .bind(user_id)
+        .bind(step)
+        .execute(&self.db)
+        .await?;
+
+        Ok(updated.rows_affected() == 1)
+    }
+
+    async fn use_recovery_code(&self, user_id: Uuid, code_hash: &str) -> Result<bool> {
+        let updated = sqlx::query(
+            "UPDATE recovery_codes SET used_at = ?3
+            WHERE user_id = ?1 AND code_hash = ?2 AND used_at IS NULL",
+        )
+        .bind(user_id)
+        .bind(code_hash)
+        .bind(now())
+        .execute(&self.db)
+        .await?;
+
+        Ok(updated.rows_affected() == 1)
+    }
+
+    async fn webauthn_credential_ids(&self, user_id: Uuid) -> Result<Vec<Vec<u8>>> {
+        sqlx::query_scalar("SELECT credential_id FROM webauthn_credentials WHERE user_id = ?1")
+            .bind(user_id)
+            .fetch_all(&self.db)
+            .await
+    }
+
+    async fn webauthn_credentials(&self, user_id: Uuid) -> Result<Vec<WebauthnCredential>> {
+        let rows = sqlx::query_as::<_, CredentialRow>(
+            "SELECT id, name, created_at, last_used_at FROM webauthn_credentials
+            WHERE user_id = ?1
+            ORDER BY created_at",
+        )
+        .bind(user_id)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows.into_iter().map(WebauthnCredential::from).collect())
+    }
+
+    async fn webauthn_credential(
+        &self,
+        user_id: Uuid,
+        credential_id: &[u8],
+    ) -> Result<Option<StoredCredential>> {
+        let row = sqlx::query_as::<_, (Uuid, Vec<u8>, i64)>(
+            "SELECT id, public_key, sign_count FROM webauthn_credentials
+            WHERE user_id = ?1 AND credential_id = ?2",
+        )
+        .bind(user_id)
+        .bind(credential_id)
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(row.map(|(id, public_key, sign_count)| StoredCredential {
+            id,
+            public_key,
+            sign_count,
+        }))
+    }
+
+    async fn add_webauthn_credential(
+        &self,
+        credential: &NewCredential,
+    ) -> Result<WebauthnCredential> {
+        let row = sqlx::query_as::<_, CredentialRow>(
+            "INSERT INTO webauthn_credentials
+                (id, user_id, credential_id, public_key, algorithm, sign_count, name, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            RETURNING id, name, created_at, last_used_at",
+        )
+        .bind(Uuid::new_v4())
+        .bind(credential.user_id)
+        .bind(&credential.credential_id)
+        .bind(&credential.public_key)
+        .bind(credential.algorithm)
+        .bind(credential.sign_count)
+        .bind(&credential.name)
+        .bind(now())
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(row.into())
+    }
+
+    async fn delete_webauthn_credential(&self, user_id: Uuid, id: Uuid) -> Result<bool> {
+        let deleted =
+            sqlx::query("DELETE FROM webauthn_credentials WHERE id = ?1 AND user_id = ?2")
+                .bind(id)
+                .bind(user_id)
+                .execute(&self.db)
+                .await?;
+
+        Ok(deleted.rows_affected() == 1)
+    }
+
+    async fn update_sign_count(&self, id: Uuid, sign_count: i64) -> Result<bool> {
+        let updated = sqlx::query(
+            "UPDATE webauthn_credentials SET sign_count = ?2, last_used_at = ?3
+            WHERE id = ?1 AND (sign_count < ?2 OR ?2 = 0)",
+        )
+        .bind(id)
+        .bind(sign_count)
+        .bind(now())
+        .execute(&self.db)
+        .await?;
+
+        Ok(updated.rows_affected() == 1)
+    }
+
+    async fn create_session(&self, session: &NewSession) -> Result<Uuid> {
+        let id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO sessions (
+                id, user_id, refresh_token_hash, expires_at, device_name, platform,
+                created_at, last_used_at
+            )
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)",
+        )
+        .bind(id)
+        .bind(session.user_id)
+        .bind(&session.refresh_token_hash)
+        .bind(timestamp(session.expires_at))
+        .bind(&session.device_name)
+        .bind(&session.platform)
+        .bind(now())
+        .execute(&self.db)
+        .await?;
+
+        Ok(id)
+    }
+
+    async fn session(&self, id: Uuid) -> Result<Option<Session>> {
+        let row = sqlx::query_as::<_, SessionRow>(&format!(
+            "SELECT {SESSION_COLUMNS} FROM sessions WHERE id = ?1"
+        ))
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(row.map(Session::from))
+    }
+
+    async fn active_sessions(&self, user_id: Uuid) -> Result<Vec<Session>> {
+        let rows = sqlx::query_as::<_, SessionRow>(&format!(
+            "SELECT {SESSION_COLUMNS} FROM sessions
+            WHERE user_id = ?1
+                AND revoked_at IS NULL
+                AND expires_at > ?2
+            ORDER BY last_used_at DESC"
+        ))
+        .bind(user_id)
+        .bind(now())
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows.into_iter().map(Session::from).collect())
+    }
+
+    async fn device_history(
+        &self,
+        user_id: Uuid,
+        device_name: Option<&str>,
+        platform: Option<&str>,
+    ) -> Result<DeviceHistory> {
+        // a device counts as known once it had a session, even a revoked one
+        let (has_sessions, known_device) = sqlx::query_as(
+            "SELECT
+                EXISTS(SELECT 1 FROM sessions WHERE user_id = ?1),
+                EXISTS(
+                    SELECT 1 FROM sessions
+                    WHERE user_id = ?1
+                        AND device_name IS ?2
+                        AND platform IS ?3
+                )",
+        )
+        .bind(user_id)
+        .bind(device_name)
+        .bind(platform)
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(DeviceHistory {
+            has_sessions,
+            known_device,
+        })
+    }
+
+    async fn rotate_refresh_token(
+        &self,
+        hash: &str,
+        new_hash: &str,
+        expires_at: OffsetDateTime,
+    ) -> Result<Option<Session>> {
+        let row = sqlx::query_as::<_, SessionRow>(&format!(
+            "UPDATE sessions
+            SET
+                previous_token_hash = refresh_token_hash,
+                refresh_token_hash = ?2,
+                last_used_at = ?3,
+                expires_at = ?4
+            WHERE refresh_token_hash = ?1
+                AND revoked_at IS NULL
+                AND expires_at > ?3
+                AND user_id IN (SELECT id FROM users WHERE disabled_at IS NULL)
+            RETURNING {SESSION_COLUMNS}"
+        ))
+        .bind(hash)
+        .bind(new_hash)
+        .bind(now())
+        .bind(timestamp(expires_at))
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(row.map(Session::from))
+    }
+
+    async fn revoke_rotated(&self, hash: &str) -> Result<Option<Uuid>> {
+        sqlx::query_scalar(
+            "UPDATE sessions
+            SET revoked_at = ?2
+            WHERE previous_token_hash = ?1
+                AND revoked_at IS NULL
+            RETURNING id",
+        )
+        .bind(hash)
+        .bind(now())
+        .fetch_optional(&self.db)
+        .await
+    }
+
+    async fn revoke_session(&self, user_id: Uuid, id: Uuid) -> Result<bool> {
+        let revoked = sqlx::query(
+            "UPDATE sessions SET revoked_at = ?3
+            WHERE id = ?1
+                AND user_id = ?2
+                AND revoked_at IS NULL",
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(now())
+        .execute(&self.db)
+        .await?;
+
+        Ok(revoked.rows_affected() == 1)
+    }
+
+    async fn revoke_other_sessions(&self, user_id: Uuid, keep: Uuid) -> Result<Vec<Uuid>> {
+        sqlx::query_scalar(
+            "UPDATE sessions SET revoked_at = ?3
+            WHERE user_id = ?1
+                AND id <> ?2
+                AND revoked_at IS NULL
+            RETURNING id",
+        )
+        .bind(user_id)
+        .bind(keep)
+        .bind(now())
+        .fetch_all(&self.db)
+        .await
+    }
+
+    async fn revoke_sessions(&self, user_id: Uuid) -> Result<Vec<Uuid>> {
+        sqlx::query_scalar(
+            "UPDATE sessions SET revoked_at = ?2
+            WHERE user_id = ?1
+                AND revoked_at IS NULL
+            RETURNING id",
+        )
+        .bind(user_id)
+        .bind(now())
+        .fetch_all(&self.db)
+        .await
+    }
+
+    async fn vaults(&self, user_id: Uuid, since: Option<OffsetDateTime>) -> Result<Vec<Vault>> {
+        let rows = sqlx::query_as::<_, VaultRow>(&format!(
+            "SELECT {VAULT_COLUMNS} FROM vaults
+            WHERE user_id = ?1
+                AND (?2 IS NULL OR updated_at >= ?2)"
+        ))
+        .bind(user_id)
+        .bind(since.map(timestamp))
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows.into_iter().map(Vault::from).collect())
+    }
+
+    async fn vault(&self, user_id: Uuid, id: Uuid) -> Result<Option<Vault>> {
+        let row = sqlx::query_as::<_, VaultRow>(&format!(
+            "SELECT {VAULT_COLUMNS} FROM vaults WHERE id = ?1 AND user_id = ?2"
+        ))
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(row.map(Vault::from))
+    }
+
+    async fn create_vault(&self, user_id: Uuid, id: Uuid, vault: &VaultData) -> Result<Vault> {
+        let row = sqlx::query_as::<_, VaultRow>(&format!(
+            "INSERT INTO vaults
+                (id, user_id, encrypted_name, encrypted_vault_key, created_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+            RETURNING {VAULT_COLUMNS}"
+        ))
+        .bind(id)
+        .bind(user_id)
+        .bind(&vault.encrypted_name)
+        .bind(&vault.encrypted_vault_key)
+        .bind(now())
+        .fetch_one(&self.db)
+        .await?;
+        Ok(row.into())
+    }
+
+    async fn put_vault(
+        &self,
+        user_id: Uuid,
+        id: Uuid,
+        vault: &VaultData,
+        revision: Option<i64>,
+    ) -> Result<Put<Vault>> {
+        let mut tx = self.begin().await?;
+        let now = now();
+
+        let existing = sqlx::query_as::<_, VaultRow>(&format!(
+            "SELECT {VAULT_COLUMNS} FROM vaults WHERE id = ?1"
+        ))
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(existing) = existing else {
+            // only creates come without a revision, updates must not bring
+            // back what another device deleted
+            if revision.is_some() {
+                return Ok(Put::Gone);
+            }
+            let created = sqlx::query_as::<_, VaultRow>(&format!(
+                "INSERT INTO vaults
+                    (id, user_id, encrypted_name, encrypted_vault_key, created_at, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+                RETURNING {VAULT_COLUMNS}"
+            ))
+            .bind(id)
+            .bind(user_id)
+            .bind(&vault.encrypted_name)
+            .bind(&vault.encrypted_vault_key)
+            .bind(&now)
+            .fetch_one(&mut *tx)
+            .await;
+            let created = match created {
+                // a concurrent create of the same id won the race, answer as
+                // if it had come first
+                Err(e)
+                    if e.as_database_error()
+                        .is_some_and(|e| e.is_unique_violation()) =>
+                {
+                    tx.rollback().await?;
+                    let existing = sqlx::query_as::<_, VaultRow>(&format!(
+                        "SELECT {VAULT_COLUMNS} FROM vaults WHERE id = ?1"
+                    ))
+                    .bind(id)
+                    .fetch_optional(&self.db)
+                    .await?;
+                    return Ok(match existing {
+                        Some(existing) if existing.user_id == user_id => {
+                            Put::Stale(existing.into())
+                        }
+                        _ => Put::Taken,
+                    });
+                }
+                created => created?,
+            };
+
+            tx.commit().await?;
+            return Ok(Put::Created(created.into()));
+        };
+
+        if existing.user_id != user_id {
+            return Ok(Put::Taken);
+        }
+        let unchanged = existing.encrypted_name == vault.encrypted_name
+            && existing.encrypted_vault_key == vault.encrypted_vault_key;
+        let current = existing.revision;
+        if let Err(outcome) = check_update(Vault::from(existing), current, unchanged, revision) {
+            return Ok(outcome);
+        }
+
+        let updated = sqlx::query_as::<_, VaultRow>(&format!(
+            "UPDATE vaults
+            SET
+                encrypted_name = ?1,
+                encrypted_vault_key = ?2,
+                revision = revision + 1,
+                updated_at = ?3
+            WHERE id = ?4
+            RETURNING {VAULT_COLUMNS}"
+        ))
+        .bind(&vault.encrypted_name)
+        .bind(&vault.encrypted_vault_key)
+        .bind(&now)
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(Put::Updated(updated.into()))
+    }
+
+    async fn delete_vault(&self, user_id: Uuid, id: Uuid) -> Result<bool> {
+        let mut tx = self.begin().await?;
+
+        let deleted = sqlx::query("DELETE FROM vaults WHERE id = ?1 AND user_id = ?2")
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            == 1;
+
+        if deleted {
+            sqlx::query(
+                "INSERT INTO tombstones (id, user_id, entity_type, entity_id, vault_id, deleted_at)
+                VALUES (?1, ?2, 'vault', ?3, ?3, ?4)",
+            )
+            .bind(Uuid::new_v4())
+            .bind(user_id)
+            .bind(id)
+            .bind(now())
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn records(&self, vault_id: Uuid, since: Option<OffsetDateTime>) -> Result<Vec<Record>> {
+        let rows = sqlx::query_as::<_, RecordRow>(&format!(
+            "SELECT {RECORD_COLUMNS} FROM records
+            WHERE vault_id = ?1
+                AND (?2 IS NULL OR updated_at >= ?2)"
+        ))
+        .bind(vault_id)
+        .bind(since.map(timestamp))
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows.into_iter().map(Record::from).collect())
+    }
+
+    async fn record(&self, vault_id: Uuid, id: Uuid) -> Result<Option<Record>> {
+        let row = sqlx::query_as::<_, RecordRow>(&format!(
+            "SELECT {RECORD_COLUMNS} FROM records WHERE vault_id = ?1 AND id = ?2"
+        ))
+        .bind(vault_id)
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(row.map(Record::from))
+    }
+
+    async fn create_record(&self, vault_id: Uuid, id: Uuid, record: &RecordData) -> Result<Record> {
+        let row = sqlx::query_as::<_, RecordRow>(&format!(
+            "INSERT INTO records
+                (id, vault_id, encrypted_record_key, encrypted_data_blob, created_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+            RETURNING {RECORD_COLUMNS}"
+        ))
+        .bind(id)
+        .bind(vault_id)
+        .bind(&record.encrypted_record_key)
+        .bind(&record.encrypted_data_blob)
+        .bind(now())
+        .fetch_one(&self.db)
+        .await?;
+        Ok(row.into())
+    }
+
+    async fn put_record(
+        &self,
+        vault_id: Uuid,
+        id: Uuid,
+        record: &RecordData,
+        revision: Option<i64>,
+    ) -> Result<Put<Record>> {
+        let mut tx = self.begin().await?;
+        let now = now();
+
+        let existing = sqlx::query_as::<_, RecordRow>(&format!(
+            "SELECT {RECORD_COLUMNS} FROM records WHERE id = ?1"
+        ))
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(existing) = existing else {
+            // only creates come without a revision, updates must not bring
+            // back what another device deleted
+            if revision.is_some() {
+                return Ok(Put::Gone);
+            }
+            let created = sqlx::query_as::<_, RecordRow>(&format!(
+                "INSERT INTO records
+                    (id, vault_id, encrypted_record_key, encrypted_data_blob, created_at, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+                RETURNING {RECORD_COLUMNS}"
+            ))
+            .bind(id)
+            .bind(vault_id)
+            .bind(&record.encrypted_record_key)
+            .bind(&record.encrypted_data_blob)
+            .bind(&now)
+            .fetch_one(&mut *tx)
+            .await;
+            let created = match created {
+                // a concurrent create of the same id won the race, answer as
+                // if it had come first
+                Err(e)
+                    if e.as_database_error()
+                        .is_some_and(|e| e.is_unique_violation()) =>
+                {
+                    tx.rollback().await?;
+                    let existing = sqlx::query_as::<_, RecordRow>(&format!(
+                        "SELECT {RECORD_COLUMNS} FROM records WHERE id = ?1"
+                    ))
+                    .bind(id)
+                    .fetch_optional(&self.db)
+                    .await?;
+                    return Ok(match existing {
+                        Some(existing) if existing.vault_id == vault_id => {
+                            Put::Stale(existing.into())
+                        }
+                        _ => Put::Taken,
+                    });
+                }
+                created => created?,
+            };
+
+            tx.commit().await?;
+            return Ok(Put::Created(created.into()));
+        };
+
+        // records can not be moved between vaults (or users)
+        if existing.vault_id != vault_id {
+            return Ok(Put::Taken);
+        }
+        let unchanged = existing.encrypted_record_key == record.encrypted_record_key
+            && existing.encrypted_data_blob == record.encrypted_data_blob;
+        let current = existing.revision;
+        if let Err(outcome) = check_update(Record::from(existing), current, unchanged, revision) {
+            return Ok(outcome);
+        }
+
+        let updated = sqlx::query_as::<_, RecordRow>(&format!(
+            "UPDATE records
+            SET
+                encrypted_record_key = ?1,
+                encrypted_data_blob = ?2,
+                revision = revision + 1,
+                updated_at = ?3
+            WHERE id = ?4
+            RETURNING {RECORD_COLUMNS}"
+        ))
+        .bind(&record.encrypted_record_key)
+        .bind(&record.encrypted_data_blob)
+        .bind(&now)
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(Put::Updated(updated.into()))
+    }
+
+    async fn delete_record(&self, vault: &Vault, id: Uuid) -> Result<bool> {
+        let mut tx = self.begin().await?;
+
+        let deleted = sqlx::query("DELETE FROM records WHERE vault_id = ?1 AND id = ?2")
+            .bind(vault.id)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            == 1;
+
+        if deleted {
+            sqlx::query(
+                "INSERT INTO tombstones (id, user_id, entity_type, entity_id, vault_id, deleted_at)
+                VALUES (?1, ?2, 'record', ?3, ?4, ?5)",
+            )
+            .bind(Uuid::new_v4())
+            .bind(vault.user_id)
+            .bind(id)
+            .bind(vault.id)
+            .bind(now())
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn changes(&self, user_id: Uuid, since: OffsetDateTime) -> Result<Changes> {
+        // writes take their timestamp once they hold the write lock, so with
+        // the lock held none of them is pending, and all later ones are newer
+        let mut tx = self.begin().await?;
+        let cursor = OffsetDateTime::now_utc();
+        let since = timestamp(since);
+
+        let vaults = sqlx::query_as::<_, VaultRow>(&format!(
+            "SELECT {VAULT_COLUMNS} FROM vaults
+            WHERE user_id = ?1
+                AND updated_at >= ?2"
+        ))
+        .bind(user_id)
+        .bind(&since)
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(Vault::from)
+        .collect();
+
+        let records = sqlx::query_as::<_, RecordRow>(
+            "SELECT r.* FROM records r
+            JOIN vaults v ON v.id = r.vault_id
+            WHERE v.user_id = ?1
+                AND r.updated_at >= ?2",
+        )
+        .bind(user_id)
+        .bind(&since)
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(Record::from)
+        .collect();
+
+        let tombstones = sqlx::query_as::<_, (String, Uuid, Uuid, OffsetDateTime)>(
+            "SELECT entity_type, entity_id, vault_id, deleted_at FROM tombstones
+            WHERE user_id = ?1
+                AND deleted_at >= ?2
+            ORDER BY deleted_at",
+        )
+        .bind(user_id)
+        .bind(&since)
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|(entity_type, entity_id, vault_id, deleted_at)| {
+            tombstone(&entity_type, entity_id, vault_id, deleted_at)
+        })
+        .collect();
+
+        tx.commit().await?;
+        Ok(Changes {
+            cursor,
+            vaults,
+            records,
+            tombstones,
+        })
+    }
+
+    async fn delete_tombstones(&self, before: OffsetDateTime) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM tombstones WHERE deleted_at < ?1")
+            .bind(timestamp(before))
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn signing_keys(&self) -> Result<Vec<SigningKey>> {
+        let rows = sqlx::query_as::<_, (String, String, Vec<u8>, OffsetDateTime, OffsetDateTime)>(
+            "SELECT kid, private_key, public_key, created_at, active_at FROM signing_keys
+            ORDER BY active_at DESC",
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(
+                |(kid, private_key, public_key, created_at, active_at)| SigningKey {
+                    kid,
+                    private_key,
+                    public_key,
+                    created_at,
+                    active_at,
+                },
+            )
+            .collect())
+    }
+
+    async fn add_signing_key(&self, key: &SigningKey) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO signing_keys (kid, private_key, public_key, created_at, active_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+        )
+        .bind(&key.kid)
+        .bind(&key.private_key)
+        .bind(&key.public_key)
+        .bind(timestamp(key.created_at))
+        .bind(timestamp(key.active_at))
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_replaced_signing_keys(&self, before: OffsetDateTime) -> Result<Vec<String>> {
+        sqlx::query_scalar(
+            "DELETE FROM signing_keys AS old
+            WHERE EXISTS (
+                SELECT 1 FROM signing_keys AS new
+                WHERE new.active_at > old.active_at AND new.active_at < ?1
+            )
+            RETURNING kid",
+        )
+        .bind(timestamp(before))
+        .fetch_all(&self.db)
+        .await
+    }
+
+    async fn schema_state(&self) -> Result<SchemaState> {
+        super::schema_state(&MIGRATOR, &self.db).await
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        MIGRATOR.run(&self.db).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_sort_as_text() {
+        let whole = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let later = whole + time::Duration::microseconds(1);
+
+        assert_eq!(timestamp(whole), "2023-11-14T22:13:20.000000Z");
+        assert!(timestamp(whole) < timestamp(later));
+        assert_eq!(
+            OffsetDateTime::parse(
+                &timestamp(later),
+                &time::format_description::well_known::Rfc3339
+            )
+            .unwrap(),
+            later
+        );
+    }
+
+    #[tokio::test]
+    async fn vault_updates_check_the_revision() {
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        store.migrate().await.unwrap();
+        let user = NewUser {
+            email: "a@example.com".to_string(),
+            salt: String::new(),
+            password_file: String::new(),
+            credential_id: Uuid::new_v4(),
+            kdf: KdfParams::default(),
+        };
+        assert!(store.create_user(&user).await.unwrap());
+        assert!(!store.create_user(&user).await.unwrap());
+        let user_id = store.user_by_email(&user.email).await.unwrap().unwrap().id;
+
+        let id = Uuid::new_v4();
+        let data = |name: &str| VaultData {
+            encrypted_name: name.to_string(),
+            encrypted_vault_key: "key".to_string(),
+        };
+        let Put::Created(vault) = store
+            .put_vault(user_id, id, &data("a"), None)
+            .await
+            .unwrap()
+        else {
+            panic!("not created");
+        };
+        assert_eq!(vault.revision, 1);
+
+        assert!(matches!(
+            store
+                .put_vault(user_id, id, &data("b"), None)
+                .await
+                .unwrap(),
+            Put::RevisionRequired
+        ));
+        assert!(matches!(
+            store
+                .put_vault(user_id, id, &data("b"), Some(2))
+                .await
+                .unwrap(),
+            Put::Stale(_)
+        ));
+        let Put::Updated(vault) = store
+            .put_vault(user_id, id, &data("b"), Some(1))
+            .await
+            .unwrap()
+        else {
+            panic!("not updated");
+        };
+        assert_eq!(vault.revision, 2);
+        assert!(matches!(
+            store
+                .put_vault(Uuid::new_v4(), id, &data("c"), Some(2))
+                .await
+                .unwrap(),
+            Put::Taken
+        ));
+
+        let record_id = Uuid::new_v4();
+        let record = RecordData {
+            encrypted_record_key: "key".to_string(),
+            encrypted_data_blob: "data".to_string(),
+        };
+        assert!(matches!(
+            store
+                .put_record(id, record_id, &record, None)
+                .await
+                .unwrap(),
+            Put::Created(_)
+        ));
+        assert!(store.delete_record(&vault, record_id).await.unwrap());
+        assert!(matches!(
+            store
+                .put_record(id, record_id, &record, Some(1))
+                .await
+                .unwrap(),
+            Put::Gone
+        ));
+
+        assert!(store.delete_vault(user_id, id).await.unwrap());
+        assert!(matches!(
+            store
+                .put_vault(user_id, id, &data("c"), Some(2))
+                .await
+                .unwrap(),
+            Put::Gone
+        ));
+        let changes = store
+            .changes(user_id, OffsetDateTime::UNIX_EPOCH)
+            .await
+            .unwrap();
+        assert!(changes.vaults.is_empty());
+        assert_eq!(changes.tombstones.len(), 2);
+
+        let before = OffsetDateTime::now_utc() + time::Duration::seconds(1);
+        assert_eq!(store.delete_tombstones(before).await.unwrap(), 2);
+        let changes = store
+            .changes(user_id, OffsetDateTime::UNIX_EPOCH)
+            .await
+            .unwrap();
+        assert!(changes.tombstones.is_empty());
+    }
+
+    #[tokio::test]
+    async fn password_changes_check_the_vault_revisions() {
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        store.migrate().await.unwrap();
+        let user = NewUser {
+            email: "a@example.com".to_string(),
+            salt: "old".to_string(),
+            password_file: String::new(),
+            credential_id: Uuid::new_v4(),
+            kdf: KdfParams::default(),
+        };
+        store.create_user(&user).await.unwrap();
+        let user_id = store.user_by_email(&user.email).await.unwrap().unwrap().id;
+
+        let data = VaultData {
+            encrypted_name: "name".to_string(),
+            encrypted_vault_key: "key".to_string(),
+        };
+        let Put::Created(vault) = store
+            .put_vault(user_id, Uuid::new_v4(), &data, None)
+            .await
+            .unwrap()
+        else {
+            panic!("not created");
+        };
+        let change = |revision| PasswordChange {
+            password_file: String::new(),
+            salt: "new".to_string(),
+            credential_id: user.credential_id,
+            kdf: KdfParams::default(),
+            vaults: vec![sanctum_shared::models::RewrappedVault {
+                id: vault.id,
+                encrypted_vault_key: "rewrapped".to_string(),
+                encrypted_name: "name".to_string(),
+                revision,
+            }],
+        };
+
+        // another device changed the vault after it was rewrapped
+        let outcome = store
+            .change_password(user_id, Uuid::new_v4(), &change(vault.revision - 1))
+            .await
+            .unwrap();
+        assert!(matches!(outcome, PasswordChangeOutcome::StaleVault));
+        assert_eq!(store.user(user_id).await.unwrap().unwrap().salt, "old");
+
+        let PasswordChangeOutcome::Changed { vaults, .. } = store
+            .change_password(user_id, Uuid::new_v4(), &change(vault.revision))
+            .await
+            .unwrap()
+        else {
+            panic!("not changed");
+        };
+        assert_eq!(vaults[0].encrypted_vault_key, "rewrapped");
+        assert_eq!(vaults[0].revision, vault.revision + 1);
+        assert!(vaults[0].updated_at >= vault.updated_at);
+    }
+
+    #[tokio::test]
+    async fn writes_in_flight_are_not_behind_the_cursor() {
+        // an in-memory database only has one connection
+        let path = std::env::temp_dir().join(format!("sanctum-{}.db", Uuid::new_v4()));
+        let store = std::sync::Arc::new(
+            SqliteStore::connect(&format!("sqlite://{}", path.display()))
+                .await
+                .unwrap(),
+        );
+        store.migrate().await.unwrap();
+        let user = NewUser {
+            email: "a@example.com".to_string(),
+            salt: String::new(),
+            password_file: String::new(),
+            credential_id: Uuid::new_v4(),
+            kdf: KdfParams::default(),
+        };
+        store.create_user(&user).await.unwrap();
+        let user_id = store.user_by_email(&user.email).await.unwrap().unwrap().id;
+
+        // a write that took its timestamp, but commits after the sync started
+        let id = Uuid::new_v4();
+        let mut tx = store.begin().await.unwrap();
+        sqlx::query(
+            "INSERT INTO vaults
+                (id, user_id, encrypted_name, encrypted_vault_key, created_at, updated_at)
+            VALUES (?1, ?2, 'name', 'key', ?3, ?3)",
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(now())
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+
+        let sync = tokio::spawn({
+            let store = store.clone();
+            async move {
+                store
+                    .changes(user_id, OffsetDateTime::UNIX_EPOCH)
+                    .await
+                    .unwrap()
+            }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        tx.commit().await.unwrap();
+
+        let first = sync.await.unwrap();
+        let next = store.changes(user_id, first.cursor).await.unwrap();
+        store.db.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        assert!(
+            first
+                .vaults
+                .iter()
+                .chain(&next.vaults)
+                .any(|vault| vault.id == id)
+        );
+    }
+
+    #[tokio::test]
+    async fn disabled_accounts_lose_their_sessions() {
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        store.migrate().await.unwrap();
+        let user = NewUser {
+            email: "a@example.com".to_string(),
+            salt: String::new(),
+            password_file: String::new(),
+            credential_id: Uuid::new_v4(),
+            kdf: KdfParams::default(),
+        };
+        store.create_user(&user).await.unwrap();
+        let user_id = store.user_by_email(&user.email).await.unwrap().unwrap().id;
+        let expires_at = OffsetDateTime::now_utc() + time::Duration::days(1);
+        let session_id = store
+            .create_session(&NewSession {
+                user_id,
+                refresh_token_hash: "a".to_string(),
+                expires_at,
+                device_name: None,
+                platform: None,
+            })
+            .await
+            .unwrap();
+
+        let revoked = store.set_disabled(user_id, true).await.unwrap();
+        assert_eq!(revoked, vec![session_id]);
+        assert!(
+            store
+                .user(user_id)
+                .await
+                .unwrap()
+                .unwrap()
+                .disabled_at
+                .is_some()
+        );
+        assert!(
+            store
+                .rotate_refresh_token("a", "b", expires_at)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        assert!(store.set_disabled(user_id, false).await.unwrap().is_empty());
+        let summaries = store.user_summaries().await.unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert!(summaries[0].disabled_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn schema_state_compares_migrations() {
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        let state = store.schema_state().await.unwrap();
+        assert_eq!(state.pending.len(), MIGRATOR.iter().count());
+        assert!(state.unknown.is_empty());
+
+        store.migrate().await.unwrap();
+        assert_eq!(store.schema_state().await.unwrap(), SchemaState::default());
+
+        // a migration of a newer version
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations
+                (version, description, success, checksum, execution_time)
+            VALUES (29991231000000, 'newer', TRUE, x'00', 0)",
+        )
+        .execute(&store.db)
+        .await
+        .unwrap();
+        let state = store.schema_state().await.unwrap();
+        assert!(state.pending.is_empty());
+        assert_eq!(state.unknown, vec![29991231000000]);
+    }
+}
@@ -0,0 +1,87 @@
+use axum::{
+    Json, Router,
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+};
+use sanctum_shared::models::SyncResponse;
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+use crate::{AppStateRef, error::ApiError, middleware::Session};
+
+/// How often expired tombstones are deleted.
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+pub fn routes() -> Router<AppStateRef> {
+    Router::new().route("/sync", get(sync))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SyncQuery {
+    /// The cursor returned by the previous sync in UNIX seconds.
+    /// When omitted, everything is returned.
+    since: Option<i64>,
+}
+
+/// GET /sync?since=<timestamp>
+///
+/// Return every vault and record of the current user that was created or
+/// updated since the given cursor, together with tombstones for everything
+/// that was deleted in the meantime.
+///
+/// Changes are selected with `>=`, like the `since` of the list endpoints,
+/// so a change that lands in the same second as the cursor is sent again on
+/// the next sync rather than being lost. The cursor is taken by the store,
+/// so writes still in flight while reading are not left behind it, see
+/// [`Store::changes`](crate::store::Store::changes).
+///
+/// Tombstones are only kept for the configured `tombstone_retention`. A
+/// cursor older than that gets everything, marked as `full`.
+async fn sync(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+    Query(params): Query<SyncQuery>,
+) -> Result<(StatusCode, Json<SyncResponse>), ApiError> {
+    let now = OffsetDateTime::now_utc();
+
+    let since = params
+        .since
+        .map(OffsetDateTime::from_unix_timestamp)
+        .transpose()
+        .map_err(|_| ApiError::BadRequest("Invalid timestamp"))?
+        .filter(|since| *since >= now - state.config.tombstone_retention());
+
+    let changes = state
+        .store
+        .changes(user_id, since.unwrap_or(OffsetDateTime::UNIX_EPOCH))
+        .await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(SyncResponse {
+            cursor: changes.cursor.unix_timestamp(),
+            full: since.is_none(),
+            vaults: changes.vaults,
+            records: changes.records,
+            tombstones: changes.tombstones,
+        }),
+    ))
+}
+
+/// Delete the tombstones older than the `tombstone_retention` every
+/// [`CLEANUP_INTERVAL`].
+pub fn spawn_cleanup(state: AppStateRef) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let before = OffsetDateTime::now_utc() - state.config.tombstone_retention();
+            match state.store.delete_tombstones(before).await {
+                Ok(0) => {}
+                Ok(count) => tracing::info!("Deleted {} expired tombstones", count),
+                Err(e) => tracing::error!("Failed to delete expired tombstones: {:?}", e),
+            }
+        }
+    });
+}
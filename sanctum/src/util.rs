@@ -1,3 +1,30 @@
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use email_address::{EmailAddress, Options};
+use rand::{RngCore, rngs::OsRng};
+use sha2::{Digest, Sha256};
+
 pub fn normalize_email(email: &str) -> String {
     email.to_lowercase()
 }
+
+/// Whether the email is a plain address (RFC 5322 `addr-spec`), without
+/// a display name.
+pub fn is_valid_email(email: &str) -> bool {
+    EmailAddress::parse_with_options(email, Options::default().without_display_text()).is_ok()
+}
+
+/// A random, URL-safe token with 256 bits of entropy.
+pub fn generate_token() -> String {
+    let mut token = [0u8; 32];
+    OsRng.fill_bytes(&mut token);
+    BASE64_URL_SAFE_NO_PAD.encode(token)
+}
+
+/// Tokens from [`generate_token`] are random, so a plain SHA-256 is enough
+/// to keep a leaked database from handing out working tokens.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
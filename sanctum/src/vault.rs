@@ -13,8 +13,9 @@ use uuid::Uuid;
 
 use crate::{
     AppStateRef,
-    middleware::{OwnedVault, Session},
-    vault,
+    error::ApiError,
+    middleware::{IfMatch, OwnedVault, Session},
+    store::{Put, RecordData, VaultData},
 };
 
 pub fn routes() -> Router<AppStateRef> {
@@ -48,43 +49,14 @@ async fn list_vaults(
     State(state): State<AppStateRef>,
     Session(user_id): Session,
     Query(params): Query<ListVaultsQuery>,
-) -> Result<(StatusCode, Json<Vec<Vault>>), StatusCode> {
-    let result = if let Some(timestamp) = params.since {
-        let since =
-            OffsetDateTime::from_unix_timestamp(timestamp).map_err(|_| StatusCode::BAD_REQUEST)?;
-
-        // fetch all vaults for the current user
-        // which were created or updated since the given timestamp
-        sqlx::query_as!(
-            Vault,
-            r#"
-            SELECT * FROM vaults
-            WHERE user_id = $1
-                AND updated_at > $2
-            "#,
-            user_id,
-            since
-        )
-        .fetch_all(&state.db)
-        .await
-    } else {
-        // fetch all vaults for the current user
-        sqlx::query_as!(
-            Vault,
-            r#"
-            SELECT * FROM vaults
-            WHERE user_id = $1
-            "#,
-            user_id
-        )
-        .fetch_all(&state.db)
-        .await
-    };
-
-    let vaults = result.map_err(|e| {
-        tracing::error!("Failed to fetch vaults: {:?}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+) -> Result<(StatusCode, Json<Vec<Vault>>), ApiError> {
+    // all vaults, or those created or updated since the given timestamp
+    let since = params
+        .since
+        .map(OffsetDateTime::from_unix_timestamp)
+        .transpose()
+        .map_err(|_| ApiError::BadRequest("Invalid timestamp"))?;
+    let vaults = state.store.vaults(user_id, since).await?;
 
     Ok((StatusCode::OK, Json(vaults)))
 }
@@ -95,19 +67,18 @@ async fn create_vault(
     State(state): State<AppStateRef>,
     Session(user_id): Session,
     Json(payload): Json<CreateVaultRequest>,
-) -> Result<(StatusCode, Json<Vault>), StatusCode> {
-    let vault = sqlx::query_as!(
-        Vault,
-        "INSERT INTO vaults
-            (user_id, encrypted_name, encrypted_vault_key)
-        VALUES ($1, $2, $3) RETURNING *",
-        user_id,
-        payload.encrypted_name,
-        payload.encrypted_vault_key
-    )
-    .fetch_one(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<(StatusCode, Json<Vault>), ApiError> {
+    let vault = state
+        .store
+        .create_vault(
+            user_id,
+            Uuid::new_v4(),
+            &VaultData {
+                encrypted_name: payload.encrypted_name,
+                encrypted_vault_key: payload.encrypted_vault_key,
+            },
+        )
+        .await?;
 
     Ok((StatusCode::CREATED, Json(vault)))
 }
@@ -118,17 +89,12 @@ async fn get_vault(
     State(state): State<AppStateRef>,
     Session(user_id): Session,
     Path(vault_id): Path<Uuid>,
-) -> Result<(StatusCode, Json<Vault>), StatusCode> {
-    let vault = sqlx::query_as!(
-        Vault,
-        "SELECT * FROM vaults WHERE id = $1 AND user_id = $2",
-        vault_id,
-        user_id
-    )
-    .fetch_optional(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-    .ok_or(StatusCode::NOT_FOUND)?;
+) -> Result<(StatusCode, Json<Vault>), ApiError> {
+    let vault = state
+        .store
+        .vault(user_id, vault_id)
+        .await?
+        .ok_or(ApiError::VaultNotFound)?;
 
     Ok((StatusCode::OK, Json(vault)))
 }
@@ -137,109 +103,48 @@ async fn get_vault(
 ///
 /// Create or update a vault with ownership check
 ///
+/// Updates must carry the revision the client last saw in `If-Match`.
+///
 /// - returns 201 Created when created
 /// - returns 200 OK when updated or idempotent
 /// - returns 409 Conflict when id exists and belongs to another user
+/// - returns 404 Not Found when updating a vault that was deleted
+/// - returns 412 Precondition Failed with the current vault when the
+///   revision is stale
+/// - returns 428 Precondition Required when updating without `If-Match`
 async fn create_or_update_vault(
     State(state): State<AppStateRef>,
     Session(user_id): Session,
     Path(vault_id): Path<Uuid>,
+    IfMatch(revision): IfMatch,
     Json(payload): Json<CreateVaultRequest>,
-) -> Result<(StatusCode, Json<Vault>), StatusCode> {
-    // start a transaction
-    let mut tx = state
-        .db
-        .begin()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    // try to select the row FOR UPDATE if it exists
-    let existing = sqlx::query_as!(
-        Vault,
-        "SELECT * FROM vaults WHERE id = $1 FOR UPDATE",
-        vault_id
-    )
-    .fetch_optional(&mut *tx)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    if let Some(existing) = existing {
-        // check if the user owns the vault
-        if existing.user_id != user_id {
-            tx.rollback()
-                .await
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-            return Err(StatusCode::CONFLICT);
-        }
-
-        // check if the payload is idempotent
-        if existing.encrypted_name == payload.encrypted_name
-            && existing.encrypted_vault_key == payload.encrypted_vault_key
-        {
-            tx.commit()
-                .await
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-            return Ok((StatusCode::OK, Json(existing)));
-        }
-
-        // update and return updated row
-        let updated = sqlx::query_as!(
-            Vault,
-            "UPDATE vaults
-            SET
-                encrypted_name = $1,
-                encrypted_vault_key = $2,
-                updated_at = now()
-            WHERE id = $3
-            RETURNING *",
-            payload.encrypted_name,
-            payload.encrypted_vault_key,
-            vault_id
+) -> Result<(StatusCode, Json<Vault>), ApiError> {
+    let put = state
+        .store
+        .put_vault(
+            user_id,
+            vault_id,
+            &VaultData {
+                encrypted_name: payload.encrypted_name,
+                encrypted_vault_key: payload.encrypted_vault_key,
+            },
+            revision,
         )
-        .fetch_one(&mut *tx)
-        .await
-        .map_err(|_| {
-            // TODO: ensure we rollback on error
-            // (map_err returns StatusCode, but we still try to rollback)
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-    }
+        .await?;
 
-    // not found -> insert with provided id
-    let created = sqlx::query_as!(
-        Vault,
-        "INSERT INTO vaults
-            (id, user_id, encrypted_name, encrypted_vault_key)
-        VALUES ($1, $2, $3, $4)
-        RETURNING *",
-        vault_id,
-        user_id,
-        payload.encrypted_name,
-        payload.encrypted_vault_key
-    )
-    .fetch_one(&mut *tx)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    tx.commit()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok((StatusCode::CREATED, Json(created)))
+    put_response(put, "The vault id is taken", ApiError::VaultNotFound)
 }
 
+/// DELETE /vaults/{vault_id}
+///
+/// Delete a vault (and with it all of its records) and leave a
+/// tombstone behind, so other devices learn about it on their next sync.
 async fn delete_vault(
     State(state): State<AppStateRef>,
     Path(vault_id): Path<Uuid>,
     Session(user_id): Session,
-) -> Result<StatusCode, StatusCode> {
-    sqlx::query!(
-        "DELETE FROM vaults WHERE id = $1 AND user_id = $2",
-        vault_id,
-        user_id
-    )
-    .execute(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<StatusCode, ApiError> {
+    state.store.delete_vault(user_id, vault_id).await?;
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -248,18 +153,26 @@ async fn delete_vault(
 //                                       Records
 // ----------------------------------------------------------------------------------------
 
+#[derive(Debug, Deserialize)]
+pub struct ListRecordsQuery {
+    /// The timestamp to start listing records from in UNIX seconds.
+    since: Option<i64>,
+}
+
+/// GET /vaults/{vault_id}/records
+/// List all records of a vault owned by the current user.
 async fn list_records(
     State(state): State<AppStateRef>,
     OwnedVault(vault): OwnedVault,
-) -> Result<(StatusCode, Json<Vec<Record>>), StatusCode> {
-    let records = sqlx::query_as!(
-        Record,
-        "SELECT * FROM records WHERE vault_id = $1",
-        vault.id,
-    )
-    .fetch_all(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Query(params): Query<ListRecordsQuery>,
+) -> Result<(StatusCode, Json<Vec<Record>>), ApiError> {
+    // all records, or those created or updated since the given timestamp
+    let since = params
+        .since
+        .map(OffsetDateTime::from_unix_timestamp)
+        .transpose()
+        .map_err(|_| ApiError::BadRequest("Invalid timestamp"))?;
+    let records = state.store.records(vault.id, since).await?;
 
     Ok((StatusCode::OK, Json(records)))
 }
@@ -268,61 +181,100 @@ async fn create_record(
     State(state): State<AppStateRef>,
     OwnedVault(vault): OwnedVault,
     Json(payload): Json<CreateRecordRequest>,
-) -> Result<(StatusCode, Json<Record>), StatusCode> {
-    let record = sqlx::query_as!(
-        Record,
-        "INSERT INTO records (vault_id, encrypted_record_key, encrypted_data_blob) VALUES ($1, $2, $3) RETURNING *",
-        vault.id,
-        payload.encrypted_record_key,
-        payload.encrypted_data_blob
-    )
-    .fetch_one(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<(StatusCode, Json<Record>), ApiError> {
+    let record = state
+        .store
+        .create_record(
+            vault.id,
+            Uuid::new_v4(),
+            &RecordData {
+                encrypted_record_key: payload.encrypted_record_key,
+                encrypted_data_blob: payload.encrypted_data_blob,
+            },
+        )
+        .await?;
 
     Ok((StatusCode::CREATED, Json(record)))
 }
 
 async fn get_record(
     State(state): State<AppStateRef>,
-    Path(record_id): Path<Uuid>,
+    Path((_, record_id)): Path<(Uuid, Uuid)>,
     OwnedVault(vault): OwnedVault,
-) -> Result<(StatusCode, Json<Record>), StatusCode> {
-    let record = sqlx::query_as!(
-        Record,
-        "SELECT * FROM records WHERE vault_id = $1 AND id = $2",
-        vault.id,
-        record_id
-    )
-    .fetch_optional(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-    .ok_or(StatusCode::NOT_FOUND)?;
+) -> Result<(StatusCode, Json<Record>), ApiError> {
+    let record = state
+        .store
+        .record(vault.id, record_id)
+        .await?
+        .ok_or(ApiError::RecordNotFound)?;
 
     Ok((StatusCode::OK, Json(record)))
 }
 
+/// PUT /vaults/{vault_id}/records/{record_id}
+///
+/// Create or update a record in a vault owned by the current user
+///
+/// Updates must carry the revision the client last saw in `If-Match`.
+///
+/// - returns 201 Created when created
+/// - returns 200 OK when updated or idempotent
+/// - returns 409 Conflict when id exists in another vault
+/// - returns 404 Not Found when updating a record that was deleted
+/// - returns 412 Precondition Failed with the current record when the
+///   revision is stale
+/// - returns 428 Precondition Required when updating without `If-Match`
 async fn update_record(
     State(state): State<AppStateRef>,
-    Session(user_id): Session,
-) -> Result<StatusCode, StatusCode> {
-    // todo!("should this really exists right now??")
-    Ok(StatusCode::NOT_IMPLEMENTED)
+    Path((_, record_id)): Path<(Uuid, Uuid)>,
+    OwnedVault(vault): OwnedVault,
+    IfMatch(revision): IfMatch,
+    Json(payload): Json<CreateRecordRequest>,
+) -> Result<(StatusCode, Json<Record>), ApiError> {
+    let put = state
+        .store
+        .put_record(
+            vault.id,
+            record_id,
+            &RecordData {
+                encrypted_record_key: payload.encrypted_record_key,
+                encrypted_data_blob: payload.encrypted_data_blob,
+            },
+            revision,
+        )
+        .await?;
+
+    put_response(put, "The record id is taken", ApiError::RecordNotFound)
 }
 
+/// DELETE /vaults/{vault_id}/records/{record_id}
+///
+/// Delete a record and leave a tombstone behind for other devices.
 async fn delete_record(
     State(state): State<AppStateRef>,
-    Path(record_id): Path<Uuid>,
+    Path((_, record_id)): Path<(Uuid, Uuid)>,
     OwnedVault(vault): OwnedVault,
-) -> Result<StatusCode, StatusCode> {
-    sqlx::query!(
-        "DELETE FROM records WHERE vault_id = $1 AND id = $2",
-        vault.id,
-        record_id
-    )
-    .execute(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<StatusCode, ApiError> {
+    state.store.delete_record(&vault, record_id).await?;
 
     Ok(StatusCode::NO_CONTENT)
 }
+
+/// The response to a PUT of a vault or record, see [`Put`].
+fn put_response<T>(
+    put: Put<T>,
+    taken: &'static str,
+    gone: ApiError,
+) -> Result<(StatusCode, Json<T>), ApiError> {
+    match put {
+        Put::Created(created) => Ok((StatusCode::CREATED, Json(created))),
+        Put::Updated(updated) => Ok((StatusCode::OK, Json(updated))),
+        // not a 409, whose body is an error like for `Taken`
+        Put::Stale(current) => Ok((StatusCode::PRECONDITION_FAILED, Json(current))),
+        Put::RevisionRequired => Err(ApiError::PreconditionRequired(
+            "Send the revision the update is based on as If-Match",
+        )),
+        Put::Taken => Err(ApiError::Conflict(taken)),
+        Put::Gone => Err(gone),
+    }
+}
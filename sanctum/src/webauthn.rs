@@ -0,0 +1,475 @@
+//! WebAuthn security keys and passkeys as a second factor for the login,
+//! as alternative to TOTP (see `mfa.rs`).
+//!
+//! The ceremonies are verified by [`sanctum_shared::webauthn`]. The server
+//! is the relying party for its public URL, the issuer of its tokens.
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use rand::{RngCore, rngs::OsRng};
+use sanctum_shared::{
+    models::{
+        LoginFinishResponse, LoginStartResponse, WebauthnCredential, WebauthnDeleteRequest,
+        WebauthnDeleteStartRequest, WebauthnLoginFinishRequest, WebauthnLoginOptions,
+        WebauthnLoginStartRequest, WebauthnRegistrationOptions, WebauthnRegistrationRequest,
+    },
+    webauthn::{self, RelyingParty},
+};
+use uuid::Uuid;
+
+use crate::{
+    AppStateRef, account,
+    error::ApiError,
+    mfa,
+    middleware::{ClientIp, Session},
+    store::NewCredential,
+    util::hash_token,
+};
+
+/// The name authenticators show for the relying party.
+const RP_NAME: &str = "Sanctum";
+
+/// How long a challenge is valid, in seconds.
+const CHALLENGE_TTL: u64 = 5 * 60;
+
+/// Longest name stored for a credential, anything beyond is cut off.
+const MAX_NAME_LENGTH: usize = 100;
+
+fn relying_party(state: &AppStateRef) -> RelyingParty<'_> {
+    RelyingParty {
+        id: state.config.public_host(),
+        origin: state.config.issuer(),
+    }
+}
+
+/// POST /auth/webauthn/register/start
+///
+/// The options for creating a credential on the authenticator. Its
+/// response goes to [`register_finish`].
+pub async fn register_start(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+) -> Result<Json<WebauthnRegistrationOptions>, ApiError> {
+    let email = state
+        .store
+        .user(user_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?
+        .email;
+    let credentials = credential_ids(&state, user_id).await?;
+
+    let challenge = generate_challenge();
+    state
+        .cache
+        .set_ex(
+            &format!("webauthn_registration_{}", user_id),
+            &challenge,
+            CHALLENGE_TTL,
+        )
+        .await?;
+
+    Ok(Json(WebauthnRegistrationOptions {
+        challenge,
+        rp_id: relying_party(&state).id.to_string(),
+        rp_name: RP_NAME.to_string(),
+        user_id: BASE64_URL_SAFE_NO_PAD.encode(user_id.as_bytes()),
+        user_name: email,
+        algorithms: webauthn::ALGORITHMS.to_vec(),
+        exclude_credentials: credentials
+            .iter()
+            .map(|id| BASE64_URL_SAFE_NO_PAD.encode(id))
+            .collect(),
+        timeout: CHALLENGE_TTL * 1000,
+    }))
+}
+
+/// POST /auth/webauthn/register/finish
+///
+/// Store the credential created with the options from [`register_start`].
+/// Logins ask for it from now on.
+///
+/// - returns 400 Bad Request when the response does not verify
+/// - returns 409 Conflict when the credential is registered already
+pub async fn register_finish(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+    Json(payload): Json<WebauthnRegistrationRequest>,
+) -> Result<Json<WebauthnCredential>, ApiError> {
+    let challenge = state
+        .cache
+        .get_del(&format!("webauthn_registration_{}", user_id))
+        .await?
+        .ok_or(ApiError::BadRequest("No registration was started"))?;
+
+    let credential = webauthn::verify_registration(
+        &relying_party(&state),
+        &b64_decode(&challenge).map_err(ApiError::internal)?,
+        &b64_decode(&payload.client_data_json)?,
+        &b64_decode(&payload.attestation_object)?,
+    )
+    .map_err(|e| {
+        tracing::debug!("Rejected WebAuthn registration: {}", e);
+        ApiError::BadRequest("The security key response does not verify")
+    })?;
+
+    let name = payload
+        .name
+        .trim()
+        .chars()
+        .take(MAX_NAME_LENGTH)
+        .collect::<String>();
+    let credential = state
+        .store
+        .add_webauthn_credential(&NewCredential {
+            user_id,
+            credential_id: credential.id,
+            public_key: credential.public_key,
+            algorithm: credential.algorithm as i32,
+            sign_count: credential.sign_count as i64,
+            name,
+        })
+        .await
+        .map_err(|e| match e.as_database_error() {
+            Some(e) if e.is_unique_violation() => {
+                ApiError::Conflict("The security key is registered already")
+            }
+            _ => {
+                tracing::error!("Failed to store WebAuthn credential: {:?}", e);
+                ApiError::Internal
+            }
+        })?;
+
+    Ok(Json(credential))
+}
+
+/// GET /auth/webauthn/credentials
+pub async fn list_credentials(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+) -> Result<Json<Vec<WebauthnCredential>>, ApiError> {
+    let credentials = state.store.webauthn_credentials(user_id).await?;
+
+    Ok(Json(credentials))
+}
+
+/// POST /auth/webauthn/credentials/delete/start
+///
+/// Start the OPAQUE login that proves the password for
+/// [`delete_credential`].
+pub async fn delete_credential_start(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+    Json(payload): Json<WebauthnDeleteStartRequest>,
+) -> Result<Json<LoginStartResponse>, ApiError> {
+    let user = state
+        .store
+        .user(user_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+
+    account::start_proof(&state, &user, "webauthn_delete", &payload.client_start)
+        .await
+        .map(Json)
+}
+
+/// DELETE /auth/webauthn/credentials/{credential_id}
+///
+/// Remove a second factor, after finishing the login started with
+/// [`delete_credential_start`]. Like disabling TOTP, the session alone is
+/// not enough.
+///
+/// - returns 401 Unauthorized for a wrong password or an unknown login id
+/// - returns 404 Not Found when the user has no such credential
+/// - returns 429 Too Many Requests while the account is locked
+pub async fn delete_credential(
+    State(state): State<AppStateRef>,
+    Session(user_id): Session,
+    Path(credential_id): Path<Uuid>,
+    Json(payload): Json<WebauthnDeleteRequest>,
+) -> Result<StatusCode, ApiError> {
+    let email = state
+        .store
+        .user(user_id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?
+        .email;
+    account::finish_proof(
+        &state,
+        user_id,
+        &email,
+        "webauthn_delete",
+        &payload.login_id,
+        &payload.client_finish,
+    )
+    .await?;
+
+    let deleted = state
+        .store
+        .delete_webauthn_credential(user_id, credential_id)
+        .await?;
+
+    if !deleted {
+        return Err(ApiError::CredentialNotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /auth/webauthn/login/start
+///
+/// The options for an assertion that finishes a login waiting for its
+/// second factor, see [`mfa::challenge`].
+///
+/// - returns 401 Unauthorized for an unknown MFA token
+pub async fn login_start(
+    State(state): State<AppStateRef>,
+    ClientIp(ip): ClientIp,
+    Json(payload): Json<WebauthnLoginStartRequest>,
+) -> Result<Json<WebauthnLoginOptions>, ApiError> {
+    let pending = mfa::pending_login(&state, ip, &payload.mfa_token).await?;
+
+    let credentials = credential_ids(&state, pending.user_id).await?;
+    if credentials.is_empty() {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let challenge = generate_challenge();
+    state
+        .cache
+        .set_ex(&login_key(&payload.mfa_token), &challenge, CHALLENGE_TTL)
+        .await?;
+
+    Ok(Json(WebauthnLoginOptions {
+        challenge,
+        rp_id: relying_party(&state).id.to_string(),
+        allow_credentials: credentials
+            .iter()
+            .map(|id| BASE64_URL_SAFE_NO_PAD.encode(id))
+            .collect(),
+        timeout: CHALLENGE_TTL * 1000,
+    }))
+}
+
+/// POST /auth/webauthn/login/finish
+///
+/// Finish a login with the assertion for the options from [`login_start`].
+///
+/// - returns 401 Unauthorized when the assertion does not verify
+/// - failed assertions count towards the lockout of the account
+pub async fn login_finish(
+    State(state): State<AppStateRef>,
+    ClientIp(ip): ClientIp,
+    Json(payload): Json<WebauthnLoginFinishRequest>,
+) -> Result<Json<LoginFinishResponse>, ApiError> {
+    let pending = mfa::pending_login(&state, ip, &payload.mfa_token).await?;
+
+    // every challenge is good for a single assertion
+    let challenge = state
+        .cache
+        .get_del(&login_key(&payload.mfa_token))
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+
+    if !use_assertion(&state, pending.user_id, &challenge, &payload).await? {
+        return Err(mfa::reject_code(&state, &payload.mfa_token, &pending).await);
+    }
+    mfa::finish_login(&state, &payload.mfa_token, pending).await
+}
+
+/// Verify an assertion by one of the user's credentials, and store its
+/// new signature counter.
+async fn use_assertion(
+    state: &AppStateRef,
+    user_id: Uuid,
+    challenge: &str,
+    payload: &WebauthnLoginFinishRequest,
+) -> Result<bool, ApiError> {
+    let credential_id = b64_decode(&payload.credential_id)?;
+    let Some(credential) = state
+        .store
+        .webauthn_credential(user_id, &credential_id)
+        .await?
+    else {
+        return Ok(false);
+    };
+
+    let sign_count = match webauthn::verify_assertion(
+        &relying_party(state),
+        &b64_decode(challenge).map_err(ApiError::internal)?,
+        &credential.public_key,
+        credential.sign_count as u32,
+        &b64_decode(&payload.client_data_json)?,
+        &b64_decode(&payload.authenticator_data)?,
+        &b64_decode(&payload.signature)?,
+    ) {
+        Ok(sign_count) => sign_count,
+        Err(e) => {
+            tracing::warn!("Rejected WebAuthn assertion for {}: {}", user_id, e);
+            return Ok(false);
+        }
+    };
+
+    // a concurrent login may have used a later assertion in the meantime
+    Ok(state
+        .store
+        .update_sign_count(credential.id, sign_count as i64)
+        .await?)
+}
+
+async fn credential_ids(state: &AppStateRef, user_id: Uuid) -> Result<Vec<Vec<u8>>, ApiError> {
+    Ok(state.store.webauthn_credential_ids(user_id).await?)
+}
+
+/// A random challenge, base64url encoded the way it comes back in the
+/// client data.
+fn generate_challenge() -> String {
+    let mut challenge = [0u8; 32];
+    OsRng.fill_bytes(&mut challenge);
+    BASE64_URL_SAFE_NO_PAD.encode(challenge)
+}
+
+fn login_key(mfa_token: &str) -> String {
+    format!("webauthn_login_{}", hash_token(mfa_token))
+}
+
+fn b64_decode(value: &str) -> Result<Vec<u8>, ApiError> {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(value)
+        .map_err(|_| ApiError::BadRequest("Invalid base64"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use base64::prelude::BASE64_STANDARD;
+    use opaque_ke::ServerSetup;
+    use sanctum_shared::models::KdfParams;
+
+    use super::*;
+    use crate::{
+        AppState,
+        cache::MemoryCache,
+        config::Config,
+        jwt,
+        mail::{Mailer, Transport},
+        store::{NewUser, SqliteStore, Store},
+    };
+
+    const PASSWORD: &[u8] = b"correct horse battery staple";
+
+    #[tokio::test]
+    async fn deleting_a_credential_takes_the_password() {
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        store.migrate().await.unwrap();
+        let state = Arc::new(AppState {
+            config: Config::default(),
+            server_setup: ServerSetup::new(&mut OsRng),
+            store: Arc::new(store),
+            cache: Arc::new(MemoryCache::new()),
+            signing_keys: jwt::SigningKeys::new("0123456789abcdef0123456789abcdef"),
+            mfa_key: [0; 32],
+            mailer: Mailer::new("sanctum@example.com".parse().unwrap(), Transport::Log),
+        });
+
+        let credential_id = Uuid::new_v4();
+        let (client, message) = sanctum_shared::register::client_start(PASSWORD).unwrap();
+        let message = sanctum_shared::register::server_start(
+            &state.server_setup,
+            credential_id.as_bytes(),
+            &message,
+        )
+        .unwrap();
+        let message = sanctum_shared::register::client_finish(PASSWORD, &client, &message).unwrap();
+        let user = NewUser {
+            email: "a@example.com".to_string(),
+            salt: String::new(),
+            password_file: BASE64_STANDARD
+                .encode(sanctum_shared::register::server_finish(&message).unwrap()),
+            credential_id,
+            kdf: KdfParams::default(),
+        };
+        state.store.create_user(&user).await.unwrap();
+        let user_id = state
+            .store
+            .user_by_email(&user.email)
+            .await
+            .unwrap()
+            .unwrap()
+            .id;
+        let credential = state
+            .store
+            .add_webauthn_credential(&NewCredential {
+                user_id,
+                credential_id: vec![1, 2, 3],
+                public_key: vec![4, 5, 6],
+                algorithm: -7,
+                sign_count: 0,
+                name: "Key".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let delete = |login_id: String, client_finish: String| {
+            delete_credential(
+                State(state.clone()),
+                Session(user_id),
+                Path(credential.id),
+                Json(WebauthnDeleteRequest {
+                    login_id,
+                    client_finish,
+                }),
+            )
+        };
+
+        // the session alone doesn't do
+        assert!(matches!(
+            delete("unknown".to_string(), String::new()).await,
+            Err(ApiError::Unauthorized)
+        ));
+        assert_eq!(
+            state
+                .store
+                .webauthn_credentials(user_id)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let (client, message) = sanctum_shared::login::client_start(PASSWORD).unwrap();
+        let Json(start) = delete_credential_start(
+            State(state.clone()),
+            Session(user_id),
+            Json(WebauthnDeleteStartRequest {
+                client_start: BASE64_STANDARD.encode(message),
+            }),
+        )
+        .await
+        .unwrap();
+        let message = sanctum_shared::login::client_finish(
+            PASSWORD,
+            &client,
+            &BASE64_STANDARD.decode(start.message).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            delete(start.login_id, BASE64_STANDARD.encode(message))
+                .await
+                .unwrap(),
+            StatusCode::NO_CONTENT
+        );
+        assert!(
+            state
+                .store
+                .webauthn_credentials(user_id)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+}